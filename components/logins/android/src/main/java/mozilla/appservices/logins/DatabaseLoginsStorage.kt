@@ -92,17 +92,20 @@ class DatabaseLoginsStorage(private val dbPath: String) : AutoCloseable, LoginsS
     }
 
     @Throws(LoginsStorageException::class)
-    override fun sync(syncInfo: SyncUnlockInfo) {
-        rustCallWithLock { raw, error ->
+    override fun sync(syncInfo: SyncUnlockInfo, force: Boolean): Long? {
+        val nextSyncAfter = rustCallWithLock { raw, error ->
+            val forceArg: Byte = if (force) { 1 } else { 0 }
             PasswordSyncAdapter.INSTANCE.sync15_passwords_sync(
                     raw,
                     syncInfo.kid,
                     syncInfo.fxaAccessToken,
                     syncInfo.syncKey,
                     syncInfo.tokenserverURL,
+                    forceArg,
                     error
             )
         }
+        return if (nextSyncAfter == 0L) null else nextSyncAfter
     }
 
     @Throws(LoginsStorageException::class)
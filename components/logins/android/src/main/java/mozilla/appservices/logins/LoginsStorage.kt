@@ -76,12 +76,17 @@ interface LoginsStorage : AutoCloseable {
     /**
      * Synchronize the logins storage layer with a remote layer.
      *
+     * @param force Sync even if the server asked us to back off. This should
+     * only be used for syncs the user explicitly asked for.
+     * @return The time, in milliseconds since the epoch, before which the
+     * server asked us not to sync again, or null if it didn't.
      * @throws [SyncAuthInvalidException] if authentication needs to be refreshed
      * @throws [RequestFailedException] if there was a network error during connection.
+     * @throws [SyncBackoffException] if the server asked us to back off, and [force] is false.
      * @throws [LoginsStorageException] On unexpected errors (IO failure, rust panics, etc)
      */
     @Throws(LoginsStorageException::class)
-    fun sync(syncInfo: SyncUnlockInfo)
+    fun sync(syncInfo: SyncUnlockInfo, force: Boolean = false): Long?
 
     /**
      * Delete all locally stored login sync metadata (last sync timestamps, etc).
@@ -51,3 +51,9 @@ class InvalidKeyException(msg: String) : LoginsStorageException(msg)
  * This error is emitted if a request to a sync server failed.
  */
 class RequestFailedException(msg: String) : LoginsStorageException(msg)
+
+/**
+ * This error is emitted if we didn't sync because the server asked us to
+ * back off. The sync should be retried later.
+ */
+class SyncBackoffException(msg: String) : LoginsStorageException(msg)
@@ -88,9 +88,10 @@ class MemoryLoginsStorage(private var list: List<ServerPassword>) : AutoCloseabl
 
     @Synchronized
     @Throws(LoginsStorageException::class)
-    override fun sync(syncInfo: SyncUnlockInfo) {
+    override fun sync(syncInfo: SyncUnlockInfo, force: Boolean): Long? {
         checkUnlocked()
         Log.w("MemoryLoginsStorage", "Not syncing because this implementation can not sync")
+        return null
     }
 
     @Synchronized
@@ -74,8 +74,9 @@ internal interface PasswordSyncAdapter : Library {
         access_token: String,
         sync_key: String,
         token_server_url: String,
+        force: Byte,
         error: RustError.ByReference
-    )
+    ): Long
 
     fun sync15_passwords_wipe(handle: LoginsDbHandle, error: RustError.ByReference)
     fun sync15_passwords_wipe_local(handle: LoginsDbHandle, error: RustError.ByReference)
@@ -13,6 +13,7 @@ import mozilla.appservices.logins.LoginsStorageException
 import mozilla.appservices.logins.NoSuchRecordException
 import mozilla.appservices.logins.RequestFailedException
 import mozilla.appservices.logins.SyncAuthInvalidException
+import mozilla.appservices.logins.SyncBackoffException
 import mozilla.appservices.logins.getAndConsumeRustString
 import mozilla.appservices.logins.getRustString
 
@@ -49,6 +50,7 @@ open class RustError : Structure() {
             4 -> return InvalidRecordException(message)
             5 -> return InvalidKeyException(message)
             6 -> return RequestFailedException(message)
+            8 -> return SyncBackoffException(message)
             else -> return LoginsStorageException(message)
         }
     }
@@ -365,7 +365,7 @@ fn main() -> Result<()> {
             'S' | 's' => {
                 log::info!("Syncing!");
                 let mut sync_ping = telemetry::SyncTelemetryPing::new();
-                if let Err(e) = engine.sync(&cli_fxa.client_init, &cli_fxa.root_sync_key, &mut sync_ping) {
+                if let Err(e) = engine.sync(&cli_fxa.client_init, &cli_fxa.root_sync_key, Some(telemetry::SyncReason::User), &mut sync_ping, false) {
                     log::warn!("Sync failed! {}", e);
                     log::warn!("BT: {:?}", e.backtrace());
                 } else {
@@ -14,6 +14,7 @@ use ffi_support::{
 };
 use logins::{Login, PasswordEngine, Result};
 use std::os::raw::c_char;
+use std::time::UNIX_EPOCH;
 use sync15::telemetry;
 
 fn logging_init() {
@@ -116,20 +117,30 @@ pub extern "C" fn sync15_passwords_sync(
     access_token: FfiStr<'_>,
     sync_key: FfiStr<'_>,
     tokenserver_url: FfiStr<'_>,
+    force: u8,
     error: &mut ExternError,
-) {
+) -> i64 {
     log::debug!("sync15_passwords_sync");
-    ENGINES.call_with_result(error, handle, |state| -> Result<()> {
+    ENGINES.call_with_result(error, handle, |state| -> Result<i64> {
         let mut sync_ping = telemetry::SyncTelemetryPing::new();
-        state.sync(
+        let next_sync_after = state.sync(
             &sync15::Sync15StorageClientInit {
                 key_id: key_id.into_string(),
                 access_token: access_token.into_string(),
                 tokenserver_url: parse_url(tokenserver_url.as_str())?,
             },
             &sync15::KeyBundle::from_ksync_base64(sync_key.as_str())?,
+            // The apps don't tell us why they're syncing yet.
+            None,
             &mut sync_ping,
-        )
+            force != 0,
+        )?;
+        // Returns the time before which we shouldn't sync again, in
+        // milliseconds since the epoch, or 0 if the server didn't ask us to
+        // wait.
+        Ok(next_sync_after
+            .and_then(|when| when.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_millis() as i64))
     })
 }
 
@@ -46,6 +46,10 @@ public enum LoginsStoreError: Error {
     /// abort some operation.
     case interrupted(message: String)
 
+    /// This error is emitted if we didn't sync because the server asked us
+    /// to back off.
+    case syncBackoff(message: String)
+
     // The name is attempting to indicate that we free rustError.message if it
     // existed, and that it's a very bad idea to touch it after you call this
     // function
@@ -83,6 +87,9 @@ public enum LoginsStoreError: Error {
         case Sync15Passwords_InterruptedError:
             return .interrupted(message: String(freeingRustString: message!))
 
+        case Sync15Passwords_SyncBackoffError:
+            return .syncBackoff(message: String(freeingRustString: message!))
+
         default:
             return .Unspecified(message: String(freeingRustString: message!))
         }
@@ -129,13 +129,20 @@ open class LoginsStorage {
         close()
     }
 
-    /// Synchronize with the server.
-    open func sync(unlockInfo: SyncUnlockInfo) throws {
-        try queue.sync(execute: {
+    /// Synchronize with the server. If `force` is true, we sync even if the
+    /// server asked us to back off; this should only be used for syncs the
+    /// user explicitly asked for. Returns the time, in milliseconds since the
+    /// epoch, before which the server asked us not to sync again, or nil if
+    /// it didn't. Throws `LoginsStoreError.syncBackoff` if the server asked us
+    /// to back off, and `force` is false.
+    @discardableResult
+    open func sync(unlockInfo: SyncUnlockInfo, force: Bool = false) throws -> Int64? {
+        return try queue.sync(execute: {
             let engine = try self.getUnlocked()
-            try LoginsStoreError.unwrap({ err in
-                sync15_passwords_sync(engine, unlockInfo.kid, unlockInfo.fxaAccessToken, unlockInfo.syncKey, unlockInfo.tokenserverURL, err)
+            let nextSyncAfter = try LoginsStoreError.unwrap({ err in
+                sync15_passwords_sync(engine, unlockInfo.kid, unlockInfo.fxaAccessToken, unlockInfo.syncKey, unlockInfo.tokenserverURL, force ? 1 : 0, err)
             })
+            return nextSyncAfter == 0 ? nil : nextSyncAfter
         })
     }
 
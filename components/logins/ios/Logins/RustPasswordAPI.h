@@ -16,6 +16,7 @@ typedef enum Sync15PasswordsErrorCode {
     Sync15Passwords_InvalidKeyError  = 5,
     Sync15Passwords_NetworkError     = 6,
     Sync15Passwords_InterruptedError = 7,
+    Sync15Passwords_SyncBackoffError = 8,
 } Sync15PasswordsErrorCode;
 
 typedef struct Sync15PasswordsError {
@@ -50,12 +51,13 @@ char *_Nullable sync15_passwords_get_by_id(Sync15PasswordEngineHandle handle,
 char *_Nullable sync15_passwords_get_all(Sync15PasswordEngineHandle handle,
                                          Sync15PasswordsError *_Nonnull error_out);
 
-void sync15_passwords_sync(Sync15PasswordEngineHandle handle,
-                           char const *_Nonnull key_id,
-                           char const *_Nonnull access_token,
-                           char const *_Nonnull sync_key,
-                           char const *_Nonnull token_server_url,
-                           Sync15PasswordsError *_Nonnull error);
+int64_t sync15_passwords_sync(Sync15PasswordEngineHandle handle,
+                              char const *_Nonnull key_id,
+                              char const *_Nonnull access_token,
+                              char const *_Nonnull sync_key,
+                              char const *_Nonnull token_server_url,
+                              uint8_t force,
+                              Sync15PasswordsError *_Nonnull error);
 
 void sync15_passwords_wipe(Sync15PasswordEngineHandle handle,
                            Sync15PasswordsError *_Nonnull error);
@@ -15,15 +15,15 @@ use rusqlite::{
 };
 use sql_support::{self, ConnExt};
 use sql_support::{SqlInterruptHandle, SqlInterruptScope};
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::ops::Deref;
 use std::path::Path;
 use std::result;
 use std::sync::{atomic::AtomicUsize, Arc};
 use std::time::SystemTime;
 use sync15::{
-    extract_v1_state, telemetry, CollSyncIds, CollectionRequest, IncomingChangeset,
-    OutgoingChangeset, Payload, ServerTimestamp, Store, StoreSyncAssociation,
+    extract_v1_state, telemetry, CollSyncIds, CollectionRequest, IncomingChangeset, LocalChange,
+    OutgoingChangeset, Payload, ServerTimestamp, Store, StorePreview, StoreSyncAssociation,
 };
 
 pub struct LoginDb {
@@ -570,6 +570,12 @@ impl LoginDb {
     pub fn reset(&self, assoc: &StoreSyncAssociation) -> Result<()> {
         log::info!("Executing reset on password store!");
         let tx = self.db.unchecked_transaction()?;
+        self.reset_in_tx(assoc)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn reset_in_tx(&self, assoc: &StoreSyncAssociation) -> Result<()> {
         self.execute_all(&[
             &*CLONE_ENTIRE_MIRROR_SQL,
             "DELETE FROM loginsM",
@@ -587,6 +593,48 @@ impl LoginDb {
             }
         };
         self.delete_meta(schema::GLOBAL_STATE_META_KEY)?;
+        Ok(())
+    }
+
+    /// Makes sure the sync state is for the account with the hashed uid
+    /// `uid`. If the user switched accounts, we stash the other account's
+    /// sync IDs and global state, and restore the ones for `uid` if we've
+    /// synced it before. Local logins are kept, but the mirror only describes
+    /// the other account's server, so we reset, and the next sync merges
+    /// everything. If the restored sync IDs still match the server, that
+    /// sync isn't a first sync, so it only uploads the logins which aren't
+    /// on the server, or changed more recently locally.
+    pub fn use_sync_account(&self, uid: &str) -> Result<()> {
+        let last_uid = self.get_meta::<String>(schema::SYNC_ACCOUNT_META_KEY)?;
+        if last_uid.as_ref().map(String::as_str) == Some(uid) {
+            return Ok(());
+        }
+        let tx = self.unchecked_transaction()?;
+        // If we haven't recorded an account yet, the state we have is for
+        // this one.
+        if let Some(last_uid) = last_uid {
+            log::info!("Sync account changed, switching sync state");
+            for &key in schema::ACCOUNT_META_KEYS {
+                let stashed_key = format!("{}:{}", key, last_uid);
+                self.delete_meta(&stashed_key)?;
+                self.execute_named_cached(
+                    "INSERT INTO loginsSyncMeta (key, value)
+                     SELECT :stashed_key, value FROM loginsSyncMeta WHERE key = :key",
+                    named_params! { ":stashed_key": stashed_key, ":key": key },
+                )?;
+            }
+            self.reset_in_tx(&StoreSyncAssociation::Disconnected)?;
+            for &key in schema::ACCOUNT_META_KEYS {
+                let stashed_key = format!("{}:{}", key, uid);
+                self.execute_named_cached(
+                    "INSERT INTO loginsSyncMeta (key, value)
+                     SELECT :key, value FROM loginsSyncMeta WHERE key = :stashed_key",
+                    named_params! { ":key": key, ":stashed_key": stashed_key },
+                )?;
+                self.delete_meta(&stashed_key)?;
+            }
+        }
+        self.put_meta(schema::SYNC_ACCOUNT_META_KEY, &uid)?;
         tx.commit()?;
         Ok(())
     }
@@ -754,6 +802,34 @@ impl LoginDb {
         Ok(self.fetch_outgoing(inbound.timestamp, scope)?)
     }
 
+    fn do_preview_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+        scope: &SqlInterruptScope,
+    ) -> Result<StorePreview> {
+        let before = self.get_all()?;
+        let data = self.fetch_login_data(&inbound.changes, scope)?;
+        let plan = self.reconcile(data, inbound.timestamp, telem, scope)?;
+        // Execute the plan so that we can see what it changes, then roll it
+        // back. Dropping the transaction on error also rolls it back.
+        let tx = self.db.unchecked_transaction()?;
+        plan.execute(&tx, scope)?;
+        let after = self.get_all()?;
+        let outgoing = self.fetch_outgoing(inbound.timestamp, scope)?;
+        tx.rollback()?;
+
+        let snapshot = |logins: Vec<Login>| -> HashMap<String, Login> {
+            logins
+                .into_iter()
+                .map(|login| (login.id.clone(), login))
+                .collect()
+        };
+        let mut preview = StorePreview::new(outgoing);
+        preview.local_changes = LocalChange::between(snapshot(before), snapshot(after));
+        Ok(preview)
+    }
+
     fn put_meta(&self, key: &str, value: &dyn ToSql) -> Result<()> {
         self.execute_named_cached(
             "REPLACE INTO loginsSyncMeta (key, value) VALUES (:key, :value)",
@@ -892,6 +968,14 @@ impl<'a> Store for LoginStore<'a> {
         self.db.wipe(&self.scope)?;
         Ok(())
     }
+
+    fn preview_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> result::Result<StorePreview, failure::Error> {
+        Ok(self.db.do_preview_incoming(inbound, telem, &self.scope)?)
+    }
 }
 
 lazy_static! {
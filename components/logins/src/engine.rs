@@ -6,9 +6,10 @@ use crate::error::*;
 use crate::login::Login;
 use std::cell::Cell;
 use std::path::Path;
+use std::time::SystemTime;
 use sync15::{
-    sync_multiple, telemetry, KeyBundle, MemoryCachedState, StoreSyncAssociation,
-    Sync15StorageClientInit,
+    preview_multiple, sync_multiple, telemetry, KeyBundle, MemoryCachedState, StorePreview,
+    StoreSyncAssociation, Sync15StorageClientInit, SyncResult,
 };
 
 // This isn't really an engine in the firefox sync15 desktop sense -- it's
@@ -91,16 +92,33 @@ impl PasswordEngine {
         self.db.new_interrupt_handle()
     }
 
-    /// A convenience wrapper around sync_multiple.
+    /// A convenience wrapper around sync_multiple. `reason` is why the app
+    /// started this sync, if it knows, for telemetry. If `force` is true, we
+    /// sync even if the server asked us to back off; this should only be
+    /// used for syncs explicitly requested by the user. Returns the time
+    /// before which the server asked us not to sync again, if any.
     pub fn sync(
         &self,
         storage_init: &Sync15StorageClientInit,
         root_sync_key: &KeyBundle,
+        reason: Option<telemetry::SyncReason>,
         sync_ping: &mut telemetry::SyncTelemetryPing,
-    ) -> Result<()> {
+        force: bool,
+    ) -> Result<Option<SystemTime>> {
         // migrate our V1 state - this needn't live for long.
         self.db.migrate_global_state()?;
 
+        // Make sure the state we sync with is for this account, in case the
+        // user switched accounts. Even on failure we set the persisted
+        // state, so that we remember any backoff the tokenserver asked for.
+        let mut disk_cached_state = self.db.get_global_state()?;
+        let mut mem_cached_state = self.mem_cached_state.take();
+        let uid =
+            mem_cached_state.hashed_uid(&mut disk_cached_state, storage_init, sync_ping, force);
+        self.mem_cached_state.replace(mem_cached_state);
+        self.db.set_global_state(&disk_cached_state)?;
+        self.db.use_sync_account(&uid?)?;
+
         let mut disk_cached_state = self.db.get_global_state()?;
         let mut mem_cached_state = self.mem_cached_state.take();
         let store = LoginStore::new(&self.db);
@@ -111,15 +129,21 @@ impl PasswordEngine {
             &mut mem_cached_state,
             storage_init,
             root_sync_key,
+            None,
+            reason,
             sync_ping,
             &store.scope,
+            force,
         );
         // We always update the state - sync_multiple does the right thing
         // if it needs to be dropped (ie, they will be None or contain Nones etc)
         self.db.set_global_state(&disk_cached_state)?;
-        let failures = result?;
+        let SyncResult {
+            failures,
+            next_sync_after,
+        } = result?;
         if failures.is_empty() {
-            Ok(())
+            Ok(next_sync_after)
         } else {
             assert_eq!(failures.len(), 1);
             let (name, err) = failures.into_iter().next().unwrap();
@@ -127,6 +151,30 @@ impl PasswordEngine {
             Err(err.into())
         }
     }
+
+    /// Performs a dry run of `sync`, returning the changes it would make to
+    /// the local logins and the records it would upload, without changing
+    /// anything locally or on the server. Returns `None` if the passwords
+    /// engine is declined.
+    pub fn preview_sync(
+        &self,
+        storage_init: &Sync15StorageClientInit,
+        root_sync_key: &KeyBundle,
+    ) -> Result<Option<StorePreview>> {
+        let disk_cached_state = self.db.get_global_state()?;
+        let store = LoginStore::new(&self.db);
+        let mut preview = preview_multiple(
+            &[&store],
+            &disk_cached_state,
+            storage_init,
+            root_sync_key,
+            &store.scope,
+        )?;
+        if let Some(err) = preview.failures.remove("passwords") {
+            return Err(err.into());
+        }
+        Ok(preview.stores.remove("passwords"))
+    }
 }
 
 #[cfg(test)]
@@ -135,6 +183,7 @@ mod test {
     use crate::util;
     use more_asserts::*;
     use std::time::SystemTime;
+    use sync15::{CollSyncIds, Store};
     // Doesn't check metadata fields
     fn assert_logins_equiv(a: &Login, b: &Login) {
         assert_eq!(b.id, a.id);
@@ -251,6 +300,49 @@ mod test {
         // Should be two even though we updated twice
         assert_eq!(b_after_update.times_used, 2);
     }
+
+    #[test]
+    fn test_switch_sync_account() {
+        let engine = PasswordEngine::new_in_memory(Some("secret")).unwrap();
+        let id = engine
+            .add(Login {
+                hostname: "https://www.example.com".into(),
+                http_realm: Some("Some String Here".into()),
+                username: "coolperson21".into(),
+                password: "p4ssw0rd".into(),
+                ..Login::default()
+            })
+            .unwrap();
+        let alice_ids = CollSyncIds {
+            global: "alice-global".into(),
+            coll: "alice-coll".into(),
+        };
+        engine.db.use_sync_account("alice").unwrap();
+        engine
+            .db
+            .reset(&StoreSyncAssociation::Connected(alice_ids.clone()))
+            .unwrap();
+        engine
+            .db
+            .set_global_state(&Some("alice-state".into()))
+            .unwrap();
+        let get_sync_assoc = || LoginStore::new(&engine.db).get_sync_assoc().unwrap();
+
+        // Switching to a new account starts over, but keeps local logins.
+        engine.db.use_sync_account("bob").unwrap();
+        assert_eq!(get_sync_assoc(), StoreSyncAssociation::Disconnected);
+        assert_eq!(engine.db.get_global_state().unwrap(), None);
+        assert!(engine.get(&id).unwrap().is_some());
+
+        // Switching back restores the first account's state.
+        engine.db.use_sync_account("alice").unwrap();
+        assert_eq!(get_sync_assoc(), StoreSyncAssociation::Connected(alice_ids));
+        assert_eq!(
+            engine.db.get_global_state().unwrap(),
+            Some("alice-state".into())
+        );
+        assert!(engine.get(&id).unwrap().is_some());
+    }
 }
 
 #[test]
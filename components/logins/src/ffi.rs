@@ -37,6 +37,9 @@ pub mod error_codes {
 
     /// A request to the sync server failed.
     pub const INTERRUPTED: i32 = 6;
+
+    /// We didn't sync because the server asked us to back off.
+    pub const BACKOFF: i32 = 8;
 }
 
 fn get_code(err: &Error) -> ErrorCode {
@@ -48,6 +51,7 @@ fn get_code(err: &Error) -> ErrorCode {
                     ErrorCode::new(error_codes::AUTH_INVALID)
                 }
                 Sync15ErrorKind::RequestError(_) => ErrorCode::new(error_codes::NETWORK),
+                Sync15ErrorKind::BackoffError(_) => ErrorCode::new(error_codes::BACKOFF),
                 _ => ErrorCode::new(error_codes::UNEXPECTED),
             }
         }
@@ -87,6 +87,10 @@
 //!    [GLOBAL_STATE_META_KEY]. This is a `sync15::GlobalState` stored as
 //!    JSON.
 //!
+//! 3. The hashed uid of the account we're syncing is stored under
+//!    [SYNC_ACCOUNT_META_KEY]. The global state and sync IDs for other
+//!    accounts are stored under their keys suffixed with `:` and the uid.
+//!
 
 use crate::error::*;
 use lazy_static::lazy_static;
@@ -209,6 +213,15 @@ pub(crate) static LAST_SYNC_META_KEY: &str = "last_sync_time";
 pub(crate) static GLOBAL_STATE_META_KEY: &str = "global_state_v2";
 pub(crate) static GLOBAL_SYNCID_META_KEY: &str = "global_sync_id";
 pub(crate) static COLLECTION_SYNCID_META_KEY: &str = "passwords_sync_id";
+// The hashed uid of the account the sync state is for. The state for other
+// accounts the user has synced is stashed under the keys below, suffixed with
+// their uid.
+pub(crate) static SYNC_ACCOUNT_META_KEY: &str = "sync_account_uid";
+pub(crate) static ACCOUNT_META_KEYS: &[&str] = &[
+    GLOBAL_STATE_META_KEY,
+    GLOBAL_SYNCID_META_KEY,
+    COLLECTION_SYNCID_META_KEY,
+];
 
 pub(crate) fn init(db: &Connection) -> Result<()> {
     let user_version = db.query_one::<i64>("PRAGMA user_version")?;
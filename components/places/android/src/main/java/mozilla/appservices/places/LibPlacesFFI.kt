@@ -52,6 +52,7 @@ internal interface LibPlacesFFI : Library {
     /** Create a new places api */
     fun places_api_new(
         db_path: String,
+        json_frecency_settings: String?,
         out_err: RustError.ByReference
     ): PlacesApiHandle
 
@@ -143,6 +144,12 @@ internal interface LibPlacesFFI : Library {
         out_err: RustError.ByReference
     )
 
+    fun places_recalculate_stale_frecencies(
+        handle: PlacesConnectionHandle,
+        maxRows: Int,
+        out_err: RustError.ByReference
+    ): Long
+
     fun places_prune_destructively(
         handle: PlacesConnectionHandle,
         out_err: RustError.ByReference
@@ -169,6 +176,48 @@ internal interface LibPlacesFFI : Library {
         error: RustError.ByReference
     ): RustBuffer.ByValue
 
+    fun places_get_visit_groups(
+        handle: PlacesConnectionHandle,
+        grouping: Int,
+        utcOffsetMinutes: Int,
+        offset: Long,
+        count: Long,
+        excludeTypes: Int,
+        error: RustError.ByReference
+    ): RustBuffer.ByValue
+
+    fun places_get_top_sites(
+        handle: PlacesConnectionHandle,
+        limit: Int,
+        error: RustError.ByReference
+    ): RustBuffer.ByValue
+
+    fun places_pin_top_site(
+        handle: PlacesConnectionHandle,
+        url: String,
+        title: String?,
+        position: Int,
+        error: RustError.ByReference
+    )
+
+    fun places_unpin_top_site(
+        handle: PlacesConnectionHandle,
+        url: String,
+        error: RustError.ByReference
+    )
+
+    fun places_block_top_site(
+        handle: PlacesConnectionHandle,
+        url: String,
+        error: RustError.ByReference
+    )
+
+    fun places_unblock_top_site(
+        handle: PlacesConnectionHandle,
+        url: String,
+        error: RustError.ByReference
+    )
+
     fun places_get_visit_count(
         handle: PlacesConnectionHandle,
         excludeTypes: Int,
@@ -181,8 +230,9 @@ internal interface LibPlacesFFI : Library {
         access_token: String,
         sync_key: String,
         tokenserver_url: String,
+        force: Byte,
         out_err: RustError.ByReference
-    )
+    ): Long
 
     fun sync15_bookmarks_sync(
         handle: PlacesConnectionHandle,
@@ -190,8 +240,9 @@ internal interface LibPlacesFFI : Library {
         access_token: String,
         sync_key: String,
         tokenserver_url: String,
+        force: Byte,
         out_err: RustError.ByReference
-    )
+    ): Long
 
     fun bookmarks_get_all_with_url(
         handle: PlacesConnectionHandle,
@@ -244,6 +295,12 @@ internal interface LibPlacesFFI : Library {
     /** Destroy strings returned from libplaces_ffi calls. */
     fun places_destroy_string(s: Pointer)
 
+    fun places_api_set_frecency_settings(
+        handle: PlacesApiHandle,
+        jsonSettings: String,
+        error: RustError.ByReference
+    )
+
     fun places_api_return_write_conn(
         apiHandle: PlacesApiHandle,
         writeHandle: PlacesConnectionHandle,
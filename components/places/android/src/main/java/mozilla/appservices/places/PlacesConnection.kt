@@ -24,14 +24,20 @@ import java.lang.ref.WeakReference
  * where necessary).
  *
  * @param path an absolute path to a file that will be used for the internal database.
+ * @param frecencySettings the settings to calculate frecency with, as for
+ *  [PlacesManager.setFrecencySettings], or null to use the defaults.
  */
-class PlacesApi(path: String) : PlacesManager, AutoCloseable {
+class PlacesApi(
+    path: String,
+    frecencySettings: Map<String, Int>? = null
+) : PlacesManager, AutoCloseable {
     private var handle: AtomicLong = AtomicLong(0)
     private var writeConn: PlacesWriterConnection
 
     init {
+        val json = frecencySettings?.let { JSONObject(it).toString() }
         handle.set(rustCall(this) { error ->
-            LibPlacesFFI.INSTANCE.places_api_new(path, error)
+            LibPlacesFFI.INSTANCE.places_api_new(path, json, error)
         })
         writeConn = PlacesWriterConnection(rustCall(this) { error ->
             LibPlacesFFI.INSTANCE.places_connection_new(handle.get(), READ_WRITE, error)
@@ -77,30 +83,43 @@ class PlacesApi(path: String) : PlacesManager, AutoCloseable {
         }
     }
 
-    override fun syncHistory(syncInfo: SyncAuthInfo) {
-        rustCall(this) { error ->
+    override fun syncHistory(syncInfo: SyncAuthInfo, force: Boolean): Long? {
+        val nextSyncAfter = rustCall(this) { error ->
+            val forceArg: Byte = if (force) { 1 } else { 0 }
             LibPlacesFFI.INSTANCE.sync15_history_sync(
                     this.handle.get(),
                     syncInfo.kid,
                     syncInfo.fxaAccessToken,
                     syncInfo.syncKey,
                     syncInfo.tokenserverURL,
+                    forceArg,
                     error
             )
         }
+        return if (nextSyncAfter == 0L) null else nextSyncAfter
     }
 
-    override fun syncBookmarks(syncInfo: SyncAuthInfo) {
-        rustCall(this) { error ->
+    override fun syncBookmarks(syncInfo: SyncAuthInfo, force: Boolean): Long? {
+        val nextSyncAfter = rustCall(this) { error ->
+            val forceArg: Byte = if (force) { 1 } else { 0 }
             LibPlacesFFI.INSTANCE.sync15_bookmarks_sync(
                     this.handle.get(),
                     syncInfo.kid,
                     syncInfo.fxaAccessToken,
                     syncInfo.syncKey,
                     syncInfo.tokenserverURL,
+                    forceArg,
                     error
             )
         }
+        return if (nextSyncAfter == 0L) null else nextSyncAfter
+    }
+
+    override fun setFrecencySettings(settings: Map<String, Int>) {
+        val json = JSONObject(settings).toString()
+        rustCall(this) { error ->
+            LibPlacesFFI.INSTANCE.places_api_set_frecency_settings(this.handle.get(), json, error)
+        }
     }
 }
 
@@ -270,6 +289,38 @@ open class PlacesReaderConnection internal constructor(connHandle: Long) :
         }
     }
 
+    override fun getVisitGroups(
+        grouping: VisitGrouping,
+        offset: Long,
+        count: Long,
+        utcOffsetMinutes: Int,
+        excludeTypes: List<VisitType>
+    ): List<VisitGroup> {
+        val groupBuffer = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_get_visit_groups(
+                    this.handle.get(), grouping.type, utcOffsetMinutes, offset, count,
+                    visitTransitionSet(excludeTypes), error)
+        }
+        try {
+            val groups = MsgTypes.HistoryVisitGroups.parseFrom(groupBuffer.asCodedInputStream()!!)
+            return VisitGroup.fromMessage(groups)
+        } finally {
+            LibPlacesFFI.INSTANCE.places_destroy_bytebuffer(groupBuffer)
+        }
+    }
+
+    override fun getTopSites(limit: Int): List<TopSite> {
+        val sitesBuffer = rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_get_top_sites(this.handle.get(), limit, error)
+        }
+        try {
+            val sites = MsgTypes.TopSites.parseFrom(sitesBuffer.asCodedInputStream()!!)
+            return TopSite.fromMessage(sites)
+        } finally {
+            LibPlacesFFI.INSTANCE.places_destroy_bytebuffer(sitesBuffer)
+        }
+    }
+
     override fun getVisitCount(excludeTypes: List<VisitType>): Long {
         return rustCall { error ->
             LibPlacesFFI.INSTANCE.places_get_visit_count(
@@ -375,6 +426,31 @@ class PlacesWriterConnection internal constructor(connHandle: Long, api: PlacesA
         }
     }
 
+    override fun pinTopSite(url: String, position: Int, title: String?) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_pin_top_site(
+                    this.handle.get(), url, title, position, error)
+        }
+    }
+
+    override fun unpinTopSite(url: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_unpin_top_site(this.handle.get(), url, error)
+        }
+    }
+
+    override fun blockTopSite(url: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_block_top_site(this.handle.get(), url, error)
+        }
+    }
+
+    override fun unblockTopSite(url: String) {
+        rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_unblock_top_site(this.handle.get(), url, error)
+        }
+    }
+
     override fun deleteVisitsSince(since: Long) {
         deleteVisitsBetween(since, Long.MAX_VALUE)
     }
@@ -398,6 +474,13 @@ class PlacesWriterConnection internal constructor(connHandle: Long, api: PlacesA
         }
     }
 
+    override fun recalculateStaleFrecencies(maxRows: Int): Long {
+        return rustCall { error ->
+            LibPlacesFFI.INSTANCE.places_recalculate_stale_frecencies(
+                    this.handle.get(), maxRows, error)
+        }
+    }
+
     override fun pruneDestructively() {
         rustCall { error ->
             LibPlacesFFI.INSTANCE.places_prune_destructively(this.handle.get(), error)
@@ -517,8 +600,14 @@ interface PlacesManager {
      * take some time due to the network etc. Because only 1 thread can be
      * using a PlacesAPI at a time, it is recommended, but not enforced, that
      * you have all connections you intend using open before calling this.
+     *
+     * @param force Sync even if the server asked us to back off. This should
+     * only be used for syncs the user explicitly asked for.
+     * @return The time, in milliseconds since the epoch, before which the
+     * server asked us not to sync again, or null if it didn't.
+     * @throws SyncBackoff if the server asked us to back off, and [force] is false.
      */
-    fun syncHistory(syncInfo: SyncAuthInfo)
+    fun syncHistory(syncInfo: SyncAuthInfo, force: Boolean = false): Long?
 
     /**
      * Syncs the places bookmarks store.
@@ -527,8 +616,24 @@ interface PlacesManager {
      * take some time due to the network etc. Because only 1 thread can be
      * using a PlacesAPI at a time, it is recommended, but not enforced, that
      * you have all connections you intend using open before calling this.
+     *
+     * The arguments, result and errors are as for [syncHistory].
      */
-    fun syncBookmarks(syncInfo: SyncAuthInfo)
+    fun syncBookmarks(syncInfo: SyncAuthInfo, force: Boolean = false): Long?
+
+    /**
+     * Changes the weights and bonuses used to calculate frecency, for
+     * experimenting with them.
+     *
+     * This marks all frecencies as stale. New visits use the new settings
+     * right away, but existing frecencies keep their old values until they're
+     * recalculated with [WritableHistoryConnection.recalculateStaleFrecencies].
+     *
+     * @param settings The settings to change, by their name in
+     *  `places::frecency::FrecencySettings`, like `typed_visit_bonus`. Any
+     *  settings that aren't in the map use their defaults.
+     */
+    fun setFrecencySettings(settings: Map<String, Int>)
 }
 
 interface InterruptibleConnection : AutoCloseable {
@@ -604,6 +709,36 @@ interface ReadableHistoryConnection : InterruptibleConnection {
      */
     fun getVisitPage(offset: Long, count: Long, excludeTypes: List<VisitType> = listOf()): List<VisitInfo>
 
+    /**
+     * Return a "page" of groups of visits, for showing history by day or by
+     * site. Day groups are in descending order by day, and site groups are
+     * in descending order by their most recent visit.
+     *
+     * @param grouping Whether to group visits by day or by site.
+     * @param offset The offset where the page begins.
+     * @param count The number of groups to return in the page.
+     * @param utcOffsetMinutes For day groups, how far ahead of UTC the user's
+     *  timezone is, in minutes, so that days start at local midnight.
+     * @param excludeTypes List of visit types to exclude.
+     */
+    fun getVisitGroups(
+        grouping: VisitGrouping,
+        offset: Long,
+        count: Long,
+        utcOffsetMinutes: Int = 0,
+        excludeTypes: List<VisitType> = listOf()
+    ): List<VisitGroup>
+
+    /**
+     * Return the top sites for the new tab page: the sites the user pinned,
+     * at their positions, and the user's most frecent sites, one per site,
+     * in the other positions. Hidden pages, like redirect sources, error
+     * pages, and sites the user removed with `blockTopSite` are skipped.
+     *
+     * @param limit The maximum number of sites to return.
+     */
+    fun getTopSites(limit: Int): List<TopSite>
+
     /**
      * Get the number of history visits.
      *
@@ -649,6 +784,20 @@ interface WritableHistoryConnection : ReadableHistoryConnection {
      */
     fun runMaintenance()
 
+    /**
+     * Recalculate frecencies that are out of date, for example, after a
+     * large history import, or after changing the frecency settings with
+     * [PlacesManager.setFrecencySettings]. Like [runMaintenance], this
+     * should be called when the app is idle, until it returns 0.
+     *
+     * Frecencies are recalculated in chunks, so this can be interrupted
+     * with [interrupt] without losing the chunks that were already done.
+     *
+     * @param maxRows The maximum number of frecencies to recalculate.
+     * @return The number of out of date frecencies remaining.
+     */
+    fun recalculateStaleFrecencies(maxRows: Int = 1000): Long
+
     /**
      * Aggressively prune history visits. These deletions are not intended
      * to be synced, however due to the way history sync works, this can
@@ -725,6 +874,41 @@ interface WritableHistoryConnection : ReadableHistoryConnection {
      * @param visitTimestamp The timestamp of the visit to delete, in MS since the unix epoch
      */
     fun deleteVisit(url: String, visitTimestamp: Long)
+
+    /**
+     * Pin a site to a position in the top sites, replacing the site that
+     * was pinned there. If the site was blocked, it's unblocked.
+     *
+     * @param url The URL of the site to pin.
+     * @param position The position to pin it to, starting at 0.
+     * @param title The title to show for the site, or null to use the
+     *  page's title from history.
+     */
+    fun pinTopSite(url: String, position: Int, title: String? = null)
+
+    /**
+     * Unpin a site from the top sites. It will still appear if it's frecent
+     * enough.
+     *
+     * @param url The URL of the site to unpin.
+     */
+    fun unpinTopSite(url: String)
+
+    /**
+     * Remove a site from the top sites, and unpin it, until it's unblocked
+     * or pinned again. This doesn't remove the site from history.
+     *
+     * @param url The URL of the site to block.
+     */
+    fun blockTopSite(url: String)
+
+    /**
+     * Allow a site removed with `blockTopSite` to appear in the top sites
+     * again.
+     *
+     * @param url The URL of the site to unblock.
+     */
+    fun unblockTopSite(url: String)
 }
 
 class InterruptHandle internal constructor(raw: RawPlacesInterruptHandle) : AutoCloseable {
@@ -757,6 +941,12 @@ open class UrlParseFailed(msg: String) : PlacesException(msg)
 open class PlacesConnectionBusy(msg: String) : PlacesException(msg)
 open class OperationInterrupted(msg: String) : PlacesException(msg)
 
+/**
+ * Thrown when we don't sync because the server asked us to back off. The
+ * sync should be retried later.
+ */
+open class SyncBackoff(msg: String) : PlacesException(msg)
+
 enum class VisitType(val type: Int) {
     /** This isn't a visit, but a request to update meta data about a page */
     UPDATE_PLACE(-1),
@@ -776,6 +966,20 @@ enum class VisitType(val type: Int) {
     RELOAD(9)
 }
 
+/**
+ * How [ReadableHistoryConnection.getVisitGroups] groups visits.
+ */
+enum class VisitGrouping(val type: Int) {
+    /** By the local day the visits were made on. */
+    DAY(0),
+    /**
+     * By the base domain ("eTLD+1") of the visited page, like
+     * "example.co.uk" for "www.example.co.uk", so that subdomains of a site
+     * are grouped together.
+     */
+    BASE_DOMAIN(1)
+}
+
 private val intToVisitType: Map<Int, VisitType> = VisitType.values().associateBy(VisitType::type)
 
 /**
@@ -884,3 +1088,85 @@ data class VisitInfo(
         }
     }
 }
+
+/**
+ * A group of history visits. Returned by `PlacesAPI.getVisitGroups`.
+ */
+data class VisitGroup(
+    /**
+     * For [VisitGrouping.DAY], the start of the local day, in integer
+     * milliseconds since the unix epoch.
+     */
+    val dayStart: Long?,
+
+    /**
+     * For [VisitGrouping.BASE_DOMAIN], the base domain of the visited pages.
+     */
+    val baseDomain: String?,
+
+    /**
+     * The time of the earliest visit in the group, in integer milliseconds
+     * since the unix epoch.
+     */
+    val firstVisitTime: Long,
+
+    /**
+     * The time of the most recent visit in the group, in integer
+     * milliseconds since the unix epoch.
+     */
+    val lastVisitTime: Long,
+
+    /**
+     * The number of visits in the group.
+     */
+    val visitCount: Long
+) {
+    companion object {
+        internal fun fromMessage(msg: MsgTypes.HistoryVisitGroups): List<VisitGroup> {
+            return msg.groupsList.map {
+                VisitGroup(dayStart = if (it.hasDayStart()) it.dayStart else null,
+                    baseDomain = if (it.hasBaseDomain()) it.baseDomain else null,
+                    firstVisitTime = it.firstVisit,
+                    lastVisitTime = it.lastVisit,
+                    visitCount = it.visitCount)
+            }
+        }
+    }
+}
+
+/**
+ * A site on the new tab page. Returned by `PlacesAPI.getTopSites`.
+ */
+data class TopSite(
+    /**
+     * The URL of the site.
+     */
+    val url: String,
+
+    /**
+     * The title of the site, if known.
+     */
+    val title: String?,
+
+    /**
+     * The frecency of the site's page, or 0 for a pinned site that hasn't
+     * been visited.
+     */
+    val frecency: Long,
+
+    /**
+     * Whether the user pinned the site to its position.
+     */
+    val pinned: Boolean
+) {
+    companion object {
+        internal fun fromMessage(msg: MsgTypes.TopSites): List<TopSite> {
+            return msg.sitesList.map {
+                TopSite(url = it.url,
+                    title = if (it.hasTitle()) it.title else null,
+                    frecency = it.frecency,
+                    pinned = it.pinned)
+            }
+        }
+    }
+}
@@ -45,6 +45,7 @@ internal open class RustError : Structure() {
             3 -> return PlacesConnectionBusy(message)
             4 -> return OperationInterrupted(message)
             5 -> return BookmarksCorruption(message)
+            6 -> return SyncBackoff(message)
 
             64 -> return InvalidParent(message)
             65 -> return UnknownBookmarkItem(message)
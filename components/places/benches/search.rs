@@ -46,7 +46,7 @@ pub struct TestDb {
 
 impl TestDb {
     pub fn new() -> Rc<Self> {
-        use std::sync::{Arc, Mutex};
+        use std::sync::{Arc, Mutex, RwLock};
         let dir = TempDir::new("placesbench").unwrap();
         let file = dir.path().join("places.sqlite");
         let mut db = PlacesDb::open(
@@ -54,6 +54,7 @@ impl TestDb {
             ConnectionType::ReadWrite,
             0,
             Arc::new(Mutex::new(())),
+            Arc::new(RwLock::new(Default::default())),
         )
         .unwrap();
         println!("Populating test database...");
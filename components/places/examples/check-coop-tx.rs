@@ -7,11 +7,12 @@
 // % RUST_LOG=places::db::tx=debug cargo run --example check-coop-tx
 
 use places::api::places_api::ConnectionType;
+use places::frecency::FrecencySettings;
 use places::PlacesDb;
 use rusqlite::NO_PARAMS;
 use std::fs::remove_file;
 use std::sync::mpsc::sync_channel;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 
 type Result<T> = std::result::Result<T, failure::Error>;
@@ -35,12 +36,27 @@ fn main() -> Result<()> {
     let _ = env_logger::try_init();
 
     let coop_tx_lock = Arc::new(Mutex::new(()));
+    let frecency_settings = Arc::new(RwLock::new(FrecencySettings::default()));
 
-    let dbmain = PlacesDb::open(path, ConnectionType::ReadWrite, 0, coop_tx_lock.clone()).unwrap();
+    let dbmain = PlacesDb::open(
+        path,
+        ConnectionType::ReadWrite,
+        0,
+        coop_tx_lock.clone(),
+        frecency_settings.clone(),
+    )
+    .unwrap();
     let (tx, rx) = sync_channel(0);
 
     let child = thread::spawn(move || {
-        let db1 = PlacesDb::open(path, ConnectionType::Sync, 0, coop_tx_lock.clone()).unwrap();
+        let db1 = PlacesDb::open(
+            path,
+            ConnectionType::Sync,
+            0,
+            coop_tx_lock.clone(),
+            frecency_settings.clone(),
+        )
+        .unwrap();
         // assert_eq!(rx.recv().unwrap(), 0);
         let mut t = db1
             .begin_transaction()
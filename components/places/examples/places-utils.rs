@@ -237,8 +237,11 @@ fn sync(
         &mut mem_cached_state,
         &cli_fxa.client_init.clone(),
         &cli_fxa.root_sync_key,
+        None,
+        Some(telemetry::SyncReason::User),
         &mut sync_ping,
         &interruptee,
+        false,
     ) {
         log::warn!("Sync failed! {}", e);
         log::warn!("BT: {:?}", e.backtrace());
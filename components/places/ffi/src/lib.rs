@@ -13,13 +13,15 @@ use ffi_support::{
     define_string_destructor, ByteBuffer, ConcurrentHandleMap, ExternError, FfiStr,
 };
 use places::error::*;
+use places::frecency::FrecencySettings;
 use places::msg_types::BookmarkNodeList;
 use places::storage::bookmarks;
-use places::types::{SyncGuid, VisitTransitionSet};
+use places::types::{SyncGuid, Timestamp, VisitTransitionSet};
 use places::{storage, ConnectionType, PlacesApi, PlacesDb};
 use sql_support::SqlInterruptHandle;
 use std::os::raw::c_char;
 use std::sync::Arc;
+use std::time::SystemTime;
 
 use places::api::matcher::{match_url, search_frecent, SearchParams};
 
@@ -28,6 +30,12 @@ fn parse_url(url: &str) -> sync15::Result<url::Url> {
     Ok(url::Url::parse(url)?)
 }
 
+// The sync functions return the time before which we shouldn't sync again,
+// in milliseconds since the epoch, or 0 if the server didn't ask us to wait.
+fn next_sync_after_millis(next_sync_after: Option<SystemTime>) -> i64 {
+    next_sync_after.map_or(0, |when| Timestamp::from(when).as_millis() as i64)
+}
+
 #[no_mangle]
 pub extern "C" fn places_enable_logcat_logging() {
     #[cfg(target_os = "android")]
@@ -49,12 +57,26 @@ lazy_static::lazy_static! {
 
 /// Instantiate a places API. Returned api must be freed with
 /// `places_api_destroy`. Returns null and logs on errors (for now).
+///
+/// `json_frecency_settings` is either null, to calculate frecency with the
+/// default settings, or a JSON object with the settings to change, as for
+/// `places_api_set_frecency_settings`.
 #[no_mangle]
-pub extern "C" fn places_api_new(db_path: FfiStr<'_>, error: &mut ExternError) -> u64 {
+pub extern "C" fn places_api_new(
+    db_path: FfiStr<'_>,
+    json_frecency_settings: FfiStr<'_>,
+    error: &mut ExternError,
+) -> u64 {
     log::debug!("places_api_new");
-    APIS.insert_with_result(error, || {
+    APIS.insert_with_result(error, || -> places::Result<_> {
         let path = db_path.as_str();
-        PlacesApi::new(path)
+        match json_frecency_settings.as_opt_str() {
+            Some(json) => {
+                let settings: FrecencySettings = serde_json::from_str(json)?;
+                PlacesApi::new_with_frecency_settings(path, settings)
+            }
+            None => PlacesApi::new(path),
+        }
     })
 }
 
@@ -103,6 +125,22 @@ pub extern "C" fn places_api_return_write_conn(
     })
 }
 
+/// Changes the settings used to calculate frecency. `json_settings` is a JSON
+/// object with the `places::frecency::FrecencySettings` to change; any
+/// settings that aren't in it use their defaults.
+#[no_mangle]
+pub extern "C" fn places_api_set_frecency_settings(
+    handle: u64,
+    json_settings: FfiStr<'_>,
+    error: &mut ExternError,
+) {
+    log::debug!("places_api_set_frecency_settings");
+    APIS.call_with_result(error, handle, |api| -> places::Result<_> {
+        let settings: FrecencySettings = serde_json::from_str(json_settings.as_str())?;
+        api.set_frecency_settings(settings)
+    })
+}
+
 /// Get the interrupt handle for a connection. Must be destroyed with
 /// `places_interrupt_handle_destroy`.
 #[no_mangle]
@@ -282,6 +320,24 @@ pub extern "C" fn places_run_maintenance(handle: u64, error: &mut ExternError) {
     CONNECTIONS.call_with_result(error, handle, |conn| storage::run_maintenance(conn))
 }
 
+/// Recalculates up to `max_rows` stale frecencies, and returns the number of
+/// stale frecencies remaining. This can be interrupted with the connection's
+/// interrupt handle.
+#[no_mangle]
+pub extern "C" fn places_recalculate_stale_frecencies(
+    handle: u64,
+    max_rows: i32,
+    error: &mut ExternError,
+) -> i64 {
+    log::debug!("places_recalculate_stale_frecencies");
+    CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        let scope = conn.begin_interrupt_scope();
+        let progress =
+            storage::history::recalculate_stale_frecencies(conn, max_rows as usize, &scope)?;
+        Ok(progress.remaining as i64)
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn places_prune_destructively(handle: u64, error: &mut ExternError) {
     log::debug!("places_prune_destructively");
@@ -358,6 +414,93 @@ pub extern "C" fn places_get_visit_page(
     })
 }
 
+/// Returns a page of groups of visits, as a `HistoryVisitGroups` protobuf.
+/// `grouping` is 0 to group visits by local day, in a timezone
+/// `utc_offset_minutes` ahead of UTC, or 1 to group them by site.
+#[no_mangle]
+pub extern "C" fn places_get_visit_groups(
+    handle: u64,
+    grouping: i32,
+    utc_offset_minutes: i32,
+    offset: i64,
+    count: i64,
+    exclude_types: i32,
+    error: &mut ExternError,
+) -> ByteBuffer {
+    log::debug!("places_get_visit_groups");
+    CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        let grouping = match grouping {
+            0 => storage::history::VisitGrouping::Day { utc_offset_minutes },
+            1 => storage::history::VisitGrouping::BaseDomain,
+            // Note: it's a bug in our FFI android (or swift, eventually) code
+            // if this fires.
+            _ => panic!("Bug: Invalid visit grouping {}", grouping),
+        };
+        storage::history::get_visit_groups(
+            conn,
+            grouping,
+            offset,
+            count,
+            VisitTransitionSet::from_u16(exclude_types as u16)
+                .expect("Bug: Invalid VisitTransitionSet"),
+        )
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_get_top_sites(
+    handle: u64,
+    limit: i32,
+    error: &mut ExternError,
+) -> ByteBuffer {
+    log::debug!("places_get_top_sites");
+    CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        storage::top_sites::get_top_sites(conn, limit as u32)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_pin_top_site(
+    handle: u64,
+    url: FfiStr<'_>,
+    title: FfiStr<'_>,
+    position: i32,
+    error: &mut ExternError,
+) {
+    log::debug!("places_pin_top_site");
+    CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        let url = parse_url(url.as_str())?;
+        storage::top_sites::pin_top_site(conn, &url, title.as_opt_str(), position as u32)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_unpin_top_site(handle: u64, url: FfiStr<'_>, error: &mut ExternError) {
+    log::debug!("places_unpin_top_site");
+    CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        let url = parse_url(url.as_str())?;
+        storage::top_sites::unpin_top_site(conn, &url)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_block_top_site(handle: u64, url: FfiStr<'_>, error: &mut ExternError) {
+    log::debug!("places_block_top_site");
+    CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        let url = parse_url(url.as_str())?;
+        storage::top_sites::block_top_site(conn, &url)
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn places_unblock_top_site(handle: u64, url: FfiStr<'_>, error: &mut ExternError) {
+    log::debug!("places_unblock_top_site");
+    CONNECTIONS.call_with_result(error, handle, |conn| -> places::Result<_> {
+        let url = parse_url(url.as_str())?;
+        storage::top_sites::unblock_top_site(conn, &url)
+    })
+}
+
 #[no_mangle]
 pub extern "C" fn sync15_history_sync(
     handle: u64,
@@ -365,20 +508,24 @@ pub extern "C" fn sync15_history_sync(
     access_token: FfiStr<'_>,
     sync_key: FfiStr<'_>,
     tokenserver_url: FfiStr<'_>,
+    force: u8,
     error: &mut ExternError,
-) {
+) -> i64 {
     log::debug!("sync15_history_sync");
     APIS.call_with_result(error, handle, |api| -> places::Result<_> {
-        // Note that api.sync returns a SyncPing which we drop on the floor.
-        api.sync_history(
+        // Note that we drop the SyncPing in the result on the floor.
+        let result = api.sync_history(
             &sync15::Sync15StorageClientInit {
                 key_id: key_id.into_string(),
                 access_token: access_token.into_string(),
                 tokenserver_url: parse_url(tokenserver_url.as_str())?,
             },
             &sync15::KeyBundle::from_ksync_base64(sync_key.as_str())?,
+            // The apps don't tell us why they're syncing yet.
+            None,
+            force != 0,
         )?;
-        Ok(())
+        Ok(next_sync_after_millis(result.next_sync_after))
     })
 }
 
@@ -389,20 +536,24 @@ pub extern "C" fn sync15_bookmarks_sync(
     access_token: FfiStr<'_>,
     sync_key: FfiStr<'_>,
     tokenserver_url: FfiStr<'_>,
+    force: u8,
     error: &mut ExternError,
-) {
+) -> i64 {
     log::debug!("sync15_bookmarks_sync");
     APIS.call_with_result(error, handle, |api| -> places::Result<_> {
-        // Note that api.sync returns a SyncPing which we drop on the floor.
-        api.sync_bookmarks(
+        // Note that we drop the SyncPing in the result on the floor.
+        let result = api.sync_bookmarks(
             &sync15::Sync15StorageClientInit {
                 key_id: key_id.into_string(),
                 access_token: access_token.into_string(),
                 tokenserver_url: parse_url(tokenserver_url.as_str())?,
             },
             &sync15::KeyBundle::from_ksync_base64(sync_key.as_str())?,
+            // The apps don't tell us why they're syncing yet.
+            None,
+            force != 0,
         )?;
-        Ok(())
+        Ok(next_sync_after_millis(result.next_sync_after))
     })
 }
 
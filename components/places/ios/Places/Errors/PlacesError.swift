@@ -35,6 +35,9 @@ public enum PlacesError: Error {
     /// The requested operation failed because the store is corrupt
     case databaseCorrupt(message: String)
 
+    /// We didn't sync because the server asked us to back off.
+    case syncBackoff(message: String)
+
     /// Thrown on insertions and updates that specify a parent which
     /// is not a folder
     case invalidParent(message: String)
@@ -100,6 +103,9 @@ public enum PlacesError: Error {
         case Places_Corrupt:
             return .databaseCorrupt(message: String(freeingPlacesString: message!))
 
+        case Places_SyncBackoff:
+            return .syncBackoff(message: String(freeingPlacesString: message!))
+
         default:
             return .unexpected(message: String(freeingPlacesString: message!))
         }
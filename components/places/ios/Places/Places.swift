@@ -29,7 +29,7 @@ public class PlacesAPI {
      */
     public init(path: String) throws {
         let handle = try PlacesError.unwrap { error in
-            places_api_new(path, error)
+            places_api_new(path, nil, error)
         }
         self.handle = handle
         do {
@@ -102,12 +102,22 @@ public class PlacesAPI {
 
     /**
      * Sync the bookmarks collection.
+     *
+     * - Parameter force: Sync even if the server asked us to back off. This
+     *                    should only be used for syncs the user explicitly
+     *                    asked for.
+     * - Returns: The time, in milliseconds since the epoch, before which the
+     *            server asked us not to sync again, or nil if it didn't.
+     * - Throws: `PlacesError.syncBackoff` if the server asked us to back off,
+     *           and `force` is false.
      */
-    open func syncBookmarks(unlockInfo: SyncUnlockInfo) throws {
+    @discardableResult
+    open func syncBookmarks(unlockInfo: SyncUnlockInfo, force: Bool = false) throws -> Int64? {
         return try queue.sync {
-            try PlacesError.unwrap { err in
-                sync15_bookmarks_sync(handle, unlockInfo.kid, unlockInfo.fxaAccessToken, unlockInfo.syncKey, unlockInfo.tokenserverURL, err)
+            let nextSyncAfter = try PlacesError.unwrap { err in
+                sync15_bookmarks_sync(handle, unlockInfo.kid, unlockInfo.fxaAccessToken, unlockInfo.syncKey, unlockInfo.tokenserverURL, force ? 1 : 0, err)
             }
+            return nextSyncAfter == 0 ? nil : nextSyncAfter
         }
     }
 }
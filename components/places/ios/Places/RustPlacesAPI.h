@@ -16,6 +16,7 @@ typedef enum PlacesErrorCode {
     Places_DatabaseBusy = 3,
     Places_DatabaseInterrupted = 4,
     Places_Corrupt = 5,
+    Places_SyncBackoff = 6,
 
     Places_InvalidPlace_InvalidParent = 64 + 0,
     Places_InvalidPlace_NoSuchItem = 64 + 1,
@@ -47,6 +48,7 @@ enum {
 };
 
 PlacesAPIHandle places_api_new(const char *_Nonnull db_path,
+                               const char *_Nullable json_frecency_settings,
                                PlacesRustError *_Nonnull out_err);
 
 
@@ -122,19 +124,21 @@ PlacesRustBuffer places_get_visit_infos(PlacesConnectionHandle handle,
                                         int32_t exclude_types,
                                         PlacesRustError *_Nonnull out_err);
 
-void sync15_history_sync(PlacesConnectionHandle handle,
-                         char const *_Nonnull key_id,
-                         char const *_Nonnull access_token,
-                         char const *_Nonnull sync_key,
-                         char const *_Nonnull tokenserver_url,
-                         PlacesRustError *_Nonnull out_err);
+int64_t sync15_history_sync(PlacesConnectionHandle handle,
+                            char const *_Nonnull key_id,
+                            char const *_Nonnull access_token,
+                            char const *_Nonnull sync_key,
+                            char const *_Nonnull tokenserver_url,
+                            uint8_t force,
+                            PlacesRustError *_Nonnull out_err);
 
-void sync15_bookmarks_sync(PlacesConnectionHandle handle,
-                           char const *_Nonnull key_id,
-                           char const *_Nonnull access_token,
-                           char const *_Nonnull sync_key,
-                           char const *_Nonnull tokenserver_url,
-                           PlacesRustError *_Nonnull out_err);
+int64_t sync15_bookmarks_sync(PlacesConnectionHandle handle,
+                              char const *_Nonnull key_id,
+                              char const *_Nonnull access_token,
+                              char const *_Nonnull sync_key,
+                              char const *_Nonnull tokenserver_url,
+                              uint8_t force,
+                              PlacesRustError *_Nonnull out_err);
 
 // MARK: Bookmarks APIs
 
@@ -167,6 +167,28 @@ CREATE TABLE IF NOT EXISTS moz_tags_relation(
     PRIMARY KEY(tag_id, place_id)
 ) WITHOUT ROWID;
 
+-- URLs the user removed from their top sites, which we won't suggest again.
+CREATE TABLE IF NOT EXISTS moz_topsites_blocked(
+    url TEXT PRIMARY KEY
+) WITHOUT ROWID;
+
+-- Sites the user pinned to a position in their top sites. These don't need to
+-- be in moz_places.
+CREATE TABLE IF NOT EXISTS moz_topsites_pinned(
+    position INTEGER PRIMARY KEY,
+    url TEXT NOT NULL UNIQUE,
+    title TEXT
+);
+
+-- A full-text index over the titles, URLs, and tags of places, for searching
+-- history. The rowid is the place's id. Triggers for the read-write and Sync
+-- connections keep it up to date.
+CREATE VIRTUAL TABLE IF NOT EXISTS moz_places_fts USING fts5(
+    title,
+    url,
+    tags
+);
+
 -- This table holds synced items, including tombstones. It's unused if Sync
 -- isn't configured. At the end of a sync, this table's contents should match
 -- both what's on the server, and the local tree in `moz_bookmarks`.
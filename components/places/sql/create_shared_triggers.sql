@@ -28,6 +28,30 @@ BEGIN
     DELETE FROM moz_places_tombstones WHERE guid = NEW.guid;
 END;
 
+-- These triggers keep the full-text index in sync with moz_places. Tags are
+-- indexed by the tag triggers below.
+CREATE TEMP TRIGGER moz_places_afterinsert_trigger_fts
+AFTER INSERT ON moz_places FOR EACH ROW
+BEGIN
+    INSERT INTO moz_places_fts(rowid, title, url, tags)
+    VALUES (NEW.id, IFNULL(NEW.title, ''), NEW.url, '');
+END;
+
+CREATE TEMP TRIGGER moz_places_afterupdate_trigger_fts
+AFTER UPDATE OF title, url ON moz_places FOR EACH ROW
+BEGIN
+    UPDATE moz_places_fts SET
+        title = IFNULL(NEW.title, ''),
+        url = NEW.url
+    WHERE rowid = NEW.id;
+END;
+
+CREATE TEMP TRIGGER moz_places_afterdelete_trigger_fts
+AFTER DELETE ON moz_places FOR EACH ROW
+BEGIN
+    DELETE FROM moz_places_fts WHERE rowid = OLD.id;
+END;
+
 -- Triggers which update visit_count and last_visit_date based on historyvisits
 -- table changes.
 -- NOTE: the values "0, 4, 7, 8, 9" below are EXCLUDED_VISIT_TYPES, stolen
@@ -277,6 +301,10 @@ BEGIN
     UPDATE moz_places SET
         foreign_count = foreign_count + 1
     WHERE id = NEW.place_id;
+
+    UPDATE moz_places_fts SET
+        tags = {new_place_tags}
+    WHERE rowid = NEW.place_id;
 END;
 
 CREATE TEMP TRIGGER moz_tags_relations_afterupdate_trigger
@@ -293,6 +321,14 @@ BEGIN
     UPDATE moz_places SET
         foreign_count = foreign_count - 1
     WHERE id = OLD.place_id;
+
+    UPDATE moz_places_fts SET
+        tags = {new_place_tags}
+    WHERE rowid = NEW.place_id;
+
+    UPDATE moz_places_fts SET
+        tags = {old_place_tags}
+    WHERE rowid = OLD.place_id;
 END;
 
 CREATE TEMP TRIGGER moz_tags_relations_afterdelete_trigger
@@ -305,4 +341,8 @@ BEGIN
     UPDATE moz_places SET
         foreign_count = foreign_count - 1
     WHERE id = OLD.place_id;
+
+    UPDATE moz_places_fts SET
+        tags = {old_place_tags}
+    WHERE rowid = OLD.place_id;
 END;
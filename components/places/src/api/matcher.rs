@@ -108,6 +108,71 @@ pub fn accept_result(conn: &PlacesDb, result: &SearchResult) -> Result<()> {
     Ok(())
 }
 
+/// Searches the titles, URLs, and tags of visited pages for all the words in
+/// `query`, using the full-text index. Each word matches as a prefix, so
+/// results can update as the user types. Results are ranked by relevance,
+/// boosted by frecency, so that among equally good matches, pages the user
+/// visits more often come first.
+pub fn search_history(
+    conn: &PlacesDb,
+    query: &str,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<SearchResult>> {
+    let expr = match fts_match_expression(query) {
+        Some(expr) => expr,
+        None => return Ok(Vec::new()),
+    };
+    let scope = conn.begin_interrupt_scope();
+    let results = conn.query_rows_and_then_named_cached(
+        HISTORY_SEARCH_SQL,
+        &[
+            (":searchString", &query),
+            (":matchExpr", &expr),
+            (":limit", &limit),
+            (":offset", &offset),
+        ],
+        SearchResult::from_history_row,
+    )?;
+    scope.err_if_interrupted()?;
+    Ok(results)
+}
+
+// Weights for the `title`, `url`, and `tags` columns of the full-text index
+// are passed to `bm25`, which returns more negative scores for better matches.
+// We multiply the score by up to 2x for frecent pages: half the boost at a
+// frecency of 100, about what a page visited once today gets.
+const HISTORY_SEARCH_SQL: &str = "
+    SELECT :searchString AS searchString, h.url, h.title, h.frecency,
+           NULLIF(f.tags, '') AS tags,
+           EXISTS(SELECT 1 FROM moz_bookmarks b WHERE b.fk = h.id) AS bookmarked
+    FROM moz_places_fts f
+    JOIN moz_places h ON h.id = f.rowid
+    WHERE moz_places_fts MATCH :matchExpr
+      AND NOT h.hidden
+      AND EXISTS(SELECT 1 FROM moz_historyvisits v WHERE v.place_id = h.id)
+    ORDER BY bm25(moz_places_fts, 10.0, 2.0, 5.0) *
+             (1.0 + MAX(h.frecency, 0) / (MAX(h.frecency, 0) + 100.0)),
+             h.id
+    LIMIT :limit
+    OFFSET :offset";
+
+/// Turns a query into an FTS5 expression which matches all its words, as
+/// prefixes. Words are quoted, so that punctuation and FTS5 operators like
+/// `OR` and `NEAR` are matched literally. Returns `None` if the query has no
+/// words.
+fn fts_match_expression(query: &str) -> Option<String> {
+    let words = query
+        .split_whitespace()
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" "))
+    }
+}
+
 pub fn split_after_prefix(href: &str) -> (&str, &str) {
     match memchr::memchr(b':', href.as_bytes()) {
         None => ("", href),
@@ -244,6 +309,34 @@ impl SearchResult {
         })
     }
 
+    pub fn from_history_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        let mut reasons = Vec::new();
+
+        let search_string = row.get::<_, String>("searchString")?;
+        let url = row.get::<_, String>("url")?;
+        let title = row.get::<_, Option<String>>("title")?.unwrap_or_default();
+        let frecency = row.get::<_, i64>("frecency")?;
+        let bookmarked = row.get::<_, bool>("bookmarked")?;
+
+        if bookmarked {
+            reasons.push(MatchReason::Bookmark);
+        }
+        let tags = row.get::<_, Option<String>>("tags")?;
+        if let Some(tags) = tags {
+            reasons.push(MatchReason::Tags(tags));
+        }
+        let url = Url::parse(&url).expect("Invalid URL in Places");
+
+        Ok(Self {
+            search_string,
+            url,
+            title,
+            icon_url: None,
+            frecency,
+            reasons,
+        })
+    }
+
     pub fn from_origin_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
         let search_string = row.get::<_, String>("searchString")?;
         let url = row.get::<_, String>("url")?;
@@ -740,4 +833,75 @@ mod tests {
         )
         .unwrap();
     }
+
+    #[test]
+    fn search_history_fts() {
+        use crate::storage::history::{delete_place_by_guid, url_to_guid};
+        use crate::storage::tags::{tag_url, untag_url};
+
+        let conn = new_mem_connection();
+        let home = Url::parse("https://rust.example/home").unwrap();
+        let book = Url::parse("https://rust.example/book").unwrap();
+        let cafe = Url::parse("https://example.com/menu").unwrap();
+        for _ in 0..3 {
+            let visit = VisitObservation::new(home.clone())
+                .with_title("Rust home".to_string())
+                .with_visit_type(VisitTransition::Typed)
+                .with_at(Timestamp::now());
+            apply_observation(&conn, visit).expect("Should apply visit");
+        }
+        for (url, title) in &[(&book, "Rust book"), (&cafe, "Café menu")] {
+            let visit = VisitObservation::new((*url).clone())
+                .with_title(title.to_string())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Timestamp::now());
+            apply_observation(&conn, visit).expect("Should apply visit");
+        }
+        tag_url(&conn, &book, "learning").expect("Should tag book");
+
+        let urls = |query: &str, limit: u32, offset: u32| -> Vec<String> {
+            search_history(&conn, query, limit, offset)
+                .expect("Should search history")
+                .into_iter()
+                .map(|result| result.url.into_string())
+                .collect()
+        };
+
+        // Equally good matches are ranked by frecency.
+        assert_eq!(urls("rust", 10, 0), vec![home.as_str(), book.as_str()]);
+        assert_eq!(urls("rust", 1, 1), vec![book.as_str()]);
+
+        // All words must match, as prefixes, in the title, URL, or tags.
+        assert_eq!(urls("rust boo", 10, 0), vec![book.as_str()]);
+        assert_eq!(urls("ru exam", 10, 0), vec![home.as_str(), book.as_str()]);
+        assert_eq!(urls("cafe", 10, 0), vec![cafe.as_str()]);
+        let by_tag = search_history(&conn, "learn", 10, 0).expect("Should search by tag");
+        assert_eq!(by_tag.len(), 1);
+        assert_eq!(by_tag[0].url, book);
+        assert_eq!(by_tag[0].title, "Rust book");
+        assert_eq!(
+            by_tag[0].reasons,
+            vec![MatchReason::Tags("learning".into())]
+        );
+
+        // Operators and punctuation are matched literally.
+        assert!(urls("rust OR", 10, 0).is_empty());
+        assert_eq!(urls("\"rust", 10, 0), vec![home.as_str(), book.as_str()]);
+        assert!(urls("  ", 10, 0).is_empty());
+
+        // The index follows changes to titles, tags, and places.
+        let visit = VisitObservation::new(book.clone())
+            .with_title("Rust guide".to_string())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Timestamp::now());
+        apply_observation(&conn, visit).expect("Should apply visit");
+        assert_eq!(urls("guide", 10, 0), vec![book.as_str()]);
+        untag_url(&conn, &book, "learning").expect("Should untag book");
+        assert!(urls("learning", 10, 0).is_empty());
+        let guid = url_to_guid(&conn, &home)
+            .expect("Should fetch guid")
+            .expect("Should have a place");
+        delete_place_by_guid(&conn, &guid).expect("Should delete place");
+        assert_eq!(urls("rust", 10, 0), vec![book.as_str()]);
+    }
 }
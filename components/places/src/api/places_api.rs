@@ -5,8 +5,10 @@
 use crate::bookmark_sync::store::BookmarksStore;
 use crate::db::db::PlacesDb;
 use crate::error::*;
+use crate::frecency::FrecencySettings;
 use crate::history_sync::store::HistoryStore;
-use crate::storage::{delete_meta, get_meta, put_meta};
+use crate::storage::history::mark_frecencies_stale_if_settings_changed;
+use crate::storage::{delete_meta, get_meta, put_meta, restore_account_meta, stash_account_meta};
 use crate::util::normalize_path;
 use lazy_static::lazy_static;
 use rusqlite::OpenFlags;
@@ -17,8 +19,9 @@ use std::mem;
 use std::path::{Path, PathBuf};
 use std::sync::{
     atomic::{AtomicBool, AtomicUsize, Ordering},
-    Arc, Mutex, Weak,
+    Arc, Mutex, RwLock, Weak,
 };
+use std::time::SystemTime;
 use sync15::{telemetry, MemoryCachedState};
 
 // Not clear if this should be here, but this is the "global sync state"
@@ -28,6 +31,19 @@ use sync15::{telemetry, MemoryCachedState};
 // per collection.
 pub const GLOBAL_STATE_META_KEY: &str = "global_sync_state_v2";
 
+// The hashed uid of the account which the sync state in the database is for.
+// The state for other accounts the user has synced is stashed under keys
+// suffixed with their uid.
+const SYNC_ACCOUNT_META_KEY: &str = "sync_account_uid";
+
+/// The result of a successful history or bookmarks sync.
+#[derive(Debug)]
+pub struct SyncResult {
+    pub telemetry: telemetry::SyncTelemetryPing,
+    /// The time before which the server asked us not to sync again, if any.
+    pub next_sync_after: Option<SystemTime>,
+}
+
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum ConnectionType {
@@ -81,13 +97,25 @@ pub struct PlacesApi {
     sync_state: Mutex<Option<SyncState>>,
     coop_tx_lock: Arc<Mutex<()>>,
     sync_conn_active: AtomicBool,
+    frecency_settings: Arc<RwLock<FrecencySettings>>,
     id: usize,
 }
 impl PlacesApi {
     /// Create a new, or fetch an already open, PlacesApi backed by a file on disk.
     pub fn new(db_name: impl AsRef<Path>) -> Result<Arc<Self>> {
         let db_name = normalize_path(db_name)?;
-        Self::new_or_existing(db_name)
+        Self::new_or_existing(db_name, None)
+    }
+
+    /// Like `new`, but calculates frecency with `frecency_settings`, instead
+    /// of the defaults. If the PlacesApi is already open, this changes its
+    /// settings.
+    pub fn new_with_frecency_settings(
+        db_name: impl AsRef<Path>,
+        frecency_settings: FrecencySettings,
+    ) -> Result<Arc<Self>> {
+        let db_name = normalize_path(db_name)?;
+        Self::new_or_existing(db_name, Some(frecency_settings))
     }
 
     /// Create a new, or fetch an already open, memory-based PlacesApi. You must
@@ -95,25 +123,34 @@ impl PlacesApi {
     ///  reader connections to the same memory DB open.
     pub fn new_memory(db_name: &str) -> Result<Arc<Self>> {
         let name = PathBuf::from(format!("file:{}?mode=memory&cache=shared", db_name));
-        Self::new_or_existing(name)
+        Self::new_or_existing(name, None)
     }
     fn new_or_existing_into(
         target: &mut HashMap<PathBuf, Weak<PlacesApi>>,
         db_name: PathBuf,
+        frecency_settings: Option<FrecencySettings>,
         delete_on_fail: bool,
     ) -> Result<Arc<Self>> {
         let id = ID_COUNTER.fetch_add(1, Ordering::SeqCst);
         match target.get(&db_name).and_then(Weak::upgrade) {
-            Some(existing) => Ok(existing.clone()),
+            Some(existing) => {
+                if let Some(frecency_settings) = frecency_settings {
+                    existing.set_frecency_settings(frecency_settings)?;
+                }
+                Ok(existing.clone())
+            }
             None => {
                 // We always create a new read-write connection for an initial open so
                 // we can create the schema and/or do version upgrades.
                 let coop_tx_lock = Arc::new(Mutex::new(()));
+                let shared_frecency_settings =
+                    Arc::new(RwLock::new(frecency_settings.clone().unwrap_or_default()));
                 match PlacesDb::open(
                     &db_name,
                     ConnectionType::ReadWrite,
                     id,
                     coop_tx_lock.clone(),
+                    shared_frecency_settings.clone(),
                 ) {
                     Ok(connection) => {
                         let new = PlacesApi {
@@ -121,6 +158,7 @@ impl PlacesApi {
                             write_connection: Mutex::new(Some(connection)),
                             sync_state: Mutex::new(None),
                             sync_conn_active: AtomicBool::new(false),
+                            frecency_settings: shared_frecency_settings,
                             id,
                             coop_tx_lock,
                         };
@@ -134,7 +172,7 @@ impl PlacesApi {
                         }
                         if let ErrorKind::DatabaseUpgradeError = e.kind() {
                             fs::remove_file(&db_name)?;
-                            Self::new_or_existing_into(target, db_name, false)
+                            Self::new_or_existing_into(target, db_name, frecency_settings, false)
                         } else {
                             Err(e)
                         }
@@ -144,9 +182,12 @@ impl PlacesApi {
         }
     }
 
-    fn new_or_existing(db_name: PathBuf) -> Result<Arc<Self>> {
+    fn new_or_existing(
+        db_name: PathBuf,
+        frecency_settings: Option<FrecencySettings>,
+    ) -> Result<Arc<Self>> {
         let mut guard = APIS.lock().unwrap();
-        Self::new_or_existing_into(&mut guard, db_name, true)
+        Self::new_or_existing_into(&mut guard, db_name, frecency_settings, true)
     }
 
     /// Open a connection to the database.
@@ -159,6 +200,7 @@ impl PlacesApi {
                     ConnectionType::ReadOnly,
                     self.id,
                     self.coop_tx_lock.clone(),
+                    self.frecency_settings.clone(),
                 )
             }
             ConnectionType::ReadWrite => {
@@ -187,6 +229,7 @@ impl PlacesApi {
                 ConnectionType::Sync,
                 self.id,
                 self.coop_tx_lock.clone(),
+                self.frecency_settings.clone(),
             )?;
             Ok(SyncConn {
                 db,
@@ -210,6 +253,20 @@ impl PlacesApi {
         Ok(())
     }
 
+    /// Changes the settings we use to calculate frecency, for all connections.
+    /// Changing them marks all frecencies as stale, so that
+    /// `storage::history::recalculate_stale_frecencies` can recalculate them
+    /// in the background. New frecencies use the new settings right away.
+    pub fn set_frecency_settings(&self, frecency_settings: FrecencySettings) -> Result<()> {
+        *self.frecency_settings.write().unwrap() = frecency_settings;
+        // If the app has the write connection, it'll mark them the next time
+        // it recalculates stale frecencies.
+        if let Some(db) = self.write_connection.lock().unwrap().as_ref() {
+            mark_frecencies_stale_if_settings_changed(db)?;
+        }
+        Ok(())
+    }
+
     fn get_disk_persisted_state(&self, conn: &PlacesDb) -> Result<Option<String>> {
         Ok(get_meta::<String>(&conn, GLOBAL_STATE_META_KEY)?)
     }
@@ -221,14 +278,65 @@ impl PlacesApi {
         }
     }
 
-    // TODO: We need a better result here so we can return telemetry.
-    // We possibly want more than just a `SyncTelemetryPing` so we can
-    // return additional "custom" telemetry if the app wants it.
+    // Makes sure the sync state in the database, and the global state we
+    // loaded from it, are for the account we're about to sync. If the user
+    // switched accounts, we stash the other account's state, so that we can
+    // resume syncing it if they switch back.
+    fn use_sync_account(
+        &self,
+        conn: &PlacesDb,
+        sync_state: &SyncState,
+        client_init: &sync15::Sync15StorageClientInit,
+        sync_ping: &mut telemetry::SyncTelemetryPing,
+        force: bool,
+    ) -> Result<()> {
+        let mut mem_cached_state = sync_state.mem_cached_state.take();
+        let mut disk_cached_state = sync_state.disk_cached_state.take();
+        let uid =
+            mem_cached_state.hashed_uid(&mut disk_cached_state, client_init, sync_ping, force);
+        // Even on failure we set the persisted state, so that we remember
+        // any backoff the tokenserver asked for.
+        self.set_disk_persisted_state(conn, &disk_cached_state)?;
+        sync_state.mem_cached_state.replace(mem_cached_state);
+        sync_state.disk_cached_state.replace(disk_cached_state);
+        let uid = uid?;
+
+        let last_uid = get_meta::<String>(conn, SYNC_ACCOUNT_META_KEY)?;
+        if last_uid.as_ref() == Some(&uid) {
+            return Ok(());
+        }
+        let tx = conn.begin_transaction()?;
+        // If we haven't recorded an account yet, the state we have is for
+        // this one.
+        if let Some(last_uid) = last_uid {
+            log::info!("Sync account changed, switching sync state");
+            stash_account_meta(conn, &[GLOBAL_STATE_META_KEY], &last_uid)?;
+            restore_account_meta(conn, &[GLOBAL_STATE_META_KEY], &uid)?;
+            HistoryStore::switch_account(conn, &last_uid, &uid)?;
+            BookmarksStore::switch_account(conn, &last_uid, &uid)?;
+        }
+        put_meta(conn, SYNC_ACCOUNT_META_KEY, &uid)?;
+        tx.commit()?;
+        sync_state
+            .disk_cached_state
+            .replace(self.get_disk_persisted_state(conn)?);
+        Ok(())
+    }
+
+    // TODO: We possibly want more than just a `SyncTelemetryPing` in the
+    // result, so we can return additional "custom" telemetry if the app
+    // wants it.
+    /// Syncs history. `reason` is why the app started this sync, if it
+    /// knows, for telemetry. If `force` is true, we sync even if the server
+    /// asked us to back off; this should only be used for syncs explicitly
+    /// requested by the user.
     pub fn sync_history(
         &self,
         client_init: &sync15::Sync15StorageClientInit,
         key_bundle: &sync15::KeyBundle,
-    ) -> Result<telemetry::SyncTelemetryPing> {
+        reason: Option<telemetry::SyncReason>,
+        force: bool,
+    ) -> Result<SyncResult> {
         let mut guard = self.sync_state.lock().unwrap();
         let conn = self.open_sync_connection()?;
         if guard.is_none() {
@@ -242,18 +350,21 @@ impl PlacesApi {
         // Note that counter-intuitively, this must be called before we do a
         // bookmark sync too, to ensure the shared global state is correct.
         HistoryStore::migrate_v1_global_state(&conn)?;
+        let mut sync_ping = telemetry::SyncTelemetryPing::new();
+        self.use_sync_account(&conn, sync_state, client_init, &mut sync_ping, force)?;
 
         let interruptee = conn.begin_interrupt_scope();
         let store = HistoryStore::new(&conn, &interruptee);
         let mut mem_cached_state = sync_state.mem_cached_state.take();
         let mut disk_cached_state = sync_state.disk_cached_state.take();
-        let mut sync_ping = telemetry::SyncTelemetryPing::new();
         let result = store.sync(
             &client_init,
             &key_bundle,
             &mut mem_cached_state,
             &mut disk_cached_state,
+            reason,
             &mut sync_ping,
+            force,
         );
         // even on failure we set the persisted state - sync itself takes care
         // to ensure this has been None'd out if necessary.
@@ -261,17 +372,21 @@ impl PlacesApi {
         sync_state.mem_cached_state.replace(mem_cached_state);
         sync_state.disk_cached_state.replace(disk_cached_state);
 
-        result?;
-
-        Ok(sync_ping)
+        Ok(SyncResult {
+            telemetry: sync_ping,
+            next_sync_after: result?,
+        })
     }
 
     // TODO: reduce duplication with above
+    /// Syncs bookmarks. `reason` and `force` are as for `sync_history`.
     pub fn sync_bookmarks(
         &self,
         client_init: &sync15::Sync15StorageClientInit,
         key_bundle: &sync15::KeyBundle,
-    ) -> Result<telemetry::SyncTelemetryPing> {
+        reason: Option<telemetry::SyncReason>,
+        force: bool,
+    ) -> Result<SyncResult> {
         let mut guard = self.sync_state.lock().unwrap();
         let conn = self.open_sync_connection()?;
         if guard.is_none() {
@@ -285,18 +400,21 @@ impl PlacesApi {
         // Note that counter-intuitively, this must be called before we do a
         // bookmark sync too, to ensure the shared global state is correct.
         HistoryStore::migrate_v1_global_state(&conn)?;
+        let mut sync_ping = telemetry::SyncTelemetryPing::new();
+        self.use_sync_account(&conn, sync_state, client_init, &mut sync_ping, force)?;
 
         let interruptee = conn.begin_interrupt_scope();
         let store = BookmarksStore::new(&conn, &interruptee);
         let mut mem_cached_state = sync_state.mem_cached_state.take();
         let mut disk_cached_state = sync_state.disk_cached_state.take();
-        let mut sync_ping = telemetry::SyncTelemetryPing::new();
         let result = store.sync(
             &client_init,
             &key_bundle,
             &mut mem_cached_state,
             &mut disk_cached_state,
+            reason,
             &mut sync_ping,
+            force,
         );
         // even on failure we set the persisted state - sync itself takes care
         // to ensure this has been None'd out if necessary.
@@ -304,9 +422,10 @@ impl PlacesApi {
         sync_state.mem_cached_state.replace(mem_cached_state);
         sync_state.disk_cached_state.replace(disk_cached_state);
 
-        result?;
-
-        Ok(sync_ping)
+        Ok(SyncResult {
+            telemetry: sync_ping,
+            next_sync_after: result?,
+        })
     }
 }
 
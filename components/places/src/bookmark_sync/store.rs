@@ -12,8 +12,11 @@ use super::{SyncedBookmarkKind, SyncedBookmarkValidity};
 use crate::api::places_api::ConnectionType;
 use crate::db::{PlacesDb, PlacesTransaction};
 use crate::error::*;
-use crate::frecency::{calculate_frecency, DEFAULT_FRECENCY_SETTINGS};
-use crate::storage::{bookmarks::BookmarkRootGuid, delete_meta, get_meta, put_meta};
+use crate::frecency::calculate_frecency;
+use crate::storage::{
+    bookmarks::BookmarkRootGuid, delete_meta, get_meta, put_meta, restore_account_meta,
+    stash_account_meta,
+};
 use crate::types::{BookmarkType, SyncGuid, SyncStatus, Timestamp};
 use dogear::{
     self, Content, Deletion, IntoTree, Item, MergedDescendant, MergedRoot, Tree, UploadReason,
@@ -23,16 +26,22 @@ use sql_support::{self, ConnExt, SqlInterruptScope};
 use std::collections::HashMap;
 use std::fmt;
 use std::result;
+use std::time::{Duration, SystemTime};
 use sync15::{
-    telemetry, CollSyncIds, CollectionRequest, IncomingChangeset, KeyBundle, MemoryCachedState,
-    OutgoingChangeset, Payload, ServerTimestamp, Store, StoreSyncAssociation,
-    Sync15StorageClientInit,
+    telemetry, CollSyncIds, CollectionRequest, IncomingChangeset, KeyBundle, LocalChange,
+    MemoryCachedState, OutgoingChangeset, Payload, ServerTimestamp, Store, StorePreview,
+    StoreSyncAssociation, Sync15StorageClientInit, SyncResult,
 };
 pub const LAST_SYNC_META_KEY: &str = "bookmarks_last_sync_time";
 // Note that all engines in this crate should use a *different* meta key
 // for the global sync ID, because engines are reset individually.
 const GLOBAL_SYNCID_META_KEY: &str = "bookmarks_global_sync_id";
 const COLLECTION_SYNCID_META_KEY: &str = "bookmarks_sync_id";
+// The bookmarks engine version we support. This matches Desktop, and the
+// version in the `meta/global` records we create.
+const ENGINE_VERSION: usize = 2;
+// The meta keys which describe the account we're syncing with.
+const ACCOUNT_META_KEYS: &[&str] = &[GLOBAL_SYNCID_META_KEY, COLLECTION_SYNCID_META_KEY];
 
 /// The maximum number of URLs for which to recalculate frecencies at once.
 /// This is a trade-off between write efficiency and transaction time: higher
@@ -435,6 +444,7 @@ impl<'a> BookmarksStore<'a> {
     fn update_frecencies(&self) -> Result<()> {
         let mut tx = self.db.begin_transaction()?;
 
+        let settings = self.db.frecency_settings();
         let mut frecencies = Vec::with_capacity(MAX_FRECENCIES_TO_RECALCULATE_PER_CHUNK);
         loop {
             let sql = format!(
@@ -450,12 +460,7 @@ impl<'a> BookmarksStore<'a> {
                 // Frecency recalculation runs several statements, so check to
                 // make sure we aren't interrupted before each calculation.
                 self.interruptee.err_if_interrupted()?;
-                let frecency = calculate_frecency(
-                    &self.db,
-                    &DEFAULT_FRECENCY_SETTINGS,
-                    place_id,
-                    Some(false),
-                )?;
+                let frecency = calculate_frecency(&self.db, &settings, place_id, Some(false))?;
                 frecencies.push((place_id, frecency));
             }
             if frecencies.is_empty() {
@@ -505,26 +510,159 @@ impl<'a> BookmarksStore<'a> {
         Ok(())
     }
 
+    /// Returns true if we haven't validated for `VALIDATION_INTERVAL`, and
+    /// the synced tree is small enough to validate. We record the time
+    /// whether or not the tree is too big, so that we don't count it again
+    /// until the next interval.
+    fn should_validate(&self) -> Result<bool> {
+        let now = Timestamp::now();
+        if let Some(last) = get_meta::<Timestamp>(self.db, LAST_VALIDATION_META_KEY)? {
+            // If the clock went backward, we validate anyway.
+            match now.duration_since(last) {
+                Some(since) if since < VALIDATION_INTERVAL => return Ok(false),
+                _ => {}
+            }
+        }
+        put_meta(self.db, LAST_VALIDATION_META_KEY, &now)?;
+        let count = self
+            .db
+            .query_one::<i64>("SELECT COUNT(*) FROM moz_bookmarks_synced WHERE NOT isDeleted")?;
+        Ok(count as usize <= MAX_VALIDATION_RECORDS)
+    }
+
+    /// Compares the synced tree, which matches the server's after a sync,
+    /// with the local tree, and counts the problems in each.
+    fn find_problems(&self) -> Result<ProblemCounts> {
+        let root_guid = BookmarkRootGuid::Root.as_guid();
+        let count = |sql: String| -> Result<usize> { Ok(self.db.query_one::<i64>(&sql)? as usize) };
+        Ok(ProblemCounts {
+            checked: count(
+                "SELECT COUNT(*) FROM moz_bookmarks_synced WHERE NOT isDeleted".to_string(),
+            )?,
+            // Items whose parent doesn't exist on the server.
+            orphans: count(format!(
+                "SELECT COUNT(*) FROM moz_bookmarks_synced v
+                 WHERE NOT v.isDeleted AND
+                       v.guid <> '{root_guid}' AND
+                       NOT EXISTS(SELECT 1 FROM moz_bookmarks_synced p
+                                  WHERE p.guid = v.parentGuid AND
+                                        NOT p.isDeleted)",
+                root_guid = root_guid.as_ref()
+            ))?,
+            // Children in a folder's `children` which don't exist.
+            missing_children: count(format!(
+                "SELECT COUNT(*) FROM moz_bookmarks_synced_structure s
+                 WHERE s.guid <> '{root_guid}' AND
+                       NOT EXISTS(SELECT 1 FROM moz_bookmarks_synced v
+                                  WHERE v.guid = s.guid AND
+                                        NOT v.isDeleted)",
+                root_guid = root_guid.as_ref()
+            ))?,
+            // Children whose `parentid` isn't the folder that lists them.
+            parent_child_mismatches: count(format!(
+                "SELECT COUNT(*) FROM moz_bookmarks_synced_structure s
+                 JOIN moz_bookmarks_synced v ON v.guid = s.guid
+                 WHERE NOT v.isDeleted AND
+                       s.guid <> '{root_guid}' AND
+                       v.parentGuid IS NOT s.parentGuid",
+                root_guid = root_guid.as_ref()
+            ))?,
+            // Children listed in more than one folder.
+            duplicate_children: count(format!(
+                "SELECT COUNT(*) FROM (
+                   SELECT guid FROM moz_bookmarks_synced_structure
+                   WHERE guid <> '{root_guid}'
+                   GROUP BY guid
+                   HAVING COUNT(*) > 1
+                 )",
+                root_guid = root_guid.as_ref()
+            ))?,
+            // Valid items on the server which we don't have locally.
+            client_missing: count(format!(
+                "SELECT COUNT(*) FROM moz_bookmarks_synced v
+                 LEFT JOIN moz_bookmarks b ON b.guid = v.guid
+                 WHERE NOT v.isDeleted AND
+                       v.validity = {valid} AND
+                       v.kind <> {livemark} AND
+                       b.guid IS NULL",
+                valid = SyncedBookmarkValidity::Valid as u8,
+                livemark = SyncedBookmarkKind::Livemark as u8
+            ))?,
+            // Synced local items which aren't on the server.
+            server_missing: count(format!(
+                "SELECT COUNT(*) FROM moz_bookmarks b
+                 WHERE b.syncStatus = {sync_status} AND
+                       NOT EXISTS(SELECT 1 FROM moz_bookmarks_synced v
+                                  WHERE v.guid = b.guid AND
+                                        NOT v.isDeleted)",
+                sync_status = SyncStatus::Normal as u8
+            ))?,
+            // Unchanged local items with a different parent on the server.
+            structural_differences: count(format!(
+                "SELECT COUNT(*) FROM moz_bookmarks b
+                 JOIN moz_bookmarks p ON p.id = b.parent
+                 JOIN moz_bookmarks_synced v ON v.guid = b.guid
+                 WHERE NOT v.isDeleted AND
+                       b.syncChangeCounter = 0 AND
+                       b.guid <> '{root_guid}' AND
+                       v.parentGuid IS NOT p.guid",
+                root_guid = root_guid.as_ref()
+            ))?,
+        })
+    }
+
+    /// Stashes the bookmark sync IDs for the account `from`, and restores the
+    /// IDs for `to`, when the user switches accounts. Local bookmarks are
+    /// kept. The mirror only describes `from`'s server, so we always reset,
+    /// and the next sync merges the whole tree. But if `to`'s sync IDs still
+    /// match, it isn't treated as a first sync, so we only upload what's
+    /// different on the server.
+    ///
+    /// This should be called in a transaction.
+    pub fn switch_account(db: &PlacesDb, from: &str, to: &str) -> Result<()> {
+        stash_account_meta(db, ACCOUNT_META_KEYS, from)?;
+        restore_account_meta(db, ACCOUNT_META_KEYS, to)?;
+        let global = get_meta(db, GLOBAL_SYNCID_META_KEY)?;
+        let coll = get_meta(db, COLLECTION_SYNCID_META_KEY)?;
+        let assoc = if let (Some(global), Some(coll)) = (global, coll) {
+            StoreSyncAssociation::Connected(CollSyncIds { global, coll })
+        } else {
+            StoreSyncAssociation::Disconnected
+        };
+        reset_sync_state(db, &assoc)
+    }
+
+    /// A convenience wrapper around sync_multiple. `reason` is why the app
+    /// started this sync, if it knows, for telemetry. Returns the time before
+    /// which the server asked us not to sync again, if any.
     pub fn sync(
         &self,
         storage_init: &Sync15StorageClientInit,
         root_sync_key: &KeyBundle,
         mem_cached_state: &mut MemoryCachedState,
         disk_cached_state: &mut Option<String>,
+        reason: Option<telemetry::SyncReason>,
         sync_ping: &mut telemetry::SyncTelemetryPing,
-    ) -> Result<()> {
+        force: bool,
+    ) -> Result<Option<SystemTime>> {
         let result = sync15::sync_multiple(
             &[self],
             disk_cached_state,
             mem_cached_state,
             storage_init,
             root_sync_key,
+            None,
+            reason,
             sync_ping,
             self.interruptee,
+            force,
         );
-        let failures = result?;
+        let SyncResult {
+            failures,
+            next_sync_after,
+        } = result?;
         if failures.is_empty() {
-            Ok(())
+            Ok(next_sync_after)
         } else {
             let (_, err) = failures.into_iter().next().unwrap();
             Err(err.into())
@@ -559,6 +697,25 @@ impl<'a> Store for BookmarksStore<'a> {
         Ok(outgoing)
     }
 
+    fn preview_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> result::Result<StorePreview, failure::Error> {
+        // Stage, merge and apply the records as a sync would, so that we can
+        // see what that changes, then roll everything back. Dropping the
+        // transaction on error also rolls it back.
+        let tx = self.db.begin_preview_transaction()?;
+        let before = fetch_local_items(self.db)?;
+        let outgoing = self.apply_incoming(inbound, incoming_telemetry)?;
+        let after = fetch_local_items(self.db)?;
+        tx.rollback()?;
+
+        let mut preview = StorePreview::new(outgoing);
+        preview.local_changes = LocalChange::between(before, after);
+        Ok(preview)
+    }
+
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
@@ -594,28 +751,7 @@ impl<'a> Store for BookmarksStore<'a> {
     /// sync time.
     fn reset(&self, assoc: &StoreSyncAssociation) -> result::Result<(), failure::Error> {
         let tx = self.db.begin_transaction()?;
-        self.db.execute_batch(&format!(
-            "DELETE FROM moz_bookmarks_synced;
-
-             DELETE FROM moz_bookmarks_deleted;
-
-             UPDATE moz_bookmarks
-             SET syncChangeCounter = 0,
-                 syncStatus = {}",
-            (SyncStatus::New as u8)
-        ))?;
-        create_synced_bookmark_roots(self.db)?;
-        put_meta(self.db, LAST_SYNC_META_KEY, &0)?;
-        match assoc {
-            StoreSyncAssociation::Disconnected => {
-                delete_meta(self.db, GLOBAL_SYNCID_META_KEY)?;
-                delete_meta(self.db, COLLECTION_SYNCID_META_KEY)?;
-            }
-            StoreSyncAssociation::Connected(ids) => {
-                put_meta(self.db, GLOBAL_SYNCID_META_KEY, &ids.global)?;
-                put_meta(self.db, COLLECTION_SYNCID_META_KEY, &ids.coll)?;
-            }
-        };
+        reset_sync_state(self.db, assoc)?;
         tx.commit()?;
         Ok(())
     }
@@ -655,6 +791,117 @@ impl<'a> Store for BookmarksStore<'a> {
         tx.commit()?;
         Ok(())
     }
+
+    fn validate(&self) -> result::Result<Option<telemetry::Validation>, failure::Error> {
+        if !self.should_validate()? {
+            return Ok(None);
+        }
+        Ok(Some(self.find_problems()?.into()))
+    }
+
+    fn engine_version(&self) -> usize {
+        ENGINE_VERSION
+    }
+}
+
+// A local item, as compared before and after previewing a sync.
+#[derive(PartialEq)]
+struct LocalItem {
+    kind: i64,
+    parent_guid: Option<String>,
+    position: i64,
+    title: Option<String>,
+    url: Option<String>,
+}
+
+// Fetches all local items, keyed by GUID.
+fn fetch_local_items(db: &PlacesDb) -> Result<HashMap<String, LocalItem>> {
+    db.query_rows_into(
+        "SELECT b.guid, b.type, p.guid AS parentGuid, b.position, b.title, h.url
+         FROM moz_bookmarks b
+         LEFT JOIN moz_bookmarks p ON p.id = b.parent
+         LEFT JOIN moz_places h ON h.id = b.fk",
+        &[],
+        |row| -> Result<_> {
+            Ok((
+                row.get::<_, String>("guid")?,
+                LocalItem {
+                    kind: row.get("type")?,
+                    parent_guid: row.get("parentGuid")?,
+                    position: row.get("position")?,
+                    title: row.get("title")?,
+                    url: row.get("url")?,
+                },
+            ))
+        },
+    )
+}
+
+// The guts of `Store::reset`, which can also be called in an existing
+// transaction.
+fn reset_sync_state(db: &PlacesDb, assoc: &StoreSyncAssociation) -> Result<()> {
+    db.execute_batch(&format!(
+        "DELETE FROM moz_bookmarks_synced;
+
+         DELETE FROM moz_bookmarks_deleted;
+
+         UPDATE moz_bookmarks
+         SET syncChangeCounter = 0,
+             syncStatus = {}",
+        (SyncStatus::New as u8)
+    ))?;
+    create_synced_bookmark_roots(db)?;
+    put_meta(db, LAST_SYNC_META_KEY, &0)?;
+    match assoc {
+        StoreSyncAssociation::Disconnected => {
+            delete_meta(db, GLOBAL_SYNCID_META_KEY)?;
+            delete_meta(db, COLLECTION_SYNCID_META_KEY)?;
+        }
+        StoreSyncAssociation::Connected(ids) => {
+            put_meta(db, GLOBAL_SYNCID_META_KEY, &ids.global)?;
+            put_meta(db, COLLECTION_SYNCID_META_KEY, &ids.coll)?;
+        }
+    };
+    Ok(())
+}
+
+/// The version of our bookmark validator, reported in the sync ping.
+const VALIDATION_VERSION: u32 = 1;
+
+/// Validating queries the whole local and synced trees, so we only do it
+/// once a day, and only if there are at most `MAX_VALIDATION_RECORDS` synced
+/// items. These match Desktop's defaults.
+const VALIDATION_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+const MAX_VALIDATION_RECORDS: usize = 1000;
+const LAST_VALIDATION_META_KEY: &str = "bookmarks_last_validation_time";
+
+/// The number of each kind of problem found by `find_problems`. The names
+/// we report for them match Desktop's bookmark validator.
+#[derive(Debug, Default, PartialEq)]
+struct ProblemCounts {
+    checked: usize,
+    orphans: usize,
+    missing_children: usize,
+    parent_child_mismatches: usize,
+    duplicate_children: usize,
+    client_missing: usize,
+    server_missing: usize,
+    structural_differences: usize,
+}
+
+impl From<ProblemCounts> for telemetry::Validation {
+    fn from(counts: ProblemCounts) -> telemetry::Validation {
+        let mut validation = telemetry::Validation::with_version(VALIDATION_VERSION);
+        validation.checked(counts.checked);
+        validation.problem("orphans", counts.orphans);
+        validation.problem("missingChildren", counts.missing_children);
+        validation.problem("parentChildMismatches", counts.parent_child_mismatches);
+        validation.problem("duplicateChildren", counts.duplicate_children);
+        validation.problem("clientMissing", counts.client_missing);
+        validation.problem("serverMissing", counts.server_missing);
+        validation.problem("structuralDifferences", counts.structural_differences);
+        validation
+    }
 }
 
 struct Driver;
@@ -1094,7 +1341,7 @@ mod tests {
     use serde_json::{json, Value};
     use url::Url;
 
-    use sync15::Payload;
+    use sync15::{LocalChangeKind, Payload};
 
     fn apply_incoming(conn: &PlacesDb, records_json: Value) {
         // suck records into the store.
@@ -1911,4 +2158,187 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_validate() -> Result<()> {
+        let api = new_mem_api();
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let records = json!([{
+            "id": "menu",
+            "type": "folder",
+            "parentid": "places",
+            "title": "menu",
+            "children": ["bookmarkAAAA", "bookmarkBBBB"],
+        }, {
+            "id": "toolbar",
+            "type": "folder",
+            "parentid": "places",
+            "title": "toolbar",
+            "children": ["bookmarkEEEE"],
+        }, {
+            "id": "unfiled",
+            "type": "folder",
+            "parentid": "places",
+            "title": "unfiled",
+            "children": ["bookmarkEEEE"],
+        }, {
+            "id": "bookmarkAAAA",
+            "type": "bookmark",
+            "parentid": "menu",
+            "title": "A",
+            "bmkUri": "http://example.com/a",
+        }, {
+            "id": "bookmarkCCCC",
+            "type": "bookmark",
+            "parentid": "folderXXXXXX",
+            "title": "C",
+            "bmkUri": "http://example.com/c",
+        }, {
+            "id": "bookmarkEEEE",
+            "type": "bookmark",
+            "parentid": "toolbar",
+            "title": "E",
+            "bmkUri": "http://example.com/e",
+        }]);
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+        if let Value::Array(records) = records {
+            for record in records {
+                let payload = Payload::from_json(record).unwrap();
+                incoming.changes.push((payload, ServerTimestamp(0.0)));
+            }
+        }
+        // Only stage the records, so that the mirror matches the server.
+        store.stage_incoming(incoming, &mut telemetry::EngineIncoming::new())?;
+
+        let problems = store.find_problems()?;
+        // The roots, A, C and E.
+        assert_eq!(problems.checked, USER_CONTENT_ROOTS.len() + 4);
+        // C's parent doesn't exist.
+        assert_eq!(problems.orphans, 1);
+        // B is in the menu, but doesn't exist.
+        assert_eq!(problems.missing_children, 1);
+        // E is in the toolbar and unfiled, but its parent is the toolbar.
+        assert_eq!(problems.parent_child_mismatches, 1);
+        assert_eq!(problems.duplicate_children, 1);
+
+        let validation = telemetry::Validation::from(problems);
+        let json = serde_json::to_value(&validation).unwrap();
+        assert_eq!(json["version"], VALIDATION_VERSION);
+        assert!(json["problems"]
+            .as_array()
+            .unwrap()
+            .contains(&json!({"name": "orphans", "count": 1})));
+        Ok(())
+    }
+
+    #[test]
+    fn test_validation_limits() -> Result<()> {
+        let api = new_mem_api();
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        // We validate at most once every interval...
+        assert!(store.validate().unwrap().is_some());
+        assert!(store.validate().unwrap().is_none());
+        let last_validated = Timestamp(Timestamp::now().as_millis() - 25 * 60 * 60 * 1000);
+        put_meta(&syncer, LAST_VALIDATION_META_KEY, &last_validated)?;
+        assert!(store.validate().unwrap().is_some());
+
+        // ...And not at all if the tree is too big.
+        syncer.execute_batch(&format!(
+            "WITH RECURSIVE
+             items(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM items WHERE n < {max})
+             INSERT INTO moz_bookmarks_synced(guid, parentGuid, kind)
+             SELECT printf('bookmark%07d', n), 'unfiled', {kind} FROM items",
+            max = MAX_VALIDATION_RECORDS,
+            kind = SyncedBookmarkKind::Bookmark as u8
+        ))?;
+        delete_meta(&syncer, LAST_VALIDATION_META_KEY)?;
+        assert!(store.validate().unwrap().is_none());
+        // But we still wait for the next interval before checking again.
+        assert!(get_meta::<Timestamp>(&syncer, LAST_VALIDATION_META_KEY)?.is_some());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_preview_incoming() -> Result<()> {
+        let api = new_mem_api();
+        let writer = api.open_connection(ConnectionType::ReadWrite)?;
+        insert_local_json_tree(
+            &writer,
+            json!({
+                "guid": &BookmarkRootGuid::Menu.as_guid(),
+                "children": [
+                    {
+                        "guid": "bookmarkAAAA",
+                        "title": "A",
+                        "url": "http://example.com/a",
+                    }
+                ],
+            }),
+        );
+
+        let syncer = api.open_sync_connection()?;
+        let interrupt_scope = syncer.begin_interrupt_scope();
+        let store = BookmarksStore::new(&syncer, &interrupt_scope);
+
+        let records = vec![
+            json!({
+                "id": "unfiled",
+                "type": "folder",
+                "parentid": "places",
+                "title": "unfiled",
+                "children": ["bookmarkBBBB"],
+            }),
+            json!({
+                "id": "bookmarkBBBB",
+                "type": "bookmark",
+                "parentid": "unfiled",
+                "title": "B",
+                "bmkUri": "http://example.com/b",
+            }),
+        ];
+        let mut incoming =
+            IncomingChangeset::new(store.collection_name().to_string(), ServerTimestamp(0.0));
+        for record in records {
+            let payload = Payload::from_json(record).unwrap();
+            incoming.changes.push((payload, ServerTimestamp(0.0)));
+        }
+
+        let preview = store
+            .preview_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should preview incoming records");
+        assert!(preview.local_changes.contains(&LocalChange {
+            id: "bookmarkBBBB".into(),
+            kind: LocalChangeKind::Insert,
+        }));
+        assert!(preview
+            .local_changes
+            .iter()
+            .all(|change| change.kind != LocalChangeKind::Delete));
+        // We'd upload the new local bookmark.
+        assert!(preview
+            .outgoing
+            .changes
+            .iter()
+            .any(|p| p.id == "bookmarkAAAA"));
+
+        // Nothing should have changed.
+        assert!(get_raw_bookmark(&writer, &"bookmarkBBBB".into())?.is_none());
+        assert!(get_raw_bookmark(&writer, &"bookmarkAAAA".into())?.is_some());
+        assert_eq!(get_meta::<i64>(&syncer, LAST_SYNC_META_KEY)?, None);
+        assert_eq!(
+            syncer.query_one::<i64>(
+                "SELECT COUNT(*) FROM moz_bookmarks_synced WHERE guid = 'bookmarkBBBB'"
+            )?,
+            0
+        );
+        Ok(())
+    }
 }
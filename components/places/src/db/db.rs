@@ -5,12 +5,15 @@
 use super::schema;
 use crate::api::places_api::ConnectionType;
 use crate::error::*;
+use crate::frecency::FrecencySettings;
+use crate::storage::history::mark_frecencies_stale_if_settings_changed;
 use rusqlite::Connection;
 use sql_support::{ConnExt, SqlInterruptHandle, SqlInterruptScope};
+use std::cell::Cell;
 use std::ops::Deref;
 use std::path::Path;
 
-use std::sync::{atomic::AtomicUsize, Arc, Mutex};
+use std::sync::{atomic::AtomicUsize, Arc, Mutex, RwLock};
 
 pub const MAX_VARIABLE_NUMBER: usize = 999;
 
@@ -22,6 +25,11 @@ pub struct PlacesDb {
     api_id: usize,
     in_memory: bool,
     pub(super) coop_tx_lock: Arc<Mutex<()>>,
+    // Shared by all connections from the same `PlacesApi`, so that changing
+    // the settings affects connections that are already open.
+    frecency_settings: Arc<RwLock<FrecencySettings>>,
+    // Set while a `PreviewTransaction` is open.
+    pub(super) in_preview: Cell<bool>,
 }
 
 impl PlacesDb {
@@ -30,6 +38,7 @@ impl PlacesDb {
         conn_type: ConnectionType,
         api_id: usize,
         coop_tx_lock: Arc<Mutex<()>>,
+        frecency_settings: Arc<RwLock<FrecencySettings>>,
         in_memory: bool,
     ) -> Result<Self> {
         let initial_pragmas = "
@@ -73,7 +82,9 @@ impl PlacesDb {
             api_id,
             interrupt_counter: Arc::new(AtomicUsize::new(0)),
             coop_tx_lock,
+            frecency_settings,
             in_memory,
+            in_preview: Cell::new(false),
         };
         match res.conn_type() {
             // For read-only connections, we can avoid opening a transaction,
@@ -84,6 +95,9 @@ impl PlacesDb {
                 // since we want to pass &PlacesDb and not &Connection to schema::init.
                 let tx = res.unchecked_transaction()?;
                 schema::init(&res)?;
+                if res.conn_type() == ConnectionType::ReadWrite {
+                    mark_frecencies_stale_if_settings_changed(&res)?;
+                }
                 tx.commit()?;
             }
         }
@@ -96,12 +110,14 @@ impl PlacesDb {
         conn_type: ConnectionType,
         api_id: usize,
         coop_tx_lock: Arc<Mutex<()>>,
+        frecency_settings: Arc<RwLock<FrecencySettings>>,
     ) -> Result<Self> {
         Ok(Self::with_connection(
             Connection::open_with_flags(path, conn_type.rusqlite_flags())?,
             conn_type,
             api_id,
             coop_tx_lock,
+            frecency_settings,
             false,
         )?)
     }
@@ -115,6 +131,7 @@ impl PlacesDb {
             conn_ty,
             0,
             Arc::new(Mutex::new(())),
+            Arc::new(RwLock::new(FrecencySettings::default())),
             true,
         )?)
     }
@@ -145,6 +162,11 @@ impl PlacesDb {
     pub fn is_in_memory(&self) -> bool {
         self.in_memory
     }
+
+    /// Returns the settings we currently use to calculate frecency.
+    pub fn frecency_settings(&self) -> FrecencySettings {
+        self.frecency_settings.read().unwrap().clone()
+    }
 }
 
 impl Drop for PlacesDb {
@@ -181,6 +203,7 @@ fn define_functions(c: &Connection) -> Result<()> {
         true,
         sql_fns::strip_prefix_and_userinfo,
     )?;
+    c.create_scalar_function("get_base_domain", 1, true, sql_fns::get_base_domain)?;
     c.create_scalar_function("reverse_host", 1, true, sql_fns::reverse_host)?;
     c.create_scalar_function("autocomplete_match", 10, true, sql_fns::autocomplete_match)?;
     c.create_scalar_function("hash", -1, true, sql_fns::hash)?;
@@ -289,6 +312,12 @@ mod sql_fns {
         Ok(matcher.invoke())
     }
 
+    #[inline(never)]
+    pub fn get_base_domain(ctx: &Context<'_>) -> Result<String> {
+        let host_and_port = get_raw_str(ctx, "get_base_domain", 0)?;
+        Ok(crate::util::base_domain(host_and_port))
+    }
+
     #[inline(never)]
     pub fn reverse_host(ctx: &Context<'_>) -> Result<String> {
         // We reuse this memory so no need for get_raw.
@@ -7,6 +7,6 @@
 pub mod db;
 mod schema;
 mod tx;
-pub use self::tx::PlacesTransaction;
+pub use self::tx::{PlacesTransaction, PreviewTransaction};
 
 pub use crate::db::db::PlacesDb;
@@ -15,7 +15,7 @@ use crate::storage::bookmarks::create_bookmark_roots;
 use rusqlite::NO_PARAMS;
 use sql_support::ConnExt;
 
-const VERSION: i64 = 8;
+const VERSION: i64 = 10;
 
 // Shared schema and temp tables for the read-write and Sync connections.
 const CREATE_SHARED_SCHEMA_SQL: &str = include_str!("../../sql/create_shared_schema.sql");
@@ -35,6 +35,18 @@ lazy_static::lazy_static! {
             include_str!("../../sql/create_shared_triggers.sql"),
             increase_frecency_stats = update_origin_frecency_stats("+"),
             decrease_frecency_stats = update_origin_frecency_stats("-"),
+            new_place_tags = place_tags("NEW.place_id"),
+            old_place_tags = place_tags("OLD.place_id"),
+        )
+    };
+
+    // Fills the full-text index for databases created before we had one.
+    static ref POPULATE_PLACES_FTS_SQL: String = {
+        format!(
+            "INSERT INTO moz_places_fts(rowid, title, url, tags)
+             SELECT h.id, IFNULL(h.title, ''), h.url, {tags}
+             FROM moz_places h",
+            tags = place_tags("h.id"),
         )
     };
 }
@@ -73,6 +85,18 @@ fn update_origin_frecency_stats(op: &str) -> String {
     )
 }
 
+// The tags for the place with the id `place_id`, as we store them in the
+// full-text index.
+fn place_tags(place_id: &str) -> String {
+    format!(
+        "(SELECT IFNULL(GROUP_CONCAT(t.tag, ' '), '')
+          FROM moz_tags_relation r
+          JOIN moz_tags t ON t.id = r.tag_id
+          WHERE r.place_id = {place_id})",
+        place_id = place_id
+    )
+}
+
 fn get_current_schema_version(db: &PlacesDb) -> Result<i64> {
     Ok(db.query_one::<i64>("PRAGMA user_version")?)
 }
@@ -202,6 +226,21 @@ fn upgrade(db: &PlacesDb, from: i64) -> Result<()> {
         ],
         || Ok(()),
     )?;
+    migration(
+        db,
+        8,
+        9,
+        &[
+            CREATE_SHARED_SCHEMA_SQL,
+            // Full-text index. We clear it out first, in case we're running
+            // this migration again on a database that already has one.
+            "DELETE FROM moz_places_fts",
+            &POPULATE_PLACES_FTS_SQL,
+        ],
+        || Ok(()),
+    )?;
+    migration(db, 9, 10, &[CREATE_SHARED_SCHEMA_SQL], || Ok(()))?; // top sites tables.
+
     // Add more migrations here...
 
     if get_current_schema_version(db)? == VERSION {
@@ -236,6 +275,61 @@ mod tests {
             .expect("should allow running twice");
     }
 
+    #[test]
+    fn test_upgrade_fills_fts() {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite).expect("no memory db");
+        conn.execute_all(&[
+            "INSERT INTO moz_places(guid, url, url_hash, title)
+             VALUES('place_guid__', 'http://example.com/', hash('http://example.com/'),
+                    'Example page')",
+            "INSERT INTO moz_tags(tag, lastModified) VALUES('ex', 1)",
+            "INSERT INTO moz_tags_relation(tag_id, place_id)
+             VALUES(last_insert_rowid(), (SELECT id FROM moz_places))",
+            // Pretend we're upgrading a database from before we had an index.
+            "DELETE FROM moz_places_fts",
+            "PRAGMA user_version = 8",
+        ])
+        .expect("should set up the old database");
+        upgrade(&conn, 8).expect("should upgrade");
+        let (title, url, tags) = conn
+            .query_row(
+                "SELECT title, url, tags FROM moz_places_fts",
+                NO_PARAMS,
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                    ))
+                },
+            )
+            .expect("should fill the index");
+        assert_eq!(title, "Example page");
+        assert_eq!(url, "http://example.com/");
+        assert_eq!(tags, "ex");
+    }
+
+    #[test]
+    fn test_upgrade_refills_fts() {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite).expect("no memory db");
+        conn.execute_all(&[
+            "INSERT INTO moz_places(guid, url, url_hash, title)
+             VALUES('place_guid__', 'http://example.com/', hash('http://example.com/'),
+                    'Example page')",
+            // Pretend we're running the migrations again on a database that
+            // already has an index.
+            "PRAGMA user_version = 8",
+        ])
+        .expect("should set up the database");
+        upgrade(&conn, 8).expect("should upgrade again");
+        let titles = conn
+            .query_rows_and_then_named("SELECT title FROM moz_places_fts", &[], |row| {
+                row.get::<_, String>(0)
+            })
+            .expect("should query the index");
+        assert_eq!(titles, vec!["Example page".to_string()]);
+    }
+
     fn has_tombstone(conn: &PlacesDb, guid: &SyncGuid) -> bool {
         let count: Result<Option<u32>> = conn.try_query_row(
             "SELECT COUNT(*) from moz_places_tombstones
@@ -609,7 +703,7 @@ mod tests {
             ),
             NO_PARAMS,
         )
-        .expect("should insert regular bookmark folder");;
+        .expect("should insert regular bookmark folder");
         conn.execute(
             "DELETE FROM moz_bookmarks WHERE guid = 'bookmarkguid'",
             NO_PARAMS,
@@ -642,7 +736,7 @@ mod tests {
                         (3, 1, 0, 1, 1, 'bookmarkguid')",
             NO_PARAMS,
         )
-        .expect("should insert regular bookmark folder");;
+        .expect("should insert regular bookmark folder");
         // tombstone should have vanished.
         assert_eq!(
             select_simple_int(&conn, "SELECT COUNT(*) from moz_bookmarks_deleted"),
@@ -669,7 +763,7 @@ mod tests {
                         (3, 1, 0, 1, 1, 'fake_guid___')",
             NO_PARAMS,
         )
-        .expect("should insert regular bookmark folder");;
+        .expect("should insert regular bookmark folder");
         // tombstone should remain.
         assert_eq!(
             select_simple_int(&conn, "SELECT COUNT(*) from moz_bookmarks_deleted"),
@@ -85,6 +85,14 @@ impl PlacesDb {
         let _lock = self.coop_tx_lock.lock().unwrap();
         get_tx_with_retry_on_locked(self.conn())
     }
+
+    /// Begin a coop transaction for a preview, on either write connection.
+    /// Unlike a chunked transaction, this is never committed, so it holds the
+    /// database lock until the preview rolls it back.
+    pub(super) fn preview_coop_transaction(&self) -> Result<UncheckedTransaction<'_>> {
+        let _lock = self.coop_tx_lock.lock().unwrap();
+        get_tx_with_retry_on_locked(self.conn())
+    }
 }
 
 /// This transaction is suitable for when a transaction is used purely for
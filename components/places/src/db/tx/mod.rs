@@ -9,6 +9,7 @@ use crate::error::*;
 use coop_transaction::ChunkedCoopTransaction;
 use rusqlite::Connection;
 use sql_support::{ConnExt, UncheckedTransaction};
+use std::cell::Cell;
 
 macro_rules! debug_complaint {
     ($($fmt_args:tt)*) => {
@@ -30,6 +31,9 @@ enum PlacesTransactionRepr<'conn> {
     // Note: these might seem pointless, but can allow us to ensure consistency
     // between separate reads.
     ReadOnly(UncheckedTransaction<'conn>),
+    // A transaction begun inside a `PreviewTransaction`, which doesn't commit
+    // or roll back anything itself, since the preview always rolls back.
+    Preview(&'conn Connection),
 }
 
 impl<'conn> PlacesTransaction<'conn> {
@@ -40,10 +44,12 @@ impl<'conn> PlacesTransaction<'conn> {
     ///   warning and does nothing.
     #[inline]
     pub fn maybe_commit(&mut self) -> Result<()> {
-        if let PlacesTransactionRepr::ChunkedWrite(tx) = &mut self.0 {
-            tx.maybe_commit()?;
-        } else {
-            debug_complaint!("maybe_commit called on a non-chunked transaction");
+        match &mut self.0 {
+            PlacesTransactionRepr::ChunkedWrite(tx) => tx.maybe_commit()?,
+            PlacesTransactionRepr::Preview(_) => {}
+            _ => {
+                debug_complaint!("maybe_commit called on a non-chunked transaction");
+            }
         }
         Ok(())
     }
@@ -54,6 +60,7 @@ impl<'conn> PlacesTransaction<'conn> {
             PlacesTransactionRepr::ChunkedWrite(t) => t.commit()?,
             PlacesTransactionRepr::UnchunkedWrite(t) => t.commit()?,
             PlacesTransactionRepr::ReadOnly(t) => t.commit()?,
+            PlacesTransactionRepr::Preview(_) => {}
         };
         Ok(())
     }
@@ -66,6 +73,7 @@ impl<'conn> PlacesTransaction<'conn> {
             PlacesTransactionRepr::ChunkedWrite(t) => t.rollback()?,
             PlacesTransactionRepr::UnchunkedWrite(t) => t.rollback()?,
             PlacesTransactionRepr::ReadOnly(t) => t.rollback()?,
+            PlacesTransactionRepr::Preview(_) => {}
         };
         Ok(())
     }
@@ -77,7 +85,15 @@ impl super::PlacesDb {
     /// - For Sync connections, begins a chunked coop transaction.
     /// - for ReadWrite connections, begins a normal coop transaction
     /// - for ReadOnly connections, begins an unchecked transaction.
+    ///
+    /// Inside a `PreviewTransaction`, this doesn't begin anything, and
+    /// committing the transaction it returns does nothing.
     pub fn begin_transaction(&self) -> Result<PlacesTransaction<'_>> {
+        if self.in_preview.get() {
+            return Ok(PlacesTransaction(PlacesTransactionRepr::Preview(
+                self.conn(),
+            )));
+        }
         Ok(PlacesTransaction(match self.conn_type() {
             ConnectionType::Sync => {
                 PlacesTransactionRepr::ChunkedWrite(self.chunked_coop_trransaction()?)
@@ -91,6 +107,52 @@ impl super::PlacesDb {
             }
         }))
     }
+
+    /// Begin a transaction for previewing changes, which is rolled back when
+    /// it's dropped. Until then, `begin_transaction` joins this transaction
+    /// instead of beginning a new one, and `maybe_commit` doesn't commit, so
+    /// that code which writes in its own transactions can be previewed. Note
+    /// that this holds the write lock until the preview finishes.
+    pub fn begin_preview_transaction(&self) -> Result<PreviewTransaction<'_>> {
+        assert!(
+            !self.in_preview.get(),
+            "begin_preview_transaction called inside a preview"
+        );
+        let tx = match self.conn_type() {
+            ConnectionType::ReadOnly => self.unchecked_transaction()?,
+            _ => self.preview_coop_transaction()?,
+        };
+        self.in_preview.set(true);
+        Ok(PreviewTransaction {
+            tx: Some(tx),
+            in_preview: &self.in_preview,
+        })
+    }
+}
+
+/// A transaction which always rolls back, for previewing changes.
+/// Construct one with `PlacesDb::begin_preview_transaction()`.
+pub struct PreviewTransaction<'conn> {
+    tx: Option<UncheckedTransaction<'conn>>,
+    in_preview: &'conn Cell<bool>,
+}
+
+impl<'conn> PreviewTransaction<'conn> {
+    /// Consumes and rolls back the preview.
+    pub fn rollback(mut self) -> Result<()> {
+        if let Some(tx) = self.tx.take() {
+            tx.rollback()?;
+        }
+        Ok(())
+    }
+}
+
+impl<'conn> Drop for PreviewTransaction<'conn> {
+    fn drop(&mut self) {
+        // The transaction, if we still have it, rolls back when it's dropped
+        // after this.
+        self.in_preview.set(false);
+    }
 }
 
 impl<'conn> std::ops::Deref for PlacesTransaction<'conn> {
@@ -101,6 +163,7 @@ impl<'conn> std::ops::Deref for PlacesTransaction<'conn> {
             PlacesTransactionRepr::ChunkedWrite(t) => &t,
             PlacesTransactionRepr::UnchunkedWrite(t) => &t,
             PlacesTransactionRepr::ReadOnly(t) => &t,
+            PlacesTransactionRepr::Preview(conn) => conn,
         }
     }
 }
@@ -32,6 +32,9 @@ pub mod error_codes {
     /// The requested operation failed because the store is corrupt
     pub const DATABASE_CORRUPT: i32 = 5;
 
+    /// We didn't sync because the server asked us to back off.
+    pub const SYNC_BACKOFF: i32 = 6;
+
     // Skip a bunch of spaces to make it clear these are part of a group,
     // even as more and more errors get added. We're only exposing the
     // InvalidPlaceInfo items that can actually be triggered, the others
@@ -105,6 +108,10 @@ fn get_code(err: &Error) -> ErrorCode {
             log::info!("The store is corrupt: {}", e);
             ErrorCode::new(error_codes::DATABASE_CORRUPT)
         }
+        ErrorKind::SyncAdapterError(e) if e.backoff_until().is_some() => {
+            log::info!("Sync backoff: {}", e);
+            ErrorCode::new(error_codes::SYNC_BACKOFF)
+        }
 
         err => {
             log::error!("Unexpected error: {:?}", err);
@@ -121,6 +128,8 @@ impl From<Error> for ExternError {
 
 implement_into_ffi_by_json!(SearchResult);
 implement_into_ffi_by_protobuf!(msg_types::HistoryVisitInfos);
+implement_into_ffi_by_protobuf!(msg_types::HistoryVisitGroups);
+implement_into_ffi_by_protobuf!(msg_types::TopSites);
 implement_into_ffi_by_protobuf!(msg_types::BookmarkNode);
 implement_into_ffi_by_protobuf!(msg_types::BookmarkNodeList);
 implement_into_ffi_by_delegation!(
@@ -11,6 +11,8 @@ mod plan;
 pub mod record;
 pub mod store;
 
+// We fetch incoming records in pages of this size, so a first sync of a big
+// account can be resumed if it's interrupted.
 const MAX_INCOMING_PLACES: usize = 5000;
 const MAX_OUTGOING_PLACES: usize = 5000;
 const MAX_VISITS: usize = 20;
@@ -281,6 +281,7 @@ mod tests {
     use crate::api::matcher::{search_frecent, SearchParams};
     use crate::api::places_api::ConnectionType;
     use crate::db::PlacesDb;
+    use crate::history_sync::store::HistoryStore;
     use crate::history_sync::ServerVisitTimestamp;
     use crate::observation::VisitObservation;
     use crate::storage::history::history_sync::fetch_visits;
@@ -290,7 +291,7 @@ mod tests {
     use serde_json::json;
     use sql_support::ConnExt;
     use std::time::Duration;
-    use sync15::{IncomingChangeset, ServerTimestamp};
+    use sync15::{IncomingChangeset, LocalChange, LocalChangeKind, ServerTimestamp, Store};
     use url::Url;
 
     fn get_existing_guid(conn: &PlacesDb, url: &Url) -> SyncGuid {
@@ -1030,4 +1031,68 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_preview_incoming() -> Result<()> {
+        let _ = env_logger::try_init();
+        let db = PlacesDb::open_in_memory(ConnectionType::Sync)?;
+        let url = Url::parse("https://example.com")?;
+        let obs = VisitObservation::new(url.clone())
+            .with_visit_type(VisitTransition::Link)
+            .with_at(Some(SystemTime::now().into()));
+        apply_observation(&db, obs)?;
+        let guid = get_existing_guid(&db, &url);
+        let new_url = Url::parse("https://example.org")?;
+
+        let mut incoming = IncomingChangeset::new("history".to_string(), ServerTimestamp(0f64));
+        // A new visit to the local page, and a new page.
+        let ts = SystemTime::now() - Duration::new(60, 0);
+        for (id, url) in &[(guid.as_ref(), &url), ("bbbbbbbbbbbb", &new_url)] {
+            let payload = Payload::from_json(json!({
+                "id": id,
+                "title": "title",
+                "histUri": url.as_str(),
+                "visits": [ {"date": ServerVisitTimestamp::from(ts), "type": 1}]
+            }))?;
+            incoming.changes.push((payload, ServerTimestamp(0f64)));
+        }
+
+        let interruptee = db.begin_interrupt_scope();
+        let store = HistoryStore::new(&db, &interruptee);
+        let preview = store
+            .preview_incoming(incoming, &mut telemetry::EngineIncoming::new())
+            .expect("Should preview incoming records");
+        assert_eq!(
+            preview.local_changes,
+            vec![
+                LocalChange {
+                    id: guid.as_ref().to_string(),
+                    kind: LocalChangeKind::Update,
+                },
+                LocalChange {
+                    id: "bbbbbbbbbbbb".to_string(),
+                    kind: LocalChangeKind::Insert,
+                },
+            ]
+        );
+        // We upload the local page, which now has the new visit, too.
+        assert_eq!(preview.outgoing.changes.len(), 1);
+        assert_eq!(preview.outgoing.changes[0].id, guid.as_ref());
+
+        // Nothing should have changed.
+        let (_, visits) = fetch_visits(&db, &url, 3)?.expect("page exists");
+        assert_eq!(visits.len(), 1);
+        assert!(fetch_visits(&db, &new_url, 3)?.is_none());
+        assert_eq!(get_sync(&db, &url), (SyncStatus::New, 1));
+
+        // And we should be able to begin a transaction again.
+        apply_plan(
+            &db,
+            IncomingChangeset::new("history".to_string(), ServerTimestamp(0f64)),
+            &mut telemetry::EngineIncoming::new(),
+            &NeverInterrupts,
+        )?;
+        assert_eq!(get_sync(&db, &url), (SyncStatus::Normal, 1));
+        Ok(())
+    }
 }
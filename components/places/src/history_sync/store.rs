@@ -6,16 +6,20 @@ use crate::api::places_api::{ConnectionType, GLOBAL_STATE_META_KEY};
 use crate::db::PlacesDb;
 use crate::error::*;
 use crate::storage::history::history_sync::reset_storage;
+use crate::storage::{restore_account_meta, stash_account_meta};
 use rusqlite::types::{FromSql, ToSql};
 use rusqlite::Connection;
-use sql_support::SqlInterruptScope;
+use sql_support::{ConnExt, SqlInterruptScope};
+use std::collections::HashMap;
 use std::ops::Deref;
 use std::result;
+use std::time::SystemTime;
 use sync15::telemetry;
 use sync15::{
-    extract_v1_state, sync_multiple, CollSyncIds, CollectionRequest, IncomingChangeset, KeyBundle,
-    MemoryCachedState, OutgoingChangeset, ServerTimestamp, Store, StoreSyncAssociation,
-    Sync15StorageClientInit,
+    extract_v1_state, sync_multiple, BatchProgress, CollSyncIds, CollectionRequest,
+    IncomingChangeset, KeyBundle, LocalChange, MemoryCachedState, OutgoingChangeset, RequestOrder,
+    ServerTimestamp, Store, StorePreview, StoreSyncAssociation, Sync15StorageClientInit,
+    SyncResult,
 };
 
 use super::plan::{apply_plan, finish_plan};
@@ -26,6 +30,22 @@ const LAST_SYNC_META_KEY: &str = "history_last_sync_time";
 // for the global sync ID, because engines are reset individually.
 const GLOBAL_SYNCID_META_KEY: &str = "history_global_sync_id";
 const COLLECTION_SYNCID_META_KEY: &str = "history_sync_id";
+// The progress of a batch upload which a sync was interrupted during, so that
+// large uploads, like the first one, can complete over several syncs.
+const PENDING_BATCH_META_KEY: &str = "history_pending_batch";
+// The highest visit ID when the user switched away from an account, so that
+// if they switch back, we upload the visits made in the meantime.
+const SWITCHED_AWAY_VISIT_META_KEY: &str = "history_switched_away_visit_id";
+// The meta keys which describe the account we're syncing with.
+const ACCOUNT_META_KEYS: &[&str] = &[
+    GLOBAL_SYNCID_META_KEY,
+    COLLECTION_SYNCID_META_KEY,
+    LAST_SYNC_META_KEY,
+    SWITCHED_AWAY_VISIT_META_KEY,
+];
+// A first sync can download tens of thousands of visits, so we decrypt them
+// on a few threads.
+const MAX_DECRYPT_THREADS: usize = 4;
 
 // A HistoryStore is short-lived and constructed each sync by something which
 // owns the connection and ClientInfo.
@@ -65,6 +85,25 @@ impl<'a> HistoryStore<'a> {
         Ok(outgoing)
     }
 
+    fn do_preview_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> Result<StorePreview> {
+        // Apply the records as a sync would, so that we can see what that
+        // changes, then roll everything back. Dropping the transaction on
+        // error also rolls it back.
+        let tx = self.db.begin_preview_transaction()?;
+        let before = fetch_local_pages(self.db)?;
+        let outgoing = self.do_apply_incoming(inbound, incoming_telemetry)?;
+        let after = fetch_local_pages(self.db)?;
+        tx.rollback()?;
+
+        let mut preview = StorePreview::new(outgoing);
+        preview.local_changes = LocalChange::between(before, after);
+        Ok(preview)
+    }
+
     fn do_sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
@@ -87,6 +126,7 @@ impl<'a> HistoryStore<'a> {
         let tx = self.db.begin_transaction()?;
         reset_storage(self.db)?;
         self.put_meta(LAST_SYNC_META_KEY, &0)?;
+        self.delete_meta(PENDING_BATCH_META_KEY)?;
         match assoc {
             StoreSyncAssociation::Disconnected => {
                 self.delete_meta(GLOBAL_SYNCID_META_KEY)?;
@@ -130,27 +170,65 @@ impl<'a> HistoryStore<'a> {
         Ok(())
     }
 
-    /// A convenience wrapper around sync_multiple.
+    /// Stashes the history sync state for the account `from`, and restores
+    /// the state for `to`, when the user switches accounts. Local history is
+    /// kept. If we haven't synced `to` before, or its sync IDs have changed
+    /// since, its next sync is a first sync. Otherwise, it resumes from where
+    /// it left off, uploading the visits made while `from` was signed in.
+    /// Visits removed in the meantime aren't removed from `to`.
+    ///
+    /// Like `migrate_v1_global_state`, this takes a connection, and should
+    /// be called in a transaction.
+    pub fn switch_account(db: &PlacesDb, from: &str, to: &str) -> Result<()> {
+        crate::storage::delete_meta(db, PENDING_BATCH_META_KEY)?;
+        let max_visit_id = db.query_one::<Option<i64>>("SELECT MAX(id) FROM moz_historyvisits")?;
+        crate::storage::put_meta(db, SWITCHED_AWAY_VISIT_META_KEY, &max_visit_id.unwrap_or(0))?;
+        stash_account_meta(db, ACCOUNT_META_KEYS, from)?;
+        restore_account_meta(db, ACCOUNT_META_KEYS, to)?;
+        if let Some(visit_id) = crate::storage::get_meta::<i64>(db, SWITCHED_AWAY_VISIT_META_KEY)? {
+            db.execute_named_cached(
+                "UPDATE moz_places
+                 SET sync_change_counter = sync_change_counter + 1
+                 WHERE id IN (SELECT place_id FROM moz_historyvisits
+                              WHERE is_local AND id > :visit_id)",
+                &[(":visit_id", &visit_id)],
+            )?;
+            crate::storage::delete_meta(db, SWITCHED_AWAY_VISIT_META_KEY)?;
+        }
+        Ok(())
+    }
+
+    /// A convenience wrapper around sync_multiple. `reason` is why the app
+    /// started this sync, if it knows, for telemetry. Returns the time before
+    /// which the server asked us not to sync again, if any.
     pub fn sync(
         &self,
         storage_init: &Sync15StorageClientInit,
         root_sync_key: &KeyBundle,
         mem_cached_state: &mut MemoryCachedState,
         disk_cached_state: &mut Option<String>,
+        reason: Option<telemetry::SyncReason>,
         sync_ping: &mut telemetry::SyncTelemetryPing,
-    ) -> Result<()> {
+        force: bool,
+    ) -> Result<Option<SystemTime>> {
         let result = sync_multiple(
             &[self],
             disk_cached_state,
             mem_cached_state,
             storage_init,
             root_sync_key,
+            None,
+            reason,
             sync_ping,
             self.interruptee,
+            force,
         );
-        let failures = result?;
+        let SyncResult {
+            failures,
+            next_sync_after,
+        } = result?;
         if failures.is_empty() {
-            Ok(())
+            Ok(next_sync_after)
         } else {
             assert_eq!(failures.len(), 1);
             let (name, err) = failures.into_iter().next().unwrap();
@@ -160,6 +238,35 @@ impl<'a> HistoryStore<'a> {
     }
 }
 
+// A local page, as compared before and after previewing a sync.
+#[derive(PartialEq)]
+struct LocalPage {
+    url: String,
+    title: Option<String>,
+    visit_count: i64,
+}
+
+// Fetches all local pages, keyed by GUID.
+fn fetch_local_pages(db: &PlacesDb) -> Result<HashMap<String, LocalPage>> {
+    db.query_rows_into(
+        "SELECT h.guid, h.url, h.title,
+                (SELECT COUNT(*) FROM moz_historyvisits v
+                 WHERE v.place_id = h.id) AS visitCount
+         FROM moz_places h",
+        &[],
+        |row| -> Result<_> {
+            Ok((
+                row.get::<_, String>("guid")?,
+                LocalPage {
+                    url: row.get("url")?,
+                    title: row.get("title")?,
+                    visit_count: row.get("visitCount")?,
+                },
+            ))
+        },
+    )
+}
+
 impl<'a> Deref for HistoryStore<'a> {
     type Target = Connection;
     #[inline]
@@ -181,6 +288,14 @@ impl<'a> Store for HistoryStore<'a> {
         Ok(self.do_apply_incoming(inbound, incoming_telemetry)?)
     }
 
+    fn preview_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        incoming_telemetry: &mut telemetry::EngineIncoming,
+    ) -> result::Result<StorePreview, failure::Error> {
+        Ok(self.do_preview_incoming(inbound, incoming_telemetry)?)
+    }
+
     fn sync_finished(
         &self,
         new_timestamp: ServerTimestamp,
@@ -198,6 +313,7 @@ impl<'a> Store for HistoryStore<'a> {
         Ok(CollectionRequest::new("history")
             .full()
             .newer_than(since)
+            .sort_by(RequestOrder::Oldest)
             .limit(MAX_INCOMING_PLACES))
     }
 
@@ -216,8 +332,32 @@ impl<'a> Store for HistoryStore<'a> {
         Ok(())
     }
 
+    fn get_pending_batch(&self) -> result::Result<Option<BatchProgress>, failure::Error> {
+        Ok(match self.get_meta::<String>(PENDING_BATCH_META_KEY)? {
+            Some(json) => Some(serde_json::from_str(&json)?),
+            None => None,
+        })
+    }
+
+    fn set_pending_batch(
+        &self,
+        progress: Option<&BatchProgress>,
+    ) -> result::Result<(), failure::Error> {
+        match progress {
+            Some(progress) => {
+                self.put_meta(PENDING_BATCH_META_KEY, &serde_json::to_string(progress)?)?
+            }
+            None => self.delete_meta(PENDING_BATCH_META_KEY)?,
+        }
+        Ok(())
+    }
+
     fn wipe(&self) -> result::Result<(), failure::Error> {
         log::warn!("not implemented");
         Ok(())
     }
+
+    fn max_decrypt_threads(&self) -> usize {
+        MAX_DECRYPT_THREADS
+    }
 }
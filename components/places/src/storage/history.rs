@@ -7,10 +7,13 @@ use crate::db::PlacesDb;
 use crate::error::Result;
 use crate::frecency;
 use crate::hash;
-use crate::msg_types::{HistoryVisitInfo, HistoryVisitInfos};
+use crate::msg_types::{
+    HistoryVisitGroup, HistoryVisitGroups, HistoryVisitInfo, HistoryVisitInfos,
+};
 use crate::observation::VisitObservation;
 use crate::storage::{delete_pending_temp_tables, get_meta, put_meta};
 use crate::types::{SyncGuid, SyncStatus, Timestamp, VisitTransition, VisitTransitionSet};
+use interrupt::Interruptee;
 use rusqlite::types::ToSql;
 use rusqlite::Result as RusqliteResult;
 use rusqlite::{Row, NO_PARAMS};
@@ -25,6 +28,10 @@ use url::Url;
 /// add visits to them remotely.
 static DELETION_HIGH_WATER_MARK_META_KEY: &str = "history_deleted_hwm";
 
+/// The frecency settings we last calculated frecencies with, as JSON. If it's
+/// missing, we calculated them with the defaults.
+static FRECENCY_SETTINGS_META_KEY: &str = "frecency_settings";
+
 /// Returns the RowId of a new visit in moz_historyvisits, or None if no new visit was added.
 pub fn apply_observation(db: &PlacesDb, visit_ob: VisitObservation) -> Result<Option<RowId>> {
     let tx = db.begin_transaction()?;
@@ -121,7 +128,7 @@ pub fn apply_observation_direct(
 pub fn update_frecency(db: &PlacesDb, id: RowId, redirect_boost: Option<bool>) -> Result<()> {
     let score = frecency::calculate_frecency(
         db.conn(),
-        &frecency::DEFAULT_FRECENCY_SETTINGS,
+        &db.frecency_settings(),
         id.0, // TODO: calculate_frecency should take a RowId here.
         redirect_boost,
     )?;
@@ -151,6 +158,98 @@ pub fn frecency_stale_at(db: &PlacesDb, url: &Url) -> Result<Option<Timestamp>>
     Ok(result)
 }
 
+/// Marks the frecencies of all pages as stale if `db`'s frecency settings
+/// changed since we last calculated them, so that
+/// `recalculate_stale_frecencies` recalculates them with the new settings.
+pub fn mark_frecencies_stale_if_settings_changed(db: &PlacesDb) -> Result<()> {
+    let settings = serde_json::to_string(&db.frecency_settings())?;
+    let last_settings = match get_meta::<String>(db, FRECENCY_SETTINGS_META_KEY)? {
+        Some(last_settings) => last_settings,
+        None => serde_json::to_string(&frecency::DEFAULT_FRECENCY_SETTINGS)?,
+    };
+    if settings == last_settings {
+        return Ok(());
+    }
+    log::info!("Frecency settings changed; marking all frecencies as stale");
+    // We might be in a transaction already, if we're opening the connection,
+    // so we don't start one here. If we're interrupted before we store the
+    // new settings, we'll mark them as stale again next time, which is fine.
+    db.execute_named_cached(
+        "INSERT INTO moz_places_stale_frecencies(place_id, stale_at)
+         SELECT id, now() FROM moz_places
+         WHERE true
+         ON CONFLICT(place_id) DO UPDATE SET
+           stale_at = excluded.stale_at",
+        &[],
+    )?;
+    put_meta(db, FRECENCY_SETTINGS_META_KEY, &settings)?;
+    Ok(())
+}
+
+/// How far `recalculate_stale_frecencies` got.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct FrecencyRecalculationProgress {
+    /// The number of frecencies we recalculated.
+    pub recalculated: usize,
+    /// The number of stale frecencies left to recalculate.
+    pub remaining: usize,
+}
+
+/// Recalculates up to `max_rows` stale frecencies, most recently marked
+/// first. This is meant to be called when the app is idle, like
+/// `run_maintenance`, until there are none remaining.
+///
+/// We commit after each chunk, so if we're interrupted, we keep the
+/// frecencies we recalculated before the chunk we were working on.
+pub fn recalculate_stale_frecencies(
+    db: &PlacesDb,
+    max_rows: usize,
+    interruptee: &impl Interruptee,
+) -> Result<FrecencyRecalculationProgress> {
+    // The settings might have changed while the app had the connection.
+    mark_frecencies_stale_if_settings_changed(db)?;
+    let settings = db.frecency_settings();
+    let place_ids = db.query_rows_and_then_named_cached(
+        "SELECT place_id FROM moz_places_stale_frecencies
+         ORDER BY stale_at DESC
+         LIMIT :limit",
+        &[(":limit", &(max_rows as i64))],
+        |row| row.get::<_, i64>(0),
+    )?;
+    let mut progress = FrecencyRecalculationProgress::default();
+    sql_support::each_chunk(&place_ids, |chunk, _| -> Result<()> {
+        let tx = db.begin_transaction()?;
+        for &place_id in chunk {
+            // Frecency recalculation runs several statements, so check to
+            // make sure we aren't interrupted before each calculation.
+            interruptee.err_if_interrupted()?;
+            let frecency = frecency::calculate_frecency(db.conn(), &settings, place_id, None)?;
+            db.execute_named_cached(
+                "UPDATE moz_places SET frecency = :frecency WHERE id = :place_id",
+                &[(":frecency", &frecency), (":place_id", &place_id)],
+            )?;
+        }
+        db.execute(
+            &format!(
+                "DELETE FROM moz_places_stale_frecencies WHERE place_id IN ({})",
+                sql_support::repeat_sql_vars(chunk.len()),
+            ),
+            chunk,
+        )?;
+        tx.commit()?;
+        progress.recalculated += chunk.len();
+        log::debug!(
+            "Recalculated {} of {} stale frecencies",
+            progress.recalculated,
+            place_ids.len()
+        );
+        Ok(())
+    })?;
+    progress.remaining =
+        db.query_one::<i64>("SELECT COUNT(*) FROM moz_places_stale_frecencies")? as usize;
+    Ok(progress)
+}
+
 // Add a single visit - you must know the page rowid. Does not update the
 // page info - if you are calling this, you will also need to update the
 // parent page with an updated change counter etc.
@@ -251,7 +350,6 @@ pub fn wipe_local(db: &PlacesDb) -> Result<()> {
 }
 
 fn wipe_local_in_tx(db: &PlacesDb, tx: crate::db::PlacesTransaction<'_>) -> Result<()> {
-    use crate::frecency::DEFAULT_FRECENCY_SETTINGS;
     db.execute_all(&[
         "DELETE FROM moz_places WHERE foreign_count == 0",
         "DELETE FROM moz_historyvisits",
@@ -264,7 +362,7 @@ fn wipe_local_in_tx(db: &PlacesDb, tx: crate::db::PlacesTransaction<'_>) -> Resu
             "UPDATE moz_places SET
                 frecency = {unvisited_bookmark_frec},
                 sync_change_counter = 0",
-            unvisited_bookmark_frec = DEFAULT_FRECENCY_SETTINGS.unvisited_bookmark_bonus
+            unvisited_bookmark_frec = db.frecency_settings().unvisited_bookmark_bonus
         ),
     ])?;
 
@@ -1103,6 +1201,106 @@ pub fn get_visit_page(
     Ok(HistoryVisitInfos { infos })
 }
 
+/// How `get_visit_groups` groups visits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VisitGrouping {
+    /// By the local day they were made on, in a timezone `utc_offset_minutes`
+    /// ahead of UTC. Groups are ordered from the most recent day. Note that
+    /// the same offset is used for every visit, so days on the other side of
+    /// a daylight saving time change will start an hour early or late.
+    Day { utc_offset_minutes: i32 },
+    /// By the base domain ("eTLD+1") of the visited page, like
+    /// "example.co.uk" for "www.example.co.uk", so that subdomains of a site
+    /// are grouped together. Groups are ordered from the most recently
+    /// visited.
+    BaseDomain,
+}
+
+const MILLIS_PER_DAY: i64 = 24 * 60 * 60 * 1000;
+
+/// Returns a page of groups of visits, with the number of visits and the times
+/// of the first and last visit in each, for the history UI. Visits of types in
+/// `exclude_types` aren't counted.
+pub fn get_visit_groups(
+    db: &PlacesDb,
+    grouping: VisitGrouping,
+    offset: i64,
+    count: i64,
+    exclude_types: VisitTransitionSet,
+) -> Result<HistoryVisitGroups> {
+    let allowed_types = exclude_types.complement();
+    let groups = match grouping {
+        VisitGrouping::Day { utc_offset_minutes } => {
+            let utc_offset = i64::from(utc_offset_minutes) * 60 * 1000;
+            db.query_rows_and_then_named_cached(
+                "SELECT (v.visit_date + :utc_offset) / :millis_per_day AS day,
+                        MIN(v.visit_date) AS first_visit,
+                        MAX(v.visit_date) AS last_visit,
+                        COUNT(*) AS visit_count
+                 FROM moz_historyvisits v
+                 WHERE ((1 << v.visit_type) & :allowed_types) != 0
+                 GROUP BY day
+                 ORDER BY day DESC
+                 LIMIT :count
+                 OFFSET :offset",
+                rusqlite::named_params! {
+                    ":utc_offset": utc_offset,
+                    ":millis_per_day": MILLIS_PER_DAY,
+                    ":allowed_types": allowed_types,
+                    ":count": count,
+                    ":offset": offset,
+                },
+                |row| -> Result<_> {
+                    Ok(HistoryVisitGroup {
+                        day_start: Some(row.get::<_, i64>("day")? * MILLIS_PER_DAY - utc_offset),
+                        base_domain: None,
+                        first_visit: row.get::<_, Timestamp>("first_visit")?.0 as i64,
+                        last_visit: row.get::<_, Timestamp>("last_visit")?.0 as i64,
+                        visit_count: row.get("visit_count")?,
+                    })
+                },
+            )?
+        }
+        // Looking up the base domain is much slower than grouping, so we
+        // group the visits by origin first, and only look up the base domain
+        // once for each origin.
+        VisitGrouping::BaseDomain => db.query_rows_and_then_named_cached(
+            "SELECT get_base_domain(o.host) AS site,
+                    MIN(g.origin_first_visit) AS first_visit,
+                    MAX(g.origin_last_visit) AS last_visit,
+                    SUM(g.origin_visit_count) AS visit_count
+             FROM (SELECT h.origin_id,
+                          MIN(v.visit_date) AS origin_first_visit,
+                          MAX(v.visit_date) AS origin_last_visit,
+                          COUNT(*) AS origin_visit_count
+                   FROM moz_historyvisits v
+                   JOIN moz_places h ON h.id = v.place_id
+                   WHERE ((1 << v.visit_type) & :allowed_types) != 0
+                   GROUP BY h.origin_id) g
+             JOIN moz_origins o ON o.id = g.origin_id
+             GROUP BY site
+             ORDER BY last_visit DESC, site
+             LIMIT :count
+             OFFSET :offset",
+            rusqlite::named_params! {
+                ":allowed_types": allowed_types,
+                ":count": count,
+                ":offset": offset,
+            },
+            |row| -> Result<_> {
+                Ok(HistoryVisitGroup {
+                    day_start: None,
+                    base_domain: Some(row.get("site")?),
+                    first_visit: row.get::<_, Timestamp>("first_visit")?.0 as i64,
+                    last_visit: row.get::<_, Timestamp>("last_visit")?.0 as i64,
+                    visit_count: row.get("visit_count")?,
+                })
+            },
+        )?,
+    };
+    Ok(HistoryVisitGroups { groups })
+}
+
 #[cfg(test)]
 mod tests {
     use super::history_sync::*;
@@ -1203,6 +1401,140 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_get_visit_groups() -> Result<()> {
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        // Midnight UTC.
+        let midnight = 1_500_000_000_000 / MILLIS_PER_DAY * MILLIS_PER_DAY;
+        let hour = 60 * 60 * 1000;
+        let to_add = [
+            (
+                "https://www.example.com/a",
+                midnight - hour / 2,
+                VisitTransition::Link,
+            ),
+            (
+                "https://example.com/b",
+                midnight + hour,
+                VisitTransition::Typed,
+            ),
+            (
+                "https://mozilla.org/",
+                midnight - 2 * hour,
+                VisitTransition::Link,
+            ),
+            (
+                "https://developer.mozilla.org/",
+                midnight - 3 * hour,
+                VisitTransition::Link,
+            ),
+            (
+                "https://example.com/c",
+                midnight + 2 * hour,
+                VisitTransition::Reload,
+            ),
+        ];
+        for &(url, when, visit_type) in &to_add {
+            apply_observation(
+                &conn,
+                VisitObservation::new(Url::parse(url)?)
+                    .with_at(Timestamp(when as u64))
+                    .with_visit_type(visit_type),
+            )?;
+        }
+        let exclude_types = VisitTransitionSet::single(VisitTransition::Reload);
+        let group = |day_start: Option<i64>, base_domain: Option<&str>, first, last, count| {
+            HistoryVisitGroup {
+                day_start,
+                base_domain: base_domain.map(str::to_owned),
+                first_visit: first,
+                last_visit: last,
+                visit_count: count,
+            }
+        };
+
+        // An hour ahead of UTC, the visit at 11:30pm UTC is from the next day.
+        let days = get_visit_groups(
+            &conn,
+            VisitGrouping::Day {
+                utc_offset_minutes: 60,
+            },
+            0,
+            10,
+            exclude_types,
+        )?;
+        assert_eq!(
+            days.groups,
+            vec![
+                group(
+                    Some(midnight - hour),
+                    None,
+                    midnight - hour / 2,
+                    midnight + hour,
+                    2
+                ),
+                group(
+                    Some(midnight - MILLIS_PER_DAY - hour),
+                    None,
+                    midnight - 3 * hour,
+                    midnight - 2 * hour,
+                    2
+                ),
+            ]
+        );
+        let days = get_visit_groups(
+            &conn,
+            VisitGrouping::Day {
+                utc_offset_minutes: 0,
+            },
+            0,
+            10,
+            exclude_types,
+        )?;
+        assert_eq!(
+            days.groups
+                .iter()
+                .map(|g| (g.day_start, g.visit_count))
+                .collect::<Vec<_>>(),
+            vec![(Some(midnight), 1), (Some(midnight - MILLIS_PER_DAY), 3)]
+        );
+
+        // Subdomains are part of the same site.
+        let sites = get_visit_groups(&conn, VisitGrouping::BaseDomain, 0, 10, exclude_types)?;
+        assert_eq!(
+            sites.groups,
+            vec![
+                group(
+                    None,
+                    Some("example.com"),
+                    midnight - hour / 2,
+                    midnight + hour,
+                    2
+                ),
+                group(
+                    None,
+                    Some("mozilla.org"),
+                    midnight - 3 * hour,
+                    midnight - 2 * hour,
+                    2
+                ),
+            ]
+        );
+        let sites = get_visit_groups(&conn, VisitGrouping::BaseDomain, 1, 1, exclude_types)?;
+        assert_eq!(sites.groups.len(), 1);
+        assert_eq!(sites.groups[0].base_domain, Some("mozilla.org".to_string()));
+        let sites = get_visit_groups(
+            &conn,
+            VisitGrouping::BaseDomain,
+            0,
+            10,
+            VisitTransitionSet::empty(),
+        )?;
+        assert_eq!(sites.groups[0].visit_count, 3);
+        assert_eq!(sites.groups[0].last_visit, midnight + 2 * hour);
+        Ok(())
+    }
+
     fn get_custom_observed_page<F>(conn: &mut PlacesDb, url: &str, custom: F) -> Result<PageInfo>
     where
         F: Fn(VisitObservation) -> VisitObservation,
@@ -2150,4 +2482,153 @@ mod tests {
         assert_eq!(db_title.len(), crate::storage::TITLE_LENGTH_MAX);
         assert!(title.starts_with(&db_title));
     }
+
+    #[test]
+    fn test_frecency_settings() -> Result<()> {
+        use crate::api::places_api::test::new_mem_api;
+        use crate::frecency::FrecencySettings;
+        use interrupt::NeverInterrupts;
+
+        fn frecency(conn: &PlacesDb, url: &Url) -> Result<i32> {
+            Ok(fetch_page_info(conn, url)?
+                .expect("Should have page")
+                .page
+                .frecency)
+        }
+
+        let _ = env_logger::try_init();
+        let api = new_mem_api();
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+        let typed = Url::parse("https://example.com/typed")?;
+        let linked = Url::parse("https://example.com/linked")?;
+        apply_observation(
+            &conn,
+            VisitObservation::new(typed.clone()).with_visit_type(VisitTransition::Typed),
+        )?;
+        apply_observation(
+            &conn,
+            VisitObservation::new(linked.clone()).with_visit_type(VisitTransition::Link),
+        )?;
+        let typed_frecency = frecency(&conn, &typed)?;
+        assert!(typed_frecency > frecency(&conn, &linked)?);
+        assert_eq!(
+            recalculate_stale_frecencies(&conn, 10, &NeverInterrupts)?.recalculated,
+            0
+        );
+
+        // The app has the write connection, so we should mark the frecencies
+        // as stale when we next recalculate them.
+        api.set_frecency_settings(FrecencySettings {
+            typed_visit_bonus: 0,
+            unvisited_typed_bonus: 0,
+            ..FrecencySettings::default()
+        })?;
+        assert_eq!(
+            recalculate_stale_frecencies(&conn, 1, &NeverInterrupts)?.recalculated,
+            1
+        );
+        assert_eq!(
+            recalculate_stale_frecencies(&conn, 10, &NeverInterrupts)?.recalculated,
+            1
+        );
+        assert!(frecency_stale_at(&conn, &typed)?.is_none());
+        assert!(frecency_stale_at(&conn, &linked)?.is_none());
+        assert!(frecency(&conn, &typed)? < typed_frecency);
+        assert_eq!(
+            recalculate_stale_frecencies(&conn, 10, &NeverInterrupts)?.recalculated,
+            0
+        );
+
+        // New visits should use the new settings right away.
+        apply_observation(
+            &conn,
+            VisitObservation::new(linked.clone()).with_visit_type(VisitTransition::Typed),
+        )?;
+        assert!(frecency_stale_at(&conn, &linked)?.is_none());
+
+        // If we have the write connection, we should mark them right away.
+        api.close_connection(conn)?;
+        api.set_frecency_settings(FrecencySettings::default())?;
+        let conn = api.open_connection(ConnectionType::ReadWrite)?;
+        assert!(frecency_stale_at(&conn, &typed)?.is_some());
+        assert!(frecency_stale_at(&conn, &linked)?.is_some());
+        assert_eq!(
+            recalculate_stale_frecencies(&conn, 10, &NeverInterrupts)?.recalculated,
+            2
+        );
+        assert_eq!(frecency(&conn, &typed)?, typed_frecency);
+
+        // Setting the same settings again shouldn't mark anything.
+        api.set_frecency_settings(FrecencySettings::default())?;
+        assert_eq!(
+            recalculate_stale_frecencies(&conn, 10, &NeverInterrupts)?.recalculated,
+            0
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_recalculate_stale_frecencies() -> Result<()> {
+        use interrupt::NeverInterrupts;
+        use std::cell::Cell;
+
+        // Interrupts after `was_interrupted` is called this many times.
+        struct InterruptAfter(Cell<usize>);
+        impl Interruptee for InterruptAfter {
+            fn was_interrupted(&self) -> bool {
+                let remaining = self.0.get();
+                self.0.set(remaining.saturating_sub(1));
+                remaining == 0
+            }
+        }
+
+        let _ = env_logger::try_init();
+        let conn = PlacesDb::open_in_memory(ConnectionType::ReadWrite)?;
+        let urls = (0..5)
+            .map(|i| Url::parse(&format!("https://example.com/{}", i)))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        for url in &urls {
+            apply_observation(
+                &conn,
+                VisitObservation::new(url.clone()).with_visit_type(VisitTransition::Link),
+            )?;
+        }
+        conn.execute_batch(
+            "INSERT INTO moz_places_stale_frecencies(place_id, stale_at)
+             SELECT id, now() FROM moz_places;
+             UPDATE moz_places SET frecency = 1;",
+        )?;
+
+        // If we're interrupted, we should roll back the chunk we were
+        // working on.
+        assert!(recalculate_stale_frecencies(&conn, 10, &InterruptAfter(Cell::new(2))).is_err());
+        for url in &urls {
+            assert!(frecency_stale_at(&conn, url)?.is_some());
+        }
+
+        assert_eq!(
+            recalculate_stale_frecencies(&conn, 3, &NeverInterrupts)?,
+            FrecencyRecalculationProgress {
+                recalculated: 3,
+                remaining: 2,
+            }
+        );
+        assert_eq!(
+            recalculate_stale_frecencies(&conn, 3, &NeverInterrupts)?,
+            FrecencyRecalculationProgress {
+                recalculated: 2,
+                remaining: 0,
+            }
+        );
+        for url in &urls {
+            assert!(frecency_stale_at(&conn, url)?.is_none());
+            let page = fetch_page_info(&conn, url)?.expect("Should have page").page;
+            assert!(page.frecency > 1);
+        }
+        assert_eq!(
+            recalculate_stale_frecencies(&conn, 3, &NeverInterrupts)?,
+            FrecencyRecalculationProgress::default()
+        );
+        Ok(())
+    }
 }
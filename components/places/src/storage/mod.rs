@@ -8,6 +8,7 @@
 pub mod bookmarks;
 pub mod history;
 pub mod tags;
+pub mod top_sites;
 
 use crate::db::PlacesDb;
 use crate::error::{ErrorKind, InvalidPlaceInfo, Result};
@@ -191,7 +192,12 @@ impl HistoryVisitInfo {
 }
 
 pub fn run_maintenance(conn: &PlacesDb) -> Result<()> {
-    conn.execute_all(&["VACUUM", "PRAGMA optimize"])?;
+    conn.execute_all(&[
+        // Merge the full-text index's segments, to keep searches fast.
+        "INSERT INTO moz_places_fts(moz_places_fts) VALUES('optimize')",
+        "VACUUM",
+        "PRAGMA optimize",
+    ])?;
     Ok(())
 }
 
@@ -217,6 +223,44 @@ pub(crate) fn delete_meta(db: &PlacesDb, key: &str) -> Result<()> {
     Ok(())
 }
 
+// The key we keep an account's value for `key` under, while another account
+// is signed in.
+fn account_meta_key(key: &str, uid: &str) -> String {
+    format!("{}:{}", key, uid)
+}
+
+/// Moves the values of `keys` aside for the account `uid`, so that they can
+/// be restored by `restore_account_meta` if the user signs back in to it.
+pub(crate) fn stash_account_meta(db: &PlacesDb, keys: &[&str], uid: &str) -> Result<()> {
+    for &key in keys {
+        let stashed_key = account_meta_key(key, uid);
+        delete_meta(db, &stashed_key)?;
+        db.execute_named_cached(
+            "INSERT INTO moz_meta (key, value)
+             SELECT :stashed_key, value FROM moz_meta WHERE key = :key",
+            &[(":stashed_key", &stashed_key), (":key", &key)],
+        )?;
+        delete_meta(db, key)?;
+    }
+    Ok(())
+}
+
+/// Replaces the values of `keys` with the ones stashed for the account `uid`,
+/// or removes them if we haven't synced that account before.
+pub(crate) fn restore_account_meta(db: &PlacesDb, keys: &[&str], uid: &str) -> Result<()> {
+    for &key in keys {
+        let stashed_key = account_meta_key(key, uid);
+        delete_meta(db, key)?;
+        db.execute_named_cached(
+            "INSERT INTO moz_meta (key, value)
+             SELECT :key, value FROM moz_meta WHERE key = :stashed_key",
+            &[(":key", &key), (":stashed_key", &stashed_key)],
+        )?;
+        delete_meta(db, &stashed_key)?;
+    }
+    Ok(())
+}
+
 /// Delete all items in the temp tables we use for staging changes.
 pub(crate) fn delete_pending_temp_tables(conn: &PlacesDb) -> Result<()> {
     conn.execute_batch(
@@ -253,4 +297,34 @@ mod tests {
             .is_none());
         delete_meta(&conn, "foo").expect("delete non-existing should work");
     }
+
+    #[test]
+    fn test_account_meta() -> Result<()> {
+        let conn = new_mem_connection();
+        let keys = &["sync_id", "last_sync"];
+        put_meta(&conn, "sync_id", &"alice-id")?;
+        put_meta(&conn, "last_sync", &1234)?;
+
+        // Switching to a new account leaves no state.
+        stash_account_meta(&conn, keys, "alice")?;
+        restore_account_meta(&conn, keys, "bob")?;
+        assert_eq!(get_meta::<String>(&conn, "sync_id")?, None);
+        assert_eq!(get_meta::<i64>(&conn, "last_sync")?, None);
+        put_meta(&conn, "sync_id", &"bob-id")?;
+
+        // Switching back restores the first account's state, with its type.
+        stash_account_meta(&conn, keys, "bob")?;
+        restore_account_meta(&conn, keys, "alice")?;
+        assert_eq!(
+            get_meta::<String>(&conn, "sync_id")?,
+            Some("alice-id".into())
+        );
+        assert_eq!(get_meta::<i64>(&conn, "last_sync")?, Some(1234));
+        assert_eq!(get_meta::<String>(&conn, "sync_id:alice")?, None);
+        assert_eq!(
+            get_meta::<String>(&conn, "sync_id:bob")?,
+            Some("bob-id".into())
+        );
+        Ok(())
+    }
 }
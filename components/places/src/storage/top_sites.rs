@@ -0,0 +1,324 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Top sites for the new tab page: the user's most frecent sites, with the
+// sites they pinned at fixed positions, and without the ones they removed.
+
+use super::TITLE_LENGTH_MAX;
+use crate::db::PlacesDb;
+use crate::error::Result;
+use crate::msg_types::{TopSite, TopSites};
+use sql_support::ConnExt;
+use url::Url;
+
+/// Returns up to `limit` top sites. Pinned sites are at their positions, and
+/// the user's most frecent pages fill the others, one per origin. If there
+/// aren't enough frecent pages to fill the positions before a pinned site,
+/// it moves up.
+///
+/// We skip hidden pages, which includes pages that were only visited as
+/// redirect sources or in frames, pages with no frecency, which includes
+/// pages that were only visited with errors, and pages the user removed with
+/// `block_top_site`. We also skip pages from the same origin as a pinned site.
+pub fn get_top_sites(db: &PlacesDb, limit: u32) -> Result<TopSites> {
+    let pinned = db.query_rows_and_then_named_cached(
+        "SELECT p.position, p.url, IFNULL(p.title, h.title) AS title,
+                MAX(IFNULL(h.frecency, 0), 0) AS frecency
+         FROM moz_topsites_pinned p
+         LEFT JOIN moz_places h ON h.url_hash = hash(p.url) AND h.url = p.url
+         WHERE p.position < :limit
+         ORDER BY p.position",
+        &[(":limit", &limit)],
+        |row| -> Result<_> {
+            Ok((
+                row.get::<_, i64>("position")?,
+                TopSite {
+                    url: row.get("url")?,
+                    title: row.get("title")?,
+                    frecency: row.get("frecency")?,
+                    pinned: true,
+                },
+            ))
+        },
+    )?;
+    // SQLite takes the bare columns in an aggregate query from the row with
+    // the `MAX`, so this is the most frecent page from each origin.
+    let frecent = db.query_rows_and_then_named_cached(
+        "SELECT h.url, h.title, MAX(h.frecency) AS frecency
+         FROM moz_places h
+         WHERE h.frecency > 0
+           AND NOT h.hidden
+           AND h.visit_count_local + h.visit_count_remote > 0
+           AND NOT EXISTS(SELECT 1 FROM moz_topsites_blocked b
+                          WHERE b.url = h.url)
+           AND NOT EXISTS(SELECT 1 FROM moz_topsites_pinned p
+                          JOIN moz_origins o ON o.prefix = get_prefix(p.url)
+                                            AND o.host = get_host_and_port(p.url)
+                          WHERE o.id = h.origin_id)
+         GROUP BY h.origin_id
+         ORDER BY frecency DESC, h.id
+         LIMIT :limit",
+        &[(":limit", &limit)],
+        |row| -> Result<_> {
+            Ok(TopSite {
+                url: row.get("url")?,
+                title: row.get("title")?,
+                frecency: row.get("frecency")?,
+                pinned: false,
+            })
+        },
+    )?;
+
+    let mut pinned = pinned.into_iter().peekable();
+    let mut frecent = frecent.into_iter();
+    let mut sites = Vec::new();
+    while sites.len() < limit as usize {
+        let next = match pinned.peek() {
+            Some((position, _)) if *position as usize <= sites.len() => {
+                pinned.next().map(|(_, site)| site)
+            }
+            _ => frecent
+                .next()
+                .or_else(|| pinned.next().map(|(_, site)| site)),
+        };
+        match next {
+            Some(site) => sites.push(site),
+            None => break,
+        }
+    }
+    Ok(TopSites { sites })
+}
+
+/// Pins `url` to `position` in the top sites, replacing the site that was
+/// pinned there, and moving `url` if it was pinned elsewhere. If `title` is
+/// `None`, we show the page's title from history.
+pub fn pin_top_site(db: &PlacesDb, url: &Url, title: Option<&str>, position: u32) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    let title = title.map(|title| crate::util::slice_up_to(title, TITLE_LENGTH_MAX));
+    // `REPLACE` removes both the site at `position`, and any other pin for
+    // `url`.
+    db.execute_named_cached(
+        "REPLACE INTO moz_topsites_pinned(position, url, title)
+         VALUES(:position, :url, :title)",
+        &[
+            (":position", &position),
+            (":url", &url.as_str()),
+            (":title", &title),
+        ],
+    )?;
+    db.execute_named_cached(
+        "DELETE FROM moz_topsites_blocked WHERE url = :url",
+        &[(":url", &url.as_str())],
+    )?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Unpins `url` from the top sites. It stays in the top sites if it's frecent
+/// enough.
+pub fn unpin_top_site(db: &PlacesDb, url: &Url) -> Result<()> {
+    db.execute_named_cached(
+        "DELETE FROM moz_topsites_pinned WHERE url = :url",
+        &[(":url", &url.as_str())],
+    )?;
+    Ok(())
+}
+
+/// Removes `url` from the top sites, and unpins it, until it's unblocked or
+/// pinned again. This doesn't affect history.
+pub fn block_top_site(db: &PlacesDb, url: &Url) -> Result<()> {
+    let tx = db.begin_transaction()?;
+    db.execute_named_cached(
+        "INSERT OR IGNORE INTO moz_topsites_blocked(url) VALUES(:url)",
+        &[(":url", &url.as_str())],
+    )?;
+    unpin_top_site(db, url)?;
+    tx.commit()?;
+    Ok(())
+}
+
+/// Lets `url` appear in the top sites again, after `block_top_site`.
+pub fn unblock_top_site(db: &PlacesDb, url: &Url) -> Result<()> {
+    db.execute_named_cached(
+        "DELETE FROM moz_topsites_blocked WHERE url = :url",
+        &[(":url", &url.as_str())],
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::api::places_api::test::new_mem_connection;
+    use crate::observation::VisitObservation;
+    use crate::storage::history::apply_observation;
+    use crate::types::{Timestamp, VisitTransition};
+
+    fn visit(db: &PlacesDb, url: &str, title: &str, visit_type: VisitTransition, count: usize) {
+        for _ in 0..count {
+            let obs = VisitObservation::new(Url::parse(url).unwrap())
+                .with_title(title.to_string())
+                .with_visit_type(visit_type)
+                .with_at(Timestamp::now());
+            apply_observation(db, obs).expect("Should apply visit");
+        }
+    }
+
+    fn urls(db: &PlacesDb, limit: u32) -> Vec<(String, bool)> {
+        get_top_sites(db, limit)
+            .expect("Should get top sites")
+            .sites
+            .into_iter()
+            .map(|site| (site.url, site.pinned))
+            .collect()
+    }
+
+    #[test]
+    fn test_top_sites() -> Result<()> {
+        let _ = env_logger::try_init();
+        let db = new_mem_connection();
+        visit(
+            &db,
+            "https://example.com/",
+            "Example",
+            VisitTransition::Typed,
+            5,
+        );
+        visit(
+            &db,
+            "https://example.com/page",
+            "Page",
+            VisitTransition::Link,
+            1,
+        );
+        visit(
+            &db,
+            "https://mozilla.org/",
+            "Mozilla",
+            VisitTransition::Typed,
+            3,
+        );
+        visit(
+            &db,
+            "https://rust-lang.org/",
+            "Rust",
+            VisitTransition::Link,
+            1,
+        );
+        // Hidden, and error pages shouldn't be included.
+        apply_observation(
+            &db,
+            VisitObservation::new(Url::parse("https://redirect.example/")?)
+                .with_visit_type(VisitTransition::Link)
+                .with_is_redirect_source(true),
+        )?;
+        apply_observation(
+            &db,
+            VisitObservation::new(Url::parse("https://error.example/")?)
+                .with_visit_type(VisitTransition::Typed)
+                .with_is_error(true),
+        )?;
+
+        // One page per origin, by frecency.
+        assert_eq!(
+            urls(&db, 10),
+            vec![
+                ("https://example.com/".to_string(), false),
+                ("https://mozilla.org/".to_string(), false),
+                ("https://rust-lang.org/".to_string(), false),
+            ]
+        );
+        assert_eq!(
+            urls(&db, 1),
+            vec![("https://example.com/".to_string(), false)]
+        );
+
+        // Blocking a page removes it.
+        block_top_site(&db, &Url::parse("https://mozilla.org/")?)?;
+        assert_eq!(
+            urls(&db, 10),
+            vec![
+                ("https://example.com/".to_string(), false),
+                ("https://rust-lang.org/".to_string(), false),
+            ]
+        );
+
+        // Pinned sites are at their positions, and replace frecent pages from
+        // the same origin.
+        pin_top_site(&db, &Url::parse("https://rust-lang.org/")?, None, 0)?;
+        pin_top_site(
+            &db,
+            &Url::parse("https://pinned.example/")?,
+            Some("Pinned"),
+            1,
+        )?;
+        let sites = get_top_sites(&db, 10)?.sites;
+        assert_eq!(
+            sites,
+            vec![
+                TopSite {
+                    url: "https://rust-lang.org/".into(),
+                    title: Some("Rust".into()),
+                    frecency: sites[0].frecency,
+                    pinned: true,
+                },
+                TopSite {
+                    url: "https://pinned.example/".into(),
+                    title: Some("Pinned".into()),
+                    frecency: 0,
+                    pinned: true,
+                },
+                TopSite {
+                    url: "https://example.com/".into(),
+                    title: Some("Example".into()),
+                    frecency: sites[2].frecency,
+                    pinned: false,
+                },
+            ]
+        );
+        assert!(sites[0].frecency > 0);
+
+        // Pinned sites past the limit aren't shown, and ones after the
+        // frecent pages move up.
+        pin_top_site(&db, &Url::parse("https://pinned.example/")?, None, 5)?;
+        assert_eq!(
+            urls(&db, 10),
+            vec![
+                ("https://rust-lang.org/".to_string(), true),
+                ("https://example.com/".to_string(), false),
+                ("https://pinned.example/".to_string(), true),
+            ]
+        );
+        assert_eq!(
+            urls(&db, 2),
+            vec![
+                ("https://rust-lang.org/".to_string(), true),
+                ("https://example.com/".to_string(), false),
+            ]
+        );
+
+        // Pinning a blocked page unblocks it, and blocking a pinned page
+        // unpins it.
+        pin_top_site(&db, &Url::parse("https://mozilla.org/")?, None, 0)?;
+        assert_eq!(
+            urls(&db, 2),
+            vec![
+                ("https://mozilla.org/".to_string(), true),
+                ("https://example.com/".to_string(), false),
+            ]
+        );
+        block_top_site(&db, &Url::parse("https://mozilla.org/")?)?;
+        unpin_top_site(&db, &Url::parse("https://pinned.example/")?)?;
+        assert_eq!(
+            urls(&db, 10),
+            vec![
+                ("https://example.com/".to_string(), false),
+                ("https://rust-lang.org/".to_string(), false),
+            ]
+        );
+        unblock_top_site(&db, &Url::parse("https://mozilla.org/")?)?;
+        assert_eq!(urls(&db, 10).len(), 3);
+        Ok(())
+    }
+}
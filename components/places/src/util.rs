@@ -3,8 +3,17 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use crate::error::{ErrorKind, Result};
+use lazy_static::lazy_static;
+use publicsuffix::List;
 use std::path::{Path, PathBuf};
 
+lazy_static! {
+    // A copy of https://publicsuffix.org/list/public_suffix_list.dat, which
+    // we should update from there now and then.
+    static ref PUBLIC_SUFFIX_LIST: List = List::from_str(include_str!("public_suffix_list.dat"))
+        .expect("Bug: the bundled public suffix list should parse");
+}
+
 /// Equivalent to `&s[..max_len.min(s.len())]`, but handles the case where
 /// `s.is_char_boundary(max_len)` is false (which would otherwise panic).
 pub fn slice_up_to(s: &str, max_len: usize) -> &str {
@@ -69,6 +78,23 @@ pub fn normalize_path(p: impl AsRef<Path>) -> Result<PathBuf> {
     Ok(canonical)
 }
 
+/// Returns the base domain, or "eTLD+1", of `host_and_port`: its public
+/// suffix, like "com" or "co.uk", and the label before that. Hosts which
+/// don't have one, like IP addresses and public suffixes themselves, are
+/// returned as they are. Either way, the port is dropped.
+pub fn base_domain(host_and_port: &str) -> String {
+    let host = match host_and_port.rfind(':') {
+        // The colons in an IPv6 address aren't a port.
+        Some(index) if !host_and_port.ends_with(']') => &host_and_port[..index],
+        _ => host_and_port,
+    };
+    PUBLIC_SUFFIX_LIST
+        .parse_domain(host)
+        .ok()
+        .and_then(|domain| domain.root().map(str::to_owned))
+        .unwrap_or_else(|| host.to_owned())
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -86,6 +112,19 @@ mod test {
         assert_eq!(slice_up_to(s, 8), s);
     }
     #[test]
+    fn test_base_domain() {
+        assert_eq!(base_domain("www.example.com"), "example.com");
+        assert_eq!(base_domain("example.com:8080"), "example.com");
+        assert_eq!(base_domain("a.b.example.co.uk"), "example.co.uk");
+        // Sites on private suffixes are separate.
+        assert_eq!(base_domain("mozilla.github.io"), "mozilla.github.io");
+        assert_eq!(base_domain("co.uk"), "co.uk");
+        assert_eq!(base_domain("localhost:8000"), "localhost");
+        assert_eq!(base_domain("127.0.0.1:80"), "127.0.0.1");
+        assert_eq!(base_domain("[::1]"), "[::1]");
+        assert_eq!(base_domain("[::1]:8080"), "[::1]");
+    }
+    #[test]
     fn test_unurl_path() {
         assert_eq!(
             unurl_path("file:///foo%20bar/baz").to_string_lossy(),
@@ -208,6 +208,12 @@ impl Payload {
         self
     }
 
+    #[inline]
+    pub fn with_ttl(mut self, ttl: u32) -> Payload {
+        self.data.insert("ttl".into(), ttl.into());
+        self
+    }
+
     #[inline]
     pub fn id(&self) -> &str {
         &self.id[..]
@@ -4,11 +4,21 @@
 
 use crate::bso_record::{EncryptedBso, Payload};
 use crate::client::{Sync15ClientResponse, Sync15StorageClient};
-use crate::error::{self, ErrorKind, Result};
+use crate::error::{self, Error, ErrorKind, Result};
 use crate::key_bundle::KeyBundle;
-use crate::request::{CollectionRequest, NormalResponseHandler, UploadInfo};
+use crate::request::{
+    BatchProgress, CollectionRequest, NormalResponseHandler, RequestOrder, UploadInfo,
+};
+use crate::sync::Store;
+use crate::telemetry;
 use crate::util::ServerTimestamp;
 use crate::CollState;
+use interrupt::Interruptee;
+use rc_crypto::digest;
+use std::collections::{HashMap, HashSet};
+use std::panic;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone)]
 pub struct RecordChangeset<Payload> {
@@ -65,31 +75,229 @@ impl IncomingChangeset {
         collection: String,
         collection_request: &CollectionRequest,
     ) -> Result<IncomingChangeset> {
-        let (records, timestamp) = match client.get_encrypted_records(collection_request)? {
-            Sync15ClientResponse::Success {
-                record,
-                last_modified,
-                ..
-            } => (record, last_modified),
-            other => return Err(other.create_storage_error().into()),
-        };
+        Ok(Self::fetch_page(client, state, collection, collection_request)?.0)
+    }
+
+    /// Fetches a single page of records, decrypting them on this thread.
+    /// See `fetch_page_with`.
+    pub fn fetch_page(
+        client: &Sync15StorageClient,
+        state: &mut CollState,
+        collection: String,
+        collection_request: &CollectionRequest,
+    ) -> Result<(IncomingChangeset, Option<String>)> {
+        Self::fetch_page_with(
+            client,
+            state,
+            collection,
+            collection_request,
+            &mut IncomingDecryptor::new(1),
+        )
+    }
+
+    /// Fetches a single page of records for `collection_request`. If there
+    /// are more records to fetch, also returns the offset to fetch the next
+    /// page from.
+    ///
+    /// The changeset's `timestamp` is the last sync time that's safe to
+    /// persist after applying the page - the collection's last modified time
+    /// for the last page, or a high-water mark below which we've seen every
+    /// record for earlier pages. We can only find a high-water mark if the
+    /// records are sorted oldest first; otherwise it's the request's `newer`
+    /// time, so we don't skip any records if the sync is interrupted.
+    ///
+    /// The records are decrypted with `decryptor`, which keeps track of the
+    /// time that takes across pages.
+    pub fn fetch_page_with(
+        client: &Sync15StorageClient,
+        state: &mut CollState,
+        collection: String,
+        collection_request: &CollectionRequest,
+        decryptor: &mut IncomingDecryptor,
+    ) -> Result<(IncomingChangeset, Option<String>)> {
+        let (records, last_modified, next_offset) =
+            match client.get_encrypted_records_page(collection_request)? {
+                (
+                    Sync15ClientResponse::Success {
+                        record,
+                        last_modified,
+                        ..
+                    },
+                    next_offset,
+                ) => (record, last_modified, next_offset),
+                (other, _) => return Err(other.create_storage_error().into()),
+            };
         // xxx - duplication below of `timestamp` smells wrong
-        state.last_modified = timestamp;
+        state.last_modified = last_modified;
+        let timestamp = if next_offset.is_none() {
+            last_modified
+        } else {
+            let since = collection_request.newer.unwrap_or_default();
+            match collection_request.order {
+                Some(RequestOrder::Oldest) => high_water_mark(&records)
+                    .filter(|hwm| *hwm > since)
+                    .unwrap_or(since),
+                _ => since,
+            }
+        };
         let mut result = IncomingChangeset::new(collection, timestamp);
-        result.changes.reserve(records.len());
-        for record in records {
+        result.changes = decryptor.decrypt(records, &state.key)?;
+        Ok((result, next_offset))
+    }
+}
+
+/// Decrypting a record and parsing its payload is the most expensive part of
+/// a large first sync, so stores can spread it over several threads. We only
+/// use as many as we can give this many records each.
+const MIN_RECORDS_PER_THREAD: usize = 250;
+
+/// Decrypts and parses incoming records, optionally on several threads, and
+/// keeps track of how long that takes for telemetry.
+#[derive(Debug)]
+pub struct IncomingDecryptor {
+    max_threads: usize,
+    threads_used: usize,
+    records: usize,
+    took: Duration,
+}
+
+impl IncomingDecryptor {
+    pub fn new(max_threads: usize) -> IncomingDecryptor {
+        IncomingDecryptor {
+            max_threads: max_threads.max(1),
+            threads_used: 0,
+            records: 0,
+            took: Duration::default(),
+        }
+    }
+
+    /// Decrypts `records`, keeping them in the same order. If any record
+    /// fails, returns the error for the first one that did, as if we'd
+    /// decrypted them one at a time.
+    pub fn decrypt(
+        &mut self,
+        records: Vec<EncryptedBso>,
+        key: &KeyBundle,
+    ) -> Result<Vec<(Payload, ServerTimestamp)>> {
+        let started = Instant::now();
+        let num_records = records.len();
+        let threads = self
+            .max_threads
+            .min(num_records / MIN_RECORDS_PER_THREAD)
+            .max(1);
+        let result = if threads == 1 {
+            decrypt_records(records, key)
+        } else {
+            // Each thread decrypts a contiguous chunk, and we join them in
+            // order, so that both the records and the first error come out
+            // as they would on a single thread.
+            let chunk_size = (num_records + threads - 1) / threads;
+            let mut remaining = records.into_iter();
+            let handles = (0..threads)
+                .map(|_| {
+                    let chunk: Vec<EncryptedBso> = remaining.by_ref().take(chunk_size).collect();
+                    let key = key.clone();
+                    thread::spawn(move || decrypt_records(chunk, &key))
+                })
+                .collect::<Vec<_>>();
+            let mut decrypted = Vec::with_capacity(num_records);
+            let mut first_error = None;
+            for handle in handles {
+                match handle.join() {
+                    Ok(Ok(chunk)) => decrypted.extend(chunk),
+                    Ok(Err(e)) => {
+                        if first_error.is_none() {
+                            first_error = Some(e);
+                        }
+                    }
+                    Err(panic) => panic::resume_unwind(panic),
+                }
+            }
+            match first_error {
+                Some(e) => Err(e),
+                None => Ok(decrypted),
+            }
+        };
+        self.threads_used = self.threads_used.max(threads);
+        self.records += num_records;
+        self.took += started.elapsed();
+        result
+    }
+
+    /// Returns how long we spent decrypting, and on how many records and
+    /// threads, for the engine's telemetry. Returns `None` if there weren't
+    /// any records.
+    pub fn telemetry_step(&self) -> Option<telemetry::Step> {
+        if self.records == 0 {
+            return None;
+        }
+        let mut step = telemetry::Step::new("decrypt");
+        step.took(self.took);
+        step.count("records", self.records);
+        step.count("threads", self.threads_used);
+        Some(step)
+    }
+}
+
+fn decrypt_records(
+    records: Vec<EncryptedBso>,
+    key: &KeyBundle,
+) -> Result<Vec<(Payload, ServerTimestamp)>> {
+    records
+        .into_iter()
+        .map(|record| {
+            let id = record.id.clone();
             // if we see a HMAC error, we've made an explicit decision to
             // NOT handle it here, but restart the global state machine.
             // That should cause us to re-read crypto/keys and things should
             // work (although if for some reason crypto/keys was updated but
             // not all storage was wiped we are probably screwed.)
-            let decrypted = record.decrypt(&state.key)?;
-            result.changes.push(decrypted.into_timestamped_payload());
-        }
-        Ok(result)
+            match record.decrypt(key) {
+                Ok(decrypted) => Ok(decrypted.into_timestamped_payload()),
+                Err(e) => {
+                    log::warn!("Failed to decrypt incoming record {}: {}", id, e);
+                    Err(e)
+                }
+            }
+        })
+        .collect()
+}
+
+/// Returns true if `e` means the server won't let us add to or commit a
+/// batch we're resuming: it returns a 400 or a 404 if the batch expired or it
+/// doesn't know it, and a 412 if the collection changed since we started it,
+/// which the post queue reports as `BatchInterrupted`.
+fn is_abandoned_batch_error(e: &Error) -> bool {
+    match e.kind() {
+        ErrorKind::StorageHttpError { code: 400, .. }
+        | ErrorKind::StorageHttpError { code: 404, .. }
+        | ErrorKind::StorageHttpError { code: 412, .. }
+        | ErrorKind::BatchInterrupted => true,
+        _ => false,
     }
 }
 
+/// Returns a hash of `payload`, so that we can tell if a record changed
+/// without keeping it.
+fn payload_hash(payload: &Payload) -> Result<String> {
+    let json = serde_json::to_vec(payload)?;
+    let hash = digest::digest(&digest::SHA256, &json)?;
+    Ok(base16::encode_lower(&hash))
+}
+
+/// Returns the newest modified time for which we know we've seen every
+/// record in a page of records sorted oldest first. Records uploaded in the
+/// same batch share a modified time, so the last records in the page might
+/// continue on the next page - but everything older than them is complete.
+fn high_water_mark(records: &[EncryptedBso]) -> Option<ServerTimestamp> {
+    let last = records.last()?.modified;
+    records
+        .iter()
+        .rev()
+        .map(|record| record.modified)
+        .find(|modified| *modified < last)
+}
+
 #[derive(Debug, Clone)]
 pub struct CollectionUpdate<'a> {
     client: &'a Sync15StorageClient,
@@ -97,6 +305,10 @@ pub struct CollectionUpdate<'a> {
     collection: String,
     xius: ServerTimestamp,
     to_update: Vec<EncryptedBso>,
+    // Hashes of the cleartext payloads of `to_update`, keyed by id, if we
+    // know them. We only skip records when we resume a batch if they're
+    // unchanged.
+    payload_hashes: HashMap<String, String>,
     fully_atomic: bool,
 }
 
@@ -115,6 +327,7 @@ impl<'a> CollectionUpdate<'a> {
             collection,
             xius,
             to_update: records,
+            payload_hashes: HashMap::new(),
             fully_atomic,
         }
     }
@@ -131,20 +344,77 @@ impl<'a> CollectionUpdate<'a> {
             // Not actually interrupted, but we know we'd fail the XIUS check.
             return Err(ErrorKind::BatchInterrupted.into());
         }
+        let payload_hashes = changeset
+            .changes
+            .iter()
+            .map(|payload| Ok((payload.id.clone(), payload_hash(payload)?)))
+            .collect::<Result<_>>()?;
         let to_update = changeset.encrypt(&state.key)?;
-        Ok(CollectionUpdate::new(
-            client,
-            state,
-            collection,
-            xius,
-            to_update,
-            fully_atomic,
-        ))
+        let mut update =
+            CollectionUpdate::new(client, state, collection, xius, to_update, fully_atomic);
+        update.payload_hashes = payload_hashes;
+        Ok(update)
     }
 
     /// Returns a list of the IDs that failed if allowed_dropped_records is true, otherwise
     /// returns an empty vec.
     pub fn upload(self) -> error::Result<UploadInfo> {
+        self.upload_records(None, None)
+    }
+
+    /// Like `upload`, but persists the progress of the batch through `store`
+    /// after each post, and resumes the batch a previous sync didn't commit
+    /// instead of uploading its records again. If that batch can't be
+    /// resumed, because it expired or the collection changed since, we
+    /// abandon it and start a new one.
+    pub fn upload_resumable(
+        self,
+        store: &dyn Store,
+        interruptee: &dyn Interruptee,
+    ) -> error::Result<UploadInfo> {
+        if let Some(progress) = store.get_pending_batch()? {
+            if self.can_resume(&progress) {
+                log::info!(
+                    "Resuming batch {} with {} records already uploaded",
+                    progress.batch,
+                    progress.committed_ids.len()
+                );
+                match self.upload_records(Some(&progress), Some((store, interruptee))) {
+                    Err(ref e) if is_abandoned_batch_error(e) => {
+                        log::warn!(
+                            "Can't resume batch {}, starting again: {}",
+                            progress.batch,
+                            e
+                        );
+                    }
+                    result => return result,
+                }
+            } else {
+                log::info!("Abandoning batch {}", progress.batch);
+            }
+            store.set_pending_batch(None)?;
+        }
+        self.upload_records(None, Some((store, interruptee)))
+    }
+
+    // We can only resume a batch if the collection hasn't changed since we
+    // started it, and we still want to upload every record in it.
+    fn can_resume(&self, progress: &BatchProgress) -> bool {
+        if progress.last_modified < self.state.last_modified {
+            return false;
+        }
+        let ids: HashSet<&str> = self.to_update.iter().map(|r| r.id.as_str()).collect();
+        progress
+            .committed_ids
+            .iter()
+            .all(|id| ids.contains(id.as_str()))
+    }
+
+    fn upload_records(
+        &self,
+        resume_from: Option<&BatchProgress>,
+        persist_to: Option<(&dyn Store, &dyn Interruptee)>,
+    ) -> error::Result<UploadInfo> {
         let mut failed = vec![];
         let mut q = self.client.new_post_queue(
             &self.collection,
@@ -153,14 +423,73 @@ impl<'a> CollectionUpdate<'a> {
             NormalResponseHandler::new(!self.fully_atomic),
         )?;
 
-        for record in self.to_update.into_iter() {
-            let enqueued = q.enqueue(&record)?;
+        let mut already_uploaded: HashSet<String> = HashSet::new();
+        if let Some(progress) = resume_from {
+            // Records which changed since we added them to the batch, or
+            // which we don't have a hash for, are uploaded again, and replace
+            // the old versions in the batch. Note that the queue then
+            // undercounts the records in the batch, but if that means we go
+            // over the server's limit, it rejects the batch, and we start a
+            // new one.
+            let unchanged: Vec<String> = progress
+                .committed_ids
+                .iter()
+                .filter(|id| match progress.payload_hashes.get(*id) {
+                    Some(hash) => self.payload_hashes.get(*id) == Some(hash),
+                    None => false,
+                })
+                .cloned()
+                .collect();
+            if unchanged.len() != progress.committed_ids.len() {
+                log::info!(
+                    "Uploading {} changed records in batch {} again",
+                    progress.committed_ids.len() - unchanged.len(),
+                    progress.batch
+                );
+            }
+            q.resume_batch(&BatchProgress {
+                committed_ids: unchanged.clone(),
+                ..progress.clone()
+            });
+            already_uploaded.extend(unchanged);
+        }
+        let mut num_persisted = already_uploaded.len();
+
+        for record in self.to_update.iter() {
+            if already_uploaded.contains(&record.id) {
+                continue;
+            }
+            if let Some((_, interruptee)) = persist_to {
+                interruptee.err_if_interrupted()?;
+            }
+            let enqueued = q.enqueue(record)?;
             if !enqueued && self.fully_atomic {
                 return Err(ErrorKind::RecordTooLargeError.into());
             }
+            // Only persist the batch after a post, when the server has
+            // accepted more records into it.
+            if let (Some((store, _)), Some(mut progress)) = (persist_to, q.batch_progress()) {
+                if progress.committed_ids.len() != num_persisted {
+                    progress.payload_hashes = progress
+                        .committed_ids
+                        .iter()
+                        .filter_map(|id| {
+                            let hash = self.payload_hashes.get(id)?;
+                            Some((id.clone(), hash.clone()))
+                        })
+                        .collect();
+                    store.set_pending_batch(Some(&progress))?;
+                    num_persisted = progress.committed_ids.len();
+                }
+            }
         }
 
         q.flush(true)?;
+        if let Some((store, _)) = persist_to {
+            if num_persisted > 0 {
+                store.set_pending_batch(None)?;
+            }
+        }
         let mut info = q.completed_upload_info();
         info.failed_ids.append(&mut failed);
         if self.fully_atomic {
@@ -173,3 +502,60 @@ impl<'a> CollectionUpdate<'a> {
         Ok(info)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn encrypted_records(key: &KeyBundle, count: usize) -> Vec<EncryptedBso> {
+        (0..count)
+            .map(|i| {
+                Payload::from_json(json!({"id": format!("record{}", i), "index": i}))
+                    .unwrap()
+                    .into_bso("test".into())
+                    .encrypt(key)
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_decrypt_on_threads() {
+        let key = KeyBundle::new_random().unwrap();
+        let records = encrypted_records(&key, 1000);
+
+        let mut decryptor = IncomingDecryptor::new(8);
+        let decrypted = decryptor.decrypt(records, &key).unwrap();
+        let ids: Vec<String> = decrypted.into_iter().map(|(p, _)| p.id).collect();
+        let expected: Vec<String> = (0..1000).map(|i| format!("record{}", i)).collect();
+        assert_eq!(ids, expected);
+        // We don't use more threads than we have records for.
+        assert_eq!(decryptor.threads_used, 4);
+
+        decryptor
+            .decrypt(encrypted_records(&key, 10), &key)
+            .unwrap();
+        assert_eq!(decryptor.records, 1010);
+        assert!(decryptor.telemetry_step().is_some());
+        assert!(IncomingDecryptor::new(1).telemetry_step().is_none());
+    }
+
+    #[test]
+    fn test_decrypt_first_error() {
+        let key = KeyBundle::new_random().unwrap();
+        let mut records = encrypted_records(&key, 1000);
+        records[600].payload.hmac = records[601].payload.hmac.clone();
+        records[900].payload.iv = "not base64!".into();
+
+        for &threads in &[1, 4] {
+            let err = IncomingDecryptor::new(threads)
+                .decrypt(records.clone(), &key)
+                .expect_err("should fail to decrypt");
+            match err.kind() {
+                ErrorKind::HmacMismatch => {}
+                e => panic!("Unexpected error with {} threads: {}", threads, e),
+            }
+        }
+    }
+}
@@ -6,16 +6,18 @@ use crate::bso_record::{BsoRecord, EncryptedBso};
 use crate::error::{self, ErrorKind};
 use crate::record_types::MetaGlobalRecord;
 use crate::request::{
-    BatchPoster, CollectionRequest, InfoCollections, InfoConfiguration, PostQueue, PostResponse,
-    PostResponseHandler,
+    BatchPoster, CollectionRequest, InfoCollectionUsage, InfoCollections, InfoConfiguration,
+    InfoQuota, PostQueue, PostResponse, PostResponseHandler,
 };
 use crate::token;
 use crate::util::ServerTimestamp;
+use std::cell::Cell;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 use url::Url;
 use viaduct::{
     header_names::{self, AUTHORIZATION},
-    Method, Request, Response,
+    status_codes, Method, Request, Response,
 };
 
 /// A response from a GET request on a Sync15StorageClient, encapsulating all
@@ -95,11 +97,19 @@ pub trait SetupStorageClient {
     ) -> error::Result<()>;
     fn put_crypto_keys(&self, xius: ServerTimestamp, keys: &EncryptedBso) -> error::Result<()>;
     fn wipe_all_remote(&self) -> error::Result<()>;
+    /// Deletes all the records in `collection` from the server.
+    fn wipe_remote_collection(&self, collection: &str) -> error::Result<()>;
 }
 
 #[derive(Debug)]
 pub struct Sync15StorageClient {
     tsc: token::TokenProvider,
+    // The latest time the server asked us not to sync before, via the
+    // `X-Weave-Backoff` or `Retry-After` headers.
+    backoff: Cell<Option<SystemTime>>,
+    // The number of kilobytes the server last told us we have left, via the
+    // `X-Weave-Quota-Remaining` header.
+    quota_remaining: Cell<Option<f64>>,
 }
 
 impl SetupStorageClient for Sync15StorageClient {
@@ -166,6 +176,18 @@ impl SetupStorageClient for Sync15StorageClient {
             Err(e) => Err(e),
         }
     }
+
+    fn wipe_remote_collection(&self, collection: &str) -> error::Result<()> {
+        let s = self.tsc.api_endpoint()? + "/";
+        let url = Url::parse(&s)?.join(&format!("storage/{}", collection))?;
+
+        let req = self.build_request(Method::Delete, url)?;
+        match self.exec_request(req, true) {
+            Ok(_) => Ok(()),
+            Err(ref e) if e.is_not_found() => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
 }
 
 impl Sync15StorageClient {
@@ -175,7 +197,53 @@ impl Sync15StorageClient {
             init_params.access_token,
             init_params.key_id,
         )?;
-        Ok(Sync15StorageClient { tsc })
+        Ok(Sync15StorageClient {
+            tsc,
+            backoff: Cell::new(None),
+            quota_remaining: Cell::new(None),
+        })
+    }
+
+    /// Returns the time before which the server has asked us not to sync
+    /// again, if it has made any such request via this client since the
+    /// last call.
+    pub fn take_backoff(&self) -> Option<SystemTime> {
+        self.backoff.replace(None)
+    }
+
+    /// Returns how long this client has spent fetching tokens from the
+    /// tokenserver since the last call, for telemetry.
+    pub fn take_token_fetch_duration(&self) -> Duration {
+        self.tsc.take_fetch_duration()
+    }
+
+    /// Returns our tokenserver token, if it's still valid, so that the app
+    /// can cache it.
+    pub fn export_token(&self) -> Option<token::TokenCache> {
+        self.tsc.export_token()
+    }
+
+    /// Uses a cached token instead of fetching a new one. Returns false if
+    /// the token was unusable, in which case we'll fetch a new one as usual.
+    pub fn import_token(&self, cache: token::TokenCache) -> error::Result<bool> {
+        self.tsc.import_token(cache)
+    }
+
+    /// Returns the number of kilobytes the account can store before it's
+    /// over quota, as of the last response which told us. This is `None` if
+    /// the server doesn't enforce a quota, or hasn't told us yet.
+    pub fn quota_remaining(&self) -> Option<f64> {
+        self.quota_remaining.get()
+    }
+
+    pub fn fetch_info_quota(&self) -> error::Result<Sync15ClientResponse<InfoQuota>> {
+        self.relative_storage_request(Method::Get, "info/quota")
+    }
+
+    pub fn fetch_info_collection_usage(
+        &self,
+    ) -> error::Result<Sync15ClientResponse<InfoCollectionUsage>> {
+        self.relative_storage_request(Method::Get, "info/collection_usage")
     }
 
     pub fn get_encrypted_records(
@@ -185,6 +253,26 @@ impl Sync15StorageClient {
         self.collection_request(Method::Get, collection_request)
     }
 
+    /// Like `get_encrypted_records`, but also returns the value of the
+    /// `X-Weave-Next-Offset` header. This is only set if the request had a
+    /// `limit` and there are more records to fetch, in which case they can
+    /// be fetched by repeating the request with this `offset`.
+    pub fn get_encrypted_records_page(
+        &self,
+        collection_request: &CollectionRequest,
+    ) -> error::Result<(Sync15ClientResponse<Vec<EncryptedBso>>, Option<String>)> {
+        let url = collection_request.build_url(Url::parse(&self.tsc.api_endpoint()?)?)?;
+        let resp = self.exec_request(self.build_request(Method::Get, url)?, false)?;
+        let next_offset = if resp.is_success() {
+            resp.headers
+                .get(header_names::X_WEAVE_NEXT_OFFSET)
+                .map(ToString::to_string)
+        } else {
+            None
+        };
+        Ok((self.parse_storage_response(resp)?, next_offset))
+    }
+
     #[inline]
     fn authorized(&self, req: Request) -> error::Result<Request> {
         let hawk_header_value = self.tsc.authorization(&req)?;
@@ -220,6 +308,13 @@ impl Sync15StorageClient {
         for<'a> T: serde::de::Deserialize<'a>,
     {
         let resp = self.exec_request(self.build_request(method, url)?, false)?;
+        self.parse_storage_response(resp)
+    }
+
+    fn parse_storage_response<T>(&self, resp: Response) -> error::Result<Sync15ClientResponse<T>>
+    where
+        for<'a> T: serde::de::Deserialize<'a>,
+    {
         let route: String = resp.url.path().into();
         Ok(if resp.is_success() {
             let record: T = resp.json()?;
@@ -249,6 +344,36 @@ impl Sync15StorageClient {
         let resp = req.send()?;
         log::trace!("response: {}", resp.status);
 
+        if let Some(when) = backoff_from_response(&resp, SystemTime::now()) {
+            log::warn!("Server requested backoff until {:?}", when);
+            if self.backoff.get().map_or(true, |existing| when > existing) {
+                self.backoff.set(Some(when));
+            }
+            // The server is overloaded, so there's no point in making any
+            // more requests in this sync.
+            if resp.status == status_codes::SERVICE_UNAVAILABLE {
+                return Err(ErrorKind::BackoffError(when).into());
+            }
+        }
+
+        if let Some(Ok(kb)) = resp
+            .headers
+            .get_as::<f64, _>(header_names::X_WEAVE_QUOTA_REMAINING)
+        {
+            self.quota_remaining.set(Some(kb));
+        }
+        if resp.status == status_codes::INSUFFICIENT_STORAGE {
+            log::warn!("Account is over quota");
+            return Err(ErrorKind::OverQuota.into());
+        }
+
+        // If the server rejected our token, make sure we fetch a new one for
+        // the next request, which is where we'll notice if we've been
+        // reassigned to a new node.
+        if resp.status == status_codes::UNAUTHORIZED {
+            self.tsc.reject_token();
+        }
+
         if require_success && !resp.is_success() {
             log::warn!(
                 "HTTP error {} during storage request to {}",
@@ -262,10 +387,7 @@ impl Sync15StorageClient {
             .into());
         }
 
-        // TODO:
-        // - handle backoff
-        // - x-weave-quota?
-        // - ... almost certainly other things too...
+        // TODO: almost certainly other things too...
 
         Ok(resp)
     }
@@ -316,6 +438,41 @@ impl Sync15StorageClient {
     pub fn hashed_uid(&self) -> error::Result<String> {
         self.tsc.hashed_uid()
     }
+
+    /// Returns true if the tokenserver has moved us to a different storage
+    /// node since this client was created. This fetches a new token if the
+    /// storage server rejected our current one.
+    pub fn is_node_reassigned(&self) -> bool {
+        match self.tsc.api_endpoint() {
+            Err(ref e) => e.is_node_reassigned(),
+            Ok(_) => false,
+        }
+    }
+}
+
+/// Returns the time before which the server wants us to stop syncing, based
+/// on the `X-Weave-Backoff` and `Retry-After` headers in `resp`. A 503
+/// without either header is treated as a request to back off for
+/// `RETRY_AFTER_DEFAULT_MS`.
+fn backoff_from_response(resp: &Response, now: SystemTime) -> Option<SystemTime> {
+    let header_secs = |name| -> Option<f64> {
+        resp.headers
+            .get_as::<f64, _>(name)
+            .and_then(|secs| secs.ok())
+            .filter(|secs| *secs >= 0.0)
+    };
+    let secs = match (
+        header_secs(header_names::X_WEAVE_BACKOFF),
+        header_secs(header_names::RETRY_AFTER),
+    ) {
+        (Some(backoff), Some(retry_after)) => backoff.max(retry_after),
+        (Some(secs), None) | (None, Some(secs)) => secs,
+        (None, None) if resp.status == status_codes::SERVICE_UNAVAILABLE => {
+            return Some(now + Duration::from_millis(token::RETRY_AFTER_DEFAULT_MS));
+        }
+        (None, None) => return None,
+    };
+    Some(now + Duration::from_millis((secs * 1000.0) as u64))
 }
 
 pub struct PostWrapper<'a> {
@@ -0,0 +1,103 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! The `clients` engine. Unlike the other engines, this lives in sync15
+//! itself, because every app that syncs needs to upload a client record
+//! (otherwise other devices consider it stale and stop sending it commands),
+//! and because the commands it receives (eg, "wipe the bookmarks engine")
+//! are delivered to the stores being synced.
+
+use crate::util::ServerTimestamp;
+
+mod record;
+mod store;
+
+pub(crate) use self::store::ClientsStore;
+
+/// The type of this device, as reported in our client record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceType {
+    Desktop,
+    Mobile,
+}
+
+impl DeviceType {
+    fn as_record_type(self) -> &'static str {
+        match self {
+            DeviceType::Desktop => "desktop",
+            DeviceType::Mobile => "mobile",
+        }
+    }
+
+    fn from_record_type(typ: &str) -> Option<DeviceType> {
+        match typ {
+            "desktop" => Some(DeviceType::Desktop),
+            "mobile" => Some(DeviceType::Mobile),
+            _ => None,
+        }
+    }
+}
+
+/// Information about this device, which is uploaded as our client record.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    /// The ID of our record in the `clients` collection. This must be stable
+    /// across syncs, otherwise other devices will see a new client each time.
+    pub client_id: String,
+    /// The FxA device ID for this device, if it has one.
+    pub fxa_device_id: Option<String>,
+    /// The user-visible name of this device.
+    pub device_name: String,
+    pub device_type: DeviceType,
+    /// The version of the application, eg, "67.0".
+    pub version: String,
+}
+
+/// A command sent to us by another client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Erase all local data for an engine.
+    Wipe(String),
+    /// Reset the sync state for an engine, so that its next sync is a
+    /// "first sync".
+    Reset(String),
+    /// Reset the sync state for all engines.
+    ResetAll,
+    /// Display a URI sent from another device.
+    DisplayUri {
+        uri: String,
+        sender_id: Option<String>,
+        title: Option<String>,
+    },
+}
+
+/// Implemented by the application to describe this device, and to handle
+/// the commands which can't be delivered to a `Store`.
+pub trait CommandProcessor {
+    fn settings(&self) -> &Settings;
+
+    /// Called for each `displayURI` command sent to this device. If this
+    /// fails, the command is kept in our record and will be retried on the
+    /// next sync.
+    fn display_uri(
+        &self,
+        uri: &str,
+        sender_id: Option<&str>,
+        title: Option<&str>,
+    ) -> Result<(), failure::Error>;
+}
+
+/// Another device connected to the account, as seen in the `clients`
+/// collection during the last sync.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteClient {
+    pub id: String,
+    pub fxa_device_id: Option<String>,
+    pub device_name: String,
+    /// None if the client reported a type we don't know about.
+    pub device_type: Option<DeviceType>,
+    pub version: Option<String>,
+    /// When the client last uploaded its record.
+    pub last_modified: ServerTimestamp,
+}
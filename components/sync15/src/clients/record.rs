@@ -0,0 +1,180 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use super::{Command, DeviceType, RemoteClient, Settings};
+use crate::util::ServerTimestamp;
+use serde_derive::*;
+
+/// A record in the `clients` collection, as written by Desktop and the other
+/// Firefoxes. We only model the fields we read or write.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientRecord {
+    pub id: String,
+
+    pub name: String,
+
+    #[serde(rename = "type")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub typ: Option<String>,
+
+    #[serde(default)]
+    pub commands: Vec<CommandRecord>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fxa_device_id: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub protocols: Vec<String>,
+}
+
+impl ClientRecord {
+    /// Builds the record we upload for this device, holding the commands
+    /// we haven't managed to process yet.
+    pub fn from_settings(settings: &Settings, commands: Vec<CommandRecord>) -> ClientRecord {
+        ClientRecord {
+            id: settings.client_id.clone(),
+            name: settings.device_name.clone(),
+            typ: Some(settings.device_type.as_record_type().to_string()),
+            commands,
+            fxa_device_id: settings.fxa_device_id.clone(),
+            version: Some(settings.version.clone()),
+            protocols: vec!["1.5".to_string()],
+        }
+    }
+
+    pub fn into_remote_client(self, last_modified: ServerTimestamp) -> RemoteClient {
+        RemoteClient {
+            device_type: self
+                .typ
+                .as_ref()
+                .map(String::as_str)
+                .and_then(DeviceType::from_record_type),
+            id: self.id,
+            fxa_device_id: self.fxa_device_id,
+            device_name: self.name,
+            version: self.version,
+            last_modified,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct CommandRecord {
+    pub command: String,
+
+    // `displayURI` uses `null` for a missing title, so the args are optional.
+    #[serde(default)]
+    pub args: Vec<Option<String>>,
+
+    #[serde(rename = "flowID")]
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flow_id: Option<String>,
+}
+
+impl CommandRecord {
+    /// Returns the command this record represents, or None if we don't
+    /// understand it.
+    pub fn as_command(&self) -> Option<Command> {
+        let arg = |index: usize| self.args.get(index).and_then(Clone::clone);
+        match self.command.as_str() {
+            "wipeEngine" => arg(0).map(Command::Wipe),
+            "resetEngine" => arg(0).map(Command::Reset),
+            "resetAll" => Some(Command::ResetAll),
+            "displayURI" => arg(0).map(|uri| Command::DisplayUri {
+                uri,
+                sender_id: arg(1),
+                title: arg(2),
+            }),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_desktop_record() {
+        let record: ClientRecord = serde_json::from_value(json!({
+            "id": "deadbeef",
+            "name": "Desktop Firefox",
+            "type": "desktop",
+            "commands": [{
+                "command": "displayURI",
+                "args": ["https://example.com", "abcdef", null],
+                "flowID": "flow"
+            }, {
+                "command": "wipeEngine",
+                "args": ["bookmarks"]
+            }, {
+                "command": "logout",
+                "args": []
+            }],
+            "version": "67.0",
+            "protocols": ["1.5"],
+            "os": "Darwin",
+            "appPackage": "org.mozilla.firefox"
+        }))
+        .expect("should deserialize");
+
+        assert_eq!(record.typ, Some("desktop".to_string()));
+        let commands: Vec<_> = record
+            .commands
+            .iter()
+            .map(CommandRecord::as_command)
+            .collect();
+        assert_eq!(
+            commands,
+            vec![
+                Some(Command::DisplayUri {
+                    uri: "https://example.com".into(),
+                    sender_id: Some("abcdef".into()),
+                    title: None,
+                }),
+                Some(Command::Wipe("bookmarks".into())),
+                None,
+            ]
+        );
+
+        let remote = record.into_remote_client(ServerTimestamp(1234.5));
+        assert_eq!(remote.device_type, Some(DeviceType::Desktop));
+        assert_eq!(remote.device_name, "Desktop Firefox");
+        assert_eq!(remote.version, Some("67.0".into()));
+    }
+
+    #[test]
+    fn test_our_record() {
+        let settings = Settings {
+            client_id: "ourclientid".into(),
+            fxa_device_id: Some("fxadevice".into()),
+            device_name: "Phone".into(),
+            device_type: DeviceType::Mobile,
+            version: "1.0".into(),
+        };
+        let record = ClientRecord::from_settings(&settings, vec![]);
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            json!({
+                "id": "ourclientid",
+                "name": "Phone",
+                "type": "mobile",
+                "commands": [],
+                "fxaDeviceId": "fxadevice",
+                "version": "1.0",
+                "protocols": ["1.5"]
+            })
+        );
+    }
+}
@@ -0,0 +1,411 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+use super::record::{ClientRecord, CommandRecord};
+use super::{Command, CommandProcessor, RemoteClient};
+use crate::bso_record::Payload;
+use crate::changeset::{IncomingChangeset, OutgoingChangeset};
+use crate::coll_state::StoreSyncAssociation;
+use crate::request::CollectionRequest;
+use crate::sync::Store;
+use crate::telemetry;
+use crate::util::ServerTimestamp;
+use std::cell::RefCell;
+use std::result;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const COLLECTION_NAME: &str = "clients";
+
+/// The TTL of our client record, in seconds. Matches Desktop.
+const CLIENTS_TTL: u32 = 1_814_400; // 21 days
+
+/// If our record on the server is older than this many seconds we re-upload
+/// it even if nothing changed, so other clients don't consider us stale.
+/// Matches Desktop.
+const CLIENTS_TTL_REFRESH: f64 = 604_800.0; // 7 days
+
+/// The store for the `clients` collection. It's constructed for each sync by
+/// `sync_multiple`, which gives it the other stores being synced so commands
+/// can be delivered to them.
+pub(crate) struct ClientsStore<'a> {
+    command_processor: &'a dyn CommandProcessor,
+    stores: &'a [&'a dyn Store],
+    // We fetch the entire collection each sync, so there's nothing to persist
+    // and this store is always reset (which is cheap) before it syncs.
+    assoc: RefCell<StoreSyncAssociation>,
+    remote_clients: RefCell<Vec<RemoteClient>>,
+}
+
+impl<'a> ClientsStore<'a> {
+    pub fn new(
+        command_processor: &'a dyn CommandProcessor,
+        stores: &'a [&'a dyn Store],
+    ) -> ClientsStore<'a> {
+        ClientsStore {
+            command_processor,
+            stores,
+            assoc: RefCell::new(StoreSyncAssociation::Disconnected),
+            remote_clients: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Returns the other clients we saw in the collection.
+    pub fn into_remote_clients(self) -> Vec<RemoteClient> {
+        self.remote_clients.into_inner()
+    }
+
+    fn apply_command(&self, command: Command) -> result::Result<(), failure::Error> {
+        log::info!("Processing command {:?}", command);
+        match command {
+            Command::Wipe(engine) => {
+                for store in self.stores.iter().filter(|s| s.collection_name() == engine) {
+                    store.wipe()?;
+                }
+            }
+            Command::Reset(engine) => {
+                for store in self.stores.iter().filter(|s| s.collection_name() == engine) {
+                    store.reset(&StoreSyncAssociation::Disconnected)?;
+                }
+            }
+            Command::ResetAll => {
+                for store in self.stores {
+                    store.reset(&StoreSyncAssociation::Disconnected)?;
+                }
+            }
+            Command::DisplayUri {
+                uri,
+                sender_id,
+                title,
+            } => {
+                self.command_processor.display_uri(
+                    &uri,
+                    sender_id.as_ref().map(String::as_str),
+                    title.as_ref().map(String::as_str),
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes the commands in our record, returning the ones which failed
+    /// and should be retried next sync. Commands we don't understand are
+    /// dropped, which is what Desktop does.
+    fn process_commands(&self, commands: Vec<CommandRecord>) -> Vec<CommandRecord> {
+        let mut remaining = Vec::new();
+        for record in commands {
+            let command = match record.as_command() {
+                Some(command) => command,
+                None => {
+                    log::warn!("Ignoring unknown command {}", record.command);
+                    continue;
+                }
+            };
+            if let Err(e) = self.apply_command(command) {
+                log::warn!("Failed to process command {}: {}", record.command, e);
+                remaining.push(record);
+            }
+        }
+        remaining
+    }
+}
+
+fn now_as_server_timestamp() -> ServerTimestamp {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    ServerTimestamp(since_epoch.as_secs() as f64)
+}
+
+impl<'a> Store for ClientsStore<'a> {
+    fn collection_name(&self) -> &'static str {
+        COLLECTION_NAME
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        incoming_telem: &mut telemetry::EngineIncoming,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        let settings = self.command_processor.settings();
+        let mut remote_clients = Vec::new();
+        let mut ours: Option<(ClientRecord, ServerTimestamp)> = None;
+
+        for (payload, modified) in inbound.changes {
+            if payload.is_tombstone() {
+                continue;
+            }
+            let record: ClientRecord = match payload.into_record() {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Failed to deserialize client record: {}", e);
+                    incoming_telem.failed(1);
+                    continue;
+                }
+            };
+            incoming_telem.applied(1);
+            if record.id == settings.client_id {
+                ours = Some((record, modified));
+            } else {
+                remote_clients.push(record.into_remote_client(modified));
+            }
+        }
+        *self.remote_clients.borrow_mut() = remote_clients;
+
+        let mut outgoing = OutgoingChangeset::new(COLLECTION_NAME.into(), inbound.timestamp);
+        let needs_upload = match ours {
+            Some((current, modified)) => {
+                let remaining = self.process_commands(current.commands.clone());
+                let expected = ClientRecord::from_settings(settings, remaining);
+                let is_stale = now_as_server_timestamp()
+                    .duration_since(modified)
+                    .map_or(false, |age| age.as_secs() as f64 > CLIENTS_TTL_REFRESH);
+                if is_stale {
+                    log::info!("Refreshing our stale client record");
+                }
+                if expected != current || is_stale {
+                    Some(expected)
+                } else {
+                    None
+                }
+            }
+            None => {
+                log::info!("Our client record is missing - uploading it");
+                Some(ClientRecord::from_settings(settings, Vec::new()))
+            }
+        };
+        if let Some(record) = needs_upload {
+            outgoing
+                .changes
+                .push(Payload::from_record(record)?.with_ttl(CLIENTS_TTL));
+        }
+        Ok(outgoing)
+    }
+
+    fn sync_finished(
+        &self,
+        _new_timestamp: ServerTimestamp,
+        records_synced: Vec<String>,
+    ) -> result::Result<(), failure::Error> {
+        log::info!("Uploaded {} client records", records_synced.len());
+        Ok(())
+    }
+
+    fn get_collection_request(&self) -> result::Result<CollectionRequest, failure::Error> {
+        // The collection is tiny, and we need every record to report the
+        // list of clients, so always fetch the lot.
+        Ok(CollectionRequest::new(COLLECTION_NAME).full())
+    }
+
+    fn get_sync_assoc(&self) -> result::Result<StoreSyncAssociation, failure::Error> {
+        Ok(self.assoc.borrow().clone())
+    }
+
+    fn reset(&self, assoc: &StoreSyncAssociation) -> result::Result<(), failure::Error> {
+        *self.assoc.borrow_mut() = assoc.clone();
+        Ok(())
+    }
+
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        self.remote_clients.borrow_mut().clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clients::{DeviceType, Settings};
+    use serde_json::json;
+    use std::cell::Cell;
+
+    struct TestProcessor {
+        settings: Settings,
+        uris: RefCell<Vec<String>>,
+    }
+
+    impl CommandProcessor for TestProcessor {
+        fn settings(&self) -> &Settings {
+            &self.settings
+        }
+
+        fn display_uri(
+            &self,
+            uri: &str,
+            _sender_id: Option<&str>,
+            _title: Option<&str>,
+        ) -> result::Result<(), failure::Error> {
+            self.uris.borrow_mut().push(uri.to_string());
+            Ok(())
+        }
+    }
+
+    fn test_processor() -> TestProcessor {
+        TestProcessor {
+            settings: Settings {
+                client_id: "ourclientid".into(),
+                fxa_device_id: None,
+                device_name: "Phone".into(),
+                device_type: DeviceType::Mobile,
+                version: "1.0".into(),
+            },
+            uris: RefCell::new(Vec::new()),
+        }
+    }
+
+    struct TestStore {
+        name: &'static str,
+        wipes: Cell<usize>,
+        resets: Cell<usize>,
+    }
+
+    impl TestStore {
+        fn new(name: &'static str) -> Self {
+            Self {
+                name,
+                wipes: Cell::new(0),
+                resets: Cell::new(0),
+            }
+        }
+    }
+
+    impl Store for TestStore {
+        fn collection_name(&self) -> &'static str {
+            self.name
+        }
+
+        fn apply_incoming(
+            &self,
+            _inbound: IncomingChangeset,
+            _incoming_telem: &mut telemetry::EngineIncoming,
+        ) -> result::Result<OutgoingChangeset, failure::Error> {
+            unreachable!("these tests shouldn't call these");
+        }
+
+        fn sync_finished(
+            &self,
+            _new_timestamp: ServerTimestamp,
+            _records_synced: Vec<String>,
+        ) -> result::Result<(), failure::Error> {
+            unreachable!("these tests shouldn't call these");
+        }
+
+        fn get_collection_request(&self) -> result::Result<CollectionRequest, failure::Error> {
+            unreachable!("these tests shouldn't call these");
+        }
+
+        fn get_sync_assoc(&self) -> result::Result<StoreSyncAssociation, failure::Error> {
+            unreachable!("these tests shouldn't call these");
+        }
+
+        fn reset(&self, _assoc: &StoreSyncAssociation) -> result::Result<(), failure::Error> {
+            self.resets.set(self.resets.get() + 1);
+            Ok(())
+        }
+
+        fn wipe(&self) -> result::Result<(), failure::Error> {
+            self.wipes.set(self.wipes.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn incoming(records: Vec<serde_json::Value>) -> IncomingChangeset {
+        let mut inbound = IncomingChangeset::new(COLLECTION_NAME.into(), ServerTimestamp(0.0));
+        let now = now_as_server_timestamp();
+        for record in records {
+            let payload = Payload::from_json(record).expect("should be a payload");
+            inbound.changes.push((payload, now));
+        }
+        inbound
+    }
+
+    #[test]
+    fn test_uploads_missing_record() {
+        let processor = test_processor();
+        let store = ClientsStore::new(&processor, &[]);
+        let inbound = incoming(vec![json!({
+            "id": "otherclient",
+            "name": "Desktop",
+            "type": "desktop",
+            "commands": []
+        })]);
+        let outgoing = store
+            .apply_incoming(inbound, &mut telemetry::EngineIncoming::new())
+            .expect("should apply");
+        assert_eq!(outgoing.changes.len(), 1);
+        assert_eq!(outgoing.changes[0].id, "ourclientid");
+
+        let remote_clients = store.into_remote_clients();
+        assert_eq!(remote_clients.len(), 1);
+        assert_eq!(remote_clients[0].id, "otherclient");
+        assert_eq!(remote_clients[0].device_type, Some(DeviceType::Desktop));
+    }
+
+    #[test]
+    fn test_up_to_date_record() {
+        let processor = test_processor();
+        let store = ClientsStore::new(&processor, &[]);
+        let inbound = incoming(vec![json!({
+            "id": "ourclientid",
+            "name": "Phone",
+            "type": "mobile",
+            "commands": [],
+            "version": "1.0",
+            "protocols": ["1.5"]
+        })]);
+        let outgoing = store
+            .apply_incoming(inbound, &mut telemetry::EngineIncoming::new())
+            .expect("should apply");
+        assert!(outgoing.changes.is_empty());
+        assert!(store.into_remote_clients().is_empty());
+    }
+
+    #[test]
+    fn test_commands() {
+        let processor = test_processor();
+        let bookmarks = TestStore::new("bookmarks");
+        let history = TestStore::new("history");
+        let stores: Vec<&dyn Store> = vec![&bookmarks, &history];
+        let store = ClientsStore::new(&processor, &stores);
+        let inbound = incoming(vec![json!({
+            "id": "ourclientid",
+            "name": "Phone",
+            "type": "mobile",
+            "commands": [{
+                "command": "wipeEngine",
+                "args": ["bookmarks"]
+            }, {
+                "command": "resetEngine",
+                "args": ["history"]
+            }, {
+                "command": "resetEngine",
+                "args": ["addons"]
+            }, {
+                "command": "resetAll",
+                "args": []
+            }, {
+                "command": "displayURI",
+                "args": ["https://example.com", "otherclient", "Example"]
+            }],
+            "version": "1.0",
+            "protocols": ["1.5"]
+        })]);
+        let outgoing = store
+            .apply_incoming(inbound, &mut telemetry::EngineIncoming::new())
+            .expect("should apply");
+
+        assert_eq!(bookmarks.wipes.get(), 1);
+        assert_eq!(bookmarks.resets.get(), 1);
+        assert_eq!(history.wipes.get(), 0);
+        assert_eq!(history.resets.get(), 2);
+        assert_eq!(*processor.uris.borrow(), vec!["https://example.com"]);
+
+        // We should re-upload our record without the commands.
+        assert_eq!(outgoing.changes.len(), 1);
+        let record: ClientRecord = outgoing.changes[0]
+            .clone()
+            .into_record()
+            .expect("should be a client record");
+        assert!(record.commands.is_empty());
+    }
+}
@@ -2,12 +2,13 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::error;
+use crate::error::{self, ErrorKind};
 use crate::key_bundle::KeyBundle;
 use crate::request::InfoConfiguration;
 use crate::state::GlobalState;
 use crate::sync::Store;
 use crate::util::ServerTimestamp;
+use std::result;
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct CollSyncIds {
@@ -52,6 +53,12 @@ pub enum LocalCollState {
     /// Either the global or collection sync ID has changed - we will reset the engine.
     SyncIdChanged { ids: CollSyncIds },
 
+    /// The store supports a newer version of the engine than the one in
+    /// meta/global. The global state machine records the new version and
+    /// wipes the collection before we get here, so we'll only see this in
+    /// dry runs, or if it couldn't. This is a "terminal" state.
+    EngineUpgradeRequired,
+
     /// The collection is ready to sync.
     Ready { key: KeyBundle },
 }
@@ -70,6 +77,19 @@ impl<'state> LocalCollStateMachine<'state> {
                     return Ok(LocalCollState::Declined);
                 }
                 match meta_global.engines.get(name) {
+                    Some(engine_meta) if engine_meta.version > store.engine_version() => {
+                        // Another client upgraded the engine, and we can't
+                        // read what it writes.
+                        Err(ErrorKind::EngineVersionTooNew {
+                            collection: name.clone(),
+                            server: engine_meta.version,
+                            local: store.engine_version(),
+                        }
+                        .into())
+                    }
+                    Some(engine_meta) if engine_meta.version < store.engine_version() => {
+                        Ok(LocalCollState::EngineUpgradeRequired)
+                    }
                     Some(engine_meta) => match assoc {
                         StoreSyncAssociation::Disconnected => Ok(LocalCollState::SyncIdChanged {
                             ids: CollSyncIds {
@@ -82,7 +102,7 @@ impl<'state> LocalCollStateMachine<'state> {
                                 && ids.coll == engine_meta.sync_id =>
                         {
                             Ok(LocalCollState::Ready {
-                                key: self.global_state.keys.default.clone(),
+                                key: self.global_state.keys.key_for_collection(name).clone(),
                             })
                         }
                         _ => Ok(LocalCollState::SyncIdChanged {
@@ -106,10 +126,30 @@ impl<'state> LocalCollStateMachine<'state> {
                 Ok(LocalCollState::Unknown { assoc })
             }
 
+            LocalCollState::EngineUpgradeRequired => {
+                unreachable!("can't advance from engine upgrade required")
+            }
+
             LocalCollState::Ready { .. } => unreachable!("can't advance from ready"),
         }
     }
 
+    fn ready_state(&self, store: &dyn Store, key: KeyBundle) -> CollState {
+        let name = store.collection_name();
+        let config = self.global_state.config.clone();
+        let last_modified = self
+            .global_state
+            .collections
+            .get(name)
+            .cloned()
+            .unwrap_or_default();
+        CollState {
+            config,
+            last_modified,
+            key,
+        }
+    }
+
     // A little whimsy - a portmanteau of far and fast
     fn run_and_run_as_farst_as_you_can(
         &mut self,
@@ -124,22 +164,13 @@ impl<'state> LocalCollStateMachine<'state> {
         loop {
             log::trace!("LocalCollState in {:?}", s);
             match s {
-                LocalCollState::Ready { key } => {
-                    let name = store.collection_name();
-                    let config = self.global_state.config.clone();
-                    let last_modified = self
-                        .global_state
-                        .collections
-                        .get(name)
-                        .cloned()
-                        .unwrap_or_default();
-                    return Ok(Some(CollState {
-                        config,
-                        last_modified,
-                        key,
-                    }));
-                }
+                LocalCollState::Ready { key } => return Ok(Some(self.ready_state(store, key))),
                 LocalCollState::Declined | LocalCollState::NoSuchCollection => return Ok(None),
+                // We can't sync until the engine is upgraded, which needs
+                // a full sync.
+                LocalCollState::EngineUpgradeRequired => {
+                    return Err(ErrorKind::SetupRequired.into())
+                }
 
                 _ => {
                     count += 1;
@@ -162,6 +193,33 @@ impl<'state> LocalCollStateMachine<'state> {
         let mut gingerbread_man = Self { global_state };
         gingerbread_man.run_and_run_as_farst_as_you_can(store)
     }
+
+    /// Like `get_state`, but never resets the store, for dry runs. Returns
+    /// `Err(ids)` with the new sync IDs if the store would need to be reset
+    /// before it could sync.
+    pub fn peek_state(
+        store: &dyn Store,
+        global_state: &'state GlobalState,
+    ) -> error::Result<result::Result<Option<CollState>, CollSyncIds>> {
+        let machine = Self { global_state };
+        let s = LocalCollState::Unknown {
+            assoc: store.get_sync_assoc()?,
+        };
+        Ok(match machine.advance(s, store)? {
+            LocalCollState::Ready { key } => Ok(Some(machine.ready_state(store, key))),
+            LocalCollState::SyncIdChanged { ids } => Err(ids),
+            // Upgrading gives the engine a sync ID we haven't chosen yet,
+            // so we report the current ones.
+            LocalCollState::EngineUpgradeRequired => {
+                let name = store.collection_name();
+                Err(CollSyncIds {
+                    global: global_state.global.sync_id.clone(),
+                    coll: global_state.global.engines[name].sync_id.clone(),
+                })
+            }
+            _ => Ok(None),
+        })
+    }
 }
 
 #[cfg(test)]
@@ -203,6 +261,7 @@ mod tests {
         collection_name: &'static str,
         assoc: Cell<StoreSyncAssociation>,
         num_resets: RefCell<usize>,
+        engine_version: usize,
     }
 
     impl TestStore {
@@ -211,6 +270,7 @@ mod tests {
                 collection_name,
                 assoc: Cell::new(assoc),
                 num_resets: RefCell::new(0),
+                engine_version: 1,
             }
         }
         fn get_num_resets(&self) -> usize {
@@ -256,6 +316,10 @@ mod tests {
         fn wipe(&self) -> Result<(), failure::Error> {
             unreachable!("these tests shouldn't call these");
         }
+
+        fn engine_version(&self) -> usize {
+            self.engine_version
+        }
     }
 
     #[test]
@@ -336,4 +400,71 @@ mod tests {
         assert_eq!(store.get_num_resets(), 0);
     }
 
+    #[test]
+    fn test_peek_wrong_state() {
+        let gs = get_global_state();
+        let store = TestStore::new(
+            "bookmarks",
+            StoreSyncAssociation::Connected(CollSyncIds {
+                global: "syncIDXXXXXX".to_string(),
+                coll: "syncIDYYYYYY".to_string(),
+            }),
+        );
+        let ids = LocalCollStateMachine::peek_state(&store, &gs)
+            .expect("should work")
+            .expect_err("store would be reset");
+        assert_eq!(
+            ids,
+            CollSyncIds {
+                global: "syncIDAAAAAA".to_string(),
+                coll: "syncIDBBBBBB".to_string()
+            }
+        );
+        assert_eq!(store.get_num_resets(), 0);
+    }
+
+    #[test]
+    fn test_engine_version_too_new() {
+        let mut gs = get_global_state();
+        gs.global.engines.get_mut("bookmarks").unwrap().version = 2;
+        let store = TestStore::new(
+            "bookmarks",
+            StoreSyncAssociation::Connected(CollSyncIds {
+                global: "syncIDAAAAAA".to_string(),
+                coll: "syncIDBBBBBB".to_string(),
+            }),
+        );
+        let err = LocalCollStateMachine::get_state(&store, &gs).expect_err("should refuse to sync");
+        assert!(err.is_engine_version_too_new());
+        assert_eq!(store.get_num_resets(), 0);
+    }
+
+    #[test]
+    fn test_engine_upgrade() {
+        let gs = get_global_state();
+        let mut store = TestStore::new(
+            "bookmarks",
+            StoreSyncAssociation::Connected(CollSyncIds {
+                global: "syncIDAAAAAA".to_string(),
+                coll: "syncIDBBBBBB".to_string(),
+            }),
+        );
+        store.engine_version = 2;
+
+        // A dry run reports that the store would be reset, and doesn't
+        // change anything.
+        LocalCollStateMachine::peek_state(&store, &gs)
+            .expect("should work")
+            .expect_err("store would be reset");
+        assert_eq!(store.get_num_resets(), 0);
+
+        // Upgrading needs a full sync, so we can't sync the collection
+        // until then.
+        let err = LocalCollStateMachine::get_state(&store, &gs).expect_err("should need setup");
+        match err.kind() {
+            ErrorKind::SetupRequired => {}
+            kind => panic!("Unexpected error {:?}", kind),
+        }
+        assert_eq!(store.get_num_resets(), 0);
+    }
 }
@@ -61,4 +61,64 @@ impl CollectionKeys {
     pub fn key_for_collection<'a>(&'a self, collection: &str) -> &'a KeyBundle {
         self.collections.get(collection).unwrap_or(&self.default)
     }
+
+    /// Returns a fingerprint of the key for `collection`, which changes when
+    /// the key does, but can't be used to recover it, so that we can persist
+    /// it.
+    pub fn fingerprint_for_collection(&self, collection: &str) -> Result<String> {
+        let key = self.key_for_collection(collection);
+        key.hmac_string(key.encryption_key())
+    }
+
+    /// Replaces the default key with a new random one. This changes the key
+    /// for every collection without a key of its own.
+    pub fn regenerate_default(&mut self) -> Result<()> {
+        self.default = KeyBundle::new_random()?;
+        Ok(())
+    }
+
+    /// Gives `collection` a new random key of its own.
+    pub fn regenerate_collection(&mut self, collection: &str) -> Result<()> {
+        self.collections
+            .insert(collection.into(), KeyBundle::new_random()?);
+        Ok(())
+    }
+
+    /// Returns the names of the `collections` which have a different key in
+    /// `other`, and so whose records can't be decrypted with the other keys.
+    pub fn changed_collections(&self, other: &CollectionKeys, collections: &[&str]) -> Vec<String> {
+        collections
+            .iter()
+            .filter(|name| self.key_for_collection(name) != other.key_for_collection(name))
+            .map(|name| (*name).to_string())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_changed_collections() {
+        let keys = CollectionKeys::new_random().unwrap();
+        let names = ["bookmarks", "history", "passwords"];
+
+        let mut new_keys = keys.clone();
+        assert!(keys.changed_collections(&new_keys, &names).is_empty());
+
+        new_keys.regenerate_collection("passwords").unwrap();
+        assert_eq!(
+            keys.changed_collections(&new_keys, &names),
+            vec!["passwords".to_string()]
+        );
+
+        // Collections with their own key keep it when the default changes.
+        let mut rotated = new_keys.clone();
+        rotated.regenerate_default().unwrap();
+        assert_eq!(
+            new_keys.changed_collections(&rotated, &names),
+            vec!["bookmarks".to_string(), "history".to_string()]
+        );
+    }
 }
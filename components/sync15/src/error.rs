@@ -44,6 +44,57 @@ impl Error {
             _ => false,
         }
     }
+
+    pub fn is_unauthorized(&self) -> bool {
+        match self.kind() {
+            ErrorKind::StorageHttpError { code: 401, .. } => true,
+            _ => false,
+        }
+    }
+
+    pub fn is_precondition_failed(&self) -> bool {
+        match self.kind() {
+            ErrorKind::StorageHttpError { code: 412, .. } => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this error means we've been assigned to a new storage node,
+    /// so that everything we know about the server is out of date.
+    pub fn is_node_reassigned(&self) -> bool {
+        match self.kind() {
+            ErrorKind::StorageResetError => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this error means the server refused to store more data
+    /// because the account is full.
+    pub fn is_over_quota(&self) -> bool {
+        match self.kind() {
+            ErrorKind::OverQuota => true,
+            _ => false,
+        }
+    }
+
+    /// Whether this error means another client has upgraded an engine to a
+    /// version we don't support, so that the user needs to update the app to
+    /// keep syncing it.
+    pub fn is_engine_version_too_new(&self) -> bool {
+        match self.kind() {
+            ErrorKind::EngineVersionTooNew { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// If this error is because the server asked us to back off, returns the
+    /// time before which we shouldn't make any more requests.
+    pub fn backoff_until(&self) -> Option<SystemTime> {
+        match self.kind() {
+            ErrorKind::BackoffError(when) => Some(*when),
+            _ => None,
+        }
+    }
 }
 
 impl From<ErrorKind> for Error {
@@ -112,15 +163,31 @@ pub enum ErrorKind {
     )]
     SetupRace,
 
+    #[fail(display = "The account is over its storage quota")]
+    OverQuota,
+
     #[fail(display = "Client upgrade required; server storage version too new")]
     ClientUpgradeRequired,
 
+    #[fail(
+        display = "Client upgrade required; server has version {} of the {} engine, but we only support version {}",
+        server, collection, local
+    )]
+    EngineVersionTooNew {
+        collection: String,
+        server: usize,
+        local: usize,
+    },
+
     // This means that our global state machine needs to enter a state (such as
     // "FreshStartNeeded", but the allowed_states don't include that state.)
     // It typically means we are trying to do a "fast" or "read-only" sync.
     #[fail(display = "Our storage needs setting up and we can't currently do it")]
     SetupRequired,
 
+    #[fail(display = "The {} store doesn't support dry runs", _0)]
+    DryRunUnsupported(&'static str),
+
     #[fail(display = "Store error: {}", _0)]
     StoreError(#[fail(cause)] failure::Error),
 
@@ -203,3 +270,13 @@ impl From<hawk::Error> for Error {
         ErrorKind::from(e).into()
     }
 }
+
+// SQLite errors only come from the stores in `simple_store`, so we report them
+// like any other store error.
+#[cfg(feature = "sql")]
+impl From<rusqlite::Error> for Error {
+    #[inline]
+    fn from(e: rusqlite::Error) -> Error {
+        ErrorKind::StoreError(e.into()).into()
+    }
+}
@@ -8,13 +8,18 @@
 mod bso_record;
 mod changeset;
 mod client;
+pub mod clients;
 mod coll_state;
 mod collection_keys;
 mod error;
 mod key_bundle;
+mod merge;
 mod migrate_state;
 mod record_types;
 mod request;
+pub mod schedule;
+#[cfg(feature = "sql")]
+pub mod simple_store;
 mod state;
 mod sync;
 mod sync_multiple;
@@ -24,14 +29,25 @@ mod util;
 
 // Re-export some of the types callers are likely to want for convenience.
 pub use crate::bso_record::{BsoRecord, CleartextBso, EncryptedBso, EncryptedPayload, Payload};
-pub use crate::changeset::{IncomingChangeset, OutgoingChangeset, RecordChangeset};
-pub use crate::client::{SetupStorageClient, Sync15StorageClient, Sync15StorageClientInit};
+pub use crate::changeset::{
+    IncomingChangeset, IncomingDecryptor, OutgoingChangeset, RecordChangeset,
+};
+pub use crate::client::{
+    SetupStorageClient, Sync15ClientResponse, Sync15StorageClient, Sync15StorageClientInit,
+};
 pub use crate::coll_state::{CollState, CollSyncIds, StoreSyncAssociation};
 pub use crate::error::{Error, ErrorKind, Result};
 pub use crate::key_bundle::KeyBundle;
+pub use crate::merge::{three_way_merge, ConflictPolicy, MergeCounts, MergedRecord};
 pub use crate::migrate_state::extract_v1_state;
-pub use crate::request::CollectionRequest;
+pub use crate::request::{
+    BatchProgress, CollectionRequest, InfoCollectionUsage, InfoQuota, RequestOrder,
+};
 pub use crate::state::{GlobalState, SetupStateMachine};
-pub use crate::sync::{synchronize, Store};
-pub use crate::sync_multiple::{sync_multiple, MemoryCachedState};
+pub use crate::sync::{preview, synchronize, LocalChange, LocalChangeKind, Store, StorePreview};
+pub use crate::sync_multiple::{
+    preview_multiple, reset_all, rotate_keys, sync_multiple, sync_multiple_with_command_processor,
+    wipe_remote, MemoryCachedState, SyncPreview, SyncResult,
+};
+pub use crate::token::TokenCache;
 pub use crate::util::{random_guid, ServerTimestamp, SERVER_EPOCH};
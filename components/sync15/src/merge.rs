@@ -0,0 +1,213 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// A field-by-field three-way merge for records which serialize to JSON
+// objects. Each top-level field is merged on its own, using the record as we
+// last synced it (the "parent", which is usually the mirror) to tell which
+// side changed it.
+
+use crate::error::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value as JsonValue};
+use std::collections::BTreeSet;
+
+/// Which side to take when a field changed differently on both sides.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    PreferLocal,
+    PreferRemote,
+}
+
+/// The number of fields taken from each side of a merge, for telemetry.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MergeCounts {
+    /// Fields which only changed locally.
+    pub local: u32,
+    /// Fields which only changed remotely.
+    pub remote: u32,
+    /// Fields which changed differently on both sides, and were resolved by
+    /// the `ConflictPolicy`.
+    pub conflicts: u32,
+}
+
+impl MergeCounts {
+    pub fn add(&mut self, other: MergeCounts) {
+        self.local += other.local;
+        self.remote += other.remote;
+        self.conflicts += other.conflicts;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedRecord<T> {
+    pub record: T,
+    /// True if the merged record is different from the remote one, so we
+    /// need to upload it.
+    pub upload: bool,
+    /// True if the merged record is different from the local one, so we
+    /// need to store it.
+    pub changed_locally: bool,
+    pub counts: MergeCounts,
+}
+
+/// Merges the fields of `local` and `remote`. If `parent` is `None`, we've
+/// never synced the record before, so every field with different values is
+/// a conflict.
+pub fn three_way_merge<T>(
+    local: &T,
+    parent: Option<&T>,
+    remote: &T,
+    policy: ConflictPolicy,
+) -> Result<MergedRecord<T>>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let local = into_fields(serde_json::to_value(local)?);
+    let remote = into_fields(serde_json::to_value(remote)?);
+    let parent = match parent {
+        Some(parent) => Some(into_fields(serde_json::to_value(parent)?)),
+        None => None,
+    };
+
+    let mut names: BTreeSet<&String> = local.keys().chain(remote.keys()).collect();
+    if let Some(ref parent) = parent {
+        names.extend(parent.keys());
+    }
+
+    let mut counts = MergeCounts::default();
+    let mut merged = Map::new();
+    for name in names {
+        let local_value = local.get(name);
+        let remote_value = remote.get(name);
+        let value = if local_value == remote_value {
+            local_value
+        } else {
+            let parent_value = parent.as_ref().map(|parent| parent.get(name));
+            if parent_value == Some(local_value) {
+                counts.remote += 1;
+                remote_value
+            } else if parent_value == Some(remote_value) {
+                counts.local += 1;
+                local_value
+            } else {
+                counts.conflicts += 1;
+                match policy {
+                    ConflictPolicy::PreferLocal => local_value,
+                    ConflictPolicy::PreferRemote => remote_value,
+                }
+            }
+        };
+        if let Some(value) = value {
+            merged.insert(name.clone(), value.clone());
+        }
+    }
+
+    Ok(MergedRecord {
+        upload: merged != remote,
+        changed_locally: merged != local,
+        record: serde_json::from_value(JsonValue::Object(merged))?,
+        counts,
+    })
+}
+
+// Records which don't serialize to an object are merged as a single field.
+fn into_fields(value: JsonValue) -> Map<String, JsonValue> {
+    match value {
+        JsonValue::Object(fields) => fields,
+        other => {
+            let mut fields = Map::new();
+            fields.insert(String::new(), other);
+            fields
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_derive::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Address {
+        id: String,
+        street: String,
+        city: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        phone: Option<String>,
+    }
+
+    fn address(street: &str, city: &str, phone: Option<&str>) -> Address {
+        Address {
+            id: "address1".into(),
+            street: street.into(),
+            city: city.into(),
+            phone: phone.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn test_merge_fields() {
+        let parent = address("1 Main St", "Toronto", None);
+        let local = address("2 Main St", "Toronto", None);
+        let remote = address("1 Main St", "Vancouver", Some("555-1234"));
+        let merged =
+            three_way_merge(&local, Some(&parent), &remote, ConflictPolicy::PreferRemote).unwrap();
+        assert_eq!(
+            merged.record,
+            address("2 Main St", "Vancouver", Some("555-1234"))
+        );
+        assert!(merged.upload);
+        assert!(merged.changed_locally);
+        assert_eq!(
+            merged.counts,
+            MergeCounts {
+                local: 1,
+                remote: 2,
+                conflicts: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_merge_conflicts() {
+        let parent = address("1 Main St", "Toronto", Some("555-1234"));
+        let local = address("2 Main St", "Toronto", None);
+        let remote = address("3 Main St", "Toronto", Some("555-1234"));
+
+        let merged =
+            three_way_merge(&local, Some(&parent), &remote, ConflictPolicy::PreferRemote).unwrap();
+        assert_eq!(merged.record, address("3 Main St", "Toronto", None));
+        assert!(merged.upload);
+        assert_eq!(
+            merged.counts,
+            MergeCounts {
+                local: 1,
+                remote: 0,
+                conflicts: 1,
+            }
+        );
+
+        let merged =
+            three_way_merge(&local, Some(&parent), &remote, ConflictPolicy::PreferLocal).unwrap();
+        assert_eq!(merged.record, local);
+        assert!(!merged.changed_locally);
+    }
+
+    #[test]
+    fn test_two_way_merge() {
+        let local = address("1 Main St", "Toronto", Some("555-1234"));
+        let remote = address("1 Main St", "Vancouver", None);
+        let merged = three_way_merge(&local, None, &remote, ConflictPolicy::PreferRemote).unwrap();
+        assert_eq!(merged.record, remote);
+        assert!(!merged.upload);
+        assert!(merged.changed_locally);
+        assert_eq!(merged.counts.conflicts, 2);
+
+        let merged = three_way_merge(&local, None, &local, ConflictPolicy::PreferRemote).unwrap();
+        assert!(!merged.upload);
+        assert!(!merged.changed_locally);
+        assert_eq!(merged.counts, MergeCounts::default());
+    }
+}
@@ -6,6 +6,7 @@ use crate::record_types::MetaGlobalRecord;
 use crate::state::PersistedGlobalState;
 use crate::CollSyncIds;
 use serde_json::Value;
+use std::collections::HashMap;
 
 /// Given a string persisted as our old GlobalState V1 struct, extract out
 /// the sync IDs for the collection, plus a string which should be used as the
@@ -46,6 +47,8 @@ pub fn extract_v1_state(
     };
     let pgs = PersistedGlobalState::V2 {
         declined: Some(meta_global.declined),
+        backoff_until: None,
+        key_fingerprints: HashMap::new(),
     };
     let new_global_state = serde_json::to_string(&pgs).ok();
 
@@ -132,6 +135,8 @@ mod tests {
         // state reflects that.
         let expected_state = serde_json::to_string(&PersistedGlobalState::V2 {
             declined: Some(Vec::<String>::new()),
+            backoff_until: None,
+            key_fingerprints: HashMap::new(),
         })
         .expect("should stringify");
         assert_eq!(new_state, Some(expected_state));
@@ -157,6 +162,8 @@ mod tests {
         let s = get_state_with_engine_changes_and_declined("", "\\\"foo\\\"");
         let expected_state = serde_json::to_string(&PersistedGlobalState::V2 {
             declined: Some(vec!["foo".to_string()]),
+            backoff_until: None,
+            key_fingerprints: HashMap::new(),
         })
         .unwrap();
         assert_eq!(
@@ -10,6 +10,7 @@ use std::collections::HashMap;
 use std::default::Default;
 use std::fmt;
 use std::ops::Deref;
+use std::result;
 use url::{form_urlencoded::Serializer, Url, UrlQuery};
 use viaduct::{header_names, status_codes, Response};
 
@@ -42,6 +43,7 @@ pub struct CollectionRequest {
     pub order: Option<RequestOrder>,
     pub commit: bool,
     pub batch: Option<String>,
+    pub offset: Option<String>,
 }
 
 impl CollectionRequest {
@@ -60,6 +62,7 @@ impl CollectionRequest {
             order: None,
             commit: false,
             batch: None,
+            offset: None,
         }
     }
 
@@ -114,6 +117,14 @@ impl CollectionRequest {
         self
     }
 
+    /// Sets the offset to fetch the next page of records from, as returned
+    /// in the `X-Weave-Next-Offset` header of the previous page.
+    #[inline]
+    pub fn offset(mut self, offset: Option<String>) -> CollectionRequest {
+        self.offset = offset;
+        self
+    }
+
     fn build_query(&self, pairs: &mut Serializer<UrlQuery<'_>>) {
         if self.full {
             pairs.append_pair("full", "1");
@@ -139,6 +150,9 @@ impl CollectionRequest {
         if let Some(o) = self.order {
             pairs.append_pair("sort", &format!("{}", o));
         }
+        if let Some(offset) = &self.offset {
+            pairs.append_pair("offset", offset);
+        }
         pairs.finish();
     }
 
@@ -274,7 +288,40 @@ impl Deref for InfoCollections {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
+/// The response to `info/quota`: how much the account is storing, and how
+/// much it's allowed to, in kilobytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct InfoQuota {
+    pub usage: f64,
+    /// `None` if the server doesn't enforce a quota.
+    pub quota: Option<f64>,
+}
+
+// The server sends the quota as a `[usage, quota]` array.
+impl<'de> serde::Deserialize<'de> for InfoQuota {
+    fn deserialize<D>(deserializer: D) -> result::Result<InfoQuota, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let (usage, quota): (f64, Option<f64>) = serde::Deserialize::deserialize(deserializer)?;
+        Ok(InfoQuota { usage, quota })
+    }
+}
+
+/// The response to `info/collection_usage`: the number of kilobytes stored
+/// in each collection.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct InfoCollectionUsage(HashMap<String, f64>);
+
+impl Deref for InfoCollectionUsage {
+    type Target = HashMap<String, f64>;
+
+    fn deref(&self) -> &HashMap<String, f64> {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
 pub struct UploadResult {
     batch: Option<String>,
     /// Maps record id => why failed
@@ -298,7 +345,13 @@ impl PostResponse {
         status_codes::is_success_code(self.status)
     }
     pub fn from_response(r: &Response) -> Result<PostResponse> {
-        let result: UploadResult = r.json()?;
+        // Error responses have an error code instead of an upload result,
+        // and the response handler only looks at their status.
+        let result: UploadResult = if r.is_success() {
+            r.json()?
+        } else {
+            UploadResult::default()
+        };
         // TODO Can this happen in error cases?
         let last_modified = r
             .headers
@@ -313,6 +366,24 @@ impl PostResponse {
     }
 }
 
+/// The progress of a batch upload which hasn't been committed yet. Stores
+/// persist this so that a sync which is interrupted, or killed, partway
+/// through uploading can be resumed by the next sync.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BatchProgress {
+    /// The batch id the server gave us.
+    pub batch: String,
+    /// The ids of the records the server has accepted into the batch so far.
+    pub committed_ids: Vec<String>,
+    /// The `X-Last-Modified` time of the last response, which we must send as
+    /// `X-If-Unmodified-Since` for the rest of the batch.
+    pub last_modified: ServerTimestamp,
+    /// Hashes of the cleartext payloads of `committed_ids`, keyed by id, so
+    /// that we upload the records which changed since again when we resume.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub payload_hashes: HashMap<String, String>,
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum BatchState {
     Unsupported,
@@ -517,12 +588,18 @@ where
 
     pub fn flush(&mut self, want_commit: bool) -> Result<()> {
         if self.queued.is_empty() {
+            if !self.in_batch() {
+                // Nothing to do!
+                return Ok(());
+            }
+            // We only have nothing queued in a batch if we resumed one whose
+            // records were all uploaded already, and we still need to commit
+            // it.
             assert!(
-                !self.in_batch(),
+                want_commit,
                 "Bug: Somehow we're in a batch but have no queued records"
             );
-            // Nothing to do!
-            return Ok(());
+            self.queued.push(b'[');
         }
 
         self.queued.push(b']');
@@ -640,6 +717,34 @@ pub struct UploadInfo {
 }
 
 impl<Poster> PostQueue<Poster, NormalResponseHandler> {
+    /// Continues uploading to the batch described by `progress`, instead of
+    /// starting a new one. The records in `progress.committed_ids` are
+    /// reported as successful once the batch is committed, so they shouldn't
+    /// be enqueued again.
+    pub fn resume_batch(&mut self, progress: &BatchProgress) {
+        self.batch = BatchState::InBatch(progress.batch.clone());
+        self.last_modified = progress.last_modified;
+        // We don't know how many bytes are in the batch, but the record
+        // limit is the one servers usually hit.
+        self.batch_limits.clear();
+        self.batch_limits.cur_records = progress.committed_ids.len();
+        self.on_response.pending_success = progress.committed_ids.clone();
+    }
+
+    /// Returns the progress of the current batch, or `None` if we aren't in
+    /// a batch.
+    pub fn batch_progress(&self) -> Option<BatchProgress> {
+        match &self.batch {
+            BatchState::InBatch(batch) => Some(BatchProgress {
+                batch: batch.clone(),
+                committed_ids: self.on_response.pending_success.clone(),
+                last_modified: self.last_modified,
+                payload_hashes: HashMap::new(),
+            }),
+            _ => None,
+        }
+    }
+
     // TODO: should take by move
     pub fn completed_upload_info(&mut self) -> UploadInfo {
         let mut result = UploadInfo {
@@ -722,6 +827,31 @@ mod test {
             .unwrap();
         assert_eq!(complex.as_str(),
             "https://example.com/sync/storage/specific?full=1&limit=10&older=9876.54&newer=1234.56&sort=oldest");
+
+        let page = CollectionRequest::new("paged")
+            .limit(10)
+            .sort_by(RequestOrder::Oldest)
+            .offset(Some("20".into()))
+            .build_url(base.clone())
+            .unwrap();
+        assert_eq!(
+            page.as_str(),
+            "https://example.com/sync/storage/paged?limit=10&sort=oldest&offset=20"
+        );
+    }
+
+    #[test]
+    fn test_info_quota() {
+        let quota: InfoQuota = serde_json::from_str("[12.5, 5120]").unwrap();
+        assert_eq!(
+            quota,
+            InfoQuota {
+                usage: 12.5,
+                quota: Some(5120.0),
+            }
+        );
+        let quota: InfoQuota = serde_json::from_str("[0, null]").unwrap();
+        assert_eq!(quota.quota, None);
     }
 
     #[derive(Debug, Clone)]
@@ -1423,6 +1553,103 @@ mod test {
         );
     }
 
+    // The batch id, commit flag, XIUS and record ids of a post.
+    type AcceptedPost = (Option<String>, bool, ServerTimestamp, Vec<String>);
+
+    // Accepts every record it's sent into batch "1234", and remembers each
+    // post.
+    #[derive(Debug, Clone, Default)]
+    struct AcceptingPoster(Rc<RefCell<Vec<AcceptedPost>>>);
+
+    impl BatchPoster for AcceptingPoster {
+        fn post<T, O>(
+            &self,
+            body: Vec<u8>,
+            xius: ServerTimestamp,
+            batch: Option<String>,
+            commit: bool,
+            _: &PostQueue<T, O>,
+        ) -> Result<PostResponse> {
+            let records: Vec<EncryptedBso> = serde_json::from_slice(&body).unwrap();
+            let ids: Vec<String> = records.into_iter().map(|r| r.id).collect();
+            self.0.borrow_mut().push((batch, commit, xius, ids.clone()));
+            let status = if commit {
+                status_codes::OK
+            } else {
+                status_codes::ACCEPTED
+            };
+            let mut response = fake_response(status, 200.0, Some("1234"));
+            response.result.success = ids;
+            Ok(response)
+        }
+    }
+
+    #[test]
+    fn test_pq_resume_batch() {
+        let cfg = InfoConfiguration::default();
+        let progress = BatchProgress {
+            batch: "1234".into(),
+            committed_ids: vec!["a".into(), "b".into()],
+            last_modified: ServerTimestamp(150.0),
+            payload_hashes: HashMap::new(),
+        };
+        let make_record_with_id = |id: &str| {
+            let mut record = make_record(100);
+            record.id = id.into();
+            record
+        };
+
+        let poster = AcceptingPoster::default();
+        let mut pq = PostQueue::new(
+            &cfg,
+            ServerTimestamp(100.0),
+            poster.clone(),
+            NormalResponseHandler::new(false),
+        );
+        pq.resume_batch(&progress);
+        assert_eq!(pq.batch_progress(), Some(progress.clone()));
+        pq.enqueue(&make_record_with_id("c")).unwrap();
+        pq.flush(false).unwrap();
+        assert_eq!(
+            pq.batch_progress().unwrap().committed_ids,
+            vec!["a", "b", "c"]
+        );
+        pq.flush(true).unwrap();
+        assert_eq!(pq.batch_progress(), None);
+        assert_eq!(
+            *poster.0.borrow(),
+            vec![
+                (
+                    Some("1234".into()),
+                    false,
+                    ServerTimestamp(150.0),
+                    vec!["c".to_string()]
+                ),
+                (Some("1234".into()), true, ServerTimestamp(200.0), vec![]),
+            ]
+        );
+        assert_eq!(
+            pq.completed_upload_info().successful_ids,
+            vec!["a", "b", "c"]
+        );
+
+        // If everything was uploaded before, we still need to commit.
+        let poster = AcceptingPoster::default();
+        let mut pq = PostQueue::new(
+            &cfg,
+            ServerTimestamp(100.0),
+            poster.clone(),
+            NormalResponseHandler::new(false),
+        );
+        pq.resume_batch(&progress);
+        pq.flush(true).unwrap();
+        assert_eq!(
+            *poster.0.borrow(),
+            vec![(Some("1234".into()), true, ServerTimestamp(150.0), vec![])]
+        );
+        assert_eq!(pq.completed_upload_info().successful_ids, vec!["a", "b"]);
+    }
+
     // TODO: Test
     //
     // - error cases!!! We don't test our handling of server errors at all!
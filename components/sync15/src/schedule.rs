@@ -0,0 +1,279 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+// Decide when to sync next, based on how previous syncs went and what the
+// user is doing. The intervals and heuristics are Desktop's SyncScheduler's,
+// so that all our apps behave the same way.
+
+use crate::error::Error;
+use crate::sync_multiple::SyncResult;
+use crate::telemetry::{sync_failure_from_error, SyncFailure};
+use serde_derive::*;
+use std::result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How long to wait between syncs when we're the only client.
+pub const SINGLE_DEVICE_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How long to wait between syncs when the user is idle.
+pub const IDLE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// How long to wait between syncs when the user is active.
+pub const ACTIVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// How long to wait when there are lots of local changes to upload.
+pub const IMMEDIATE_INTERVAL: Duration = Duration::from_secs(90);
+/// The number of local changes which makes us sync after `IMMEDIATE_INTERVAL`.
+pub const IMMEDIATE_CHANGE_THRESHOLD: usize = 25;
+/// How long to wait after the first failed sync. This doubles for each
+/// failure after that, up to `MAXIMUM_ERROR_INTERVAL`.
+pub const MINIMUM_ERROR_INTERVAL: Duration = Duration::from_secs(15 * 60);
+pub const MAXIMUM_ERROR_INTERVAL: Duration = Duration::from_secs(8 * 60 * 60);
+
+/// How a sync went, for `SyncSchedule::sync_finished`.
+#[derive(Debug, Default)]
+pub struct SyncOutcome {
+    /// Why the sync, or any of its engines, failed. Empty if it succeeded.
+    pub failures: Vec<SyncFailure>,
+    /// The time before which the server asked us not to sync.
+    pub backoff_until: Option<SystemTime>,
+    /// The number of other clients connected to the account.
+    pub num_remote_clients: usize,
+}
+
+impl SyncOutcome {
+    /// Describes the result of `sync_multiple`, or one of its variants.
+    pub fn from_result(
+        result: &result::Result<SyncResult, Error>,
+        num_remote_clients: usize,
+    ) -> SyncOutcome {
+        let (failures, backoff_until) = match result {
+            Ok(result) => (
+                result
+                    .failures
+                    .values()
+                    .map(sync_failure_from_error)
+                    .collect(),
+                result.next_sync_after,
+            ),
+            Err(e) => (vec![sync_failure_from_error(e)], e.backoff_until()),
+        };
+        SyncOutcome {
+            failures,
+            backoff_until,
+            num_remote_clients,
+        }
+    }
+}
+
+/// The state we need to decide when to sync next. Apps should persist this
+/// between runs, and update it with `sync_finished` after every sync.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SyncSchedule {
+    // When the last sync finished, in milliseconds since the epoch.
+    last_sync: Option<u64>,
+    // The number of syncs in a row which failed.
+    consecutive_failures: u32,
+    // Set if the last sync failed because of our credentials, in which case
+    // there's no point syncing until they change.
+    needs_reauth: bool,
+    num_remote_clients: usize,
+    // In milliseconds since the epoch.
+    backoff_until: Option<u64>,
+}
+
+impl SyncSchedule {
+    pub fn new() -> SyncSchedule {
+        SyncSchedule::default()
+    }
+
+    /// Records the outcome of a sync which finished at `now`.
+    pub fn sync_finished(&mut self, now: SystemTime, outcome: &SyncOutcome) {
+        self.last_sync = Some(to_millis(now));
+        self.num_remote_clients = outcome.num_remote_clients;
+        self.backoff_until = outcome.backoff_until.map(to_millis);
+        self.needs_reauth = outcome.failures.iter().any(|failure| match failure {
+            SyncFailure::Auth { .. } => true,
+            _ => false,
+        });
+        // Being interrupted by the app isn't a problem with the sync.
+        let failed = outcome.failures.iter().any(|failure| match failure {
+            SyncFailure::Shutdown => false,
+            _ => true,
+        });
+        if failed {
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        } else {
+            self.consecutive_failures = 0;
+        }
+    }
+
+    /// Should be called when the user signs in again after we reported an
+    /// auth failure, so that we start syncing again.
+    pub fn credentials_changed(&mut self) {
+        self.needs_reauth = false;
+    }
+
+    /// Returns when we should next sync, given the number of local changes
+    /// which haven't been synced yet and whether the user is idle. A time in
+    /// the past means we should sync now. Returns `None` if we shouldn't sync
+    /// until the user signs in again.
+    pub fn next_sync_at(&self, local_changes: usize, is_idle: bool) -> Option<SystemTime> {
+        if self.needs_reauth {
+            return None;
+        }
+        let last_sync = match self.last_sync {
+            Some(ms) => from_millis(ms),
+            None => return Some(UNIX_EPOCH),
+        };
+        let interval = if self.consecutive_failures > 0 {
+            // Double the interval for each failure after the first, taking
+            // care not to overflow.
+            let doublings = (self.consecutive_failures - 1).min(16);
+            (MINIMUM_ERROR_INTERVAL * (1 << doublings)).min(MAXIMUM_ERROR_INTERVAL)
+        } else if local_changes >= IMMEDIATE_CHANGE_THRESHOLD {
+            IMMEDIATE_INTERVAL
+        } else if self.num_remote_clients == 0 {
+            SINGLE_DEVICE_INTERVAL
+        } else if is_idle {
+            IDLE_INTERVAL
+        } else {
+            ACTIVE_INTERVAL
+        };
+        let next = last_sync + interval;
+        Some(match self.backoff_until.map(from_millis) {
+            Some(backoff_until) if backoff_until > next => backoff_until,
+            _ => next,
+        })
+    }
+}
+
+fn to_millis(when: SystemTime) -> u64 {
+    let since_epoch = when.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis())
+}
+
+fn from_millis(ms: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(ms)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    fn now() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(1_500_000_000)
+    }
+
+    fn outcome(failures: Vec<SyncFailure>, num_remote_clients: usize) -> SyncOutcome {
+        SyncOutcome {
+            failures,
+            backoff_until: None,
+            num_remote_clients,
+        }
+    }
+
+    fn http_failure() -> SyncFailure {
+        SyncFailure::Http { code: 500 }
+    }
+
+    #[test]
+    fn test_intervals() {
+        let mut schedule = SyncSchedule::new();
+        assert_eq!(schedule.next_sync_at(0, false), Some(UNIX_EPOCH));
+
+        schedule.sync_finished(now(), &outcome(vec![], 0));
+        assert_eq!(
+            schedule.next_sync_at(0, false),
+            Some(now() + SINGLE_DEVICE_INTERVAL)
+        );
+        assert_eq!(
+            schedule.next_sync_at(IMMEDIATE_CHANGE_THRESHOLD, false),
+            Some(now() + IMMEDIATE_INTERVAL)
+        );
+
+        schedule.sync_finished(now(), &outcome(vec![], 2));
+        assert_eq!(
+            schedule.next_sync_at(0, false),
+            Some(now() + ACTIVE_INTERVAL)
+        );
+        assert_eq!(schedule.next_sync_at(0, true), Some(now() + IDLE_INTERVAL));
+        assert_eq!(
+            schedule.next_sync_at(IMMEDIATE_CHANGE_THRESHOLD, true),
+            Some(now() + IMMEDIATE_INTERVAL)
+        );
+    }
+
+    #[test]
+    fn test_failures() {
+        let mut schedule = SyncSchedule::new();
+        schedule.sync_finished(now(), &outcome(vec![http_failure()], 1));
+        assert_eq!(
+            schedule.next_sync_at(IMMEDIATE_CHANGE_THRESHOLD, false),
+            Some(now() + MINIMUM_ERROR_INTERVAL)
+        );
+        schedule.sync_finished(now(), &outcome(vec![http_failure()], 1));
+        assert_eq!(
+            schedule.next_sync_at(0, false),
+            Some(now() + MINIMUM_ERROR_INTERVAL * 2)
+        );
+        for _ in 0..100 {
+            schedule.sync_finished(now(), &outcome(vec![http_failure()], 1));
+        }
+        assert_eq!(
+            schedule.next_sync_at(0, false),
+            Some(now() + MAXIMUM_ERROR_INTERVAL)
+        );
+
+        // Being interrupted doesn't count as a failure.
+        schedule.sync_finished(now(), &outcome(vec![SyncFailure::Shutdown], 1));
+        assert_eq!(
+            schedule.next_sync_at(0, false),
+            Some(now() + ACTIVE_INTERVAL)
+        );
+    }
+
+    #[test]
+    fn test_auth_failure() {
+        let mut schedule = SyncSchedule::new();
+        let auth_failure = SyncFailure::Auth {
+            from: "tokenserver".to_string(),
+        };
+        schedule.sync_finished(now(), &outcome(vec![auth_failure], 1));
+        assert_eq!(schedule.next_sync_at(0, false), None);
+        schedule.credentials_changed();
+        assert_eq!(
+            schedule.next_sync_at(0, false),
+            Some(now() + MINIMUM_ERROR_INTERVAL)
+        );
+    }
+
+    #[test]
+    fn test_backoff() {
+        let mut schedule = SyncSchedule::new();
+        let backoff_until = now() + Duration::from_secs(2 * 60 * 60);
+        let result = Err(ErrorKind::BackoffError(backoff_until).into());
+        schedule.sync_finished(now(), &SyncOutcome::from_result(&result, 1));
+        assert_eq!(schedule.next_sync_at(0, false), Some(backoff_until));
+
+        // A backoff shorter than the interval doesn't change anything.
+        let result = Ok(SyncResult {
+            failures: Default::default(),
+            next_sync_after: Some(now() + Duration::from_secs(60)),
+        });
+        schedule.sync_finished(now(), &SyncOutcome::from_result(&result, 1));
+        assert_eq!(schedule.next_sync_at(0, true), Some(now() + IDLE_INTERVAL));
+    }
+
+    #[test]
+    fn test_serialization() {
+        let mut schedule = SyncSchedule::new();
+        schedule.sync_finished(now(), &outcome(vec![http_failure()], 3));
+        let json = serde_json::to_string(&schedule).unwrap();
+        let restored: SyncSchedule = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, schedule);
+        assert_eq!(
+            restored.next_sync_at(0, false),
+            schedule.next_sync_at(0, false)
+        );
+    }
+}
@@ -0,0 +1,945 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A `Store` for collections whose records are plain serde structs, backed by
+//! SQLite. Engines that don't need anything fancier than "reconcile these
+//! records" can describe their record with `SimpleRecord` and get local and
+//! mirror tables, change tracking, tombstones and sync ID bookkeeping from
+//! `SimpleStore`, instead of reimplementing them for each collection.
+//!
+//! For a collection named `foo`, the store uses these tables:
+//!
+//! - `foo_local`: the records as the app sees them, with a change counter
+//!   which is bumped whenever a record changes locally, and tombstones for
+//!   deleted records which we still need to upload.
+//! - `foo_mirror`: the records as we last saw them on the server. This is the
+//!   shared parent for three-way merges.
+//! - `foo_outgoing`: the records we're uploading, so that we know what the
+//!   server has once they're committed.
+//! - `foo_meta`: the last sync time, sync IDs and so on.
+
+use crate::bso_record::Payload;
+use crate::changeset::{IncomingChangeset, OutgoingChangeset};
+use crate::coll_state::{CollSyncIds, StoreSyncAssociation};
+use crate::error::Result;
+use crate::merge::MergedRecord;
+use crate::request::{BatchProgress, CollectionRequest};
+use crate::sync::Store;
+use crate::telemetry;
+use crate::util::ServerTimestamp;
+use rusqlite::types::{FromSql, ToSql};
+use rusqlite::{named_params, Connection, Row, NO_PARAMS};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sql_support::{self, ConnExt};
+use std::marker::PhantomData;
+use std::result;
+
+const LAST_SYNC_META_KEY: &str = "last_sync_time";
+const GLOBAL_SYNCID_META_KEY: &str = "global_sync_id";
+const COLLECTION_SYNCID_META_KEY: &str = "sync_id";
+const PENDING_BATCH_META_KEY: &str = "pending_batch";
+
+/// A record which can be synced with a `SimpleStore`. The record is stored
+/// and uploaded as its JSON serialization, which must include its `id`.
+pub trait SimpleRecord: Serialize + DeserializeOwned + Clone + PartialEq {
+    /// The name of the collection on the server. This is also the prefix
+    /// for the store's tables, so it must be a valid SQL identifier.
+    const COLLECTION: &'static str;
+
+    fn id(&self) -> &str;
+
+    /// Resolves a conflict between a record which changed both locally and
+    /// on the server. `parent` is the record as we last synced it, or `None`
+    /// if we've never seen it on the server, in which case only a two-way
+    /// merge is possible. The store handles the trivial cases itself, so this
+    /// is only called if `local` and `remote` are different, and neither of
+    /// them is the same as `parent`. Most records can use `three_way_merge`
+    /// to merge each field.
+    fn merge(local: &Self, remote: &Self, parent: Option<&Self>) -> Result<MergeResult<Self>>;
+}
+
+/// How to resolve a conflict between a local and remote record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MergeResult<T> {
+    /// Keep the local record, and upload it.
+    TakeLocal,
+    /// Replace the local record with the remote one.
+    TakeRemote,
+    /// Replace the local record with a combination of both, and upload it.
+    Merged(T),
+}
+
+impl<T> From<MergedRecord<T>> for MergeResult<T> {
+    fn from(merged: MergedRecord<T>) -> MergeResult<T> {
+        if !merged.upload {
+            MergeResult::TakeRemote
+        } else if !merged.changed_locally {
+            MergeResult::TakeLocal
+        } else {
+            MergeResult::Merged(merged.record)
+        }
+    }
+}
+
+#[derive(Debug)]
+struct LocalRow {
+    guid: String,
+    data: Option<String>,
+    is_deleted: bool,
+    change_counter: i64,
+}
+
+impl LocalRow {
+    fn from_row(row: &Row<'_>) -> rusqlite::Result<LocalRow> {
+        Ok(LocalRow {
+            guid: row.get("guid")?,
+            data: row.get("data")?,
+            is_deleted: row.get("is_deleted")?,
+            change_counter: row.get("sync_change_counter")?,
+        })
+    }
+
+    fn is_changed(&self) -> bool {
+        self.change_counter > 0
+    }
+}
+
+pub struct SimpleStore<'a, T> {
+    db: &'a Connection,
+    phantom: PhantomData<T>,
+}
+
+impl<'a, T: SimpleRecord> SimpleStore<'a, T> {
+    pub fn new(db: &'a Connection) -> Self {
+        SimpleStore {
+            db,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Creates the store's tables, if they don't already exist. Apps should
+    /// call this when they open or upgrade their database.
+    pub fn create_tables(&self) -> Result<()> {
+        self.db.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {c}_local (
+                 guid TEXT PRIMARY KEY,
+                 -- NULL for tombstones.
+                 data TEXT,
+                 is_deleted TINYINT NOT NULL DEFAULT 0,
+                 sync_change_counter INTEGER NOT NULL DEFAULT 1
+             );
+             CREATE TABLE IF NOT EXISTS {c}_mirror (
+                 guid TEXT PRIMARY KEY,
+                 data TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS {c}_outgoing (
+                 guid TEXT PRIMARY KEY,
+                 -- NULL for tombstones.
+                 data TEXT,
+                 sync_change_counter INTEGER NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS {c}_meta (
+                 key TEXT PRIMARY KEY,
+                 value NOT NULL
+             ) WITHOUT ROWID;",
+            c = T::COLLECTION
+        ))?;
+        Ok(())
+    }
+
+    pub fn get(&self, guid: &str) -> Result<Option<T>> {
+        let data = self.db.try_query_row(
+            &format!(
+                "SELECT data FROM {c}_local WHERE guid = :guid AND NOT is_deleted",
+                c = T::COLLECTION
+            ),
+            named_params! { ":guid": guid },
+            |row| row.get::<_, String>(0),
+            true,
+        )?;
+        Ok(match data {
+            Some(data) => Some(serde_json::from_str(&data)?),
+            None => None,
+        })
+    }
+
+    pub fn get_all(&self) -> Result<Vec<T>> {
+        let rows = self.db.query_rows_and_then_named(
+            &format!(
+                "SELECT data FROM {c}_local WHERE NOT is_deleted",
+                c = T::COLLECTION
+            ),
+            &[],
+            |row| row.get::<_, String>(0),
+        )?;
+        rows.iter()
+            .map(|data| Ok(serde_json::from_str(data)?))
+            .collect()
+    }
+
+    /// Adds or changes a record, marking it as needing to be uploaded.
+    pub fn insert_or_update(&self, record: &T) -> Result<()> {
+        let data = serde_json::to_string(record)?;
+        let tx = self.db.unchecked_transaction()?;
+        let updated = self.db.execute_named_cached(
+            &format!(
+                "UPDATE {c}_local SET
+                     data = :data,
+                     is_deleted = 0,
+                     sync_change_counter = sync_change_counter + 1
+                 WHERE guid = :guid",
+                c = T::COLLECTION
+            ),
+            named_params! { ":guid": record.id(), ":data": data },
+        )?;
+        if updated == 0 {
+            self.db.execute_named_cached(
+                &format!(
+                    "INSERT INTO {c}_local(guid, data, is_deleted, sync_change_counter)
+                     VALUES(:guid, :data, 0, 1)",
+                    c = T::COLLECTION
+                ),
+                named_params! { ":guid": record.id(), ":data": data },
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Deletes a record. If the record is on the server, we keep a tombstone
+    /// to upload in the next sync. Returns false if there was no such record.
+    pub fn delete(&self, guid: &str) -> Result<bool> {
+        let tx = self.db.unchecked_transaction()?;
+        let exists: bool = self.db.query_row_named(
+            &format!(
+                "SELECT EXISTS(SELECT 1 FROM {c}_local WHERE guid = :guid AND NOT is_deleted)",
+                c = T::COLLECTION
+            ),
+            named_params! { ":guid": guid },
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(false);
+        }
+        let is_synced: bool = self.db.query_row_named(
+            &format!(
+                "SELECT EXISTS(SELECT 1 FROM {c}_mirror WHERE guid = :guid)",
+                c = T::COLLECTION
+            ),
+            named_params! { ":guid": guid },
+            |row| row.get(0),
+        )?;
+        if is_synced {
+            self.db.execute_named_cached(
+                &format!(
+                    "UPDATE {c}_local SET
+                         data = NULL,
+                         is_deleted = 1,
+                         sync_change_counter = sync_change_counter + 1
+                     WHERE guid = :guid",
+                    c = T::COLLECTION
+                ),
+                named_params! { ":guid": guid },
+            )?;
+        } else {
+            self.delete_local(guid)?;
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    /// Returns the number of records, including deletions, which we need to
+    /// upload.
+    pub fn num_changes(&self) -> Result<usize> {
+        let count: i64 = self.db.query_one(&format!(
+            "SELECT COUNT(*) FROM {c}_local WHERE sync_change_counter > 0",
+            c = T::COLLECTION
+        ))?;
+        Ok(count as usize)
+    }
+
+    fn get_local_row(&self, guid: &str) -> Result<Option<LocalRow>> {
+        Ok(self.db.try_query_row(
+            &format!(
+                "SELECT guid, data, is_deleted, sync_change_counter
+                 FROM {c}_local WHERE guid = :guid",
+                c = T::COLLECTION
+            ),
+            named_params! { ":guid": guid },
+            LocalRow::from_row,
+            true,
+        )?)
+    }
+
+    fn get_mirror(&self, guid: &str) -> Result<Option<T>> {
+        let data = self.db.try_query_row(
+            &format!(
+                "SELECT data FROM {c}_mirror WHERE guid = :guid",
+                c = T::COLLECTION
+            ),
+            named_params! { ":guid": guid },
+            |row| row.get::<_, String>(0),
+            true,
+        )?;
+        Ok(match data {
+            Some(data) => Some(serde_json::from_str(&data)?),
+            None => None,
+        })
+    }
+
+    fn put_local(&self, record: &T, change_counter: i64) -> Result<()> {
+        self.db.execute_named_cached(
+            &format!(
+                "REPLACE INTO {c}_local(guid, data, is_deleted, sync_change_counter)
+                 VALUES(:guid, :data, 0, :change_counter)",
+                c = T::COLLECTION
+            ),
+            named_params! {
+                ":guid": record.id(),
+                ":data": serde_json::to_string(record)?,
+                ":change_counter": change_counter,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete_local(&self, guid: &str) -> Result<()> {
+        self.db.execute_named_cached(
+            &format!(
+                "DELETE FROM {c}_local WHERE guid = :guid",
+                c = T::COLLECTION
+            ),
+            named_params! { ":guid": guid },
+        )?;
+        Ok(())
+    }
+
+    fn put_mirror(&self, record: &T) -> Result<()> {
+        self.db.execute_named_cached(
+            &format!(
+                "REPLACE INTO {c}_mirror(guid, data) VALUES(:guid, :data)",
+                c = T::COLLECTION
+            ),
+            named_params! {
+                ":guid": record.id(),
+                ":data": serde_json::to_string(record)?,
+            },
+        )?;
+        Ok(())
+    }
+
+    fn delete_mirror(&self, guid: &str) -> Result<()> {
+        self.db.execute_named_cached(
+            &format!(
+                "DELETE FROM {c}_mirror WHERE guid = :guid",
+                c = T::COLLECTION
+            ),
+            named_params! { ":guid": guid },
+        )?;
+        Ok(())
+    }
+
+    fn put_meta(&self, key: &str, value: &dyn ToSql) -> Result<()> {
+        self.db.execute_named_cached(
+            &format!(
+                "REPLACE INTO {c}_meta(key, value) VALUES(:key, :value)",
+                c = T::COLLECTION
+            ),
+            named_params! { ":key": key, ":value": value },
+        )?;
+        Ok(())
+    }
+
+    fn get_meta<V: FromSql>(&self, key: &str) -> Result<Option<V>> {
+        Ok(self.db.try_query_row(
+            &format!(
+                "SELECT value FROM {c}_meta WHERE key = :key",
+                c = T::COLLECTION
+            ),
+            named_params! { ":key": key },
+            |row| row.get(0),
+            true,
+        )?)
+    }
+
+    fn delete_meta(&self, key: &str) -> Result<()> {
+        self.db.execute_named_cached(
+            &format!("DELETE FROM {c}_meta WHERE key = :key", c = T::COLLECTION),
+            named_params! { ":key": key },
+        )?;
+        Ok(())
+    }
+
+    fn get_last_sync(&self) -> Result<Option<ServerTimestamp>> {
+        Ok(self
+            .get_meta::<i64>(LAST_SYNC_META_KEY)?
+            .map(|millis| ServerTimestamp(millis as f64 / 1000.0)))
+    }
+
+    fn set_last_sync(&self, last_sync: ServerTimestamp) -> Result<()> {
+        self.put_meta(LAST_SYNC_META_KEY, &(last_sync.as_millis() as i64))
+    }
+
+    fn do_apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset> {
+        let tx = self.db.unchecked_transaction()?;
+        for (payload, _) in inbound.changes {
+            self.apply_incoming_record(payload, telem)?;
+        }
+        let outgoing = self.stage_outgoing(inbound.timestamp)?;
+        tx.commit()?;
+        Ok(outgoing)
+    }
+
+    fn apply_incoming_record(
+        &self,
+        payload: Payload,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> Result<()> {
+        let guid = payload.id.clone();
+        let local = self.get_local_row(&guid)?;
+        if payload.is_tombstone() {
+            self.delete_mirror(&guid)?;
+            match local {
+                // A local change wins over a remote deletion, so we'll upload
+                // the record again.
+                Some(ref row) if row.is_changed() && !row.is_deleted => telem.reconciled(1),
+                _ => {
+                    self.delete_local(&guid)?;
+                    telem.applied(1);
+                }
+            }
+            return Ok(());
+        }
+
+        let remote: T = match payload.into_record() {
+            Ok(remote) => remote,
+            Err(e) => {
+                log::warn!(
+                    "Failed to parse incoming {} record {}: {}",
+                    T::COLLECTION,
+                    guid,
+                    e
+                );
+                telem.failed(1);
+                return Ok(());
+            }
+        };
+        let parent = self.get_mirror(&guid)?;
+        self.put_mirror(&remote)?;
+
+        let (local, change_counter) = match local {
+            Some(ref row) if row.is_changed() && !row.is_deleted => {
+                let data = row.data.as_ref().map(String::as_str).unwrap_or_default();
+                (serde_json::from_str::<T>(data)?, row.change_counter)
+            }
+            // The record is new, unchanged or deleted locally, so we take
+            // the remote record. (A remote change wins over a local deletion.)
+            _ => {
+                self.put_local(&remote, 0)?;
+                telem.applied(1);
+                return Ok(());
+            }
+        };
+        let result = if local == remote || parent.as_ref() == Some(&local) {
+            MergeResult::TakeRemote
+        } else if parent.as_ref() == Some(&remote) {
+            MergeResult::TakeLocal
+        } else {
+            T::merge(&local, &remote, parent.as_ref())?
+        };
+        match result {
+            MergeResult::TakeLocal => {}
+            MergeResult::TakeRemote => self.put_local(&remote, 0)?,
+            MergeResult::Merged(merged) => self.put_local(&merged, change_counter)?,
+        }
+        telem.reconciled(1);
+        Ok(())
+    }
+
+    /// Returns the changed records, and remembers what we're uploading so
+    /// that `sync_finished` can update the mirror.
+    fn stage_outgoing(&self, timestamp: ServerTimestamp) -> Result<OutgoingChangeset> {
+        let rows = self.db.query_rows_and_then_named(
+            &format!(
+                "SELECT guid, data, is_deleted, sync_change_counter
+                 FROM {c}_local WHERE sync_change_counter > 0",
+                c = T::COLLECTION
+            ),
+            &[],
+            LocalRow::from_row,
+        )?;
+        let mut outgoing = OutgoingChangeset::new(T::COLLECTION.into(), timestamp);
+        for row in rows {
+            let data = if row.is_deleted { None } else { row.data };
+            self.db.execute_named_cached(
+                &format!(
+                    "REPLACE INTO {c}_outgoing(guid, data, sync_change_counter)
+                     VALUES(:guid, :data, :change_counter)",
+                    c = T::COLLECTION
+                ),
+                named_params! {
+                    ":guid": row.guid,
+                    ":data": data,
+                    ":change_counter": row.change_counter,
+                },
+            )?;
+            outgoing.changes.push(match data {
+                Some(data) => serde_json::from_str::<Payload>(&data)?,
+                None => Payload::new_tombstone(row.guid),
+            });
+        }
+        Ok(outgoing)
+    }
+
+    fn do_sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: &[String],
+    ) -> Result<()> {
+        let tx = self.db.unchecked_transaction()?;
+        sql_support::each_chunk(records_synced, |chunk, _| -> Result<()> {
+            // The server now has what we uploaded, so that's the new parent.
+            self.db.execute(
+                &format!(
+                    "DELETE FROM {c}_mirror WHERE guid IN (
+                         SELECT guid FROM {c}_outgoing
+                         WHERE data IS NULL AND guid IN ({vars})
+                     )",
+                    c = T::COLLECTION,
+                    vars = sql_support::repeat_sql_vars(chunk.len())
+                ),
+                chunk,
+            )?;
+            self.db.execute(
+                &format!(
+                    "REPLACE INTO {c}_mirror(guid, data)
+                     SELECT guid, data FROM {c}_outgoing
+                     WHERE data IS NOT NULL AND guid IN ({vars})",
+                    c = T::COLLECTION,
+                    vars = sql_support::repeat_sql_vars(chunk.len())
+                ),
+                chunk,
+            )?;
+            // Records which changed again while we were syncing still need
+            // to be uploaded next time.
+            self.db.execute(
+                &format!(
+                    "UPDATE {c}_local SET
+                         sync_change_counter = MAX(0, sync_change_counter - IFNULL(
+                             (SELECT o.sync_change_counter FROM {c}_outgoing o
+                              WHERE o.guid = {c}_local.guid), 0))
+                     WHERE guid IN ({vars})",
+                    c = T::COLLECTION,
+                    vars = sql_support::repeat_sql_vars(chunk.len())
+                ),
+                chunk,
+            )?;
+            self.db.execute(
+                &format!(
+                    "DELETE FROM {c}_local
+                     WHERE is_deleted AND sync_change_counter = 0 AND guid IN ({vars})",
+                    c = T::COLLECTION,
+                    vars = sql_support::repeat_sql_vars(chunk.len())
+                ),
+                chunk,
+            )?;
+            Ok(())
+        })?;
+        self.db.execute(
+            &format!("DELETE FROM {c}_outgoing", c = T::COLLECTION),
+            NO_PARAMS,
+        )?;
+        self.set_last_sync(new_timestamp)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_sync_ids(&self) -> Result<StoreSyncAssociation> {
+        let global = self.get_meta(GLOBAL_SYNCID_META_KEY)?;
+        let coll = self.get_meta(COLLECTION_SYNCID_META_KEY)?;
+        Ok(if let (Some(global), Some(coll)) = (global, coll) {
+            StoreSyncAssociation::Connected(CollSyncIds { global, coll })
+        } else {
+            StoreSyncAssociation::Disconnected
+        })
+    }
+
+    fn do_reset(&self, assoc: &StoreSyncAssociation) -> Result<()> {
+        log::info!("Resetting the {} store", T::COLLECTION);
+        let tx = self.db.unchecked_transaction()?;
+        // We don't know what the server has any more, so we'll upload
+        // everything in the next sync, and merge it with what's there.
+        self.db.execute_batch(&format!(
+            "DELETE FROM {c}_mirror;
+             DELETE FROM {c}_outgoing;
+             DELETE FROM {c}_local WHERE is_deleted;
+             UPDATE {c}_local SET sync_change_counter = 1;",
+            c = T::COLLECTION
+        ))?;
+        self.delete_meta(LAST_SYNC_META_KEY)?;
+        self.delete_meta(PENDING_BATCH_META_KEY)?;
+        match assoc {
+            StoreSyncAssociation::Disconnected => {
+                self.delete_meta(GLOBAL_SYNCID_META_KEY)?;
+                self.delete_meta(COLLECTION_SYNCID_META_KEY)?;
+            }
+            StoreSyncAssociation::Connected(ids) => {
+                self.put_meta(GLOBAL_SYNCID_META_KEY, &ids.global)?;
+                self.put_meta(COLLECTION_SYNCID_META_KEY, &ids.coll)?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn do_wipe(&self) -> Result<()> {
+        log::info!("Wiping the {} store", T::COLLECTION);
+        let tx = self.db.unchecked_transaction()?;
+        self.db.execute_batch(&format!(
+            "DELETE FROM {c}_local;
+             DELETE FROM {c}_mirror;
+             DELETE FROM {c}_outgoing;",
+            c = T::COLLECTION
+        ))?;
+        tx.commit()?;
+        Ok(())
+    }
+}
+
+impl<'a, T: SimpleRecord> Store for SimpleStore<'a, T> {
+    fn collection_name(&self) -> &'static str {
+        T::COLLECTION
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        telem: &mut telemetry::EngineIncoming,
+    ) -> result::Result<OutgoingChangeset, failure::Error> {
+        Ok(self.do_apply_incoming(inbound, telem)?)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<String>,
+    ) -> result::Result<(), failure::Error> {
+        Ok(self.do_sync_finished(new_timestamp, &records_synced)?)
+    }
+
+    fn get_collection_request(&self) -> result::Result<CollectionRequest, failure::Error> {
+        let since = self.get_last_sync()?.unwrap_or_default();
+        Ok(CollectionRequest::new(T::COLLECTION)
+            .full()
+            .newer_than(since))
+    }
+
+    fn get_sync_assoc(&self) -> result::Result<StoreSyncAssociation, failure::Error> {
+        Ok(self.get_sync_ids()?)
+    }
+
+    fn reset(&self, assoc: &StoreSyncAssociation) -> result::Result<(), failure::Error> {
+        Ok(self.do_reset(assoc)?)
+    }
+
+    /// Deletes all the records locally. Nothing is uploaded, so the records
+    /// stay on the server.
+    fn wipe(&self) -> result::Result<(), failure::Error> {
+        Ok(self.do_wipe()?)
+    }
+
+    fn get_pending_batch(&self) -> result::Result<Option<BatchProgress>, failure::Error> {
+        Ok(match self.get_meta::<String>(PENDING_BATCH_META_KEY)? {
+            Some(json) => Some(serde_json::from_str(&json)?),
+            None => None,
+        })
+    }
+
+    fn set_pending_batch(
+        &self,
+        progress: Option<&BatchProgress>,
+    ) -> result::Result<(), failure::Error> {
+        match progress {
+            Some(progress) => {
+                self.put_meta(PENDING_BATCH_META_KEY, &serde_json::to_string(progress)?)?
+            }
+            None => self.delete_meta(PENDING_BATCH_META_KEY)?,
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+    use crate::merge::{three_way_merge, ConflictPolicy};
+    use serde_derive::*;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Note {
+        id: String,
+        title: String,
+        body: String,
+    }
+
+    impl Note {
+        fn new(id: &str, title: &str, body: &str) -> Note {
+            Note {
+                id: id.into(),
+                title: title.into(),
+                body: body.into(),
+            }
+        }
+    }
+
+    impl SimpleRecord for Note {
+        const COLLECTION: &'static str = "notes";
+
+        fn id(&self) -> &str {
+            &self.id
+        }
+
+        fn merge(local: &Note, remote: &Note, parent: Option<&Note>) -> Result<MergeResult<Note>> {
+            Ok(three_way_merge(local, parent, remote, ConflictPolicy::PreferRemote)?.into())
+        }
+    }
+
+    fn new_store(db: &Connection) -> SimpleStore<'_, Note> {
+        let store = SimpleStore::new(db);
+        store.create_tables().expect("should create tables");
+        store
+    }
+
+    fn incoming(notes: Vec<Payload>) -> IncomingChangeset {
+        let mut changeset = IncomingChangeset::new("notes".into(), ServerTimestamp(1000.0));
+        changeset.changes = notes
+            .into_iter()
+            .map(|payload| (payload, ServerTimestamp(1000.0)))
+            .collect();
+        changeset
+    }
+
+    fn payload(note: &Note) -> Payload {
+        Payload::from_record(note.clone()).unwrap()
+    }
+
+    fn outgoing_ids(outgoing: &OutgoingChangeset) -> Vec<String> {
+        let mut ids: Vec<String> = outgoing.changes.iter().map(|p| p.id.clone()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn test_local_changes() {
+        let db = Connection::open_in_memory().unwrap();
+        let store = new_store(&db);
+
+        let note = Note::new("note1", "Shopping", "Milk");
+        store.insert_or_update(&note).unwrap();
+        assert_eq!(store.get("note1").unwrap(), Some(note.clone()));
+        assert_eq!(store.num_changes().unwrap(), 1);
+
+        let mut telem = telemetry::EngineIncoming::new();
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut telem).unwrap();
+        assert_eq!(outgoing.changes, vec![payload(&note)]);
+        store
+            .sync_finished(ServerTimestamp(1001.0), vec!["note1".into()])
+            .unwrap();
+        assert_eq!(store.num_changes().unwrap(), 0);
+        assert_eq!(store.get_mirror("note1").unwrap(), Some(note));
+
+        // Deleting a record we've never uploaded doesn't need a tombstone.
+        store
+            .insert_or_update(&Note::new("note2", "Todo", ""))
+            .unwrap();
+        assert!(store.delete("note2").unwrap());
+        assert!(!store.delete("note2").unwrap());
+        assert_eq!(store.num_changes().unwrap(), 0);
+
+        // But deleting one we have does.
+        assert!(store.delete("note1").unwrap());
+        assert_eq!(store.get("note1").unwrap(), None);
+        assert!(store.get_all().unwrap().is_empty());
+        let outgoing = store.apply_incoming(incoming(vec![]), &mut telem).unwrap();
+        assert_eq!(
+            outgoing.changes,
+            vec![Payload::new_tombstone("note1".into())]
+        );
+        store
+            .sync_finished(ServerTimestamp(1002.0), vec!["note1".into()])
+            .unwrap();
+        assert!(store.get_local_row("note1").unwrap().is_none());
+        assert!(store.get_mirror("note1").unwrap().is_none());
+        assert_eq!(
+            store.get_last_sync().unwrap(),
+            Some(ServerTimestamp(1002.0))
+        );
+    }
+
+    #[test]
+    fn test_apply_incoming() {
+        let db = Connection::open_in_memory().unwrap();
+        let store = new_store(&db);
+        let synced = vec![
+            Note::new("changed", "Old title", "Old body"),
+            Note::new("deleted-remotely", "Title", "Body"),
+            Note::new("deleted-locally", "Title", "Body"),
+            Note::new("unchanged", "Title", "Body"),
+        ];
+        let mut telem = telemetry::EngineIncoming::new();
+        store
+            .apply_incoming(incoming(synced.iter().map(payload).collect()), &mut telem)
+            .unwrap();
+        assert_eq!(store.get_all().unwrap().len(), 4);
+        assert_eq!(store.num_changes().unwrap(), 0);
+
+        store
+            .insert_or_update(&Note::new("changed", "Old title", "New body"))
+            .unwrap();
+        store
+            .insert_or_update(&Note::new("deleted-remotely", "New title", "Body"))
+            .unwrap();
+        store.delete("deleted-locally").unwrap();
+        store
+            .insert_or_update(&Note::new("new-locally", "Title", "Body"))
+            .unwrap();
+
+        let outgoing = store
+            .apply_incoming(
+                incoming(vec![
+                    payload(&Note::new("changed", "New title", "Old body")),
+                    Payload::new_tombstone("deleted-remotely".into()),
+                    payload(&Note::new("deleted-locally", "New title", "Body")),
+                    payload(&Note::new("unchanged", "New title", "Body")),
+                    payload(&Note::new("new-remotely", "Title", "Body")),
+                ]),
+                &mut telem,
+            )
+            .unwrap();
+
+        // Both sides changed different fields, so we merge them.
+        assert_eq!(
+            store.get("changed").unwrap(),
+            Some(Note::new("changed", "New title", "New body"))
+        );
+        // A change wins over a deletion, in both directions.
+        assert_eq!(
+            store.get("deleted-remotely").unwrap(),
+            Some(Note::new("deleted-remotely", "New title", "Body"))
+        );
+        assert_eq!(
+            store.get("deleted-locally").unwrap(),
+            Some(Note::new("deleted-locally", "New title", "Body"))
+        );
+        assert_eq!(
+            store.get("unchanged").unwrap(),
+            Some(Note::new("unchanged", "New title", "Body"))
+        );
+        assert!(store.get("new-remotely").unwrap().is_some());
+        assert_eq!(
+            outgoing_ids(&outgoing),
+            vec!["changed", "deleted-remotely", "new-locally"]
+        );
+
+        // Changes made while we're uploading are uploaded in the next sync.
+        store
+            .insert_or_update(&Note::new("new-locally", "Title", "Edited"))
+            .unwrap();
+        store
+            .sync_finished(ServerTimestamp(1001.0), outgoing_ids(&outgoing))
+            .unwrap();
+        assert_eq!(store.num_changes().unwrap(), 1);
+        assert_eq!(
+            store.get_mirror("new-locally").unwrap(),
+            Some(Note::new("new-locally", "Title", "Body"))
+        );
+    }
+
+    #[test]
+    fn test_identical_changes() {
+        let db = Connection::open_in_memory().unwrap();
+        let store = new_store(&db);
+        let note = Note::new("note1", "Title", "Body");
+        store.insert_or_update(&note).unwrap();
+
+        let mut telem = telemetry::EngineIncoming::new();
+        let outgoing = store
+            .apply_incoming(incoming(vec![payload(&note)]), &mut telem)
+            .unwrap();
+        assert!(outgoing.changes.is_empty());
+        assert_eq!(store.num_changes().unwrap(), 0);
+        assert_eq!(store.get_mirror("note1").unwrap(), Some(note));
+    }
+
+    #[test]
+    fn test_sqlite_errors() {
+        let db = Connection::open_in_memory().unwrap();
+        // Without tables, every query fails, and should be reported as a
+        // store error.
+        let store = SimpleStore::<Note>::new(&db);
+        let errors = vec![
+            store.get("note1").unwrap_err(),
+            store
+                .insert_or_update(&Note::new("note1", "Title", "Body"))
+                .unwrap_err(),
+        ];
+        for err in errors {
+            match err.kind() {
+                ErrorKind::StoreError(e) => assert!(e.downcast_ref::<rusqlite::Error>().is_some()),
+                kind => panic!("Unexpected error kind: {:?}", kind),
+            }
+        }
+    }
+
+    #[test]
+    fn test_reset() {
+        let db = Connection::open_in_memory().unwrap();
+        let store = new_store(&db);
+        assert_eq!(
+            store.get_sync_assoc().unwrap(),
+            StoreSyncAssociation::Disconnected
+        );
+
+        let mut telem = telemetry::EngineIncoming::new();
+        store
+            .apply_incoming(
+                incoming(vec![
+                    payload(&Note::new("note1", "Title", "Body")),
+                    payload(&Note::new("note2", "Title", "Body")),
+                ]),
+                &mut telem,
+            )
+            .unwrap();
+        store.delete("note2").unwrap();
+        store
+            .sync_finished(ServerTimestamp(1001.0), vec![])
+            .unwrap();
+
+        let ids = CollSyncIds {
+            global: "global-id".into(),
+            coll: "coll-id".into(),
+        };
+        store
+            .reset(&StoreSyncAssociation::Connected(ids.clone()))
+            .unwrap();
+        assert_eq!(
+            store.get_sync_assoc().unwrap(),
+            StoreSyncAssociation::Connected(ids)
+        );
+        assert_eq!(store.get_last_sync().unwrap(), None);
+        assert!(store.get_mirror("note1").unwrap().is_none());
+        // Everything is uploaded again, but we forget about deletions.
+        assert_eq!(store.num_changes().unwrap(), 1);
+
+        store.wipe().unwrap();
+        assert!(store.get_all().unwrap().is_empty());
+        assert_eq!(store.num_changes().unwrap(), 0);
+    }
+}
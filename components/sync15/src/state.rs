@@ -3,7 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use std::collections::HashMap;
-use std::mem;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::client::{SetupStorageClient, Sync15ClientResponse};
 use crate::collection_keys::CollectionKeys;
@@ -61,13 +61,94 @@ pub enum PersistedGlobalState {
     /// V2 is just tracking the globally declined list.
     /// None means "I've no idea" and theoretically should only happen on the
     /// very first sync for an app.
-    V2 { declined: Option<Vec<String>> },
+    ///
+    /// `backoff_until` was added later, without bumping the schema version,
+    /// and is the time (in milliseconds since the epoch) before which the
+    /// server asked us not to sync again.
+    ///
+    /// `key_fingerprints` was added later, too, and maps collection names to
+    /// fingerprints of the keys we last synced them with, so that we notice
+    /// when another client changes the keys, even after a restart.
+    V2 {
+        declined: Option<Vec<String>>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        backoff_until: Option<u64>,
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        key_fingerprints: HashMap<String, String>,
+    },
 }
 
 impl Default for PersistedGlobalState {
     #[inline]
     fn default() -> PersistedGlobalState {
-        PersistedGlobalState::V2 { declined: None }
+        PersistedGlobalState::V2 {
+            declined: None,
+            backoff_until: None,
+            key_fingerprints: HashMap::new(),
+        }
+    }
+}
+
+impl PersistedGlobalState {
+    fn set_declined(&mut self, new_declined: Vec<String>) {
+        match self {
+            PersistedGlobalState::V2 { declined, .. } => *declined = Some(new_declined),
+        }
+    }
+
+    /// Returns the time before which the server asked us not to sync, if
+    /// any.
+    pub fn backoff_until(&self) -> Option<SystemTime> {
+        match self {
+            PersistedGlobalState::V2 { backoff_until, .. } => {
+                backoff_until.map(|ms| UNIX_EPOCH + Duration::from_millis(ms))
+            }
+        }
+    }
+
+    pub(crate) fn set_backoff_until(&mut self, when: Option<SystemTime>) {
+        let ms = when.map(|when| {
+            let since_epoch = when.duration_since(UNIX_EPOCH).unwrap_or_default();
+            since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis())
+        });
+        match self {
+            PersistedGlobalState::V2 { backoff_until, .. } => *backoff_until = ms,
+        }
+    }
+
+    /// Records fingerprints of the keys for `collections`, and returns the
+    /// names of those whose keys changed since we last recorded them. We
+    /// don't know if the keys for collections we haven't recorded changed,
+    /// so we don't return those.
+    pub(crate) fn update_key_fingerprints(
+        &mut self,
+        keys: &CollectionKeys,
+        collections: &[&str],
+    ) -> error::Result<Vec<String>> {
+        let key_fingerprints = match self {
+            PersistedGlobalState::V2 {
+                key_fingerprints, ..
+            } => key_fingerprints,
+        };
+        let mut changed = Vec::new();
+        for name in collections {
+            let fingerprint = keys.fingerprint_for_collection(name)?;
+            match key_fingerprints.insert((*name).to_string(), fingerprint.clone()) {
+                Some(last) if last != fingerprint => changed.push((*name).to_string()),
+                _ => {}
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Forgets the keys we last synced with, after the server's data was
+    /// wiped or we were moved to a new node.
+    pub(crate) fn clear_key_fingerprints(&mut self) {
+        match self {
+            PersistedGlobalState::V2 {
+                key_fingerprints, ..
+            } => key_fingerprints.clear(),
+        }
     }
 }
 
@@ -102,7 +183,9 @@ fn new_global(pgs: &PersistedGlobalState) -> error::Result<MetaGlobalRecord> {
     // we previously saw a meta/global then we would have updated it with what
     // it was at the time.
     let declined = match pgs {
-        PersistedGlobalState::V2 { declined: Some(d) } => d.clone(),
+        PersistedGlobalState::V2 {
+            declined: Some(d), ..
+        } => d.clone(),
         _ => {
             log::warn!("New meta/global without local app state - the list of declined engines is being reset");
             DEFAULT_DECLINED.iter().map(ToString::to_string).collect()
@@ -117,10 +200,103 @@ fn new_global(pgs: &PersistedGlobalState) -> error::Result<MetaGlobalRecord> {
     })
 }
 
+/// Applies `changes`, a map of engine names to whether the engine should be
+/// enabled, to `global`. Enabled engines get a fresh sync ID if they were
+/// previously declined or missing, so that all clients reset them, and
+/// declined engines are removed from `engines`. Returns true if `global`
+/// changed.
+fn apply_engine_changes(
+    global: &mut MetaGlobalRecord,
+    changes: &HashMap<String, bool>,
+) -> error::Result<bool> {
+    let mut changed = false;
+    for (name, &enabled) in changes {
+        let was_declined = global.declined.contains(name);
+        if enabled {
+            if was_declined || !global.engines.contains_key(name) {
+                log::info!("Enabling engine {}", name);
+                let version = DEFAULT_ENGINES
+                    .iter()
+                    .find(|(default_name, _)| default_name == name)
+                    .map_or(1, |(_, version)| *version);
+                global.declined.retain(|declined| declined != name);
+                global.engines.insert(
+                    name.clone(),
+                    MetaGlobalEngine {
+                        version,
+                        sync_id: random_guid()?,
+                    },
+                );
+                changed = true;
+            }
+        } else if !was_declined || global.engines.contains_key(name) {
+            log::info!("Declining engine {}", name);
+            if !was_declined {
+                global.declined.push(name.clone());
+            }
+            global.engines.remove(name);
+            changed = true;
+        }
+    }
+    Ok(changed)
+}
+
+/// Returns the names of the engines in `global` that are older than the
+/// versions in `versions`, a map of engine names to the versions our stores
+/// support, with those versions. Declined and missing engines aren't
+/// upgraded.
+fn engine_upgrades(
+    global: &MetaGlobalRecord,
+    versions: &HashMap<String, usize>,
+) -> Vec<(String, usize)> {
+    let mut upgrades: Vec<(String, usize)> = versions
+        .iter()
+        .filter(|(name, &version)| {
+            !global.declined.contains(name)
+                && global
+                    .engines
+                    .get(*name)
+                    .map_or(false, |engine| engine.version < version)
+        })
+        .map(|(name, &version)| (name.clone(), version))
+        .collect();
+    upgrades.sort();
+    upgrades
+}
+
+/// Records `upgrades` in `global`, giving each upgraded engine a fresh sync
+/// ID so that all clients reset it.
+fn apply_engine_upgrades(
+    global: &mut MetaGlobalRecord,
+    upgrades: &[(String, usize)],
+) -> error::Result<()> {
+    for (name, version) in upgrades {
+        log::info!("Upgrading the {} engine to version {}", name, version);
+        global.engines.insert(
+            name.clone(),
+            MetaGlobalEngine {
+                version: *version,
+                sync_id: random_guid()?,
+            },
+        );
+    }
+    Ok(())
+}
+
 pub struct SetupStateMachine<'a> {
     client: &'a dyn SetupStorageClient,
     root_key: &'a KeyBundle,
     pgs: &'a mut PersistedGlobalState,
+    // Engines the user has enabled (true) or declined (false) since the last
+    // sync, which we need to write to `meta/global`. We set this to None once
+    // they've been written.
+    engine_changes: Option<&'a HashMap<String, bool>>,
+    // The engine versions our stores support. If `meta/global` has older
+    // versions, we record the new ones, and wipe those collections.
+    engine_versions: Option<&'a HashMap<String, usize>>,
+    // True if we should wipe the server and upload a fresh `meta/global` and
+    // `crypto/keys` even though the server's are fine.
+    fresh_start: bool,
     // `allowed_states` is designed so that we can arrange for the concept of
     // a "fast" sync - so we decline to advance if we need to setup from scratch.
     // The idea is that if we need to sync before going to sleep we should do
@@ -138,13 +314,24 @@ impl<'a> SetupStateMachine<'a> {
     /// Creates a state machine for a "classic" Sync 1.5 client that supports
     /// all states, including uploading a fresh `meta/global` and `crypto/keys`
     /// after a node reassignment.
+    ///
+    /// `engine_changes` maps the names of engines the user has enabled or
+    /// declined since the last sync to whether they're now enabled. If there
+    /// are any, we'll upload a `meta/global` reflecting them.
+    ///
+    /// `engine_versions` maps engine names to the versions our stores
+    /// support. If any are newer than the versions in `meta/global`, we'll
+    /// upload a single `meta/global` with all the new versions, and then
+    /// wipe the old records from those collections.
     pub fn for_full_sync(
         client: &'a dyn SetupStorageClient,
         root_key: &'a KeyBundle,
         pgs: &'a mut PersistedGlobalState,
+        engine_changes: Option<&'a HashMap<String, bool>>,
+        engine_versions: Option<&'a HashMap<String, usize>>,
         interruptee: &'a dyn Interruptee,
     ) -> SetupStateMachine<'a> {
-        SetupStateMachine::with_allowed_states(
+        let mut machine = SetupStateMachine::with_allowed_states(
             client,
             root_key,
             pgs,
@@ -158,7 +345,25 @@ impl<'a> SetupStateMachine<'a> {
                 "FreshStartRequired",
                 "WithPreviousState",
             ],
-        )
+        );
+        machine.engine_changes = engine_changes.filter(|changes| !changes.is_empty());
+        machine.engine_versions = engine_versions;
+        machine
+    }
+
+    /// Creates a state machine which starts over: it wipes the server, and
+    /// uploads a fresh `meta/global`, with new sync IDs, and fresh
+    /// `crypto/keys`, keeping the declined engines.
+    pub fn for_fresh_start(
+        client: &'a dyn SetupStorageClient,
+        root_key: &'a KeyBundle,
+        pgs: &'a mut PersistedGlobalState,
+        interruptee: &'a dyn Interruptee,
+    ) -> SetupStateMachine<'a> {
+        let mut machine =
+            SetupStateMachine::for_full_sync(client, root_key, pgs, None, None, interruptee);
+        machine.fresh_start = true;
+        machine
     }
 
     /// Creates a state machine for a fast sync, which only uses locally
@@ -218,6 +423,9 @@ impl<'a> SetupStateMachine<'a> {
             client,
             root_key,
             pgs,
+            engine_changes: None,
+            engine_versions: None,
+            fresh_start: false,
             sequence: Vec::new(),
             allowed_states,
             interruptee,
@@ -234,6 +442,10 @@ impl<'a> SetupStateMachine<'a> {
                     Sync15ClientResponse::NotFound { .. } => InfoConfiguration::default(),
                     other => return Err(other.create_storage_error().into()),
                 };
+                if self.fresh_start {
+                    self.fresh_start = false;
+                    return Ok(FreshStartRequired { config });
+                }
                 Ok(InitialWithConfig { config })
             }
 
@@ -278,9 +490,6 @@ impl<'a> SetupStateMachine<'a> {
                     if global.storage_version < STORAGE_VERSION {
                         Ok(FreshStartRequired { config })
                     } else {
-                        // TODO: Here would be a good place to check if we've enabled
-                        // or disabled any engines locally, and update `m/g` to reflect
-                        // that.
                         Ok(InitialWithMetaGlobal {
                             config,
                             collections,
@@ -299,13 +508,48 @@ impl<'a> SetupStateMachine<'a> {
                 global,
                 global_timestamp,
             } => {
+                // If the user enabled or declined any engines, write a
+                // `meta/global` reflecting that and start over.
+                if let Some(changes) = self.engine_changes {
+                    let mut new_global = global.clone();
+                    if apply_engine_changes(&mut new_global, changes)? {
+                        if !self.put_meta_global_or_retry(global_timestamp, &new_global)? {
+                            return Ok(InitialWithConfig { config });
+                        }
+                        self.engine_changes = None;
+                        self.pgs.set_declined(new_global.declined);
+                        return Ok(InitialWithConfig { config });
+                    }
+                    self.engine_changes = None;
+                }
+                // If our stores support newer versions of any engines, record
+                // them all in one `meta/global`. We write it before wiping
+                // the old records, so that we don't wipe anything if we
+                // can't, and then start over to fetch the new `meta/global`
+                // and its timestamp.
+                if let Some(versions) = self.engine_versions {
+                    let upgrades = engine_upgrades(&global, versions);
+                    if !upgrades.is_empty() {
+                        let mut new_global = global.clone();
+                        apply_engine_upgrades(&mut new_global, &upgrades)?;
+                        if !self.put_meta_global_or_retry(global_timestamp, &new_global)? {
+                            return Ok(InitialWithConfig { config });
+                        }
+                        for (name, _) in &upgrades {
+                            // Clients with the new version will reset and
+                            // fetch everything, so we can't leave records in
+                            // the old format behind. If we can't wipe them,
+                            // start over instead.
+                            if let Err(e) = self.client.wipe_remote_collection(name) {
+                                log::warn!("Failed to wipe {} after upgrading it: {:?}", name, e);
+                                return Ok(FreshStartRequired { config });
+                            }
+                        }
+                        return Ok(InitialWithConfig { config });
+                    }
+                }
                 // Update our PersistedGlobalState with the mega/global we just read.
-                mem::replace(
-                    self.pgs,
-                    PersistedGlobalState::V2 {
-                        declined: Some(global.declined.clone()),
-                    },
-                );
+                self.pgs.set_declined(global.declined.clone());
                 // Now try and get keys etc - if we fresh-start we'll re-use declined.
                 match self.client.fetch_crypto_keys()? {
                     Sync15ClientResponse::Success {
@@ -368,8 +612,19 @@ impl<'a> SetupStateMachine<'a> {
                 // Wipe the server.
                 self.client.wipe_all_remote()?;
 
-                // Upload a fresh `meta/global`...
-                let new_global = new_global(self.pgs)?;
+                // Upload a fresh `meta/global`, including any engines the
+                // user just enabled or declined...
+                let mut new_global = new_global(self.pgs)?;
+                if let Some(changes) = self.engine_changes.take() {
+                    apply_engine_changes(&mut new_global, changes)?;
+                    self.pgs.set_declined(new_global.declined.clone());
+                }
+                // ...And the engine versions our stores support. There's
+                // nothing to wipe, because we just wiped everything.
+                if let Some(versions) = self.engine_versions {
+                    let upgrades = engine_upgrades(&new_global, versions);
+                    apply_engine_upgrades(&mut new_global, &upgrades)?;
+                }
                 self.client
                     .put_meta_global(ServerTimestamp::default(), &new_global)?;
 
@@ -387,11 +642,52 @@ impl<'a> SetupStateMachine<'a> {
         }
     }
 
+    fn count_state(&self, label: &str) -> usize {
+        self.sequence.iter().filter(|&&l| l == label).count()
+    }
+
+    /// Uploads `new_global`, replacing the `meta/global` we fetched at
+    /// `global_timestamp`. Returns false if another client changed
+    /// `meta/global` since we fetched it, and we should fetch it again and
+    /// have another go - but only once, so that we don't fight with it
+    /// forever.
+    fn put_meta_global_or_retry(
+        &self,
+        global_timestamp: ServerTimestamp,
+        new_global: &MetaGlobalRecord,
+    ) -> error::Result<bool> {
+        match self.client.put_meta_global(global_timestamp, new_global) {
+            Ok(()) => Ok(true),
+            Err(ref e)
+                if e.is_precondition_failed() && self.count_state("InitialWithMetaGlobal") == 1 =>
+            {
+                log::info!("meta/global changed while updating engines, retrying");
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Whether any engines in `global` are older than the versions our
+    /// stores support.
+    fn needs_engine_upgrades(&self, global: &MetaGlobalRecord) -> bool {
+        self.engine_versions.map_or(false, |versions| {
+            !engine_upgrades(global, versions).is_empty()
+        })
+    }
+
     /// Runs through the state machine to the ready state.
     pub fn run_to_ready(&mut self, state: Option<GlobalState>) -> error::Result<GlobalState> {
         let mut s = match state {
-            Some(old_state) => WithPreviousState { old_state },
-            None => Initial,
+            // If we need to write engine changes or upgrades, we need a
+            // fresh `meta/global`, so we can't use our previous state.
+            Some(old_state)
+                if self.engine_changes.is_none()
+                    && !self.needs_engine_upgrades(&old_state.global) =>
+            {
+                WithPreviousState { old_state }
+            }
+            _ => Initial,
         };
         loop {
             self.interruptee.err_if_interrupted()?;
@@ -480,6 +776,7 @@ mod tests {
     use crate::bso_record::{BsoRecord, EncryptedBso, EncryptedPayload, Payload};
     use crate::record_types::CryptoKeysRecord;
     use interrupt::NeverInterrupts;
+    use std::cell::RefCell;
 
     struct InMemoryClient {
         info_configuration: error::Result<Sync15ClientResponse<InfoConfiguration>>,
@@ -563,6 +860,10 @@ mod tests {
         fn wipe_all_remote(&self) -> error::Result<()> {
             Ok(())
         }
+
+        fn wipe_remote_collection(&self, _collection: &str) -> error::Result<()> {
+            unreachable!("these tests shouldn't wipe collections");
+        }
     }
 
     fn mocked_success_ts<T>(t: T, ts: f64) -> error::Result<Sync15ClientResponse<T>> {
@@ -641,10 +942,16 @@ mod tests {
                 888.0,
             ),
         };
-        let mut pgs = PersistedGlobalState::V2 { declined: None };
+        let mut pgs = PersistedGlobalState::default();
 
-        let mut state_machine =
-            SetupStateMachine::for_full_sync(&client, &root_key, &mut pgs, &NeverInterrupts);
+        let mut state_machine = SetupStateMachine::for_full_sync(
+            &client,
+            &root_key,
+            &mut pgs,
+            None,
+            None,
+            &NeverInterrupts,
+        );
         assert!(
             state_machine.run_to_ready(None).is_ok(),
             "Should drive state machine to ready"
@@ -661,4 +968,334 @@ mod tests {
             "Should cycle through all states"
         );
     }
+
+    // A server that keeps the `meta/global` and `crypto/keys` we upload, and
+    // records the changes we make to it.
+    struct UpgradingClient {
+        meta_global: RefCell<(MetaGlobalRecord, f64)>,
+        crypto_keys: RefCell<EncryptedBso>,
+        calls: RefCell<Vec<String>>,
+        fail_put_meta_global: bool,
+        fail_wipe: Option<&'static str>,
+    }
+
+    impl UpgradingClient {
+        fn new(root_key: &KeyBundle, engines: &[(&str, usize)]) -> Self {
+            let global = MetaGlobalRecord {
+                sync_id: "syncIDAAAAAA".to_owned(),
+                storage_version: 5usize,
+                engines: engines
+                    .iter()
+                    .map(|(name, version)| {
+                        (
+                            (*name).to_owned(),
+                            MetaGlobalEngine {
+                                version: *version,
+                                sync_id: format!("{}SyncID", name),
+                            },
+                        )
+                    })
+                    .collect(),
+                declined: vec![],
+            };
+            let keys = CollectionKeys::new_random()
+                .unwrap()
+                .to_encrypted_bso_with_timestamp(root_key, 888.0.into())
+                .unwrap();
+            UpgradingClient {
+                meta_global: RefCell::new((global, 999.0)),
+                crypto_keys: RefCell::new(keys),
+                calls: RefCell::new(Vec::new()),
+                fail_put_meta_global: false,
+                fail_wipe: None,
+            }
+        }
+
+        fn server_error(route: &str) -> error::Error {
+            ErrorKind::StorageHttpError {
+                code: 500,
+                route: route.to_string(),
+            }
+            .into()
+        }
+    }
+
+    impl SetupStorageClient for UpgradingClient {
+        fn fetch_info_configuration(
+            &self,
+        ) -> error::Result<Sync15ClientResponse<InfoConfiguration>> {
+            mocked_success(InfoConfiguration::default())
+        }
+
+        fn fetch_info_collections(&self) -> error::Result<Sync15ClientResponse<InfoCollections>> {
+            let collections = vec![
+                ("meta".to_owned(), self.meta_global.borrow().1.into()),
+                ("crypto".to_owned(), self.crypto_keys.borrow().modified),
+            ];
+            mocked_success(InfoCollections::new(collections.into_iter().collect()))
+        }
+
+        fn fetch_meta_global(&self) -> error::Result<Sync15ClientResponse<MetaGlobalRecord>> {
+            let (global, timestamp) = self.meta_global.borrow().clone();
+            mocked_success_ts(global, timestamp)
+        }
+
+        fn put_meta_global(
+            &self,
+            xius: ServerTimestamp,
+            global: &MetaGlobalRecord,
+        ) -> error::Result<()> {
+            self.calls.borrow_mut().push("put meta/global".to_string());
+            if self.fail_put_meta_global {
+                return Err(UpgradingClient::server_error("meta/global"));
+            }
+            let mut meta_global = self.meta_global.borrow_mut();
+            if xius != ServerTimestamp::default() && xius != ServerTimestamp(meta_global.1) {
+                return Err(ErrorKind::StorageHttpError {
+                    code: 412,
+                    route: "meta/global".to_string(),
+                }
+                .into());
+            }
+            *meta_global = (global.clone(), meta_global.1 + 1.0);
+            Ok(())
+        }
+
+        fn fetch_crypto_keys(&self) -> error::Result<Sync15ClientResponse<EncryptedBso>> {
+            let keys = self.crypto_keys.borrow().clone();
+            let timestamp = keys.modified.into();
+            mocked_success_ts(keys, timestamp)
+        }
+
+        fn put_crypto_keys(
+            &self,
+            _xius: ServerTimestamp,
+            keys: &EncryptedBso,
+        ) -> error::Result<()> {
+            self.calls.borrow_mut().push("put crypto/keys".to_string());
+            let mut new_keys = keys.clone();
+            new_keys.modified = ServerTimestamp(self.crypto_keys.borrow().modified.0 + 1.0);
+            self.crypto_keys.replace(new_keys);
+            Ok(())
+        }
+
+        fn wipe_all_remote(&self) -> error::Result<()> {
+            self.calls.borrow_mut().push("wipe all".to_string());
+            Ok(())
+        }
+
+        fn wipe_remote_collection(&self, collection: &str) -> error::Result<()> {
+            self.calls.borrow_mut().push(format!("wipe {}", collection));
+            if self.fail_wipe == Some(collection) {
+                return Err(UpgradingClient::server_error(collection));
+            }
+            Ok(())
+        }
+    }
+
+    fn engine_versions(versions: &[(&str, usize)]) -> HashMap<String, usize> {
+        versions
+            .iter()
+            .map(|(name, version)| ((*name).to_owned(), *version))
+            .collect()
+    }
+
+    #[test]
+    fn test_engine_upgrades() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let client = UpgradingClient::new(
+            &root_key,
+            &[("bookmarks", 1), ("history", 1), ("passwords", 1)],
+        );
+        let versions = engine_versions(&[("bookmarks", 2), ("history", 2), ("passwords", 1)]);
+        let mut pgs = PersistedGlobalState::default();
+
+        let mut state_machine = SetupStateMachine::for_full_sync(
+            &client,
+            &root_key,
+            &mut pgs,
+            None,
+            Some(&versions),
+            &NeverInterrupts,
+        );
+        let state = state_machine
+            .run_to_ready(None)
+            .expect("Should drive state machine to ready");
+
+        // Both engines should be upgraded with a single `meta/global`,
+        // written before we wipe anything.
+        assert_eq!(
+            *client.calls.borrow(),
+            vec!["put meta/global", "wipe bookmarks", "wipe history"]
+        );
+        for name in &["bookmarks", "history"] {
+            let engine = &state.global.engines[*name];
+            assert_eq!(engine.version, 2);
+            assert_ne!(engine.sync_id, format!("{}SyncID", name));
+        }
+        assert_eq!(state.global.engines["passwords"].sync_id, "passwordsSyncID");
+        assert_eq!(state.global.sync_id, "syncIDAAAAAA");
+        // Our global state should have the `meta/global` we uploaded, so
+        // that we can write it again later.
+        assert_eq!(state.global_timestamp, ServerTimestamp(1000.0));
+
+        // Syncing again with our previous state shouldn't change anything.
+        client.calls.borrow_mut().clear();
+        let mut state_machine = SetupStateMachine::for_full_sync(
+            &client,
+            &root_key,
+            &mut pgs,
+            None,
+            Some(&versions),
+            &NeverInterrupts,
+        );
+        let new_state = state_machine
+            .run_to_ready(Some(state.clone()))
+            .expect("Should drive state machine to ready");
+        assert!(client.calls.borrow().is_empty());
+        assert_eq!(new_state.global_timestamp, state.global_timestamp);
+        assert_eq!(state_machine.sequence, vec!["WithPreviousState", "Ready"]);
+
+        // But upgrading again should fetch a fresh `meta/global` first.
+        let versions = engine_versions(&[("passwords", 2)]);
+        let mut state_machine = SetupStateMachine::for_full_sync(
+            &client,
+            &root_key,
+            &mut pgs,
+            None,
+            Some(&versions),
+            &NeverInterrupts,
+        );
+        let state = state_machine
+            .run_to_ready(Some(state))
+            .expect("Should drive state machine to ready");
+        assert_eq!(
+            *client.calls.borrow(),
+            vec!["put meta/global", "wipe passwords"]
+        );
+        assert_eq!(state.global.engines["passwords"].version, 2);
+        assert_eq!(state.global_timestamp, ServerTimestamp(1001.0));
+    }
+
+    #[test]
+    fn test_engine_upgrade_failures() {
+        let root_key = KeyBundle::new_random().unwrap();
+        let versions = engine_versions(&[("bookmarks", 2), ("history", 2)]);
+
+        // If we can't write `meta/global`, we shouldn't wipe anything.
+        let mut client = UpgradingClient::new(&root_key, &[("bookmarks", 1), ("history", 1)]);
+        client.fail_put_meta_global = true;
+        let mut pgs = PersistedGlobalState::default();
+        let mut state_machine = SetupStateMachine::for_full_sync(
+            &client,
+            &root_key,
+            &mut pgs,
+            None,
+            Some(&versions),
+            &NeverInterrupts,
+        );
+        assert!(state_machine.run_to_ready(None).is_err());
+        assert_eq!(*client.calls.borrow(), vec!["put meta/global"]);
+
+        // If we can't wipe a collection, we should start over, instead of
+        // leaving records in the old format on the server.
+        let mut client = UpgradingClient::new(&root_key, &[("bookmarks", 1), ("history", 1)]);
+        client.fail_wipe = Some("bookmarks");
+        let mut pgs = PersistedGlobalState::default();
+        let mut state_machine = SetupStateMachine::for_full_sync(
+            &client,
+            &root_key,
+            &mut pgs,
+            None,
+            Some(&versions),
+            &NeverInterrupts,
+        );
+        let state = state_machine
+            .run_to_ready(None)
+            .expect("Should drive state machine to ready");
+        assert_eq!(
+            *client.calls.borrow(),
+            vec![
+                "put meta/global",
+                "wipe bookmarks",
+                "wipe all",
+                "put meta/global",
+                "put crypto/keys",
+            ]
+        );
+        assert_eq!(state.global.engines["bookmarks"].version, 2);
+        assert_eq!(state.global.engines["history"].version, 2);
+    }
+
+    #[test]
+    fn test_persisted_backoff() {
+        // State persisted before we tracked backoff should still parse.
+        let mut pgs: PersistedGlobalState =
+            serde_json::from_str(r#"{"schema_version":"V2","declined":["foo"]}"#).unwrap();
+        assert_eq!(pgs.backoff_until(), None);
+
+        let when = UNIX_EPOCH + Duration::from_millis(1_500_000_000_123);
+        pgs.set_backoff_until(Some(when));
+        let json = serde_json::to_string(&pgs).unwrap();
+        let pgs: PersistedGlobalState = serde_json::from_str(&json).unwrap();
+        assert_eq!(pgs.backoff_until(), Some(when));
+        match pgs {
+            PersistedGlobalState::V2 { declined, .. } => {
+                assert_eq!(declined, Some(vec!["foo".to_string()]))
+            }
+        }
+    }
+
+    #[test]
+    fn test_key_fingerprints() {
+        let mut pgs = PersistedGlobalState::default();
+        let keys = CollectionKeys::new_random().unwrap();
+        let names = ["bookmarks", "passwords"];
+        assert!(pgs
+            .update_key_fingerprints(&keys, &names)
+            .unwrap()
+            .is_empty());
+
+        // The fingerprints should survive a restart.
+        let json = serde_json::to_string(&pgs).unwrap();
+        let mut pgs: PersistedGlobalState = serde_json::from_str(&json).unwrap();
+        let mut new_keys = keys.clone();
+        new_keys.regenerate_collection("passwords").unwrap();
+        assert_eq!(
+            pgs.update_key_fingerprints(&new_keys, &names).unwrap(),
+            vec!["passwords".to_string()]
+        );
+        assert!(pgs
+            .update_key_fingerprints(&new_keys, &names)
+            .unwrap()
+            .is_empty());
+
+        pgs.clear_key_fingerprints();
+        let json = serde_json::to_string(&pgs).unwrap();
+        assert!(!json.contains("key_fingerprints"));
+    }
+
+    #[test]
+    fn test_apply_engine_changes() {
+        let mut global = new_global(&PersistedGlobalState::default()).unwrap();
+        let history_id = global.engines["history"].sync_id.clone();
+        let changes: HashMap<String, bool> = vec![
+            ("history".to_string(), false),
+            ("bookmarks".to_string(), true),
+        ]
+        .into_iter()
+        .collect();
+        assert!(apply_engine_changes(&mut global, &changes).unwrap());
+        assert!(!global.engines.contains_key("history"));
+        assert_eq!(global.declined, vec!["history".to_string()]);
+        // Applying the same changes again is a no-op.
+        assert!(!apply_engine_changes(&mut global, &changes).unwrap());
+
+        let changes: HashMap<String, bool> =
+            vec![("history".to_string(), true)].into_iter().collect();
+        assert!(apply_engine_changes(&mut global, &changes).unwrap());
+        assert!(global.declined.is_empty());
+        assert_eq!(global.engines["history"].version, 1);
+        assert_ne!(global.engines["history"].sync_id, history_id);
+    }
 }
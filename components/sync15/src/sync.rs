@@ -2,15 +2,17 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use crate::changeset::{CollectionUpdate, IncomingChangeset, OutgoingChangeset};
+use crate::changeset::{CollectionUpdate, IncomingChangeset, IncomingDecryptor, OutgoingChangeset};
 use crate::client::Sync15StorageClient;
-use crate::coll_state::{LocalCollStateMachine, StoreSyncAssociation};
-use crate::error::Error;
-use crate::request::CollectionRequest;
+use crate::coll_state::{CollState, LocalCollStateMachine, StoreSyncAssociation};
+use crate::error::{Error, ErrorKind};
+use crate::request::{BatchProgress, CollectionRequest};
 use crate::state::GlobalState;
 use crate::telemetry;
 use crate::util::ServerTimestamp;
 use interrupt::Interruptee;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
 
 /// Low-level store functionality. Stores that need custom reconciliation logic should use this.
 ///
@@ -47,6 +49,120 @@ pub trait Store {
     fn reset(&self, assoc: &StoreSyncAssociation) -> Result<(), failure::Error>;
 
     fn wipe(&self) -> Result<(), failure::Error>;
+
+    /// Reconciles `inbound` with the local data as `apply_incoming` would,
+    /// and reports what that would change locally and upload, without
+    /// changing anything. Stores typically do this by applying the records in
+    /// a transaction which they roll back. This is used for dry runs, and by
+    /// default it fails with `DryRunUnsupported`.
+    fn preview_incoming(
+        &self,
+        _inbound: IncomingChangeset,
+        _incoming_telem: &mut telemetry::EngineIncoming,
+    ) -> Result<StorePreview, failure::Error> {
+        Err(Error::from(ErrorKind::DryRunUnsupported(self.collection_name())).into())
+    }
+
+    /// Returns the progress of a batch upload which a previous sync started
+    /// but didn't commit, as last passed to `set_pending_batch`, so that we
+    /// can resume it. Stores which don't persist this, as by default, upload
+    /// everything again after an interrupted sync.
+    fn get_pending_batch(&self) -> Result<Option<BatchProgress>, failure::Error> {
+        Ok(None)
+    }
+
+    /// Persists the progress of the batch we're uploading, or forgets it once
+    /// the batch is committed or abandoned if `progress` is `None`. Stores
+    /// should also forget it when they're reset.
+    fn set_pending_batch(&self, _progress: Option<&BatchProgress>) -> Result<(), failure::Error> {
+        Ok(())
+    }
+
+    /// Checks the local data against what we know of the server's after a
+    /// successful sync, and reports any problems for the sync ping. Stores
+    /// which don't validate return `None`, as the default does. Validating
+    /// can be slow, so stores may also skip it for some syncs.
+    fn validate(&self) -> Result<Option<telemetry::Validation>, failure::Error> {
+        Ok(None)
+    }
+
+    /// The most threads to decrypt and parse incoming records on. Stores
+    /// which expect large first syncs can use more than one, as by default.
+    fn max_decrypt_threads(&self) -> usize {
+        1
+    }
+
+    /// The version of the record format this store reads and writes, which
+    /// we record for the collection in `meta/global`. Stores bump this when
+    /// they change the format in a way older clients can't read. The first
+    /// client to sync with the new version wipes the collection and uploads
+    /// everything again, and clients with older versions stop syncing the
+    /// collection until they're updated. The default is 1.
+    fn engine_version(&self) -> usize {
+        1
+    }
+}
+
+/// How applying an incoming record would change a local record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LocalChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalChange {
+    pub id: String,
+    pub kind: LocalChangeKind,
+}
+
+impl LocalChange {
+    /// Compares snapshots of the local records from before and after applying
+    /// incoming records, keyed by ID, and returns the changes, sorted by ID.
+    pub fn between<T: PartialEq>(
+        mut before: HashMap<String, T>,
+        after: HashMap<String, T>,
+    ) -> Vec<LocalChange> {
+        let mut changes = Vec::new();
+        for (id, record) in after {
+            let kind = match before.remove(&id) {
+                None => LocalChangeKind::Insert,
+                Some(old) if old != record => LocalChangeKind::Update,
+                Some(_) => continue,
+            };
+            changes.push(LocalChange { id, kind });
+        }
+        changes.extend(before.into_iter().map(|(id, _)| LocalChange {
+            id,
+            kind: LocalChangeKind::Delete,
+        }));
+        changes.sort_by(|a, b| a.id.cmp(&b.id));
+        changes
+    }
+}
+
+/// What syncing a store would do, as reported by a dry run.
+#[derive(Debug)]
+pub struct StorePreview {
+    /// True if the store's sync IDs don't match the server's, so a sync would
+    /// reset the store before applying anything. We don't fetch or reconcile
+    /// any records for these stores, so the other fields are empty.
+    pub reset_required: bool,
+    /// The local records that applying the incoming records would change.
+    pub local_changes: Vec<LocalChange>,
+    /// The records that would be uploaded.
+    pub outgoing: OutgoingChangeset,
+}
+
+impl StorePreview {
+    pub fn new(outgoing: OutgoingChangeset) -> StorePreview {
+        StorePreview {
+            reset_required: false,
+            local_changes: Vec::new(),
+            outgoing,
+        }
+    }
 }
 
 pub fn synchronize(
@@ -73,34 +189,51 @@ pub fn synchronize(
         }
     };
 
-    let collection_request = store.get_collection_request()?;
-    interruptee.err_if_interrupted()?;
-    let incoming_changes = IncomingChangeset::fetch(
+    // We apply each page of incoming records as it arrives. Stores return
+    // their outgoing changes after each page, so we upload the combination
+    // of them.
+    let mut telem_incoming = telemetry::EngineIncoming::new();
+    let mut outgoing = OutgoingChangeset::new(collection.into(), ServerTimestamp::default());
+    let mut decryptor = IncomingDecryptor::new(store.max_decrypt_threads());
+    let mut apply_took = Duration::default();
+    let download_started = Instant::now();
+    fetch_incoming(
         client,
         &mut coll_state,
-        collection.into(),
-        &collection_request,
+        store,
+        &mut decryptor,
+        interruptee,
+        |incoming_changes| {
+            let apply_started = Instant::now();
+            let page_outgoing = store.apply_incoming(incoming_changes, &mut telem_incoming)?;
+            apply_took += apply_started.elapsed();
+            merge_outgoing(&mut outgoing, page_outgoing);
+            Ok(())
+        },
     )?;
-    assert_eq!(incoming_changes.timestamp, coll_state.last_modified);
-
-    log::info!(
-        "Downloaded {} remote changes",
-        incoming_changes.changes.len()
-    );
-    let new_timestamp = incoming_changes.timestamp;
-    let mut telem_incoming = telemetry::EngineIncoming::new();
-    let mut outgoing = store.apply_incoming(incoming_changes, &mut telem_incoming)?;
+    // Downloading includes decrypting, which we also report on its own.
+    let download_took = download_started
+        .elapsed()
+        .checked_sub(apply_took)
+        .unwrap_or_default();
     telem_engine.incoming(telem_incoming);
+    telem_engine.step(telemetry::Step::timed("download", download_took));
+    if let Some(step) = decryptor.telemetry_step() {
+        telem_engine.step(step);
+    }
+    telem_engine.step(telemetry::Step::timed("apply", apply_took));
 
     interruptee.err_if_interrupted()?;
-    // xxx - duplication below smells wrong
-    outgoing.timestamp = new_timestamp;
-    coll_state.last_modified = new_timestamp;
+    // If the collection changed while we were paging, the last page has the
+    // latest time, which is what we need to upload against.
+    outgoing.timestamp = coll_state.last_modified;
 
     log::info!("Uploading {} outgoing changes", outgoing.changes.len());
+    let upload_started = Instant::now();
     let upload_info =
         CollectionUpdate::new_from_changeset(client, &coll_state, outgoing, fully_atomic)?
-            .upload()?;
+            .upload_resumable(store, interruptee)?;
+    telem_engine.step(telemetry::Step::timed("upload", upload_started.elapsed()));
 
     log::info!(
         "Upload success ({} records success, {} records failed)",
@@ -116,6 +249,176 @@ pub fn synchronize(
 
     store.sync_finished(upload_info.modified_timestamp, upload_info.successful_ids)?;
 
+    // Validation is only for telemetry, so failing to validate doesn't fail
+    // the sync.
+    let started = Instant::now();
+    match store.validate() {
+        Ok(Some(mut validation)) => {
+            validation.took(started.elapsed());
+            telem_engine.validation(validation);
+        }
+        Ok(None) => {}
+        Err(e) => log::warn!("Failed to validate {}: {}", collection, e),
+    }
+
     log::info!("Sync finished!");
     Ok(())
 }
+
+/// Performs a dry run of a sync of `store`: fetches and decrypts the
+/// incoming records, and asks the store what applying them would do. Nothing
+/// is changed locally or uploaded, and, unlike `synchronize`, the store isn't
+/// reset if its sync IDs have changed. Returns `None` if the collection is
+/// declined or isn't in `meta/global`.
+pub fn preview(
+    client: &Sync15StorageClient,
+    global_state: &GlobalState,
+    store: &dyn Store,
+    interruptee: &impl Interruptee,
+) -> Result<Option<StorePreview>, Error> {
+    let collection = store.collection_name();
+    log::info!("Previewing a sync of collection {}", collection);
+
+    let mut coll_state = match LocalCollStateMachine::peek_state(store, global_state)? {
+        Ok(Some(coll_state)) => coll_state,
+        Ok(None) => return Ok(None),
+        Err(ids) => {
+            log::info!(
+                "Syncing {} would reset it with sync IDs {:?}",
+                collection,
+                ids
+            );
+            let mut preview = StorePreview::new(OutgoingChangeset::new(
+                collection.into(),
+                ServerTimestamp::default(),
+            ));
+            preview.reset_required = true;
+            return Ok(Some(preview));
+        }
+    };
+
+    // Stores roll back what they apply, so each page would be reconciled
+    // without the ones before it. Instead, we fetch everything and preview
+    // it as a single changeset.
+    let mut incoming = IncomingChangeset::new(collection.into(), ServerTimestamp::default());
+    let mut decryptor = IncomingDecryptor::new(store.max_decrypt_threads());
+    fetch_incoming(
+        client,
+        &mut coll_state,
+        store,
+        &mut decryptor,
+        interruptee,
+        |page| {
+            incoming.changes.extend(page.changes);
+            incoming.timestamp = page.timestamp;
+            Ok(())
+        },
+    )?;
+
+    interruptee.err_if_interrupted()?;
+    let mut telem_incoming = telemetry::EngineIncoming::new();
+    let mut preview = store.preview_incoming(incoming, &mut telem_incoming)?;
+    preview.outgoing.timestamp = coll_state.last_modified;
+    log::info!(
+        "Syncing {} would change {} local records and upload {} records",
+        collection,
+        preview.local_changes.len(),
+        preview.outgoing.changes.len()
+    );
+    Ok(Some(preview))
+}
+
+/// Fetches the records the store asked for, calling `apply` with each page.
+/// If the store asked for a `limit`, the server returns the collection in
+/// pages, which we request until there are none left.
+fn fetch_incoming(
+    client: &Sync15StorageClient,
+    coll_state: &mut CollState,
+    store: &dyn Store,
+    decryptor: &mut IncomingDecryptor,
+    interruptee: &impl Interruptee,
+    mut apply: impl FnMut(IncomingChangeset) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let collection = store.collection_name();
+    let mut collection_request = store.get_collection_request()?;
+    loop {
+        interruptee.err_if_interrupted()?;
+        let (incoming_changes, next_offset) = IncomingChangeset::fetch_page_with(
+            client,
+            coll_state,
+            collection.into(),
+            &collection_request,
+            decryptor,
+        )?;
+        log::info!(
+            "Downloaded {} remote changes",
+            incoming_changes.changes.len()
+        );
+        apply(incoming_changes)?;
+        match next_offset {
+            Some(offset) => {
+                log::info!(
+                    "Fetching next page of {} from offset {}",
+                    collection,
+                    offset
+                );
+                collection_request = collection_request.offset(Some(offset));
+            }
+            None => return Ok(()),
+        }
+    }
+}
+
+/// Adds the changes in `page` to `outgoing`, replacing any changes for the
+/// same records.
+fn merge_outgoing(outgoing: &mut OutgoingChangeset, page: OutgoingChangeset) {
+    let mut positions: HashMap<String, usize> = outgoing
+        .changes
+        .iter()
+        .enumerate()
+        .map(|(i, payload)| (payload.id.clone(), i))
+        .collect();
+    for payload in page.changes {
+        match positions.get(&payload.id) {
+            Some(&i) => outgoing.changes[i] = payload,
+            None => {
+                positions.insert(payload.id.clone(), outgoing.changes.len());
+                outgoing.changes.push(payload);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_local_changes_between() {
+        let before: HashMap<String, i32> = vec![("a", 1), ("b", 2), ("c", 3)]
+            .into_iter()
+            .map(|(id, value)| (id.to_string(), value))
+            .collect();
+        let after: HashMap<String, i32> = vec![("a", 1), ("c", 4), ("d", 5)]
+            .into_iter()
+            .map(|(id, value)| (id.to_string(), value))
+            .collect();
+        assert_eq!(
+            LocalChange::between(before, after),
+            vec![
+                LocalChange {
+                    id: "b".into(),
+                    kind: LocalChangeKind::Delete,
+                },
+                LocalChange {
+                    id: "c".into(),
+                    kind: LocalChangeKind::Update,
+                },
+                LocalChange {
+                    id: "d".into(),
+                    kind: LocalChangeKind::Insert,
+                },
+            ]
+        );
+    }
+}
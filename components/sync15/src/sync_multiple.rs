@@ -5,16 +5,21 @@
 // This helps you perform a sync of multiple stores and helps you manage
 // global and local state between syncs.
 
-use crate::client::{Sync15StorageClient, Sync15StorageClientInit};
-use crate::error::Error;
+use crate::client::{SetupStorageClient, Sync15StorageClient, Sync15StorageClientInit};
+use crate::clients::{self, CommandProcessor, RemoteClient};
+use crate::coll_state::{CollSyncIds, StoreSyncAssociation};
+use crate::error::{Error, ErrorKind};
 use crate::key_bundle::KeyBundle;
 use crate::state::{GlobalState, PersistedGlobalState, SetupStateMachine};
-use crate::sync::{self, Store};
+use crate::sync::{self, Store, StorePreview};
 use crate::telemetry;
+use crate::token::TokenCache;
+use crate::util::random_guid;
 use interrupt::Interruptee;
 use std::collections::HashMap;
 use std::mem;
 use std::result;
+use std::time::{Instant, SystemTime};
 
 /// Info about the client to use. We reuse the client unless
 /// we discover the client_init has changed, in which case we re-create one.
@@ -33,6 +38,166 @@ struct ClientInfo {
 pub struct MemoryCachedState {
     last_client_info: Option<ClientInfo>,
     last_global_state: Option<GlobalState>,
+    last_remote_clients: Vec<RemoteClient>,
+    // A token the app gave us from a previous run, which we'll try to use
+    // the next time we create a client.
+    pending_token: Option<TokenCache>,
+    // The hashed uid of the account we last synced, so we notice when the
+    // user signs in to another one.
+    last_uid: Option<String>,
+}
+
+impl MemoryCachedState {
+    /// The other clients seen in the `clients` collection during the last
+    /// sync which used a `CommandProcessor`.
+    pub fn remote_clients(&self) -> &[RemoteClient] {
+        &self.last_remote_clients
+    }
+
+    /// Returns the tokenserver token from the last sync, if it's still
+    /// valid. Unlike the rest of this state, apps can persist this (in
+    /// encrypted storage, as it's a credential) and pass it to
+    /// `import_token` after a restart, to avoid fetching a new token.
+    pub fn export_token(&self) -> Option<TokenCache> {
+        match self.last_client_info {
+            Some(ref client_info) => client_info.client.export_token(),
+            None => self.pending_token.clone(),
+        }
+    }
+
+    /// Provides a token from `export_token` for the next sync to use. It's
+    /// ignored if it's expired, or was fetched for a different account.
+    pub fn import_token(&mut self, token: TokenCache) {
+        self.pending_token = Some(token);
+    }
+
+    /// Returns the hashed uid of the account `storage_init` is for, fetching
+    /// a token if we don't have one yet. Apps which let the user switch
+    /// accounts can use this before syncing to find which account's
+    /// persisted state to pass to `sync_multiple`. The token is reused by
+    /// the next sync, so this doesn't cost an extra request.
+    ///
+    /// Like `sync_multiple`, this fails with a `BackoffError` without making
+    /// any requests if the server asked us to back off, unless `force` is
+    /// true. If we can't fetch a token, the failure is recorded in
+    /// `sync_ping`, and any backoff the tokenserver asked for in
+    /// `persisted_global_state`, which should be persisted as it would be
+    /// after a sync.
+    pub fn hashed_uid(
+        &mut self,
+        persisted_global_state: &mut Option<String>,
+        storage_init: &Sync15StorageClientInit,
+        sync_ping: &mut telemetry::SyncTelemetryPing,
+        force: bool,
+    ) -> result::Result<String, Error> {
+        let mut pgs = parse_persisted_state(persisted_global_state);
+        check_backoff(&pgs, force)?;
+        match self.fetch_hashed_uid(storage_init) {
+            Ok(uid) => Ok(uid),
+            Err(e) => {
+                log::warn!("Failed to fetch the account's uid: {:?}", e);
+                let mut telem_sync = telemetry::SyncTelemetry::new();
+                telem_sync.failure(telemetry::sync_failure_from_error(&e));
+                sync_ping.sync(telem_sync);
+                if let Some(when) = e.backoff_until() {
+                    pgs.set_backoff_until(Some(when));
+                    *persisted_global_state = Some(serde_json::to_string(&pgs)?);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn fetch_hashed_uid(
+        &mut self,
+        storage_init: &Sync15StorageClientInit,
+    ) -> result::Result<String, Error> {
+        let client_info = self.take_client_info(storage_init)?;
+        let result = client_info.client.hashed_uid();
+        self.last_client_info = Some(client_info);
+        let uid = result?;
+        if self
+            .last_uid
+            .as_ref()
+            .map_or(false, |last_uid| *last_uid != uid)
+        {
+            // Nothing we cached about the other account's server is valid.
+            log::info!("Account changed, dropping the cached global state");
+            self.last_global_state = None;
+            self.last_remote_clients.clear();
+        }
+        self.last_uid = Some(uid.clone());
+        Ok(uid)
+    }
+
+    // Takes the client from the last sync, or makes a new one if there
+    // wasn't one, or it was for a different `storage_init`. Callers should
+    // put it back in `last_client_info` when they're done with it, so that
+    // the next sync reuses its token.
+    fn take_client_info(
+        &mut self,
+        storage_init: &Sync15StorageClientInit,
+    ) -> result::Result<ClientInfo, Error> {
+        match self.last_client_info.take() {
+            // if our storage_init has changed we can't reuse the client
+            Some(ref client_info) if client_info.client_init != *storage_init => {
+                self.new_client_info(storage_init)
+            }
+            // we can reuse it (which should be the common path)
+            Some(client_info) => Ok(client_info),
+            None => self.new_client_info(storage_init),
+        }
+    }
+
+    fn new_client_info(
+        &mut self,
+        storage_init: &Sync15StorageClientInit,
+    ) -> result::Result<ClientInfo, Error> {
+        let client = Sync15StorageClient::new(storage_init.clone())?;
+        if let Some(token) = self.pending_token.take() {
+            if client.import_token(token)? {
+                log::info!("Using the cached tokenserver token");
+            }
+        }
+        Ok(ClientInfo {
+            client_init: storage_init.clone(),
+            client,
+        })
+    }
+}
+
+/// The result of a call to `sync_multiple`.
+#[derive(Debug)]
+pub struct SyncResult {
+    /// A map, keyed by name and holding an error value - if any store
+    /// fails, the sync will continue on to other stores, but the error will
+    /// be placed in this map. The absence of a name in the map implies the
+    /// store succeeded.
+    pub failures: HashMap<String, Error>,
+    /// If the server asked us to back off, the time before which the next
+    /// sync shouldn't be started. Syncs started before this time will fail
+    /// with a `BackoffError` unless they are forced.
+    pub next_sync_after: Option<SystemTime>,
+}
+
+impl SyncResult {
+    /// Whether any store failed because the account is over its storage
+    /// quota, in which case the app should tell the user their account is
+    /// full. Stores which didn't need to upload anything still sync.
+    pub fn is_over_quota(&self) -> bool {
+        self.failures.values().any(Error::is_over_quota)
+    }
+}
+
+/// The result of a call to `preview_multiple`.
+#[derive(Debug, Default)]
+pub struct SyncPreview {
+    /// What syncing each store would do, keyed by name. Declined stores, and
+    /// stores we failed to preview, aren't included.
+    pub stores: HashMap<String, StorePreview>,
+    /// A map, keyed by name and holding an error value, for the stores we
+    /// failed to preview.
+    pub failures: HashMap<String, Error>,
 }
 
 /// Sync multiple stores
@@ -47,24 +212,607 @@ pub struct MemoryCachedState {
 /// * `storage_init` - Information about how the sync http client should be
 ///   configured.
 /// * `root_sync_key` - The KeyBundle used for encryption.
+/// * `engines_to_state_change` - The engines the user has enabled (true) or
+///   declined (false) since the last sync, if any. We'll write these to
+///   `meta/global`, and reset any of `stores` which were declined.
+/// * `reason` - Why the app started this sync, if it knows, for telemetry.
+/// * `force` - Whether to sync even if the server asked us to back off. This
+///   should only be used for syncs explicitly requested by the user.
 ///
-/// Returns a map, keyed by name and holding an error value - if any store
-/// fails, the sync will continue on to other stores, but the error will be
-/// places in this map. The absence of a name in the map implies the store
-/// succeeded.
+/// Returns a `SyncResult` holding the per-store failures and the time of
+/// the next allowed sync. If the server previously asked us to back off and
+/// that time hasn't yet passed, this fails with a `BackoffError` without
+/// making any requests, unless `force` is true.
+#[allow(clippy::too_many_arguments)]
 pub fn sync_multiple(
     stores: &[&dyn Store],
     persisted_global_state: &mut Option<String>,
     mem_cached_state: &mut MemoryCachedState,
     storage_init: &Sync15StorageClientInit,
     root_sync_key: &KeyBundle,
+    engines_to_state_change: Option<&HashMap<String, bool>>,
+    reason: Option<telemetry::SyncReason>,
+    sync_ping: &mut telemetry::SyncTelemetryPing,
+    interruptee: &impl Interruptee,
+    force: bool,
+) -> result::Result<SyncResult, Error> {
+    sync_multiple_with_command_processor(
+        None,
+        stores,
+        persisted_global_state,
+        mem_cached_state,
+        storage_init,
+        root_sync_key,
+        engines_to_state_change,
+        reason,
+        sync_ping,
+        interruptee,
+        force,
+    )
+}
+
+/// Like `sync_multiple`, but also syncs the `clients` collection before the
+/// other stores if a `command_processor` is given. This uploads our client
+/// record, delivers any commands sent to us to `stores` (or to the command
+/// processor, for commands which aren't for a store), and records the other
+/// clients in `mem_cached_state`. A failure to sync the clients collection
+/// is reported in the returned map under "clients".
+///
+/// If `force` is true, we sync even if the server asked us to back off -
+/// this should only be used for syncs explicitly requested by the user.
+#[allow(clippy::too_many_arguments)]
+pub fn sync_multiple_with_command_processor(
+    command_processor: Option<&dyn CommandProcessor>,
+    stores: &[&dyn Store],
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    engines_to_state_change: Option<&HashMap<String, bool>>,
+    reason: Option<telemetry::SyncReason>,
     sync_ping: &mut telemetry::SyncTelemetryPing,
     interruptee: &impl Interruptee,
+    force: bool,
+) -> result::Result<SyncResult, Error> {
+    interruptee.err_if_interrupted()?;
+    let mut pgs = parse_persisted_state(persisted_global_state);
+    check_backoff(&pgs, force)?;
+
+    // We leave None in last_client_info until we're done, so if we fail
+    // entirely, we reinitialize everything related to the client.
+    let mut client_info = mem_cached_state.take_client_info(storage_init)?;
+
+    let mut result = sync_stores(
+        command_processor,
+        stores,
+        &client_info.client,
+        &mut pgs,
+        mem_cached_state,
+        root_sync_key,
+        engines_to_state_change,
+        reason,
+        sync_ping,
+        interruptee,
+    );
+
+    // If we've been moved to a new storage node, nothing we know about the
+    // server is valid any more, so start over with a new client (and so a
+    // new token), and treat the new node as a first sync for every store.
+    let mut reassigned_backoff = None;
+    if is_node_reassigned(&result, &client_info.client) {
+        log::warn!("Node reassignment detected, resetting all engines");
+        interruptee.err_if_interrupted()?;
+        reassigned_backoff = client_info.client.take_backoff();
+        client_info = ClientInfo {
+            client_init: storage_init.clone(),
+            client: Sync15StorageClient::new(storage_init.clone())?,
+        };
+        mem_cached_state.last_global_state = None;
+        pgs.clear_key_fingerprints();
+        // The new client fetches a new token for the new node.
+        mem_cached_state.pending_token = None;
+        let mut reset_failures: HashMap<String, Error> = HashMap::new();
+        for store in stores {
+            let name = store.collection_name();
+            if let Err(e) = store.reset(&StoreSyncAssociation::Disconnected) {
+                log::warn!("Failed to reset {}! {:?}", name, e);
+                reset_failures.insert(name.into(), ErrorKind::StoreError(e).into());
+            }
+        }
+        result = sync_stores(
+            command_processor,
+            stores,
+            &client_info.client,
+            &mut pgs,
+            mem_cached_state,
+            root_sync_key,
+            engines_to_state_change,
+            reason,
+            sync_ping,
+            interruptee,
+        )
+        .map(|(mut failures, global_state)| {
+            for (name, e) in reset_failures {
+                failures.entry(name).or_insert(e);
+            }
+            (failures, global_state)
+        });
+    }
+
+    // Work out when the server wants us to sync next. This is usually
+    // recorded by the client, but errors from the tokenserver only show up
+    // as a `BackoffError`.
+    let next_sync_after = match &result {
+        Ok((failures, _)) => failures.values().filter_map(Error::backoff_until).max(),
+        Err(e) => e.backoff_until(),
+    }
+    .into_iter()
+    .chain(client_info.client.take_backoff())
+    .chain(reassigned_backoff)
+    .max();
+    pgs.set_backoff_until(next_sync_after);
+    // Re-read the time we persisted, so callers see exactly the time that
+    // a later `BackoffError` will report.
+    let next_sync_after = pgs.backoff_until();
+    if let Some(when) = next_sync_after {
+        log::warn!("Server asked us not to sync again until {:?}", when);
+    }
+    // Both the state machine and the backoff might have updated our
+    // PersistedGlobalState, so update the callers repr of it. We do this
+    // even if the sync failed, so we remember any backoff.
+    *persisted_global_state = Some(serde_json::to_string(&pgs)?);
+
+    let (failures, global_state) = result?;
+    // Keep the client (and so our token) around for next time, which is how
+    // we notice if the tokenserver moves us to another node. The global state
+    // is only reused if everything worked.
+    if failures.is_empty() {
+        log::info!("Updating persisted global state");
+        mem_cached_state.last_global_state = Some(global_state);
+    }
+    mem_cached_state.last_client_info = Some(client_info);
+
+    Ok(SyncResult {
+        failures,
+        next_sync_after,
+    })
+}
+
+/// Performs a dry run of `sync_multiple`: fetches and decrypts the incoming
+/// records for each of `stores`, and asks them what applying the records
+/// would change locally and upload. Nothing is uploaded - including
+/// `meta/global` and `crypto/keys`, so this fails with `SetupRequired` if the
+/// server needs setting up - and neither the stores nor the persisted state
+/// are changed. The `clients` collection isn't previewed, and we don't check
+/// or record any backoff the server asked for.
+pub fn preview_multiple(
+    stores: &[&dyn Store],
+    persisted_global_state: &Option<String>,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    interruptee: &impl Interruptee,
+) -> result::Result<SyncPreview, Error> {
+    interruptee.err_if_interrupted()?;
+    let mut pgs = parse_persisted_state(persisted_global_state);
+    let client = Sync15StorageClient::new(storage_init.clone())?;
+    let global_state = {
+        let mut state_machine =
+            SetupStateMachine::for_readonly_sync(&client, root_sync_key, &mut pgs, interruptee);
+        log::info!("Advancing state machine to ready (read-only)");
+        state_machine.run_to_ready(None)?
+    };
+
+    let mut result = SyncPreview::default();
+    for store in stores {
+        if let Some(when) = result.failures.values().find_map(Error::backoff_until) {
+            log::warn!(
+                "Server asked us to back off until {:?}, skipping remaining engines",
+                when
+            );
+            break;
+        }
+        interruptee.err_if_interrupted()?;
+        let name = store.collection_name();
+        match sync::preview(&client, &global_state, *store, interruptee) {
+            Ok(Some(preview)) => {
+                result.stores.insert(name.into(), preview);
+            }
+            Ok(None) => log::info!("Not previewing {}", name),
+            Err(e) => {
+                log::warn!("Preview of {} failed! {:?}", name, e);
+                result.failures.insert(name.into(), e);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Regenerates the key for `collection`, or the default key if it's `None`,
+/// and uploads the new `crypto/keys`. The records in every collection whose
+/// key changed can't be decrypted any more, so we delete them from the
+/// server and reset those of `stores`, then sync `stores` as `sync_multiple`
+/// does to upload them again with the new keys. Other clients notice the new
+/// keys when they next sync, and reset their stores for the same collections.
+///
+/// Once the new keys are uploaded, we carry on if we fail to wipe or reset a
+/// store, and report the error in the result's failures instead of syncing
+/// that store. If the server asks us to back off, we don't sync at all, and
+/// the stores upload their records with the new keys when they next sync.
+#[allow(clippy::too_many_arguments)]
+pub fn rotate_keys(
+    stores: &[&dyn Store],
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    collection: Option<&str>,
+    sync_ping: &mut telemetry::SyncTelemetryPing,
+    interruptee: &impl Interruptee,
+) -> result::Result<SyncResult, Error> {
+    interruptee.err_if_interrupted()?;
+    let mut pgs = parse_persisted_state(persisted_global_state);
+    check_backoff(&pgs, false)?;
+
+    let client_info = mem_cached_state.take_client_info(storage_init)?;
+    let result = upload_new_keys(
+        stores,
+        &client_info.client,
+        &mut pgs,
+        root_sync_key,
+        collection,
+        interruptee,
+    );
+    remember_backoff(&mut pgs, &client_info.client, result.as_ref().err());
+    *persisted_global_state = Some(serde_json::to_string(&pgs)?);
+    mem_cached_state.last_client_info = Some(client_info);
+    mem_cached_state.last_global_state = None;
+    let failures = result?;
+
+    let stores_to_sync: Vec<&dyn Store> = stores
+        .iter()
+        .filter(|store| !failures.contains_key(store.collection_name()))
+        .cloned()
+        .collect();
+    let mut result = match sync_multiple(
+        &stores_to_sync,
+        persisted_global_state,
+        mem_cached_state,
+        storage_init,
+        root_sync_key,
+        None,
+        None,
+        sync_ping,
+        interruptee,
+        false,
+    ) {
+        Ok(result) => result,
+        Err(e) => match e.backoff_until() {
+            Some(when) => SyncResult {
+                failures: HashMap::new(),
+                next_sync_after: Some(when),
+            },
+            None => return Err(e),
+        },
+    };
+    result.failures.extend(failures);
+    Ok(result)
+}
+
+// Uploads new keys for `rotate_keys`, then wipes the collections whose key
+// changed and resets their stores. Returns the stores we failed to wipe or
+// reset, keyed by name.
+fn upload_new_keys(
+    stores: &[&dyn Store],
+    client: &Sync15StorageClient,
+    pgs: &mut PersistedGlobalState,
+    root_sync_key: &KeyBundle,
+    collection: Option<&str>,
+    interruptee: &impl Interruptee,
+) -> result::Result<HashMap<String, Error>, Error> {
+    let global_state = {
+        let mut state_machine =
+            SetupStateMachine::for_full_sync(client, root_sync_key, pgs, None, None, interruptee);
+        log::info!("Advancing state machine to ready (full)");
+        state_machine.run_to_ready(None)?
+    };
+
+    let mut new_keys = global_state.keys.clone();
+    match collection {
+        Some(collection) => new_keys.regenerate_collection(collection)?,
+        None => new_keys.regenerate_default()?,
+    }
+    let names: Vec<&str> = global_state
+        .global
+        .engines
+        .keys()
+        .map(String::as_str)
+        .collect();
+    let changed = global_state.keys.changed_collections(&new_keys, &names);
+
+    log::info!("Uploading new keys for {:?}", changed);
+    client.put_crypto_keys(
+        global_state.keys.timestamp,
+        &new_keys.to_encrypted_bso(root_sync_key)?,
+    )?;
+    // We don't check for interruption here, as we'd leave records on the
+    // server which nobody can decrypt. For the same reason, we keep going
+    // if we fail to wipe a collection, so that we still reset the stores
+    // and remember the new keys.
+    let mut failures: HashMap<String, Error> = HashMap::new();
+    for name in &changed {
+        log::info!("Wiping {} from the server", name);
+        if let Err(e) = client.wipe_remote_collection(name) {
+            log::warn!("Failed to wipe {}! {:?}", name, e);
+            failures.insert(name.clone(), e);
+        }
+    }
+    let mut reset_failures: HashMap<String, Error> = HashMap::new();
+    reset_stores(stores, &changed, &mut reset_failures);
+    for (name, e) in reset_failures {
+        failures.entry(name).or_insert(e);
+    }
+
+    // We've already reset the stores for the new keys.
+    pgs.update_key_fingerprints(&new_keys, &names)?;
+    Ok(failures)
+}
+
+/// Deletes `collection`, or every collection if it's `None`, from the
+/// server, without syncing. Each wiped engine gets a new sync ID in
+/// `meta/global`, so that other clients reset it when they next sync, and
+/// those of `stores` are reset with the new IDs now. If `wipe_local` is true,
+/// they're wiped first, as the `wipeEngine` command does; otherwise, their
+/// next sync uploads everything they have again, so apps which want the
+/// data gone should also decline the engine. Returns the stores which failed
+/// to wipe or reset, keyed by name.
+///
+/// If we fail to wipe a collection, we stop, and return the error after
+/// giving the collections we did wipe new sync IDs. Our stores aren't reset
+/// in that case, but notice the new IDs when they next sync.
+#[allow(clippy::too_many_arguments)]
+pub fn wipe_remote(
+    stores: &[&dyn Store],
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    collection: Option<&str>,
+    wipe_local: bool,
+    interruptee: &impl Interruptee,
+) -> result::Result<HashMap<String, Error>, Error> {
+    interruptee.err_if_interrupted()?;
+    let mut pgs = parse_persisted_state(persisted_global_state);
+    check_backoff(&pgs, false)?;
+
+    let client_info = mem_cached_state.take_client_info(storage_init)?;
+    let result = wipe_collections(
+        stores,
+        &client_info.client,
+        &mut pgs,
+        root_sync_key,
+        collection,
+        wipe_local,
+        interruptee,
+    );
+    remember_backoff(&mut pgs, &client_info.client, result.as_ref().err());
+    *persisted_global_state = Some(serde_json::to_string(&pgs)?);
+    mem_cached_state.last_client_info = Some(client_info);
+    // Our cached global state might have the old sync IDs.
+    mem_cached_state.last_global_state = None;
+    result
+}
+
+// Does the work of `wipe_remote`, which takes care of the client and our
+// persisted state.
+fn wipe_collections(
+    stores: &[&dyn Store],
+    client: &Sync15StorageClient,
+    pgs: &mut PersistedGlobalState,
+    root_sync_key: &KeyBundle,
+    collection: Option<&str>,
+    wipe_local: bool,
+    interruptee: &impl Interruptee,
+) -> result::Result<HashMap<String, Error>, Error> {
+    let global_state = {
+        let mut state_machine =
+            SetupStateMachine::for_full_sync(client, root_sync_key, pgs, None, None, interruptee);
+        log::info!("Advancing state machine to ready (full)");
+        state_machine.run_to_ready(None)?
+    };
+
+    let names: Vec<String> = match collection {
+        Some(collection) => vec![collection.to_string()],
+        None => {
+            // Engines usually have a collection too, so we'd see them twice.
+            let mut names: Vec<String> = global_state
+                .collections
+                .keys()
+                .chain(global_state.global.engines.keys())
+                .filter(|name| *name != "meta" && *name != "crypto")
+                .cloned()
+                .collect();
+            names.sort();
+            names.dedup();
+            names
+        }
+    };
+    let mut wiped: Vec<&str> = Vec::new();
+    let mut wipe_error = None;
+    for name in &names {
+        log::info!("Wiping {} from the server", name);
+        match client.wipe_remote_collection(name) {
+            Ok(()) => wiped.push(name),
+            Err(e) => {
+                log::warn!("Failed to wipe {}! {:?}", name, e);
+                wipe_error = Some(e);
+                break;
+            }
+        }
+    }
+    // Even if we failed to wipe them all, other clients need new sync IDs
+    // for the collections we did wipe, to notice they're gone.
+    let mut new_global = global_state.global.clone();
+    for name in &wiped {
+        if let Some(engine) = new_global.engines.get_mut(*name) {
+            engine.sync_id = random_guid()?;
+        }
+    }
+    if !wiped.is_empty() {
+        client.put_meta_global(global_state.global_timestamp, &new_global)?;
+    }
+    if let Some(e) = wipe_error {
+        return Err(e);
+    }
+
+    let mut failures: HashMap<String, Error> = HashMap::new();
+    for store in stores {
+        let name = store.collection_name();
+        if !wiped.contains(&name) {
+            continue;
+        }
+        let assoc = match new_global.engines.get(name) {
+            Some(engine) => StoreSyncAssociation::Connected(CollSyncIds {
+                global: new_global.sync_id.clone(),
+                coll: engine.sync_id.clone(),
+            }),
+            None => StoreSyncAssociation::Disconnected,
+        };
+        log::info!("Resetting {} after wiping the server", name);
+        let result = if wipe_local { store.wipe() } else { Ok(()) };
+        if let Err(e) = result.and_then(|_| store.reset(&assoc)) {
+            log::warn!("Failed to reset {}! {:?}", name, e);
+            failures.insert(name.into(), ErrorKind::StoreError(e).into());
+        }
+    }
+    Ok(failures)
+}
+
+/// Starts over, without syncing: wipes everything from the server, and
+/// uploads a fresh `meta/global`, with a new sync ID for every engine, and
+/// fresh `crypto/keys`. The declined engines are kept. `stores` are reset
+/// with their new sync IDs, so their next sync uploads everything they have,
+/// and other clients reset all their stores when they next sync. Returns the
+/// stores which failed to reset, keyed by name.
+pub fn reset_all(
+    stores: &[&dyn Store],
+    persisted_global_state: &mut Option<String>,
+    mem_cached_state: &mut MemoryCachedState,
+    storage_init: &Sync15StorageClientInit,
+    root_sync_key: &KeyBundle,
+    interruptee: &impl Interruptee,
 ) -> result::Result<HashMap<String, Error>, Error> {
     interruptee.err_if_interrupted()?;
-    let mut pgs = match persisted_global_state {
+    let mut pgs = parse_persisted_state(persisted_global_state);
+    check_backoff(&pgs, false)?;
+
+    let client_info = mem_cached_state.take_client_info(storage_init)?;
+    let result = start_over(
+        stores,
+        &client_info.client,
+        &mut pgs,
+        root_sync_key,
+        interruptee,
+    );
+    remember_backoff(&mut pgs, &client_info.client, result.as_ref().err());
+    *persisted_global_state = Some(serde_json::to_string(&pgs)?);
+    mem_cached_state.last_client_info = Some(client_info);
+    let (failures, global_state) = result?;
+    mem_cached_state.last_global_state = Some(global_state);
+    Ok(failures)
+}
+
+// Does the work of `reset_all`, which takes care of the client and our
+// persisted state. Returns the stores which failed to reset, and the new
+// global state.
+fn start_over(
+    stores: &[&dyn Store],
+    client: &Sync15StorageClient,
+    pgs: &mut PersistedGlobalState,
+    root_sync_key: &KeyBundle,
+    interruptee: &impl Interruptee,
+) -> result::Result<(HashMap<String, Error>, GlobalState), Error> {
+    let global_state = {
+        let mut state_machine =
+            SetupStateMachine::for_fresh_start(client, root_sync_key, pgs, interruptee);
+        log::info!("Advancing state machine to ready (fresh start)");
+        state_machine.run_to_ready(None)?
+    };
+
+    let mut failures: HashMap<String, Error> = HashMap::new();
+    for store in stores {
+        let name = store.collection_name();
+        let assoc = match global_state.global.engines.get(name) {
+            Some(engine) => StoreSyncAssociation::Connected(CollSyncIds {
+                global: global_state.global.sync_id.clone(),
+                coll: engine.sync_id.clone(),
+            }),
+            None => StoreSyncAssociation::Disconnected,
+        };
+        log::info!("Resetting {} after starting over", name);
+        if let Err(e) = store.reset(&assoc) {
+            log::warn!("Failed to reset {}! {:?}", name, e);
+            failures.insert(name.into(), ErrorKind::StoreError(e).into());
+        }
+    }
+    // We've already reset the stores for the new keys.
+    let names: Vec<&str> = stores.iter().map(|store| store.collection_name()).collect();
+    pgs.clear_key_fingerprints();
+    pgs.update_key_fingerprints(&global_state.keys, &names)?;
+    Ok((failures, global_state))
+}
+
+/// Resets those of `stores` named in `names` without disconnecting them, so
+/// their next sync fetches every record and uploads all of theirs. Failures
+/// are added to `failures`.
+fn reset_stores(stores: &[&dyn Store], names: &[String], failures: &mut HashMap<String, Error>) {
+    for store in stores {
+        let name = store.collection_name();
+        if !names.iter().any(|n| n == name) {
+            continue;
+        }
+        log::info!("Resetting {} for new keys", name);
+        if let Err(e) = store.get_sync_assoc().and_then(|assoc| store.reset(&assoc)) {
+            log::warn!("Failed to reset {}! {:?}", name, e);
+            failures.insert(name.into(), ErrorKind::StoreError(e).into());
+        }
+    }
+}
+
+// Records any backoff the server asked `client` for, or that caused `error`,
+// in `pgs`, keeping the backoff we already had if it's later.
+fn remember_backoff(
+    pgs: &mut PersistedGlobalState,
+    client: &Sync15StorageClient,
+    error: Option<&Error>,
+) {
+    let backoff_until = pgs
+        .backoff_until()
+        .into_iter()
+        .chain(client.take_backoff())
+        .chain(error.and_then(Error::backoff_until))
+        .max();
+    pgs.set_backoff_until(backoff_until);
+}
+
+/// Fails with a `BackoffError` if the server asked us not to sync yet,
+/// unless `force` is true.
+fn check_backoff(pgs: &PersistedGlobalState, force: bool) -> result::Result<(), Error> {
+    if let Some(backoff_until) = pgs.backoff_until() {
+        if backoff_until > SystemTime::now() {
+            if !force {
+                log::info!("Server asked us to back off until {:?}", backoff_until);
+                return Err(ErrorKind::BackoffError(backoff_until).into());
+            }
+            log::warn!("Ignoring server backoff for a forced sync");
+        }
+    }
+    Ok(())
+}
+
+fn parse_persisted_state(persisted_global_state: &Option<String>) -> PersistedGlobalState {
+    match persisted_global_state {
         Some(persisted_string) => {
-            match serde_json::from_str::<PersistedGlobalState>(&persisted_string) {
+            match serde_json::from_str::<PersistedGlobalState>(persisted_string) {
                 Ok(state) => state,
                 _ => {
                     // Don't log the error since it might contain sensitive
@@ -80,60 +828,151 @@ pub fn sync_multiple(
             log::warn!("The application didn't give us persisted state - this is only expected on the very first run");
             PersistedGlobalState::default()
         }
-    };
+    }
+}
 
-    // We put None back into last_client_info now so if we fail entirely,
-    // reinitialize everything related to the client.
-    let client_info = match mem::replace(&mut mem_cached_state.last_client_info, None) {
-        Some(client_info) => {
-            // if our storage_init has changed we can't reuse the client
-            if client_info.client_init != *storage_init {
-                ClientInfo {
-                    client_init: storage_init.clone(),
-                    client: Sync15StorageClient::new(storage_init.clone())?,
-                }
-            } else {
-                // we can reuse it (which should be the common path)
-                client_info
-            }
-        }
-        None => ClientInfo {
-            client_init: storage_init.clone(),
-            client: Sync15StorageClient::new(storage_init.clone())?,
-        },
+/// Returns true if any of the errors from syncing mean we've been moved to a
+/// different storage node.
+fn is_node_reassigned(
+    result: &result::Result<(HashMap<String, Error>, GlobalState), Error>,
+    client: &Sync15StorageClient,
+) -> bool {
+    let errors: Vec<&Error> = match result {
+        Ok((failures, _)) => failures.values().collect(),
+        Err(e) => vec![e],
     };
+    // A 401 from the storage server might just mean our token expired, so
+    // we only know we've been reassigned once we've fetched a new token.
+    errors.iter().any(|e| e.is_node_reassigned())
+        || (errors.iter().any(|e| e.is_unauthorized()) && client.is_node_reassigned())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn sync_stores(
+    command_processor: Option<&dyn CommandProcessor>,
+    stores: &[&dyn Store],
+    client: &Sync15StorageClient,
+    pgs: &mut PersistedGlobalState,
+    mem_cached_state: &mut MemoryCachedState,
+    root_sync_key: &KeyBundle,
+    engines_to_state_change: Option<&HashMap<String, bool>>,
+    reason: Option<telemetry::SyncReason>,
+    sync_ping: &mut telemetry::SyncTelemetryPing,
+    interruptee: &impl Interruptee,
+) -> result::Result<(HashMap<String, Error>, GlobalState), Error> {
     interruptee.err_if_interrupted()?;
 
     // Advance the state machine to the point where it can perform a full
     // sync. This may involve uploading meta/global, crypto/keys etc.
+    let setup_started = Instant::now();
+    let engine_versions: HashMap<String, usize> = stores
+        .iter()
+        .map(|store| (store.collection_name().to_string(), store.engine_version()))
+        .collect();
     let global_state = {
         let last_state = mem::replace(&mut mem_cached_state.last_global_state, None);
         let mut state_machine = SetupStateMachine::for_full_sync(
-            &client_info.client,
-            &root_sync_key,
-            &mut pgs,
+            client,
+            root_sync_key,
+            pgs,
+            engines_to_state_change,
+            Some(&engine_versions),
             interruptee,
         );
         log::info!("Advancing state machine to ready (full)");
         let state = state_machine.run_to_ready(last_state)?;
-        // The state machine might have updated our persisted_global_state, so
-        // update the callers repr of it.
-        mem::replace(persisted_global_state, Some(serde_json::to_string(&pgs)?));
-        sync_ping.uid(client_info.client.hashed_uid()?);
+        sync_ping.uid(client.hashed_uid()?);
         // As for client_info, put None back now so we start from scratch on error.
         mem_cached_state.last_global_state = None;
         state
     };
 
+    // Setting up usually fetches our first token, which we report on its
+    // own.
+    let mut token_took = client.take_token_fetch_duration();
+    let setup_took = setup_started
+        .elapsed()
+        .checked_sub(token_took)
+        .unwrap_or_default();
+
     let mut telem_sync = telemetry::SyncTelemetry::new();
+    if let Some(reason) = reason {
+        telem_sync.reason(reason);
+    }
     let mut failures: HashMap<String, Error> = HashMap::new();
+
+    // If another client changed the keys for any collections, it wiped their
+    // records from the server and uploaded them again with the new keys, so
+    // we reset those stores to fetch everything, and upload all of ours.
+    // We compare against the persisted fingerprints of the keys we last
+    // synced with, so that we notice even if the app restarted since.
+    let names: Vec<&str> = stores.iter().map(|store| store.collection_name()).collect();
+    let changed = pgs.update_key_fingerprints(&global_state.keys, &names)?;
+    if !changed.is_empty() {
+        log::info!("Keys changed for {:?}", changed);
+        reset_stores(stores, &changed, &mut failures);
+    }
+
+    // Stores the user just declined are disconnected from sync, so that they
+    // start from scratch if they're enabled again. Stores that were enabled
+    // will be reset when they sync, because they have a new sync ID.
+    if let Some(changes) = engines_to_state_change {
+        for store in stores {
+            let name = store.collection_name();
+            if changes.get(name) == Some(&false)
+                && global_state.global.declined.iter().any(|d| d == name)
+            {
+                log::info!("Resetting declined engine {}", name);
+                if let Err(e) = store.reset(&StoreSyncAssociation::Disconnected) {
+                    log::warn!("Failed to reset {}! {:?}", name, e);
+                    failures.insert(name.into(), ErrorKind::StoreError(e).into());
+                }
+            }
+        }
+    }
+
+    if let Some(command_processor) = command_processor {
+        log::info!("Syncing clients engine!");
+        let clients_store = clients::ClientsStore::new(command_processor, stores);
+        let mut telem_engine = telemetry::Engine::new("clients");
+        let result = sync::synchronize(
+            client,
+            &global_state,
+            &clients_store,
+            true,
+            &mut telem_engine,
+            interruptee,
+        );
+        match result {
+            Ok(()) => {
+                log::info!("Sync of clients was successful!");
+                mem_cached_state.last_remote_clients = clients_store.into_remote_clients();
+            }
+            Err(e) => {
+                log::warn!("Sync of clients failed! {:?}", e);
+                let f = telemetry::sync_failure_from_error(&e);
+                failures.insert("clients".into(), e);
+                telem_engine.failure(f);
+            }
+        }
+        telem_sync.engine(telem_engine);
+        interruptee.err_if_interrupted()?;
+    }
+
     for store in stores {
+        if let Some(when) = failures.values().find_map(Error::backoff_until) {
+            log::warn!(
+                "Server asked us to back off until {:?}, skipping remaining engines",
+                when
+            );
+            break;
+        }
         let name = store.collection_name();
         log::info!("Syncing {} engine!", name);
 
         let mut telem_engine = telemetry::Engine::new(name);
         let result = sync::synchronize(
-            &client_info.client,
+            client,
             &global_state,
             *store,
             true,
@@ -159,12 +998,9 @@ pub fn sync_multiple(
         interruptee.err_if_interrupted()?;
     }
 
+    // Our token might have expired while we were syncing.
+    token_took += client.take_token_fetch_duration();
     sync_ping.sync(telem_sync);
-    if !failures.is_empty() {
-        log::info!("Updating persisted global state");
-        mem_cached_state.last_client_info = Some(client_info);
-        mem_cached_state.last_global_state = Some(global_state);
-    }
-
-    Ok(failures)
+    sync_ping.event(telemetry::Event::sync_timings(setup_took, token_took));
+    Ok((failures, global_state))
 }
@@ -14,7 +14,8 @@ use serde_derive::*;
 #[cfg(test)]
 use serde_json::{self, json};
 
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
+use viaduct::status_codes;
 
 // For skip_serializing_if
 fn skip_if_default<T: PartialEq + Default>(v: &T) -> bool {
@@ -179,6 +180,15 @@ impl Event {
         self.extra.as_mut().unwrap().insert(key, val);
         self
     }
+
+    /// An event with how long we spent setting up a sync, and fetching
+    /// tokens for it. The sync ping only has timings for engines, so we
+    /// report these in an event instead.
+    pub fn sync_timings(setup: time::Duration, token: time::Duration) -> Self {
+        Event::new("sync", "timings")
+            .extra("setup", duration_ms(setup).to_string())
+            .extra("token", duration_ms(token).to_string())
+    }
 }
 
 #[cfg(test)]
@@ -230,6 +240,20 @@ mod test_events {
             }),
         )
     }
+
+    #[test]
+    fn test_sync_timings() {
+        assert_json(
+            &Event::sync_timings(
+                time::Duration::from_millis(1500),
+                time::Duration::from_millis(250),
+            ),
+            json!({"object": "sync",
+             "method": "timings",
+             "extra": {"setup": "1500", "token": "250"}
+            }),
+        )
+    }
 }
 
 /// A Sync failure.
@@ -253,8 +277,25 @@ pub enum SyncFailure {
 }
 
 pub fn sync_failure_from_error(e: &Error) -> SyncFailure {
-    SyncFailure::Unexpected {
-        error: e.to_string(),
+    match e.kind() {
+        ErrorKind::Interrupted(_) => SyncFailure::Shutdown,
+        ErrorKind::TokenserverHttpError(401) => SyncFailure::Auth {
+            from: "tokenserver".to_string(),
+        },
+        ErrorKind::TokenserverHttpError(code) | ErrorKind::StorageHttpError { code, .. } => {
+            SyncFailure::Http {
+                code: u32::from(*code),
+            }
+        }
+        ErrorKind::OverQuota => SyncFailure::Http {
+            code: u32::from(status_codes::INSUFFICIENT_STORAGE),
+        },
+        ErrorKind::RequestError(_) | ErrorKind::EngineVersionTooNew { .. } => SyncFailure::Other {
+            error: e.to_string(),
+        },
+        _ => SyncFailure::Unexpected {
+            error: e.to_string(),
+        },
     }
 }
 
@@ -292,6 +333,45 @@ mod test {
             json!({"name": "httperror", "code": 500}),
         );
     }
+
+    #[test]
+    fn test_failure_from_error() {
+        let failure = |kind: ErrorKind| sync_failure_from_error(&kind.into());
+        assert_json(
+            &failure(ErrorKind::Interrupted(interrupt::Interrupted)),
+            json!({"name": "shutdownerror"}),
+        );
+        assert_json(
+            &failure(ErrorKind::TokenserverHttpError(401)),
+            json!({"name": "autherror", "from": "tokenserver"}),
+        );
+        assert_json(
+            &failure(ErrorKind::StorageHttpError {
+                code: 503,
+                route: "info/collections".to_string(),
+            }),
+            json!({"name": "httperror", "code": 503}),
+        );
+        assert_json(
+            &failure(ErrorKind::OverQuota),
+            json!({"name": "httperror", "code": 507}),
+        );
+        assert_json(
+            &failure(ErrorKind::EngineVersionTooNew {
+                collection: "bookmarks".to_string(),
+                server: 3,
+                local: 2,
+            }),
+            json!({
+                "name": "othererror",
+                "error": "Client upgrade required; server has version 3 of the bookmarks engine, but we only support version 2",
+            }),
+        );
+        assert_json(
+            &failure(ErrorKind::RecordTooLargeError),
+            json!({"name": "unexpectederror", "error": "Outgoing record is too large to upload"}),
+        );
+    }
 }
 
 /// Incoming record for an engine's sync
@@ -375,6 +455,110 @@ impl EngineOutgoing {
     }
 }
 
+/// A kind of problem found while validating an engine's data, and how many
+/// times we found it.
+#[derive(Debug, Serialize)]
+pub struct Problem {
+    name: &'static str,
+    count: usize,
+}
+
+/// The results of validating an engine's data after a sync.
+#[derive(Debug, Default, Serialize)]
+pub struct Validation {
+    version: u32,
+
+    #[serde(skip_serializing_if = "skip_if_default")]
+    checked: usize,
+
+    #[serde(skip_serializing_if = "skip_if_default")]
+    took: u64,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    problems: Vec<Problem>,
+}
+
+impl Validation {
+    /// `version` identifies the validator, so that problem counts from
+    /// different validators aren't compared.
+    pub fn with_version(version: u32) -> Self {
+        Validation {
+            version,
+            ..Default::default()
+        }
+    }
+
+    /// Records the number of items we checked.
+    #[inline]
+    pub fn checked(&mut self, n: usize) {
+        self.checked += n;
+    }
+
+    /// Records how long validation took.
+    pub fn took(&mut self, took: time::Duration) {
+        self.took = duration_ms(took);
+    }
+
+    /// Records `count` instances of the problem called `name`, if any.
+    pub fn problem(&mut self, name: &'static str, count: usize) {
+        if count > 0 {
+            self.problems.push(Problem { name, count });
+        }
+    }
+}
+
+/// A count recorded for a step of an engine's sync.
+#[derive(Debug, Serialize)]
+pub struct StepCount {
+    name: &'static str,
+    count: usize,
+}
+
+/// A step of an engine's sync, like decrypting the incoming records, with
+/// how long it took.
+#[derive(Debug, Serialize)]
+pub struct Step {
+    name: &'static str,
+
+    #[serde(skip_serializing_if = "skip_if_default")]
+    took: u64,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    counts: Vec<StepCount>,
+}
+
+impl Step {
+    pub fn new(name: &'static str) -> Self {
+        Step {
+            name,
+            took: 0,
+            counts: Vec::new(),
+        }
+    }
+
+    /// A step which took `took`, without any counts.
+    pub fn timed(name: &'static str, took: time::Duration) -> Self {
+        let mut step = Step::new(name);
+        step.took(took);
+        step
+    }
+
+    pub fn took(&mut self, took: time::Duration) {
+        self.took = duration_ms(took);
+    }
+
+    /// Records a count called `name`, if it's not zero.
+    pub fn count(&mut self, name: &'static str, count: usize) {
+        if count > 0 {
+            self.counts.push(StepCount { name, count });
+        }
+    }
+}
+
+fn duration_ms(d: time::Duration) -> u64 {
+    d.as_secs() * 1000 + (u64::from(d.subsec_nanos()) / 1_000_000)
+}
+
 /// One engine's sync.
 #[derive(Debug, Serialize)]
 pub struct Engine {
@@ -392,6 +576,12 @@ pub struct Engine {
     #[serde(skip_serializing_if = "Option::is_none")]
     #[serde(rename = "failureReason")]
     failure: Option<SyncFailure>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    validation: Option<Validation>,
+
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    steps: Vec<Step>,
 }
 
 impl Engine {
@@ -402,6 +592,8 @@ impl Engine {
             incoming: None,
             outgoing: Vec::new(),
             failure: None,
+            validation: None,
+            steps: Vec::new(),
         }
     }
 
@@ -428,6 +620,15 @@ impl Engine {
         }
     }
 
+    pub fn validation(&mut self, v: Validation) {
+        assert!(self.validation.is_none());
+        self.validation = Some(v);
+    }
+
+    pub fn step(&mut self, step: Step) {
+        self.steps.push(step);
+    }
+
     fn finished(&mut self) {
         self.when_took = self.when_took.finished();
     }
@@ -492,6 +693,50 @@ mod engine_tests {
         );
     }
 
+    #[test]
+    fn test_validation() {
+        let mut v = Validation::with_version(1);
+        v.checked(10);
+        v.problem("orphans", 2);
+        v.problem("missingChildren", 0);
+        let mut e = Engine::new("TestEngine");
+        e.validation(v);
+        e.finished();
+        assert_json(
+            &e,
+            json!({"name": "TestEngine",
+             "when": 0.0,
+             "validation": {
+                 "version": 1,
+                 "checked": 10,
+                 "problems": [{"name": "orphans", "count": 2}]
+             }
+            }),
+        );
+    }
+
+    #[test]
+    fn test_steps() {
+        let mut step = Step::new("decrypt");
+        step.took(time::Duration::from_millis(1500));
+        step.count("records", 10);
+        step.count("failed", 0);
+        let mut e = Engine::new("TestEngine");
+        e.step(step);
+        e.finished();
+        assert_json(
+            &e,
+            json!({"name": "TestEngine",
+             "when": 0.0,
+             "steps": [{
+                 "name": "decrypt",
+                 "took": 1500,
+                 "counts": [{"name": "records", "count": 10}]
+             }]
+            }),
+        );
+    }
+
     #[test]
     fn test_raw() {
         let mut e = Engine::new("TestEngine");
@@ -512,12 +757,33 @@ mod engine_tests {
     }
 }
 
+/// Why a sync was started.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SyncReason {
+    /// The app's sync schedule said it was time to sync.
+    Scheduled,
+    /// The user asked us to sync.
+    User,
+    /// The app is about to be suspended.
+    PreSleep,
+    /// The app just started.
+    Startup,
+    /// The user enabled or declined an engine.
+    EnabledChange,
+    /// The app was moved to the background.
+    Backgrounded,
+}
+
 /// A single sync. May have many engines, may have its own failure.
 #[derive(Debug, Serialize, Default)]
 pub struct SyncTelemetry {
     #[serde(flatten)]
     when_took: Stopwatch,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    why: Option<SyncReason>,
+
     #[serde(skip_serializing_if = "Vec::is_empty")]
     engines: Vec<Engine>,
 
@@ -541,6 +807,10 @@ impl SyncTelemetry {
         self.failure = Some(failure);
     }
 
+    pub fn reason(&mut self, reason: SyncReason) {
+        self.why = Some(reason);
+    }
+
     // Note that unlike other 'finished' methods, this isn't private - someone
     // needs to explicitly call this before handling the json payload to
     // whatever ends up submitting it.
@@ -631,6 +901,16 @@ mod sync_tests {
             }),
         );
     }
+
+    #[test]
+    fn test_reason() {
+        let mut s = SyncTelemetry::new();
+        s.reason(SyncReason::EnabledChange);
+        s.finished();
+        assert_json(&s, json!({"when": 0.0, "why": "enabledChange"}));
+        s.reason(SyncReason::PreSleep);
+        assert_json(&s, json!({"when": 0.0, "why": "preSleep"}));
+    }
 }
 
 /// The Sync ping payload, as documented at
@@ -6,17 +6,21 @@ use crate::error::{self, ErrorKind, Result};
 use crate::util::ServerTimestamp;
 use serde_derive::*;
 use std::borrow::{Borrow, Cow};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::fmt;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use url::Url;
 use viaduct::{header_names, Request};
 
-const RETRY_AFTER_DEFAULT_MS: u64 = 10000;
+pub(crate) const RETRY_AFTER_DEFAULT_MS: u64 = 10000;
+
+// When importing a cached token, we want it to remain valid for at least
+// this long, so that it doesn't expire in the middle of a sync.
+const MIN_IMPORTED_TOKEN_VALIDITY: Duration = Duration::from_secs(60);
 
 // The TokenserverToken is the token as received directly from the token server
 // and deserialized from JSON.
-#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
 struct TokenserverToken {
     id: String,
     key: String,
@@ -161,6 +165,23 @@ impl TokenContext {
         }
     }
 
+    fn from_token(
+        token: TokenserverToken,
+        server_timestamp: ServerTimestamp,
+        valid_until: SystemTime,
+    ) -> Result<Self> {
+        let credentials = hawk::Credentials {
+            id: token.id.clone(),
+            key: hawk::Key::new(token.key.as_bytes(), hawk::Digest::sha256())?,
+        };
+        Ok(TokenContext::new(
+            token,
+            credentials,
+            server_timestamp,
+            valid_until,
+        ))
+    }
+
     fn is_valid(&self, now: SystemTime) -> bool {
         // We could consider making the duration a little shorter - if it
         // only has 1 second validity there seems a reasonable chance it will
@@ -214,6 +235,9 @@ enum TokenState {
     // elt is the api_endpoint we had before we hit the backoff error.
     // XXX - should we roll Backoff and Failed together?
     Backoff(SystemTime, Option<String>),
+    // The storage server rejected our token, so we need to fetch a new one.
+    // The elt is the api_endpoint of the rejected token.
+    Rejected(String),
     // api_endpoint changed - we are never going to get a token nor move out
     // of this state.
     NodeReassigned,
@@ -226,6 +250,8 @@ struct TokenProviderImpl<TF: TokenFetcher> {
     fetcher: TF,
     // Our token state (ie, whether we have a token, and if not, why not)
     current_state: RefCell<TokenState>,
+    // How long we've spent fetching tokens, for telemetry.
+    fetch_took: Cell<Duration>,
 }
 
 impl<TF: TokenFetcher> TokenProviderImpl<TF> {
@@ -233,27 +259,20 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         TokenProviderImpl {
             fetcher,
             current_state: RefCell::new(TokenState::NoToken),
+            fetch_took: Cell::new(Duration::default()),
         }
     }
 
     // Uses our fetcher to grab a new token and if successfull, derives other
     // info from that token into a usable TokenContext.
     fn fetch_context(&self) -> Result<TokenContext> {
-        let result = self.fetcher.fetch_token()?;
-        let token = result.token;
-        let valid_until = SystemTime::now() + Duration::from_secs(token.duration);
-
-        let credentials = hawk::Credentials {
-            id: token.id.clone(),
-            key: hawk::Key::new(token.key.as_bytes(), hawk::Digest::sha256())?,
-        };
-
-        Ok(TokenContext::new(
-            token,
-            credentials,
-            result.server_timestamp,
-            valid_until,
-        ))
+        let started = Instant::now();
+        let result = self.fetcher.fetch_token();
+        self.fetch_took
+            .set(self.fetch_took.get() + started.elapsed());
+        let result = result?;
+        let valid_until = SystemTime::now() + Duration::from_secs(result.token.duration);
+        TokenContext::from_token(result.token, result.server_timestamp, valid_until)
     }
 
     // Attempt to fetch a new token and return a new state reflecting that
@@ -303,6 +322,9 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
             TokenState::Failed(_, existing_endpoint) => {
                 Some(self.fetch_token(existing_endpoint.as_ref().map(String::as_str)))
             }
+            TokenState::Rejected(existing_endpoint) => {
+                Some(self.fetch_token(Some(existing_endpoint.as_str())))
+            }
             TokenState::Token(existing_context) => {
                 if existing_context.is_valid(self.fetcher.now()) {
                     None
@@ -340,9 +362,9 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
         // Now re-fetch the state we should use for this call - if it's
         // anything other than TokenState::Token we will fail.
         match state {
-            TokenState::NoToken => {
+            TokenState::NoToken | TokenState::Rejected(_) => {
                 // it should be impossible to get here.
-                panic!("Can't be in NoToken state after advancing");
+                panic!("Can't be in NoToken or Rejected state after advancing");
             }
             TokenState::Token(ref token_context) => {
                 // make the call.
@@ -373,9 +395,94 @@ impl<TF: TokenFetcher> TokenProviderImpl<TF> {
     fn api_endpoint(&self) -> Result<String> {
         self.with_token(|ctx| Ok(ctx.token.api_endpoint.clone()))
     }
-    // TODO: we probably want a "drop_token/context" type method so that when
-    // using a token with some validity fails the caller can force a new one
-    // (in which case the new token request will probably fail with a 401)
+
+    // Drops our current token after the storage server rejected it, so that
+    // the next request fetches a new one. If the new token has a different
+    // api_endpoint, we've been reassigned to a new node.
+    fn reject_token(&self) {
+        let state: &mut TokenState = &mut self.current_state.borrow_mut();
+        if let TokenState::Token(ref existing_context) = state {
+            log::info!("Dropping token rejected by the storage server");
+            *state = TokenState::Rejected(existing_context.token.api_endpoint.clone());
+        }
+    }
+
+    fn take_fetch_duration(&self) -> Duration {
+        self.fetch_took.replace(Duration::default())
+    }
+
+    // Returns our token, if we have one which is still valid.
+    fn export_context(&self) -> Option<CachedContext> {
+        match &*self.current_state.borrow() {
+            TokenState::Token(ctx) if ctx.is_valid(self.fetcher.now()) => Some(CachedContext {
+                token: ctx.token.clone(),
+                server_timestamp: ctx.server_timestamp,
+                valid_until: to_millis(ctx.valid_until),
+            }),
+            _ => None,
+        }
+    }
+
+    // Uses a token we exported earlier, if we haven't fetched one yet and
+    // it's still valid. Returns false if we didn't use it.
+    fn import_context(&self, cached: CachedContext) -> Result<bool> {
+        let state: &mut TokenState = &mut self.current_state.borrow_mut();
+        match state {
+            TokenState::NoToken => {}
+            _ => return Ok(false),
+        }
+        let now = self.fetcher.now();
+        let valid_until = UNIX_EPOCH + Duration::from_millis(cached.valid_until);
+        // We can only tell how long the token has left using our own clock,
+        // so we don't trust it if the clock has gone backwards since we
+        // fetched it.
+        let fetched_at = valid_until
+            .checked_sub(Duration::from_secs(cached.token.duration))
+            .unwrap_or(UNIX_EPOCH);
+        if now < fetched_at {
+            log::info!("Not using cached token, as the clock has changed");
+            return Ok(false);
+        }
+        if now + MIN_IMPORTED_TOKEN_VALIDITY >= valid_until {
+            log::info!("Not using cached token, as it has expired");
+            return Ok(false);
+        }
+        *state = TokenState::Token(TokenContext::from_token(
+            cached.token,
+            cached.server_timestamp,
+            valid_until,
+        )?);
+        Ok(true)
+    }
+}
+
+// A token, as we export it to be cached by the app.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct CachedContext {
+    token: TokenserverToken,
+    // The X-Timestamp the tokenserver sent with the token.
+    server_timestamp: ServerTimestamp,
+    // When the token expires by our clock, in milliseconds since the epoch.
+    valid_until: u64,
+}
+
+fn to_millis(when: SystemTime) -> u64 {
+    let since_epoch = when.duration_since(UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_secs() * 1000 + u64::from(since_epoch.subsec_millis())
+}
+
+/// A token fetched from the tokenserver, which apps can cache between runs
+/// so that they don't need to fetch a new one each time. Tokens are
+/// credentials for the user's sync storage, so they should be kept in
+/// encrypted storage, like the sync keys.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct TokenCache {
+    // The tokenserver and key ID we fetched the token with, so that we
+    // don't use it for another account, or after the keys change.
+    server_url: String,
+    key_id: String,
+    #[serde(flatten)]
+    context: CachedContext,
 }
 
 // The public concrete object exposed by this module
@@ -403,6 +510,37 @@ impl TokenProvider {
     pub fn api_endpoint(&self) -> Result<String> {
         self.imp.api_endpoint()
     }
+
+    pub fn reject_token(&self) {
+        self.imp.reject_token()
+    }
+
+    /// Returns how long we've spent fetching tokens since the last call.
+    pub fn take_fetch_duration(&self) -> Duration {
+        self.imp.take_fetch_duration()
+    }
+
+    /// Returns our current token for the app to cache, if it's still valid.
+    pub fn export_token(&self) -> Option<TokenCache> {
+        Some(TokenCache {
+            server_url: self.imp.fetcher.server_url.to_string(),
+            key_id: self.imp.fetcher.key_id.clone(),
+            context: self.imp.export_context()?,
+        })
+    }
+
+    /// Uses a token from `export_token` instead of fetching a new one, if
+    /// it's for the same tokenserver and keys, and hasn't expired. Returns
+    /// false if we didn't use it.
+    pub fn import_token(&self, cache: TokenCache) -> Result<bool> {
+        if cache.server_url != self.imp.fetcher.server_url.as_str()
+            || cache.key_id != self.imp.fetcher.key_id
+        {
+            log::info!("Not using cached token for a different account");
+            return Ok(false);
+        }
+        self.imp.import_context(cache.context)
+    }
 }
 
 #[cfg(test)]
@@ -535,6 +673,99 @@ mod tests {
         assert_eq!(counter.get(), 2);
     }
 
+    #[test]
+    fn test_rejected() {
+        let counter: Cell<u32> = Cell::new(0);
+        let endpoint: RefCell<String> = RefCell::new("api_endpoint".to_string());
+        let fetch = || {
+            counter.set(counter.get() + 1);
+            Ok(TokenFetchResult {
+                token: TokenserverToken {
+                    id: "id".to_string(),
+                    key: "key".to_string(),
+                    api_endpoint: endpoint.borrow().clone(),
+                    uid: 1,
+                    duration: 1000,
+                    hashed_fxa_uid: "hash".to_string(),
+                },
+                server_timestamp: ServerTimestamp(0f64),
+            })
+        };
+        let tsc = make_tsc(fetch, SystemTime::now);
+
+        tsc.api_endpoint().expect("should work");
+        assert_eq!(counter.get(), 1);
+
+        // A rejected token should be re-fetched, and all is well if the
+        // endpoint is unchanged.
+        tsc.reject_token();
+        tsc.api_endpoint().expect("should work");
+        assert_eq!(counter.get(), 2);
+
+        // But if it changed, we've been reassigned to a new node.
+        *endpoint.borrow_mut() = "new_endpoint".to_string();
+        tsc.reject_token();
+        match tsc.api_endpoint() {
+            Err(ref e) if e.is_node_reassigned() => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(counter.get(), 3);
+    }
+
+    #[test]
+    fn test_export_import() {
+        let counter: Cell<u32> = Cell::new(0);
+        let fetch = || {
+            counter.set(counter.get() + 1);
+            Ok(TokenFetchResult {
+                token: TokenserverToken {
+                    id: "id".to_string(),
+                    key: "key".to_string(),
+                    api_endpoint: "api_endpoint".to_string(),
+                    uid: 1,
+                    duration: 1000,
+                    hashed_fxa_uid: "hash".to_string(),
+                },
+                server_timestamp: ServerTimestamp(1234.5),
+            })
+        };
+        let started = SystemTime::now();
+        let now: Cell<SystemTime> = Cell::new(started);
+
+        let tsc = make_tsc(fetch, || now.get());
+        assert!(tsc.export_context().is_none());
+        tsc.api_endpoint().expect("should work");
+        assert_eq!(counter.get(), 1);
+        let cached = tsc.export_context().expect("should have a token");
+        assert_eq!(cached.server_timestamp, ServerTimestamp(1234.5));
+
+        // A token we've fetched should round-trip through JSON.
+        let json = serde_json::to_string(&cached).unwrap();
+        let cached: CachedContext = serde_json::from_str(&json).unwrap();
+
+        // A new provider should use the imported token instead of fetching.
+        let tsc = make_tsc(fetch, || now.get());
+        now.set(started + Duration::from_secs(500));
+        assert!(tsc.import_context(cached.clone()).unwrap());
+        assert_eq!(tsc.api_endpoint().unwrap(), "api_endpoint");
+        assert_eq!(counter.get(), 1);
+        // But once we have a token, we keep it.
+        assert!(!tsc.import_context(cached.clone()).unwrap());
+
+        // Tokens which have expired, or are about to, are ignored.
+        let tsc = make_tsc(fetch, || now.get());
+        now.set(started + Duration::from_secs(990));
+        assert!(!tsc.import_context(cached.clone()).unwrap());
+        tsc.api_endpoint().expect("should re-fetch");
+        assert_eq!(counter.get(), 2);
+
+        // So are tokens if our clock went backwards since we fetched them,
+        // as we can't tell how long they have left.
+        let tsc = make_tsc(fetch, || now.get());
+        now.set(started - Duration::from_secs(60));
+        assert!(!tsc.import_context(cached).unwrap());
+    }
+
     #[test]
     fn test_server_url() {
         assert_eq!(
@@ -379,7 +379,9 @@ pub mod consts {
         (X_KEYID, "x-keyid"),
         (X_LAST_MODIFIED, "x-last-modified"),
         (X_TIMESTAMP, "x-timestamp"),
+        (X_WEAVE_BACKOFF, "x-weave-backoff"),
         (X_WEAVE_NEXT_OFFSET, "x-weave-next-offset"),
+        (X_WEAVE_QUOTA_REMAINING, "x-weave-quota-remaining"),
         (X_WEAVE_RECORDS, "x-weave-records"),
         (X_WEAVE_TIMESTAMP, "x-weave-timestamp"),
     );
@@ -356,5 +356,7 @@ pub mod status_codes {
         (503, SERVICE_UNAVAILABLE),
         (504, GATEWAY_TIMEOUT),
         (505, HTTP_VERSION_NOT_SUPPORTED),
+        // From https://tools.ietf.org/html/rfc4918#section-11.5
+        (507, INSUFFICIENT_STORAGE),
     ];
 }
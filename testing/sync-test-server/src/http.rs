@@ -0,0 +1,217 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Just enough HTTP/1.1 to serve the requests made by viaduct. Every
+//! response closes the connection, so we never need to deal with keep-alive
+//! or pipelining.
+
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use url::Url;
+
+#[derive(Debug, Clone)]
+pub struct Request {
+    pub method: String,
+    /// The non-empty, percent-decoded path segments.
+    pub path: Vec<String>,
+    pub query: HashMap<String, String>,
+    /// Header names are lower-cased.
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    pub fn query_param(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(String::as_str)
+    }
+
+    pub fn json(&self) -> Option<JsonValue> {
+        serde_json::from_slice(&self.body).ok()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn new(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn json(status: u16, body: &JsonValue) -> Response {
+        Response {
+            status,
+            headers: vec![("Content-Type".into(), "application/json".into())],
+            body: body.to_string().into_bytes(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: impl ToString) -> Response {
+        self.headers.push((name.into(), value.to_string()));
+        self
+    }
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        412 => "Precondition Failed",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Reads a request from `reader`. Returns `Ok(None)` if the connection was
+/// closed before a request line was sent.
+pub fn read_request(reader: &mut impl BufRead) -> io::Result<Option<Request>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+        _ => return Err(invalid_data("Malformed request line")),
+    };
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line)?;
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let colon = header
+            .find(':')
+            .ok_or_else(|| invalid_data("Malformed header"))?;
+        headers.insert(
+            header[..colon].trim().to_ascii_lowercase(),
+            header[colon + 1..].trim().to_string(),
+        );
+    }
+
+    let length = match headers.get("content-length") {
+        Some(len) => len
+            .parse::<usize>()
+            .map_err(|_| invalid_data("Bad Content-Length"))?,
+        None => 0,
+    };
+    let mut body = vec![0u8; length];
+    reader.read_exact(&mut body)?;
+
+    // The target is only a path and query, so give it a base to parse against.
+    let url = Url::parse("http://localhost/")
+        .and_then(|base| base.join(&target))
+        .map_err(|_| invalid_data("Malformed request target"))?;
+    let path = url
+        .path_segments()
+        .map(|segments| {
+            segments
+                .filter(|s| !s.is_empty())
+                .map(|s| {
+                    url::percent_encoding::percent_decode(s.as_bytes())
+                        .decode_utf8_lossy()
+                        .into_owned()
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let query = url.query_pairs().into_owned().collect();
+
+    Ok(Some(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    }))
+}
+
+pub fn write_response(writer: &mut impl Write, response: &Response) -> io::Result<()> {
+    write!(
+        writer,
+        "HTTP/1.1 {} {}\r\n",
+        response.status,
+        reason_phrase(response.status)
+    )?;
+    for (name, value) in &response.headers {
+        write!(writer, "{}: {}\r\n", name, value)?;
+    }
+    write!(
+        writer,
+        "Content-Length: {}\r\nConnection: close\r\n\r\n",
+        response.body.len()
+    )?;
+    writer.write_all(&response.body)?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_request() {
+        let raw = "POST /1.5/1/storage/bookmarks?batch=true&ids=a%2Cb HTTP/1.1\r\n\
+                   Host: localhost\r\n\
+                   X-If-Unmodified-Since: 123.45\r\n\
+                   Content-Length: 2\r\n\
+                   \r\n\
+                   []";
+        let request = read_request(&mut raw.as_bytes())
+            .expect("should parse")
+            .expect("should have a request");
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, vec!["1.5", "1", "storage", "bookmarks"]);
+        assert_eq!(request.query_param("batch"), Some("true"));
+        assert_eq!(request.query_param("ids"), Some("a,b"));
+        assert_eq!(request.header("x-if-unmodified-since"), Some("123.45"));
+        assert_eq!(request.body, b"[]");
+
+        assert!(read_request(&mut "".as_bytes()).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_write_response() {
+        let mut out = Vec::new();
+        let response = Response::json(200, &serde_json::json!([])).header("X-Last-Modified", 1.5);
+        write_response(&mut out, &response).expect("should write");
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "HTTP/1.1 200 OK\r\n\
+             Content-Type: application/json\r\n\
+             X-Last-Modified: 1.5\r\n\
+             Content-Length: 2\r\n\
+             Connection: close\r\n\
+             \r\n\
+             []"
+        );
+    }
+}
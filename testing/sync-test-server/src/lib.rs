@@ -0,0 +1,335 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+#![allow(unknown_lints)]
+#![warn(rust_2018_idioms)]
+
+//! An in-process stand-in for the Sync tokenserver and storage servers, so
+//! that we can run end-to-end tests of our engines without network access.
+//!
+//! ```no_run
+//! let server = sync_test_server::TestServer::start();
+//! // Point `Sync15StorageClientInit::tokenserver_url` at this, using any
+//! // string as the access token. Different access tokens are different
+//! // users.
+//! let tokenserver_url = server.tokenserver_url();
+//! ```
+//!
+//! This isn't a complete implementation of either server: it implements
+//! enough for our sync code, and makes no attempt to verify hawk signatures.
+
+mod http;
+mod storage;
+mod tokenserver;
+
+pub use crate::storage::{Bso, Timestamp};
+
+use crate::http::{Request, Response};
+use crate::storage::UserStorage;
+use crate::tokenserver::TokenServer;
+use serde_json::{json, Value as JsonValue};
+use std::collections::HashMap;
+use std::io::BufReader;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// The limits reported by `info/configuration`, matching the defaults used
+/// by the production servers.
+fn default_configuration() -> JsonValue {
+    json!({
+        "max_request_bytes": 2_101_248,
+        "max_post_records": 100,
+        "max_post_bytes": 2_097_152,
+        "max_total_records": 10_000,
+        "max_total_bytes": 104_857_600,
+        "max_record_payload_bytes": 2_097_152,
+    })
+}
+
+#[derive(Debug)]
+struct ServerState {
+    base_url: String,
+    config: JsonValue,
+    tokenserver: TokenServer,
+    users: HashMap<u64, UserStorage>,
+    last_timestamp: Timestamp,
+    // If set, the number of seconds sent in an `X-Weave-Backoff` header
+    // with every storage response.
+    backoff: Option<u64>,
+    // If set, every storage request fails with a 503, with this number of
+    // seconds in the `Retry-After` header.
+    retry_after: Option<u64>,
+}
+
+impl ServerState {
+    /// Returns the current server time, guaranteeing that each call returns
+    /// a later time than the one before, so every write has a unique
+    /// modified time.
+    fn now(&mut self) -> Timestamp {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let now =
+            Timestamp(since_epoch.as_secs() * 100 + u64::from(since_epoch.subsec_millis()) / 10);
+        self.last_timestamp = if now > self.last_timestamp {
+            now
+        } else {
+            Timestamp(self.last_timestamp.0 + 1)
+        };
+        self.last_timestamp
+    }
+
+    fn handle(&mut self, request: &Request) -> Response {
+        let now = self.now();
+        let path: Vec<&str> = request.path.iter().map(String::as_str).collect();
+        match &path[..] {
+            ["token", "1.0", "sync", "1.5"] if request.method == "GET" => self
+                .tokenserver
+                .issue_token(request, &self.base_url, now.0 / 100),
+            _ => match self.handle_storage(request, now) {
+                Some(response) => {
+                    let response = response.header("X-Weave-Timestamp", now.to_header());
+                    match self.backoff {
+                        Some(secs) => response.header("X-Weave-Backoff", secs),
+                        None => response,
+                    }
+                }
+                None => Response::new(404),
+            },
+        }
+    }
+
+    /// Handles a request for `/1.5/{uid}/...`, or `/node{n}/1.5/{uid}/...`
+    /// for users who've been moved to another node. Returns None if the
+    /// request isn't for a storage node.
+    fn handle_storage(&mut self, request: &Request, now: Timestamp) -> Option<Response> {
+        let (node, path) = match request.path.split_first() {
+            Some((first, rest)) if first.starts_with("node") => {
+                (first["node".len()..].parse::<u32>().ok()?, rest)
+            }
+            _ => (0, &request.path[..]),
+        };
+        let uid = match path {
+            [version, uid, ..] if version == "1.5" => uid.parse::<u64>().ok(),
+            _ => return None,
+        };
+        let authenticated = self.tokenserver.authenticate(request);
+        Some(match (authenticated, self.retry_after) {
+            (Some(_), Some(secs)) => Response::new(503).header("Retry-After", secs),
+            // The token must be for this user and node, and the user must
+            // still be assigned to this node.
+            (Some((user, token_node)), None)
+                if uid == Some(user)
+                    && token_node == node
+                    && self.tokenserver.node_for_uid(user) == node =>
+            {
+                let storage = self.users.entry(user).or_insert_with(UserStorage::default);
+                storage.handle(request, &path[2..], now, &self.config)
+            }
+            _ => Response::json(401, &json!({ "status": "invalid-credentials" })),
+        })
+    }
+}
+
+/// A running test server. The server listens on a random local port, and
+/// shuts down when this is dropped.
+pub struct TestServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<ServerState>>,
+    shutdown: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl TestServer {
+    pub fn start() -> TestServer {
+        let listener =
+            TcpListener::bind("127.0.0.1:0").expect("Should be able to bind to localhost");
+        let addr = listener
+            .local_addr()
+            .expect("Listener should have an address");
+        let state = Arc::new(Mutex::new(ServerState {
+            base_url: format!("http://{}", addr),
+            config: default_configuration(),
+            tokenserver: TokenServer::default(),
+            users: HashMap::new(),
+            last_timestamp: Timestamp::default(),
+            backoff: None,
+            retry_after: None,
+        }));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let state = Arc::clone(&state);
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                for stream in listener.incoming() {
+                    if shutdown.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    match stream {
+                        Ok(stream) => {
+                            if let Err(e) = serve(stream, &state) {
+                                log::warn!("Test server failed to handle request: {}", e);
+                            }
+                        }
+                        Err(e) => log::warn!("Test server failed to accept: {}", e),
+                    }
+                }
+            })
+        };
+        log::info!("Test server listening on {}", addr);
+        TestServer {
+            addr,
+            state,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    /// The URL to use as the tokenserver URL when syncing against this
+    /// server.
+    pub fn tokenserver_url(&self) -> Url {
+        Url::parse(&format!("http://{}/token/", self.addr)).expect("Should be a valid URL")
+    }
+
+    /// Replaces the limits reported by `info/configuration`. Missing limits
+    /// are treated as unlimited by the client, but `max_post_records` is
+    /// the only limit the server enforces.
+    pub fn set_configuration(&self, config: JsonValue) {
+        self.state.lock().unwrap().config = config;
+    }
+
+    /// Makes every storage response ask the client to back off for `secs`
+    /// seconds, using the `X-Weave-Backoff` header, or stops doing so if
+    /// `secs` is None.
+    pub fn set_backoff(&self, secs: Option<u64>) {
+        self.state.lock().unwrap().backoff = secs;
+    }
+
+    /// Makes every storage request fail with a 503 and a `Retry-After` of
+    /// `secs` seconds, as the servers do when overloaded, or stops doing so
+    /// if `secs` is None.
+    pub fn set_unavailable(&self, secs: Option<u64>) {
+        self.state.lock().unwrap().retry_after = secs;
+    }
+
+    /// Moves the user with the given access token to a new storage node,
+    /// which starts out empty. Requests using tokens for their old node fail
+    /// with a 401.
+    pub fn reassign_node(&self, access_token: &str) {
+        let mut state = self.state.lock().unwrap();
+        let uid = state.tokenserver.uid_for_access_token(access_token);
+        state.tokenserver.reassign_node(uid);
+        state.users.remove(&uid);
+    }
+
+    /// Limits the user with the given access token to storing `bytes` bytes
+    /// of payloads, or lifts their quota if `bytes` is None.
+    pub fn set_quota(&self, access_token: &str, bytes: Option<usize>) {
+        let mut state = self.state.lock().unwrap();
+        let uid = state.tokenserver.uid_for_access_token(access_token);
+        state
+            .users
+            .entry(uid)
+            .or_insert_with(UserStorage::default)
+            .quota = bytes;
+    }
+
+    /// Makes requests from the user with the given access token to delete
+    /// `collection` fail with a 500, or stops doing so if `collection` is
+    /// None.
+    pub fn set_undeletable(&self, access_token: &str, collection: Option<&str>) {
+        let mut state = self.state.lock().unwrap();
+        let uid = state.tokenserver.uid_for_access_token(access_token);
+        state
+            .users
+            .entry(uid)
+            .or_insert_with(UserStorage::default)
+            .undeletable = collection.map(str::to_string);
+    }
+
+    /// Returns the number of records the user with the given access token
+    /// has uploaded in batches that haven't been committed yet.
+    pub fn batched_records(&self, access_token: &str) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let uid = state.tokenserver.uid_for_access_token(access_token);
+        state
+            .users
+            .get(&uid)
+            .map(UserStorage::num_batched_records)
+            .unwrap_or_default()
+    }
+
+    /// Expires every uncommitted batch for the user with the given access
+    /// token.
+    pub fn expire_batches(&self, access_token: &str) {
+        let mut state = self.state.lock().unwrap();
+        let uid = state.tokenserver.uid_for_access_token(access_token);
+        if let Some(storage) = state.users.get_mut(&uid) {
+            storage.expire_batches();
+        }
+    }
+
+    /// Returns the records in a collection for the user with the given
+    /// access token, ordered by id.
+    pub fn records(&self, access_token: &str, collection: &str) -> Vec<Bso> {
+        let mut state = self.state.lock().unwrap();
+        let uid = state.tokenserver.uid_for_access_token(access_token);
+        state
+            .users
+            .get(&uid)
+            .and_then(|storage| storage.collections.get(collection))
+            .map(|coll| coll.records.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// Returns the last modified time of each collection for the user with
+    /// the given access token, as reported by `info/collections`.
+    pub fn collections(&self, access_token: &str) -> HashMap<String, Timestamp> {
+        let mut state = self.state.lock().unwrap();
+        let uid = state.tokenserver.uid_for_access_token(access_token);
+        state
+            .users
+            .get(&uid)
+            .map(|storage| {
+                storage
+                    .collections
+                    .iter()
+                    .map(|(name, coll)| (name.clone(), coll.modified))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        // Wake up the listener thread so that it notices it should stop.
+        let _ = TcpStream::connect(self.addr);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn serve(stream: TcpStream, state: &Mutex<ServerState>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = match http::read_request(&mut reader)? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    log::trace!("Test server request: {} {:?}", request.method, request.path);
+    let response = state.lock().unwrap().handle(&request);
+    log::trace!("Test server response: {}", response.status);
+    let mut stream = stream;
+    http::write_response(&mut stream, &response)
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,682 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! An in-memory implementation of the parts of the storage v1.5 API that
+//! we use. See https://mozilla-services.readthedocs.io/en/latest/storage/apis-1.5.html
+//!
+//! Timestamps are kept as integer hundredths of a second, which is the
+//! resolution of the real server, to avoid comparing floats.
+
+use crate::http::{Request, Response};
+use serde_json::{json, Value as JsonValue};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+
+/// A server timestamp, in hundredths of a second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp(pub u64);
+
+impl Timestamp {
+    pub fn parse(s: &str) -> Option<Timestamp> {
+        let secs = s.trim().parse::<f64>().ok()?;
+        if secs < 0.0 {
+            return None;
+        }
+        Some(Timestamp((secs * 100.0).round() as u64))
+    }
+
+    pub fn as_seconds(self) -> f64 {
+        self.0 as f64 / 100.0
+    }
+
+    /// Formats the timestamp the way the server does in headers.
+    pub fn to_header(self) -> String {
+        format!("{}.{:02}", self.0 / 100, self.0 % 100)
+    }
+}
+
+/// A stored record. The payload is opaque to the server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bso {
+    pub id: String,
+    pub modified: Timestamp,
+    pub payload: String,
+    pub sortindex: Option<i32>,
+    pub ttl: Option<u32>,
+}
+
+impl Bso {
+    fn to_json(&self) -> JsonValue {
+        let mut result = json!({
+            "id": self.id,
+            "modified": self.modified.as_seconds(),
+            "payload": self.payload,
+        });
+        if let Some(sortindex) = self.sortindex {
+            result["sortindex"] = sortindex.into();
+        }
+        result
+    }
+
+    /// Builds a record from an uploaded BSO, returning None if it's
+    /// malformed. Missing fields are taken from `existing`, since the
+    /// server allows partial updates.
+    fn from_upload(
+        id: &str,
+        upload: &JsonValue,
+        existing: Option<&Bso>,
+        modified: Timestamp,
+    ) -> Option<Bso> {
+        let payload = match upload.get("payload") {
+            Some(JsonValue::String(payload)) => payload.clone(),
+            Some(_) => return None,
+            None => existing?.payload.clone(),
+        };
+        let sortindex = match upload.get("sortindex") {
+            Some(sortindex) => Some(sortindex.as_i64()? as i32),
+            None => existing.and_then(|bso| bso.sortindex),
+        };
+        let ttl = match upload.get("ttl") {
+            Some(ttl) => Some(ttl.as_u64()? as u32),
+            None => existing.and_then(|bso| bso.ttl),
+        };
+        Some(Bso {
+            id: id.to_string(),
+            modified,
+            payload,
+            sortindex,
+            ttl,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Collection {
+    pub modified: Timestamp,
+    pub records: BTreeMap<String, Bso>,
+}
+
+/// An upload batch that hasn't been committed yet.
+#[derive(Debug, Clone)]
+struct Batch {
+    collection: String,
+    records: Vec<(String, JsonValue)>,
+}
+
+fn respond(status: u16, body: &JsonValue, last_modified: Timestamp) -> Response {
+    Response::json(status, body).header("X-Last-Modified", last_modified.to_header())
+}
+
+fn not_found() -> Response {
+    Response::json(404, &json!(0))
+}
+
+/// Returns a 412 response if `X-If-Unmodified-Since` was sent and `modified`
+/// is later.
+fn check_unmodified_since(request: &Request, modified: Timestamp) -> Option<Response> {
+    let xius = request
+        .header("X-If-Unmodified-Since")
+        .and_then(Timestamp::parse)?;
+    if modified > xius {
+        Some(respond(412, &json!({}), modified))
+    } else {
+        None
+    }
+}
+
+/// Returns true if every field the client sent has the right type.
+fn is_valid_upload(upload: &JsonValue) -> bool {
+    let valid_field =
+        |name: &str, is_valid: fn(&JsonValue) -> bool| upload.get(name).map_or(true, is_valid);
+    valid_field("payload", JsonValue::is_string)
+        && valid_field("sortindex", JsonValue::is_i64)
+        && valid_field("ttl", JsonValue::is_u64)
+}
+
+/// The storage for a single user.
+#[derive(Debug, Clone, Default)]
+pub struct UserStorage {
+    pub collections: HashMap<String, Collection>,
+    batches: HashMap<String, Batch>,
+    next_batch_id: u64,
+    /// If set, the number of payload bytes this user may store. Writes are
+    /// refused with a 507 once they're storing this much.
+    pub quota: Option<usize>,
+    /// If set, a collection which can't be deleted. Requests to delete it
+    /// fail with a 500.
+    pub undeletable: Option<String>,
+}
+
+impl UserStorage {
+    pub fn last_modified(&self) -> Timestamp {
+        self.collections
+            .values()
+            .map(|coll| coll.modified)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Returns the number of records in batches that haven't been committed.
+    pub fn num_batched_records(&self) -> usize {
+        self.batches.values().map(|batch| batch.records.len()).sum()
+    }
+
+    /// Forgets every batch that hasn't been committed, as if they expired.
+    pub fn expire_batches(&mut self) {
+        self.batches.clear();
+    }
+
+    /// Returns the number of payload bytes stored in each collection.
+    fn usage(&self) -> HashMap<&str, usize> {
+        self.collections
+            .iter()
+            .map(|(name, coll)| {
+                let bytes = coll.records.values().map(|bso| bso.payload.len()).sum();
+                (name.as_str(), bytes)
+            })
+            .collect()
+    }
+
+    fn collection_modified(&self, collection: &str) -> Timestamp {
+        self.collections
+            .get(collection)
+            .map(|coll| coll.modified)
+            .unwrap_or_default()
+    }
+
+    /// Handles a request for a path relative to this user's storage root, so
+    /// `path` is eg, `["storage", "bookmarks"]`.
+    pub fn handle(
+        &mut self,
+        request: &Request,
+        path: &[String],
+        now: Timestamp,
+        config: &JsonValue,
+    ) -> Response {
+        let path: Vec<&str> = path.iter().map(String::as_str).collect();
+        let quota = match self.quota {
+            Some(quota) => quota,
+            None => return self.dispatch(request, &path, now, config),
+        };
+        let usage: usize = self.usage().values().sum();
+        let response = match request.method.as_str() {
+            "POST" | "PUT" if usage >= quota => respond(507, &json!(0), self.last_modified()),
+            _ => self.dispatch(request, &path, now, config),
+        };
+        let usage: usize = self.usage().values().sum();
+        let remaining_kb = quota.saturating_sub(usage) as f64 / 1024.0;
+        response.header("X-Weave-Quota-Remaining", remaining_kb)
+    }
+
+    fn dispatch(
+        &mut self,
+        request: &Request,
+        path: &[&str],
+        now: Timestamp,
+        config: &JsonValue,
+    ) -> Response {
+        match (request.method.as_str(), path) {
+            ("GET", ["info", "quota"]) => {
+                let usage: usize = self.usage().values().sum();
+                let quota_kb = self.quota.map(|quota| quota as f64 / 1024.0);
+                respond(
+                    200,
+                    &json!([usage as f64 / 1024.0, quota_kb]),
+                    self.last_modified(),
+                )
+            }
+            ("GET", ["info", "collection_usage"]) => {
+                let usage: serde_json::Map<String, JsonValue> = self
+                    .usage()
+                    .into_iter()
+                    .map(|(name, bytes)| (name.to_string(), (bytes as f64 / 1024.0).into()))
+                    .collect();
+                respond(200, &JsonValue::Object(usage), self.last_modified())
+            }
+            ("GET", ["info", "collections"]) => {
+                let modified: serde_json::Map<String, JsonValue> = self
+                    .collections
+                    .iter()
+                    .map(|(name, coll)| (name.clone(), coll.modified.as_seconds().into()))
+                    .collect();
+                respond(200, &JsonValue::Object(modified), self.last_modified())
+            }
+            ("GET", ["info", "configuration"]) => respond(200, config, self.last_modified()),
+            ("DELETE", []) | ("DELETE", ["storage"]) => {
+                self.collections.clear();
+                self.batches.clear();
+                respond(200, &json!({}), now)
+            }
+            ("GET", ["storage", collection]) => self.get_collection(request, collection),
+            ("POST", ["storage", collection]) => {
+                self.post_collection(request, collection, now, config)
+            }
+            ("DELETE", ["storage", collection]) => self.delete_collection(request, collection, now),
+            ("GET", ["storage", collection, id]) => {
+                match self
+                    .collections
+                    .get(*collection)
+                    .and_then(|coll| coll.records.get(*id))
+                {
+                    Some(bso) => respond(200, &bso.to_json(), bso.modified),
+                    None => not_found(),
+                }
+            }
+            ("PUT", ["storage", collection, id]) => self.put_bso(request, collection, id, now),
+            ("DELETE", ["storage", collection, id]) => {
+                self.delete_bso(request, collection, id, now)
+            }
+            _ => not_found(),
+        }
+    }
+
+    fn get_collection(&self, request: &Request, collection: &str) -> Response {
+        let modified = self.collection_modified(collection);
+        let coll = match self.collections.get(collection) {
+            Some(coll) => coll,
+            None => {
+                return respond(200, &json!([]), modified).header("X-Weave-Records", 0);
+            }
+        };
+        let ids: Option<Vec<&str>> = request
+            .query_param("ids")
+            .map(|ids| ids.split(',').filter(|id| !id.is_empty()).collect());
+        let newer = request.query_param("newer").and_then(Timestamp::parse);
+        let older = request.query_param("older").and_then(Timestamp::parse);
+
+        let mut records: Vec<&Bso> = coll
+            .records
+            .values()
+            .filter(|bso| {
+                ids.as_ref()
+                    .map_or(true, |ids| ids.contains(&bso.id.as_str()))
+            })
+            .filter(|bso| newer.map_or(true, |newer| bso.modified > newer))
+            .filter(|bso| older.map_or(true, |older| bso.modified < older))
+            .collect();
+        match request.query_param("sort") {
+            Some("newest") => records.sort_by_key(|bso| Reverse(bso.modified)),
+            Some("index") => records.sort_by_key(|bso| Reverse(bso.sortindex)),
+            // The real server doesn't define an order if `sort` isn't
+            // given, but sorting oldest first keeps things deterministic.
+            _ => records.sort_by_key(|bso| bso.modified),
+        }
+
+        let offset = request
+            .query_param("offset")
+            .and_then(|offset| offset.parse::<usize>().ok())
+            .unwrap_or(0);
+        let limit = request
+            .query_param("limit")
+            .and_then(|limit| limit.parse::<usize>().ok())
+            .filter(|&limit| limit > 0);
+        let total = records.len();
+        let end = limit.map_or(total, |limit| (offset + limit).min(total));
+        let page = records.get(offset.min(end)..end).unwrap_or_default();
+
+        let body = if request.query_param("full").is_some() {
+            JsonValue::Array(page.iter().map(|bso| bso.to_json()).collect())
+        } else {
+            JsonValue::Array(page.iter().map(|bso| bso.id.clone().into()).collect())
+        };
+        let mut response = respond(200, &body, modified).header("X-Weave-Records", page.len());
+        if end < total {
+            response = response.header("X-Weave-Next-Offset", end);
+        }
+        response
+    }
+
+    fn post_collection(
+        &mut self,
+        request: &Request,
+        collection: &str,
+        now: Timestamp,
+        config: &JsonValue,
+    ) -> Response {
+        let modified = self.collection_modified(collection);
+        if let Some(response) = check_unmodified_since(request, modified) {
+            return response;
+        }
+        let uploads = match request.json() {
+            Some(JsonValue::Array(uploads)) => uploads,
+            _ => return respond(400, &json!(6), modified),
+        };
+        let max_post_records = config["max_post_records"]
+            .as_u64()
+            .unwrap_or(u64::max_value());
+        if uploads.len() as u64 > max_post_records {
+            return respond(400, &json!(17), modified);
+        }
+
+        let mut success = Vec::new();
+        let mut failed = serde_json::Map::new();
+        let mut valid = Vec::new();
+        for upload in uploads {
+            match upload.get("id").and_then(JsonValue::as_str) {
+                Some(id) if is_valid_upload(&upload) => {
+                    success.push(JsonValue::from(id));
+                    valid.push((id.to_string(), upload.clone()));
+                }
+                Some(id) => {
+                    failed.insert(id.to_string(), json!(["invalid record"]));
+                }
+                None => {}
+            }
+        }
+
+        let commit = request.query_param("commit") == Some("true");
+        let batch_id = match request.query_param("batch") {
+            None => {
+                let modified = self.apply(collection, valid, now);
+                return respond(
+                    200,
+                    &json!({ "modified": modified.as_seconds(), "success": success, "failed": failed }),
+                    modified,
+                );
+            }
+            Some("true") => {
+                self.next_batch_id += 1;
+                let batch_id = self.next_batch_id.to_string();
+                self.batches.insert(
+                    batch_id.clone(),
+                    Batch {
+                        collection: collection.to_string(),
+                        records: Vec::new(),
+                    },
+                );
+                batch_id
+            }
+            Some(batch_id) => batch_id.to_string(),
+        };
+        match self.batches.get_mut(&batch_id) {
+            Some(batch) if batch.collection == collection => batch.records.extend(valid),
+            _ => return respond(400, &json!(0), modified),
+        }
+
+        if commit {
+            let batch = self.batches.remove(&batch_id).expect("batch exists");
+            let modified = self.apply(collection, batch.records, now);
+            respond(
+                200,
+                &json!({ "modified": modified.as_seconds(), "success": success, "failed": failed }),
+                modified,
+            )
+        } else {
+            respond(
+                202,
+                &json!({ "batch": batch_id, "success": success, "failed": failed }),
+                modified,
+            )
+        }
+    }
+
+    /// Writes uploaded records to a collection, returning its new
+    /// modified time.
+    fn apply(
+        &mut self,
+        collection: &str,
+        uploads: Vec<(String, JsonValue)>,
+        now: Timestamp,
+    ) -> Timestamp {
+        let coll = self
+            .collections
+            .entry(collection.to_string())
+            .or_insert_with(Collection::default);
+        for (id, upload) in uploads {
+            if let Some(bso) = Bso::from_upload(&id, &upload, coll.records.get(&id), now) {
+                coll.records.insert(id, bso);
+            }
+        }
+        coll.modified = now;
+        now
+    }
+
+    fn delete_collection(
+        &mut self,
+        request: &Request,
+        collection: &str,
+        now: Timestamp,
+    ) -> Response {
+        if self.undeletable.as_ref().map(String::as_str) == Some(collection) {
+            return Response::new(500);
+        }
+        let modified = self.collection_modified(collection);
+        if let Some(response) = check_unmodified_since(request, modified) {
+            return response;
+        }
+        match request.query_param("ids") {
+            Some(ids) => {
+                if let Some(coll) = self.collections.get_mut(collection) {
+                    for id in ids.split(',') {
+                        coll.records.remove(id);
+                    }
+                    coll.modified = now;
+                }
+            }
+            None => {
+                self.collections.remove(collection);
+            }
+        }
+        respond(200, &json!({ "modified": now.as_seconds() }), now)
+    }
+
+    fn put_bso(
+        &mut self,
+        request: &Request,
+        collection: &str,
+        id: &str,
+        now: Timestamp,
+    ) -> Response {
+        let existing = self
+            .collections
+            .get(collection)
+            .and_then(|coll| coll.records.get(id));
+        let modified = existing.map(|bso| bso.modified).unwrap_or_default();
+        if let Some(response) = check_unmodified_since(request, modified) {
+            return response;
+        }
+        let bso = match request
+            .json()
+            .and_then(|upload| Bso::from_upload(id, &upload, existing, now))
+        {
+            Some(bso) => bso,
+            None => return respond(400, &json!(8), modified),
+        };
+        let coll = self
+            .collections
+            .entry(collection.to_string())
+            .or_insert_with(Collection::default);
+        coll.records.insert(id.to_string(), bso);
+        coll.modified = now;
+        respond(200, &json!(now.as_seconds()), now)
+    }
+
+    fn delete_bso(
+        &mut self,
+        request: &Request,
+        collection: &str,
+        id: &str,
+        now: Timestamp,
+    ) -> Response {
+        let modified = self
+            .collections
+            .get(collection)
+            .and_then(|coll| coll.records.get(id))
+            .map(|bso| bso.modified);
+        let modified = match modified {
+            Some(modified) => modified,
+            None => return not_found(),
+        };
+        if let Some(response) = check_unmodified_since(request, modified) {
+            return response;
+        }
+        let coll = self
+            .collections
+            .get_mut(collection)
+            .expect("collection exists");
+        coll.records.remove(id);
+        coll.modified = now;
+        respond(200, &json!({ "modified": now.as_seconds() }), now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, query: &[(&str, &str)], body: Option<JsonValue>) -> Request {
+        Request {
+            method: method.into(),
+            path: vec![],
+            query: query
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+            headers: HashMap::new(),
+            body: body.map(|b| b.to_string().into_bytes()).unwrap_or_default(),
+        }
+    }
+
+    fn path(p: &str) -> Vec<String> {
+        p.split('/').map(String::from).collect()
+    }
+
+    fn body(response: &Response) -> JsonValue {
+        serde_json::from_slice(&response.body).unwrap()
+    }
+
+    fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+        response
+            .headers
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(Timestamp::parse("1234.56"), Some(Timestamp(123_456)));
+        assert_eq!(Timestamp::parse("12"), Some(Timestamp(1200)));
+        assert_eq!(Timestamp::parse("nope"), None);
+        assert_eq!(Timestamp(123_405).to_header(), "1234.05");
+    }
+
+    #[test]
+    fn test_batch_upload() {
+        let config = json!({ "max_post_records": 2 });
+        let mut storage = UserStorage::default();
+        let coll = path("storage/bookmarks");
+
+        let resp = storage.handle(
+            &request(
+                "POST",
+                &[("batch", "true")],
+                Some(json!([{ "id": "a", "payload": "1" }, { "id": "b", "payload": "2" }])),
+            ),
+            &coll,
+            Timestamp(100),
+            &config,
+        );
+        assert_eq!(resp.status, 202);
+        let batch_id = body(&resp)["batch"].as_str().unwrap().to_string();
+        // Nothing is visible until the batch is committed.
+        assert!(!storage.collections.contains_key("bookmarks"));
+
+        let resp = storage.handle(
+            &request(
+                "POST",
+                &[("batch", &batch_id), ("commit", "true")],
+                Some(json!([{ "id": "c", "payload": "3", "sortindex": 5 }])),
+            ),
+            &coll,
+            Timestamp(200),
+            &config,
+        );
+        assert_eq!(resp.status, 200);
+        assert_eq!(header(&resp, "X-Last-Modified"), Some("2.00"));
+        assert_eq!(storage.collections["bookmarks"].records.len(), 3);
+
+        // Too many records for a single post.
+        let resp = storage.handle(
+            &request(
+                "POST",
+                &[],
+                Some(json!([{ "id": "a" }, { "id": "b" }, { "id": "c" }])),
+            ),
+            &coll,
+            Timestamp(300),
+            &config,
+        );
+        assert_eq!(resp.status, 400);
+    }
+
+    #[test]
+    fn test_unmodified_since() {
+        let config = json!({});
+        let mut storage = UserStorage::default();
+        let bso = path("storage/meta/global");
+        let resp = storage.handle(
+            &request("PUT", &[], Some(json!({ "payload": "{}" }))),
+            &bso,
+            Timestamp(100),
+            &config,
+        );
+        assert_eq!(resp.status, 200);
+
+        let mut put = request("PUT", &[], Some(json!({ "payload": "{}" })));
+        put.headers
+            .insert("x-if-unmodified-since".into(), "0.50".into());
+        let resp = storage.handle(&put, &bso, Timestamp(200), &config);
+        assert_eq!(resp.status, 412);
+
+        put.headers
+            .insert("x-if-unmodified-since".into(), "1.00".into());
+        let resp = storage.handle(&put, &bso, Timestamp(200), &config);
+        assert_eq!(resp.status, 200);
+    }
+
+    #[test]
+    fn test_get_collection_paging() {
+        let config = json!({});
+        let mut storage = UserStorage::default();
+        let coll = path("storage/history");
+        for (i, id) in ["a", "b", "c"].iter().enumerate() {
+            storage.handle(
+                &request("POST", &[], Some(json!([{ "id": id, "payload": "x" }]))),
+                &coll,
+                Timestamp(100 * (i as u64 + 1)),
+                &config,
+            );
+        }
+
+        let resp = storage.handle(
+            &request(
+                "GET",
+                &[("full", "1"), ("limit", "2"), ("newer", "1")],
+                None,
+            ),
+            &coll,
+            Timestamp(400),
+            &config,
+        );
+        assert_eq!(resp.status, 200);
+        let ids: Vec<_> = body(&resp)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|bso| bso["id"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(ids, vec!["b", "c"]);
+        assert_eq!(header(&resp, "X-Weave-Next-Offset"), None);
+
+        let resp = storage.handle(
+            &request("GET", &[("limit", "2"), ("sort", "newest")], None),
+            &coll,
+            Timestamp(400),
+            &config,
+        );
+        assert_eq!(body(&resp), json!(["c", "b"]));
+        assert_eq!(header(&resp, "X-Weave-Next-Offset"), Some("2"));
+    }
+}
@@ -0,0 +1,1345 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! End-to-end tests which sync our engines through the test server.
+
+use super::*;
+use interrupt::{Interruptee, NeverInterrupts};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+use sync15::clients::{CommandProcessor, DeviceType, Settings};
+use sync15::telemetry;
+use sync15::{
+    BatchProgress, CollSyncIds, CollectionRequest, ErrorKind, IncomingChangeset, KeyBundle,
+    LocalChange, LocalChangeKind, MemoryCachedState, OutgoingChangeset, Payload, RequestOrder,
+    ServerTimestamp, SetupStorageClient, Store, StorePreview, StoreSyncAssociation,
+    Sync15ClientResponse, Sync15StorageClient, Sync15StorageClientInit, SyncPreview, SyncResult,
+};
+
+fn storage_init(server: &TestServer, access_token: &str) -> Sync15StorageClientInit {
+    Sync15StorageClientInit {
+        key_id: "test-key-id".into(),
+        access_token: access_token.into(),
+        tokenserver_url: server.tokenserver_url(),
+    }
+}
+
+/// A store which keeps records as JSON strings, keyed by id, and applies
+/// incoming records by replacing whatever we have.
+struct TestStore {
+    records: RefCell<BTreeMap<String, String>>,
+    changed: RefCell<Vec<String>>,
+    last_sync: Cell<ServerTimestamp>,
+    assoc: RefCell<StoreSyncAssociation>,
+    // If non-zero, the number of records to fetch in each page.
+    page_size: Cell<usize>,
+    // If set, the number of pages to apply before failing the next one.
+    pages_until_failure: Cell<Option<usize>>,
+    // The number of incoming records we've applied.
+    num_applied: Cell<usize>,
+    pending_batch: RefCell<Option<BatchProgress>>,
+}
+
+impl TestStore {
+    fn new() -> TestStore {
+        TestStore {
+            records: RefCell::default(),
+            changed: RefCell::default(),
+            last_sync: Cell::new(ServerTimestamp(0.0)),
+            assoc: RefCell::new(StoreSyncAssociation::Disconnected),
+            page_size: Cell::new(0),
+            pages_until_failure: Cell::new(None),
+            num_applied: Cell::new(0),
+            pending_batch: RefCell::default(),
+        }
+    }
+
+    fn insert(&self, id: &str, value: &str) {
+        self.records.borrow_mut().insert(id.into(), value.into());
+        self.changed.borrow_mut().push(id.into());
+    }
+
+    fn get(&self, id: &str) -> Option<String> {
+        self.records.borrow().get(id).cloned()
+    }
+}
+
+impl Store for TestStore {
+    fn collection_name(&self) -> &'static str {
+        "addons"
+    }
+
+    fn apply_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        incoming_telem: &mut telemetry::EngineIncoming,
+    ) -> Result<OutgoingChangeset, failure::Error> {
+        match self.pages_until_failure.get() {
+            Some(0) => failure::bail!("Failing to apply this page"),
+            Some(n) => self.pages_until_failure.set(Some(n - 1)),
+            None => {}
+        }
+        for (payload, _) in inbound.changes {
+            let value = payload
+                .data
+                .get("value")
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_string();
+            self.records.borrow_mut().insert(payload.id, value);
+            self.num_applied.set(self.num_applied.get() + 1);
+            incoming_telem.applied(1);
+        }
+        // Like the real stores, remember how far we got as soon as we've
+        // applied the records.
+        self.last_sync.set(inbound.timestamp);
+        let mut outgoing = OutgoingChangeset::new("addons".into(), inbound.timestamp);
+        let records = self.records.borrow();
+        for id in self.changed.borrow().iter() {
+            outgoing.changes.push(Payload::from_json(
+                json!({ "id": id, "value": records[id] }),
+            )?);
+        }
+        Ok(outgoing)
+    }
+
+    fn sync_finished(
+        &self,
+        new_timestamp: ServerTimestamp,
+        records_synced: Vec<String>,
+    ) -> Result<(), failure::Error> {
+        self.changed
+            .borrow_mut()
+            .retain(|id| !records_synced.contains(id));
+        self.last_sync.set(new_timestamp);
+        Ok(())
+    }
+
+    fn get_collection_request(&self) -> Result<CollectionRequest, failure::Error> {
+        let request = CollectionRequest::new("addons")
+            .full()
+            .newer_than(self.last_sync.get());
+        Ok(match self.page_size.get() {
+            0 => request,
+            page_size => request.sort_by(RequestOrder::Oldest).limit(page_size),
+        })
+    }
+
+    fn get_sync_assoc(&self) -> Result<StoreSyncAssociation, failure::Error> {
+        Ok(self.assoc.borrow().clone())
+    }
+
+    fn reset(&self, assoc: &StoreSyncAssociation) -> Result<(), failure::Error> {
+        self.last_sync.set(ServerTimestamp(0.0));
+        let mut changed = self.changed.borrow_mut();
+        *changed = self.records.borrow().keys().cloned().collect();
+        *self.assoc.borrow_mut() = assoc.clone();
+        *self.pending_batch.borrow_mut() = None;
+        Ok(())
+    }
+
+    fn wipe(&self) -> Result<(), failure::Error> {
+        self.records.borrow_mut().clear();
+        self.changed.borrow_mut().clear();
+        Ok(())
+    }
+
+    fn get_pending_batch(&self) -> Result<Option<BatchProgress>, failure::Error> {
+        Ok(self.pending_batch.borrow().clone())
+    }
+
+    fn set_pending_batch(&self, progress: Option<&BatchProgress>) -> Result<(), failure::Error> {
+        *self.pending_batch.borrow_mut() = progress.cloned();
+        Ok(())
+    }
+
+    fn preview_incoming(
+        &self,
+        inbound: IncomingChangeset,
+        _incoming_telem: &mut telemetry::EngineIncoming,
+    ) -> Result<StorePreview, failure::Error> {
+        let mut records = self.records.borrow().clone();
+        let mut local_changes = Vec::new();
+        for (payload, _) in inbound.changes {
+            let value = payload
+                .data
+                .get("value")
+                .and_then(|value| value.as_str())
+                .unwrap_or_default()
+                .to_string();
+            let kind = match records.insert(payload.id.clone(), value) {
+                Some(_) => LocalChangeKind::Update,
+                None => LocalChangeKind::Insert,
+            };
+            local_changes.push(LocalChange {
+                id: payload.id,
+                kind,
+            });
+        }
+        let mut outgoing = OutgoingChangeset::new("addons".into(), inbound.timestamp);
+        for id in self.changed.borrow().iter() {
+            outgoing.changes.push(Payload::from_json(
+                json!({ "id": id, "value": records[id] }),
+            )?);
+        }
+        let mut preview = StorePreview::new(outgoing);
+        preview.local_changes = local_changes;
+        Ok(preview)
+    }
+}
+
+struct TestProcessor(Settings);
+
+impl CommandProcessor for TestProcessor {
+    fn settings(&self) -> &Settings {
+        &self.0
+    }
+
+    fn display_uri(
+        &self,
+        _uri: &str,
+        _sender_id: Option<&str>,
+        _title: Option<&str>,
+    ) -> Result<(), failure::Error> {
+        Ok(())
+    }
+}
+
+/// Everything a single device needs to sync a `TestStore`.
+struct Device {
+    store: TestStore,
+    processor: TestProcessor,
+    persisted_state: Option<String>,
+    mem_cached_state: MemoryCachedState,
+    // Engines enabled or declined since the last sync.
+    engine_changes: HashMap<String, bool>,
+}
+
+impl Device {
+    fn new(name: &str) -> Device {
+        Device {
+            store: TestStore::new(),
+            processor: TestProcessor(Settings {
+                client_id: format!("{}-client-id", name),
+                fxa_device_id: None,
+                device_name: name.into(),
+                device_type: DeviceType::Mobile,
+                version: "1.0".into(),
+            }),
+            persisted_state: None,
+            mem_cached_state: MemoryCachedState::default(),
+            engine_changes: HashMap::new(),
+        }
+    }
+
+    fn try_sync(
+        &mut self,
+        init: &Sync15StorageClientInit,
+        root_key: &KeyBundle,
+        force: bool,
+    ) -> sync15::Result<SyncResult> {
+        self.try_sync_with_interruptee(init, root_key, force, &NeverInterrupts)
+    }
+
+    fn try_sync_with_interruptee(
+        &mut self,
+        init: &Sync15StorageClientInit,
+        root_key: &KeyBundle,
+        force: bool,
+        interruptee: &impl Interruptee,
+    ) -> sync15::Result<SyncResult> {
+        let mut ping = telemetry::SyncTelemetryPing::new();
+        let engine_changes = std::mem::replace(&mut self.engine_changes, HashMap::new());
+        sync15::sync_multiple_with_command_processor(
+            Some(&self.processor),
+            &[&self.store],
+            &mut self.persisted_state,
+            &mut self.mem_cached_state,
+            init,
+            root_key,
+            Some(&engine_changes),
+            Some(if force {
+                telemetry::SyncReason::User
+            } else {
+                telemetry::SyncReason::Scheduled
+            }),
+            &mut ping,
+            interruptee,
+            force,
+        )
+    }
+
+    fn preview(&self, init: &Sync15StorageClientInit, root_key: &KeyBundle) -> SyncPreview {
+        sync15::preview_multiple(
+            &[&self.store],
+            &self.persisted_state,
+            init,
+            root_key,
+            &NeverInterrupts,
+        )
+        .expect("Preview should succeed")
+    }
+
+    fn try_rotate_keys(
+        &mut self,
+        init: &Sync15StorageClientInit,
+        root_key: &KeyBundle,
+        collection: Option<&str>,
+    ) -> sync15::Result<SyncResult> {
+        sync15::rotate_keys(
+            &[&self.store],
+            &mut self.persisted_state,
+            &mut self.mem_cached_state,
+            init,
+            root_key,
+            collection,
+            &mut telemetry::SyncTelemetryPing::new(),
+            &NeverInterrupts,
+        )
+    }
+
+    fn rotate_keys(
+        &mut self,
+        init: &Sync15StorageClientInit,
+        root_key: &KeyBundle,
+        collection: Option<&str>,
+    ) {
+        let result = self
+            .try_rotate_keys(init, root_key, collection)
+            .expect("Rotating keys should succeed");
+        assert!(
+            result.failures.is_empty(),
+            "Unexpected failures: {:?}",
+            result.failures
+        );
+    }
+
+    fn wipe_remote(
+        &mut self,
+        init: &Sync15StorageClientInit,
+        root_key: &KeyBundle,
+        collection: Option<&str>,
+        wipe_local: bool,
+    ) {
+        let failures = sync15::wipe_remote(
+            &[&self.store],
+            &mut self.persisted_state,
+            &mut self.mem_cached_state,
+            init,
+            root_key,
+            collection,
+            wipe_local,
+            &NeverInterrupts,
+        )
+        .expect("Wiping the server should succeed");
+        assert!(failures.is_empty(), "Unexpected failures: {:?}", failures);
+    }
+
+    fn reset_all(&mut self, init: &Sync15StorageClientInit, root_key: &KeyBundle) {
+        let failures = sync15::reset_all(
+            &[&self.store],
+            &mut self.persisted_state,
+            &mut self.mem_cached_state,
+            init,
+            root_key,
+            &NeverInterrupts,
+        )
+        .expect("Starting over should succeed");
+        assert!(failures.is_empty(), "Unexpected failures: {:?}", failures);
+    }
+
+    fn sync(&mut self, init: &Sync15StorageClientInit, root_key: &KeyBundle) {
+        let result = self
+            .try_sync(init, root_key, false)
+            .expect("Sync should succeed");
+        assert!(
+            result.failures.is_empty(),
+            "Unexpected failures: {:?}",
+            result.failures
+        );
+    }
+}
+
+#[test]
+fn test_sync_between_devices() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut first = Device::new("first");
+    let mut second = Device::new("second");
+
+    first.store.insert("a", "from first");
+    first.sync(&init, &root_key);
+    let collections = server.collections("alice");
+    for name in &["meta", "crypto", "clients", "addons"] {
+        assert!(collections.contains_key(*name), "Missing {}", name);
+    }
+    assert_eq!(server.records("alice", "addons").len(), 1);
+
+    second.sync(&init, &root_key);
+    assert_eq!(second.store.get("a"), Some("from first".into()));
+
+    second.store.insert("b", "from second");
+    second.store.insert("a", "changed by second");
+    second.sync(&init, &root_key);
+    first.sync(&init, &root_key);
+    assert_eq!(first.store.get("a"), Some("changed by second".into()));
+    assert_eq!(first.store.get("b"), Some("from second".into()));
+
+    // Each device should know about the other.
+    let other_names = |device: &Device| -> Vec<String> {
+        device
+            .mem_cached_state
+            .remote_clients()
+            .iter()
+            .map(|client| client.device_name.clone())
+            .collect()
+    };
+    assert_eq!(other_names(&first), vec!["second".to_string()]);
+    second.sync(&init, &root_key);
+    assert_eq!(other_names(&second), vec!["first".to_string()]);
+
+    // A different user has their own storage.
+    assert!(server.collections("bob").is_empty());
+}
+
+#[test]
+fn test_batched_upload() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    server.set_configuration(json!({
+        "max_post_records": 2,
+        "max_total_records": 100,
+    }));
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut device = Device::new("device");
+    for i in 0..7 {
+        device.store.insert(&format!("record-{}", i), "value");
+    }
+    device.sync(&init, &root_key);
+    assert_eq!(server.records("alice", "addons").len(), 7);
+
+    let mut other = Device::new("other");
+    other.sync(&init, &root_key);
+    assert_eq!(other.store.records.borrow().len(), 7);
+}
+
+/// Interrupts a sync once the server has a number of records in batches
+/// that haven't been committed, as if the app were killed mid-upload.
+struct InterruptAfterBatched<'a> {
+    server: &'a TestServer,
+    access_token: &'a str,
+    num_records: usize,
+}
+
+impl<'a> Interruptee for InterruptAfterBatched<'a> {
+    fn was_interrupted(&self) -> bool {
+        self.server.batched_records(self.access_token) >= self.num_records
+    }
+}
+
+#[test]
+fn test_resume_batched_upload() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    server.set_configuration(json!({
+        "max_post_records": 2,
+        "max_total_records": 100,
+    }));
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+    let interruptee = InterruptAfterBatched {
+        server: &server,
+        access_token: "alice",
+        num_records: 4,
+    };
+
+    let mut device = Device::new("device");
+    for i in 0..7 {
+        device.store.insert(&format!("record-{}", i), "value");
+    }
+    let result = device.try_sync_with_interruptee(&init, &root_key, false, &interruptee);
+    assert!(result.map_or(true, |result| result.failures.contains_key("addons")));
+    assert!(server.records("alice", "addons").is_empty());
+    let progress = device
+        .store
+        .pending_batch
+        .borrow()
+        .clone()
+        .expect("Should persist the batch");
+    assert_eq!(
+        progress.committed_ids,
+        vec!["record-0", "record-1", "record-2", "record-3"]
+    );
+    assert_eq!(progress.payload_hashes.len(), 4);
+
+    // A record that's already in the batch changes before we resume it.
+    device
+        .store
+        .records
+        .borrow_mut()
+        .insert("record-1".into(), "changed".into());
+
+    // The next sync should upload the rest of the records, and the changed
+    // one again, to the same batch, and commit it, instead of starting a new
+    // one.
+    device.sync(&init, &root_key);
+    assert_eq!(server.records("alice", "addons").len(), 7);
+    assert_eq!(server.batched_records("alice"), 0);
+    assert!(device.store.pending_batch.borrow().is_none());
+    assert!(device.store.changed.borrow().is_empty());
+    let mut other = Device::new("other");
+    other.sync(&init, &root_key);
+    assert_eq!(other.store.get("record-1"), Some("changed".into()));
+    assert_eq!(other.store.get("record-2"), Some("value".into()));
+
+    // If the batch expires before we resume it, we start again.
+    for i in 0..7 {
+        device.store.insert(&format!("more-{}", i), "value");
+    }
+    let result = device.try_sync_with_interruptee(&init, &root_key, false, &interruptee);
+    assert!(result.map_or(true, |result| result.failures.contains_key("addons")));
+    assert!(device.store.pending_batch.borrow().is_some());
+    server.expire_batches("alice");
+    device.sync(&init, &root_key);
+    assert_eq!(server.records("alice", "addons").len(), 14);
+    assert!(device.store.pending_batch.borrow().is_none());
+    assert!(device.store.changed.borrow().is_empty());
+}
+
+#[test]
+fn test_over_quota() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut device = Device::new("device");
+    let mut other = Device::new("other");
+    device.store.insert("a", "value");
+    device.sync(&init, &root_key);
+    other.sync(&init, &root_key);
+
+    let client = Sync15StorageClient::new(init.clone()).unwrap();
+    let usage = match client.fetch_info_collection_usage().unwrap() {
+        Sync15ClientResponse::Success { record, .. } => record,
+        other => panic!("Unexpected response {:?}", other),
+    };
+    assert!(usage["addons"] > 0.0);
+    let total_bytes = (usage.values().sum::<f64>() * 1024.0).round() as usize;
+
+    // Once the account is full, uploading fails, but syncs which don't need
+    // to upload anything still work.
+    server.set_quota("alice", Some(total_bytes));
+    device.store.insert("b", "value");
+    let result = device.try_sync(&init, &root_key, false).unwrap();
+    assert!(result.is_over_quota());
+    assert!(result.failures["addons"].is_over_quota());
+    assert_eq!(result.failures.len(), 1);
+    other.sync(&init, &root_key);
+
+    let quota = match client.fetch_info_quota().unwrap() {
+        Sync15ClientResponse::Success { record, .. } => record,
+        other => panic!("Unexpected response {:?}", other),
+    };
+    assert_eq!(quota.quota, Some(quota.usage));
+    assert_eq!(client.quota_remaining(), Some(0.0));
+}
+
+#[test]
+fn test_paged_download() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    // Upload 5 batches of 5 records, so that each batch has a different
+    // modified time.
+    let mut first = Device::new("first");
+    for batch in 0..5 {
+        for i in 0..5 {
+            first.store.insert(&format!("{}-{}", batch, i), "value");
+        }
+        first.sync(&init, &root_key);
+    }
+    let records = server.records("alice", "addons");
+    assert_eq!(records.len(), 25);
+
+    // Fetch 4 at a time, failing after 3 pages. The records in the first
+    // two batches have all been applied by then, so we can pick up after
+    // them.
+    let mut second = Device::new("second");
+    second.store.page_size.set(4);
+    second.store.pages_until_failure.set(Some(3));
+    let result = second.try_sync(&init, &root_key, false).unwrap();
+    assert!(result.failures.contains_key("addons"));
+    assert_eq!(second.store.num_applied.get(), 12);
+    let second_batch = records.iter().find(|bso| bso.id == "1-0").unwrap();
+    assert_eq!(
+        second.store.last_sync.get(),
+        ServerTimestamp(second_batch.modified.as_seconds())
+    );
+
+    second.store.num_applied.set(0);
+    second.store.pages_until_failure.set(None);
+    second.sync(&init, &root_key);
+    assert_eq!(second.store.num_applied.get(), 15);
+    assert_eq!(second.store.records.borrow().len(), 25);
+}
+
+#[test]
+fn test_dry_run() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut first = Device::new("first");
+    let mut second = Device::new("second");
+    first.store.insert("a", "from first");
+    first.sync(&init, &root_key);
+    second.sync(&init, &root_key);
+
+    first.store.insert("a", "changed by first");
+    first.store.insert("b", "from first");
+    first.sync(&init, &root_key);
+    second.store.insert("c", "from second");
+    let last_sync = second.store.last_sync.get();
+    let persisted_state = second.persisted_state.clone();
+
+    let preview = second.preview(&init, &root_key);
+    assert!(preview.failures.is_empty());
+    let addons = &preview.stores["addons"];
+    assert!(!addons.reset_required);
+    assert_eq!(
+        addons.local_changes,
+        vec![
+            LocalChange {
+                id: "a".into(),
+                kind: LocalChangeKind::Update,
+            },
+            LocalChange {
+                id: "b".into(),
+                kind: LocalChangeKind::Insert,
+            },
+        ]
+    );
+    let outgoing: Vec<&str> = addons
+        .outgoing
+        .changes
+        .iter()
+        .map(|payload| payload.id.as_str())
+        .collect();
+    assert_eq!(outgoing, vec!["c"]);
+
+    // Nothing should have changed, locally or on the server.
+    assert_eq!(second.store.get("a"), Some("from first".into()));
+    assert_eq!(second.store.get("b"), None);
+    assert_eq!(second.store.last_sync.get(), last_sync);
+    assert_eq!(second.persisted_state, persisted_state);
+    assert_eq!(server.records("alice", "addons").len(), 2);
+
+    // A store which isn't connected yet would be reset, but isn't.
+    let third = Device::new("third");
+    let preview = third.preview(&init, &root_key);
+    assert!(preview.stores["addons"].reset_required);
+    assert_eq!(
+        *third.store.assoc.borrow(),
+        StoreSyncAssociation::Disconnected
+    );
+
+    second.sync(&init, &root_key);
+    assert_eq!(second.store.get("b"), Some("from first".into()));
+    assert_eq!(server.records("alice", "addons").len(), 3);
+}
+
+#[test]
+fn test_key_rotation() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut first = Device::new("first");
+    let mut second = Device::new("second");
+    first.store.insert("a", "from first");
+    first.sync(&init, &root_key);
+    second.store.insert("b", "from second");
+    second.sync(&init, &root_key);
+    let keys_modified = server.collections("alice")["crypto"];
+
+    // Give addons its own key. The server's records are wiped, and the first
+    // device uploads what it has again.
+    first.rotate_keys(&init, &root_key, Some("addons"));
+    assert!(server.collections("alice")["crypto"] > keys_modified);
+    assert_eq!(server.records("alice", "addons").len(), 1);
+
+    // The second device notices the new key, and uploads everything again.
+    second.sync(&init, &root_key);
+    assert_eq!(sync_ids(&second.store), sync_ids(&first.store));
+    assert_eq!(server.records("alice", "addons").len(), 2);
+    first.sync(&init, &root_key);
+    assert_eq!(first.store.get("b"), Some("from second".into()));
+
+    // Changing the default key leaves addons alone.
+    let addons_modified = server.collections("alice")["addons"];
+    second.rotate_keys(&init, &root_key, None);
+    assert_eq!(server.collections("alice")["addons"], addons_modified);
+    first.store.insert("c", "from first");
+    first.sync(&init, &root_key);
+    second.sync(&init, &root_key);
+    assert_eq!(second.store.get("c"), Some("from first".into()));
+
+    // A new device can read everything with the new keys.
+    let mut third = Device::new("third");
+    third.sync(&init, &root_key);
+    assert_eq!(third.store.records.borrow().len(), 3);
+}
+
+#[test]
+fn test_key_rotation_after_restart() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut first = Device::new("first");
+    let mut second = Device::new("second");
+    first.store.insert("a", "from first");
+    first.sync(&init, &root_key);
+    second.store.insert("b", "from second");
+    second.sync(&init, &root_key);
+
+    first.rotate_keys(&init, &root_key, None);
+    assert_eq!(server.records("alice", "addons").len(), 1);
+
+    // The second device restarts, so it only has its persisted state, but
+    // still notices the new key, and uploads everything again.
+    second.mem_cached_state = MemoryCachedState::default();
+    second.sync(&init, &root_key);
+    assert_eq!(sync_ids(&second.store), sync_ids(&first.store));
+    assert_eq!(server.records("alice", "addons").len(), 2);
+}
+
+#[test]
+fn test_key_rotation_failures() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut first = Device::new("first");
+    let mut second = Device::new("second");
+    first.store.insert("a", "from first");
+    first.sync(&init, &root_key);
+    second.store.insert("b", "from second");
+    second.sync(&init, &root_key);
+    let token = first.mem_cached_state.export_token();
+    let keys_modified = server.collections("alice")["crypto"];
+    let old_fingerprint = addons_key_fingerprint(&first);
+
+    // If we can't wipe addons, we still reset the store and remember the new
+    // key, but don't sync it, as the server still has records encrypted with
+    // the old key.
+    server.set_undeletable("alice", Some("addons"));
+    let result = first
+        .try_rotate_keys(&init, &root_key, Some("addons"))
+        .expect("Uploading the new keys should succeed");
+    server.set_undeletable("alice", None);
+    match result.failures["addons"].kind() {
+        ErrorKind::StorageHttpError { code: 500, .. } => {}
+        e => panic!("Unexpected error {:?}", e),
+    }
+    assert!(server.collections("alice")["crypto"] > keys_modified);
+    assert_ne!(addons_key_fingerprint(&first), old_fingerprint);
+    assert_eq!(*first.store.changed.borrow(), vec!["a".to_string()]);
+    assert_eq!(server.records("alice", "addons").len(), 2);
+    // We reused the client from the last sync, and so its token.
+    assert_eq!(first.mem_cached_state.export_token(), token);
+
+    // If the server asks us to back off while we're rotating, we remember
+    // that, and leave syncing with the new keys until later.
+    server.set_backoff(Some(600));
+    let result = first
+        .try_rotate_keys(&init, &root_key, Some("addons"))
+        .expect("Rotating keys should succeed");
+    server.set_backoff(None);
+    assert!(result.failures.is_empty());
+    let next_sync_after = result.next_sync_after.expect("Should have backoff");
+    assert!(server.records("alice", "addons").is_empty());
+    let err = first.try_sync(&init, &root_key, false).unwrap_err();
+    assert_eq!(err.backoff_until(), Some(next_sync_after));
+
+    // Both devices upload everything again when they next sync.
+    first.try_sync(&init, &root_key, true).unwrap();
+    second.sync(&init, &root_key);
+    first.sync(&init, &root_key);
+    assert_eq!(first.store.get("b"), Some("from second".into()));
+    assert_eq!(server.records("alice", "addons").len(), 2);
+}
+
+fn addons_key_fingerprint(device: &Device) -> JsonValue {
+    let state: JsonValue = serde_json::from_str(device.persisted_state.as_ref().unwrap()).unwrap();
+    state["key_fingerprints"]["addons"].clone()
+}
+
+fn sync_ids(store: &TestStore) -> CollSyncIds {
+    match &*store.assoc.borrow() {
+        StoreSyncAssociation::Connected(ids) => ids.clone(),
+        StoreSyncAssociation::Disconnected => panic!("Should be connected"),
+    }
+}
+
+#[test]
+fn test_reset_after_server_wipe() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut device = Device::new("device");
+    device.store.insert("a", "value");
+    device.sync(&init, &root_key);
+    let old_ids = sync_ids(&device.store);
+
+    // Once the server has been wiped, we should start over with new sync IDs
+    // and upload everything we have again.
+    Sync15StorageClient::new(init.clone())
+        .unwrap()
+        .wipe_all_remote()
+        .unwrap();
+    assert!(server.collections("alice").is_empty());
+    device.sync(&init, &root_key);
+    assert_ne!(sync_ids(&device.store), old_ids);
+    assert_eq!(server.records("alice", "addons").len(), 1);
+}
+
+fn meta_global(server: &TestServer, access_token: &str) -> serde_json::Value {
+    let records = server.records(access_token, "meta");
+    let global = records
+        .iter()
+        .find(|bso| bso.id == "global")
+        .expect("Should have meta/global");
+    serde_json::from_str(&global.payload).unwrap()
+}
+
+#[test]
+fn test_wipe_remote() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut first = Device::new("first");
+    let mut second = Device::new("second");
+    first.store.insert("a", "from first");
+    first.sync(&init, &root_key);
+    second.store.insert("b", "from second");
+    second.sync(&init, &root_key);
+    let old_ids = sync_ids(&first.store);
+
+    // Wiping addons from the server gives it a new sync ID, but keeps what
+    // we have locally.
+    first.wipe_remote(&init, &root_key, Some("addons"), false);
+    assert!(server.records("alice", "addons").is_empty());
+    assert_eq!(server.records("alice", "clients").len(), 2);
+    let new_ids = sync_ids(&first.store);
+    assert_eq!(new_ids.global, old_ids.global);
+    assert_ne!(new_ids.coll, old_ids.coll);
+    assert_eq!(
+        meta_global(&server, "alice")["engines"]["addons"]["syncID"],
+        new_ids.coll
+    );
+    assert_eq!(first.store.records.borrow().len(), 1);
+
+    // The second device resets, and uploads everything it has again.
+    second.sync(&init, &root_key);
+    assert_eq!(sync_ids(&second.store), new_ids);
+    assert_eq!(server.records("alice", "addons").len(), 2);
+
+    // Wiping everything, and our local data, leaves nothing to upload.
+    first.wipe_remote(&init, &root_key, None, true);
+    assert!(server.records("alice", "addons").is_empty());
+    assert!(server.records("alice", "clients").is_empty());
+    assert!(first.store.records.borrow().is_empty());
+    first.sync(&init, &root_key);
+    assert!(server.records("alice", "addons").is_empty());
+}
+
+#[test]
+fn test_wipe_remote_failure() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut first = Device::new("first");
+    let mut second = Device::new("second");
+    first.store.insert("a", "from first");
+    first.sync(&init, &root_key);
+    second.store.insert("b", "from second");
+    second.sync(&init, &root_key);
+    let old_ids = sync_ids(&first.store);
+
+    // We wipe addons before failing to wipe clients, so addons still needs
+    // a new sync ID.
+    server.set_undeletable("alice", Some("clients"));
+    sync15::wipe_remote(
+        &[&first.store],
+        &mut first.persisted_state,
+        &mut first.mem_cached_state,
+        &init,
+        &root_key,
+        None,
+        false,
+        &NeverInterrupts,
+    )
+    .expect_err("Wiping clients should fail");
+    server.set_undeletable("alice", None);
+    assert!(server.records("alice", "addons").is_empty());
+    assert_eq!(server.records("alice", "clients").len(), 2);
+    let new_coll_id = meta_global(&server, "alice")["engines"]["addons"]["syncID"].clone();
+    assert_ne!(new_coll_id, json!(old_ids.coll));
+
+    // Both devices reset when they next sync, and upload everything again.
+    second.sync(&init, &root_key);
+    first.sync(&init, &root_key);
+    assert_eq!(json!(sync_ids(&first.store).coll), new_coll_id);
+    assert_eq!(sync_ids(&first.store), sync_ids(&second.store));
+    assert_eq!(server.records("alice", "addons").len(), 2);
+}
+
+#[test]
+fn test_reset_all() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut first = Device::new("first");
+    let mut second = Device::new("second");
+    first.store.insert("a", "from first");
+    first.sync(&init, &root_key);
+    second.store.insert("b", "from second");
+    second.sync(&init, &root_key);
+    let old_ids = sync_ids(&first.store);
+    let keys_modified = server.collections("alice")["crypto"];
+
+    // Starting over wipes the server, and uploads new sync IDs and keys.
+    first.reset_all(&init, &root_key);
+    let mut collections: Vec<String> = server.collections("alice").keys().cloned().collect();
+    collections.sort();
+    assert_eq!(collections, vec!["crypto", "meta"]);
+    assert!(server.collections("alice")["crypto"] > keys_modified);
+    let new_ids = sync_ids(&first.store);
+    assert_ne!(new_ids.global, old_ids.global);
+    assert_ne!(new_ids.coll, old_ids.coll);
+    assert_eq!(meta_global(&server, "alice")["syncID"], new_ids.global);
+
+    // Both devices upload everything they have again.
+    first.sync(&init, &root_key);
+    assert_eq!(server.records("alice", "addons").len(), 1);
+    second.sync(&init, &root_key);
+    assert_eq!(sync_ids(&second.store), new_ids);
+    assert_eq!(server.records("alice", "addons").len(), 2);
+    first.sync(&init, &root_key);
+    assert_eq!(first.store.get("b"), Some("from second".into()));
+}
+
+#[test]
+fn test_wipe_and_reset_backoff() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut device = Device::new("device");
+    device.store.insert("a", "value");
+    device.sync(&init, &root_key);
+    let token = device.mem_cached_state.export_token();
+
+    // Wiping reuses the client from the last sync, and remembers any
+    // backoff the server asks for.
+    server.set_backoff(Some(600));
+    device.wipe_remote(&init, &root_key, None, false);
+    server.set_backoff(None);
+    assert_eq!(device.mem_cached_state.export_token(), token);
+    let err = device.try_sync(&init, &root_key, false).unwrap_err();
+    assert!(err.backoff_until().is_some());
+    device.try_sync(&init, &root_key, true).unwrap();
+
+    // So does starting over.
+    server.set_backoff(Some(600));
+    device.reset_all(&init, &root_key);
+    server.set_backoff(None);
+    assert_eq!(device.mem_cached_state.export_token(), token);
+    let err = device.try_sync(&init, &root_key, false).unwrap_err();
+    assert!(err.backoff_until().is_some());
+    device.try_sync(&init, &root_key, true).unwrap();
+    assert_eq!(server.records("alice", "addons").len(), 1);
+}
+
+#[test]
+fn test_node_reassignment() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut device = Device::new("device");
+    device.store.insert("a", "value");
+    device.sync(&init, &root_key);
+    device.sync(&init, &root_key);
+    let old_ids = sync_ids(&device.store);
+
+    // The new node is empty, and our cached token is for the old node, so
+    // we'll get a 401 and need to start over with a new token.
+    server.reassign_node("alice");
+    assert!(server.collections("alice").is_empty());
+    device.sync(&init, &root_key);
+    assert_ne!(sync_ids(&device.store), old_ids);
+    assert_eq!(server.records("alice", "addons").len(), 1);
+    assert_eq!(server.records("alice", "clients").len(), 1);
+
+    // And we keep syncing with the new node.
+    device.store.insert("b", "value");
+    device.sync(&init, &root_key);
+    assert_eq!(server.records("alice", "addons").len(), 2);
+}
+
+#[test]
+fn test_engine_changes() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut first = Device::new("first");
+    let mut second = Device::new("second");
+    first.store.insert("a", "from first");
+    first.sync(&init, &root_key);
+    second.sync(&init, &root_key);
+    let old_ids = sync_ids(&second.store);
+
+    // Declining addons should remove it from `meta/global`, and disconnect
+    // our store.
+    first.engine_changes.insert("addons".into(), false);
+    first.sync(&init, &root_key);
+    let global = meta_global(&server, "alice");
+    assert!(global["engines"].get("addons").is_none());
+    assert_eq!(global["declined"], json!(["addons"]));
+    assert_eq!(
+        *first.store.assoc.borrow(),
+        StoreSyncAssociation::Disconnected
+    );
+    // So neither device syncs addons.
+    first.store.insert("b", "from first");
+    first.sync(&init, &root_key);
+    second.sync(&init, &root_key);
+    assert_eq!(server.records("alice", "addons").len(), 1);
+    assert_eq!(second.store.get("b"), None);
+
+    // Enabling it again gives it a new sync ID, so both devices start over
+    // and upload everything.
+    second.store.insert("c", "from second");
+    first.engine_changes.insert("addons".into(), true);
+    first.sync(&init, &root_key);
+    let global = meta_global(&server, "alice");
+    assert!(global["engines"].get("addons").is_some());
+    assert_eq!(global["declined"], json!([]));
+    second.sync(&init, &root_key);
+    first.sync(&init, &root_key);
+    assert_ne!(sync_ids(&second.store), old_ids);
+    assert_eq!(sync_ids(&first.store), sync_ids(&second.store));
+    assert_eq!(server.records("alice", "addons").len(), 3);
+    assert_eq!(first.store.get("c"), Some("from second".into()));
+    assert_eq!(second.store.get("b"), Some("from first".into()));
+}
+
+#[test]
+fn test_backoff() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut device = Device::new("device");
+    device.sync(&init, &root_key);
+
+    // The sync where the server asks us to back off still succeeds, but
+    // tells us when we can sync next.
+    server.set_backoff(Some(600));
+    device.store.insert("a", "value");
+    let result = device.try_sync(&init, &root_key, false).unwrap();
+    assert!(result.failures.is_empty());
+    let next_sync_after = result.next_sync_after.expect("Should have backoff");
+    assert!(next_sync_after > SystemTime::now() + Duration::from_secs(590));
+    assert_eq!(server.records("alice", "addons").len(), 1);
+
+    // We should refuse to sync again until then...
+    server.set_backoff(None);
+    device.store.insert("b", "value");
+    let err = device.try_sync(&init, &root_key, false).unwrap_err();
+    assert_eq!(err.backoff_until(), Some(next_sync_after));
+    assert_eq!(server.records("alice", "addons").len(), 1);
+
+    // ...unless we're forced to, which clears the backoff.
+    let result = device.try_sync(&init, &root_key, true).unwrap();
+    assert!(result.failures.is_empty());
+    assert_eq!(result.next_sync_after, None);
+    assert_eq!(server.records("alice", "addons").len(), 2);
+    device.sync(&init, &root_key);
+}
+
+#[test]
+fn test_service_unavailable() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let mut device = Device::new("device");
+    device.sync(&init, &root_key);
+
+    server.set_unavailable(Some(300));
+    device.store.insert("a", "value");
+    let err = device.try_sync(&init, &root_key, false).unwrap_err();
+    let backoff_until = err.backoff_until().expect("Should be a backoff error");
+    assert!(backoff_until > SystemTime::now() + Duration::from_secs(290));
+
+    // The backoff is persisted, so we don't try again even once the server
+    // recovers, and even with a fresh in-memory state.
+    server.set_unavailable(None);
+    device.mem_cached_state = MemoryCachedState::default();
+    let err = device.try_sync(&init, &root_key, false).unwrap_err();
+    let persisted_until = err.backoff_until().expect("Should be a backoff error");
+    assert!(backoff_until.duration_since(persisted_until).unwrap() < Duration::from_millis(1));
+    assert!(server.records("alice", "addons").is_empty());
+
+    device.try_sync(&init, &root_key, true).unwrap();
+    assert_eq!(server.records("alice", "addons").len(), 1);
+}
+
+#[test]
+fn test_hashed_uid() {
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    // We record failing to fetch a token like a failed sync.
+    let mut device = Device::new("device");
+    let mut bad_init = init.clone();
+    bad_init.tokenserver_url = server.tokenserver_url().join("missing/").unwrap();
+    let mut ping = telemetry::SyncTelemetryPing::new();
+    device
+        .mem_cached_state
+        .hashed_uid(&mut device.persisted_state, &bad_init, &mut ping, false)
+        .unwrap_err();
+    let ping = serde_json::to_value(&ping).unwrap();
+    assert_eq!(
+        ping["syncs"][0]["failureReason"],
+        json!({"name": "httperror", "code": 404})
+    );
+
+    let mut ping = telemetry::SyncTelemetryPing::new();
+    let uid = device
+        .mem_cached_state
+        .hashed_uid(&mut device.persisted_state, &init, &mut ping, false)
+        .unwrap();
+    device.sync(&init, &root_key);
+
+    // While the server wants us to back off, we don't fetch a token to find
+    // the uid, even with a fresh in-memory state...
+    server.set_unavailable(Some(300));
+    device.store.insert("a", "value");
+    device.try_sync(&init, &root_key, false).unwrap_err();
+    server.set_unavailable(None);
+    device.mem_cached_state = MemoryCachedState::default();
+    let err = device
+        .mem_cached_state
+        .hashed_uid(&mut device.persisted_state, &init, &mut ping, false)
+        .unwrap_err();
+    assert!(err.backoff_until().is_some());
+    assert!(device.mem_cached_state.export_token().is_none());
+
+    // ...unless we're forced to.
+    let forced_uid = device
+        .mem_cached_state
+        .hashed_uid(&mut device.persisted_state, &init, &mut ping, true)
+        .unwrap();
+    assert_eq!(forced_uid, uid);
+    assert!(device.mem_cached_state.export_token().is_some());
+}
+
+#[test]
+fn test_logins() {
+    use logins::{Login, PasswordEngine};
+
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let first = PasswordEngine::new_in_memory(None).unwrap();
+    let second = PasswordEngine::new_in_memory(None).unwrap();
+
+    let id = first
+        .add(Login {
+            hostname: "https://www.example.com".into(),
+            form_submit_url: Some("https://www.example.com/login".into()),
+            username: "alice".into(),
+            password: "hunter2".into(),
+            ..Login::default()
+        })
+        .unwrap();
+    first
+        .sync(
+            &init,
+            &root_key,
+            None,
+            &mut telemetry::SyncTelemetryPing::new(),
+            false,
+        )
+        .unwrap();
+    assert_eq!(server.records("alice", "passwords").len(), 1);
+
+    second
+        .sync(
+            &init,
+            &root_key,
+            None,
+            &mut telemetry::SyncTelemetryPing::new(),
+            false,
+        )
+        .unwrap();
+    let login = second.get(&id).unwrap().expect("Login should have synced");
+    assert_eq!(login.password, "hunter2");
+
+    // A dry run reports the change without applying it.
+    first
+        .update(Login {
+            password: "hunter3".into(),
+            ..login
+        })
+        .unwrap();
+    first
+        .sync(
+            &init,
+            &root_key,
+            None,
+            &mut telemetry::SyncTelemetryPing::new(),
+            false,
+        )
+        .unwrap();
+    let preview = second
+        .preview_sync(&init, &root_key)
+        .unwrap()
+        .expect("Passwords should be enabled");
+    assert_eq!(
+        preview.local_changes,
+        vec![LocalChange {
+            id: id.clone(),
+            kind: LocalChangeKind::Update,
+        }]
+    );
+    assert!(preview.outgoing.changes.is_empty());
+    assert_eq!(second.get(&id).unwrap().unwrap().password, "hunter2");
+
+    second.delete(&id).unwrap();
+    second
+        .sync(
+            &init,
+            &root_key,
+            None,
+            &mut telemetry::SyncTelemetryPing::new(),
+            false,
+        )
+        .unwrap();
+    first
+        .sync(
+            &init,
+            &root_key,
+            None,
+            &mut telemetry::SyncTelemetryPing::new(),
+            false,
+        )
+        .unwrap();
+    assert!(first.get(&id).unwrap().is_none());
+}
+
+#[test]
+fn test_history_and_bookmarks() {
+    use places::storage::bookmarks::{
+        self, BookmarkPosition, BookmarkRootGuid, BookmarkTreeNode, InsertableBookmark,
+        InsertableItem,
+    };
+    use places::{
+        storage::history, ConnectionType, PlacesApi, Timestamp, VisitObservation, VisitTransition,
+    };
+
+    let _ = env_logger::try_init();
+    let server = TestServer::start();
+    let init = storage_init(&server, "alice");
+    let root_key = KeyBundle::new_random().unwrap();
+
+    let first = PlacesApi::new_memory("sync_test_server_first").unwrap();
+    let second = PlacesApi::new_memory("sync_test_server_second").unwrap();
+    let url = url::Url::parse("https://www.example.com/").unwrap();
+    {
+        let mut conn = first.open_connection(ConnectionType::ReadWrite).unwrap();
+        places::apply_observation(
+            &mut conn,
+            VisitObservation::new(url.clone())
+                .with_title("Example".to_string())
+                .with_visit_type(VisitTransition::Link)
+                .with_at(Timestamp::now()),
+        )
+        .unwrap();
+        bookmarks::insert_bookmark(
+            &conn,
+            &InsertableItem::Bookmark(InsertableBookmark {
+                parent_guid: BookmarkRootGuid::Unfiled.into(),
+                position: BookmarkPosition::Append,
+                date_added: None,
+                last_modified: None,
+                guid: None,
+                url: url.clone(),
+                title: Some("Example".into()),
+            }),
+        )
+        .unwrap();
+    }
+
+    first.sync_history(&init, &root_key, None, false).unwrap();
+    first.sync_bookmarks(&init, &root_key, None, false).unwrap();
+    assert_eq!(server.records("alice", "history").len(), 1);
+
+    second.sync_history(&init, &root_key, None, false).unwrap();
+    second
+        .sync_bookmarks(&init, &root_key, None, false)
+        .unwrap();
+
+    let conn = second.open_connection(ConnectionType::ReadOnly).unwrap();
+    let visited = history::get_visited_urls(&conn, Timestamp(0), Timestamp::now(), true).unwrap();
+    assert_eq!(visited, vec![url.to_string()]);
+
+    let unfiled = bookmarks::fetch_tree(&conn, &BookmarkRootGuid::Unfiled.as_guid())
+        .unwrap()
+        .expect("Unfiled should exist");
+    let children = match unfiled {
+        BookmarkTreeNode::Folder(folder) => folder.children,
+        _ => panic!("Unfiled should be a folder"),
+    };
+    assert_eq!(children.len(), 1);
+    match &children[0] {
+        BookmarkTreeNode::Bookmark(bookmark) => assert_eq!(bookmark.url, url),
+        _ => panic!("Should have synced a bookmark"),
+    }
+}
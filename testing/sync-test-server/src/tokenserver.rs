@@ -0,0 +1,163 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! A minimal tokenserver. Each distinct OAuth access token is treated as a
+//! different user, and every token we issue maps back to that user's uid.
+//! We don't verify the hawk MAC on storage requests - we only look at the
+//! `id` so we know which user is making the request.
+
+use crate::http::{Request, Response};
+use serde_json::json;
+use std::collections::HashMap;
+
+/// How long the tokens we issue are valid for, in seconds.
+const TOKEN_DURATION: u64 = 3600;
+
+#[derive(Debug, Default)]
+pub struct TokenServer {
+    uids_by_access_token: HashMap<String, u64>,
+    // The storage node each user is assigned to. Users start on node 0.
+    nodes_by_uid: HashMap<u64, u32>,
+    // The user and node each token was issued for.
+    users_by_token_id: HashMap<String, (u64, u32)>,
+    tokens_issued: u64,
+}
+
+impl TokenServer {
+    /// Returns the uid for an access token, allocating one if we haven't
+    /// seen it before.
+    pub fn uid_for_access_token(&mut self, access_token: &str) -> u64 {
+        let next_uid = self.uids_by_access_token.len() as u64 + 1;
+        *self
+            .uids_by_access_token
+            .entry(access_token.to_string())
+            .or_insert(next_uid)
+    }
+
+    /// Returns the storage node a user is assigned to.
+    pub fn node_for_uid(&self, uid: u64) -> u32 {
+        self.nodes_by_uid.get(&uid).cloned().unwrap_or_default()
+    }
+
+    /// Moves a user to a new storage node. Tokens issued for their old node
+    /// won't be accepted any more, and new tokens will have a different
+    /// `api_endpoint`.
+    pub fn reassign_node(&mut self, uid: u64) {
+        let node = self.node_for_uid(uid) + 1;
+        self.nodes_by_uid.insert(uid, node);
+    }
+
+    /// Handles `GET /token/1.0/sync/1.5`. `storage_base` is the URL of the
+    /// storage server, without a trailing slash.
+    pub fn issue_token(
+        &mut self,
+        request: &Request,
+        storage_base: &str,
+        now_secs: u64,
+    ) -> Response {
+        let access_token = match request
+            .header("Authorization")
+            .filter(|auth| auth.starts_with("Bearer "))
+        {
+            Some(auth) => auth["Bearer ".len()..].trim().to_string(),
+            None => {
+                return Response::json(401, &json!({ "status": "invalid-credentials" }));
+            }
+        };
+        let uid = self.uid_for_access_token(&access_token);
+        let node = self.node_for_uid(uid);
+        self.tokens_issued += 1;
+        let id = format!("token-{}-{}", uid, self.tokens_issued);
+        self.users_by_token_id.insert(id.clone(), (uid, node));
+        // Users on the first node keep the simplest URL.
+        let node_base = match node {
+            0 => storage_base.to_string(),
+            node => format!("{}/node{}", storage_base, node),
+        };
+        Response::json(
+            200,
+            &json!({
+                "id": id,
+                "key": format!("key-{}", id),
+                "api_endpoint": format!("{}/1.5/{}", node_base, uid),
+                "uid": uid,
+                "duration": TOKEN_DURATION,
+                "hashed_fxa_uid": format!("{:032x}", uid),
+            }),
+        )
+        .header("X-Timestamp", now_secs)
+    }
+
+    /// Returns the uid of the user making a storage request and the node
+    /// their token was issued for, or None if the request isn't signed with
+    /// a token we issued.
+    pub fn authenticate(&self, request: &Request) -> Option<(u64, u32)> {
+        let auth = request.header("Authorization")?;
+        if !auth.starts_with("Hawk ") {
+            return None;
+        }
+        let id = auth["Hawk ".len()..]
+            .split(',')
+            .map(str::trim)
+            .find(|param| param.starts_with("id="))?["id=".len()..]
+            .trim_matches('"');
+        self.users_by_token_id.get(id).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(auth: &str) -> Request {
+        let mut headers = HashMap::new();
+        headers.insert("authorization".to_string(), auth.to_string());
+        Request {
+            method: "GET".into(),
+            path: vec![],
+            query: HashMap::new(),
+            headers,
+            body: vec![],
+        }
+    }
+
+    #[test]
+    fn test_tokens() {
+        let mut ts = TokenServer::default();
+        let resp = ts.issue_token(&request("Bearer alice"), "http://localhost", 1000);
+        assert_eq!(resp.status, 200);
+        let token: serde_json::Value = serde_json::from_slice(&resp.body).unwrap();
+        assert_eq!(token["api_endpoint"], "http://localhost/1.5/1");
+        assert_eq!(token["uid"], 1);
+
+        // The same access token is the same user.
+        let resp = ts.issue_token(&request("Bearer alice"), "http://localhost", 1000);
+        let again: serde_json::Value = serde_json::from_slice(&resp.body).unwrap();
+        assert_eq!(again["uid"], 1);
+        assert_ne!(again["id"], token["id"]);
+
+        let resp = ts.issue_token(&request("Bearer bob"), "http://localhost", 1000);
+        let bob: serde_json::Value = serde_json::from_slice(&resp.body).unwrap();
+        assert_eq!(bob["uid"], 2);
+
+        let hawk = format!(
+            "Hawk id=\"{}\", ts=\"1000\", nonce=\"abc\", mac=\"xyz\"",
+            token["id"].as_str().unwrap()
+        );
+        assert_eq!(ts.authenticate(&request(&hawk)), Some((1, 0)));
+        assert_eq!(ts.authenticate(&request("Hawk id=\"unknown\"")), None);
+        assert_eq!(ts.authenticate(&request("Bearer alice")), None);
+
+        let resp = ts.issue_token(&request("Basic xyz"), "http://localhost", 1000);
+        assert_eq!(resp.status, 401);
+
+        // After moving to a new node, the old token is still for the old
+        // node, and new tokens have a new endpoint.
+        ts.reassign_node(1);
+        assert_eq!(ts.authenticate(&request(&hawk)), Some((1, 0)));
+        let resp = ts.issue_token(&request("Bearer alice"), "http://localhost", 1000);
+        let moved: serde_json::Value = serde_json::from_slice(&resp.body).unwrap();
+        assert_eq!(moved["api_endpoint"], "http://localhost/node1/1.5/1");
+    }
+}
@@ -77,9 +77,13 @@ pub fn touch_login(e: &PasswordEngine, id: &str, times: usize) -> LoginResult<Lo
 
 pub fn sync_logins(client: &mut TestClient) -> Result<(), failure::Error> {
     let (init, key) = client.data_for_sync()?;
-    client
-        .logins_engine
-        .sync(&init, &key, &mut telemetry::SyncTelemetryPing::new())?;
+    client.logins_engine.sync(
+        &init,
+        &key,
+        None,
+        &mut telemetry::SyncTelemetryPing::new(),
+        false,
+    )?;
     Ok(())
 }
 